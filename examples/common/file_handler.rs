@@ -1,7 +1,7 @@
 use rustot::ota::pal::{OtaPal, OtaPalError, PalImageState};
 use sha2::{Digest, Sha256};
 use std::fs::File;
-use std::io::{Cursor, Write};
+use std::io::{Cursor, Read, Write};
 
 pub struct FileHandler {
     filebuf: Option<Cursor<Vec<u8>>>,
@@ -82,6 +82,20 @@ impl OtaPal for FileHandler {
         }
     }
 
+    fn read_block(
+        &mut self,
+        _file: &rustot::ota::encoding::FileContext,
+        block_offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        if let Some(ref mut filebuf) = &mut self.filebuf {
+            filebuf.set_position(block_offset as u64);
+            filebuf.read(buf).map_err(|_e| OtaPalError::BadFileHandle)
+        } else {
+            Err(OtaPalError::BadFileHandle)
+        }
+    }
+
     fn get_active_firmware_version(
         &self,
     ) -> Result<rustot::ota::pal::Version, OtaPalError<Self::Error>> {
@@ -1,6 +1,5 @@
 mod common;
 
-use jobs::data_types::NextJobExecutionChanged;
 use mqttrust_core::bbqueue::BBBuffer;
 use mqttrust_core::PublishNotification;
 use mqttrust_core::{EventLoop, MqttOptions, Notification};
@@ -12,8 +11,7 @@ use common::clock::SysClock;
 use common::file_handler::FileHandler;
 use common::network::Network;
 use ota::encoding::json::OtaJob;
-use rustot::jobs::data_types::DescribeJobExecutionResponse;
-use rustot::jobs::{self, StatusDetails};
+use rustot::jobs::{owned_status_details, JobMessage, StatusDetails};
 use rustot::ota;
 use rustot::ota::agent::OtaAgent;
 use std::thread;
@@ -39,41 +37,30 @@ impl<'a> Jobs<'a> {
 
 enum OtaUpdate<'a> {
     JobUpdate(&'a str, OtaJob<'a>, Option<StatusDetails>),
-    Data(&'a mut [u8]),
+    Data(&'a [u8]),
 }
 
 fn handle_ota<'a>(publish: &'a mut PublishNotification) -> Result<OtaUpdate<'a>, ()> {
-    match jobs::Topic::from_str(publish.topic_name.as_str()) {
-        Some(jobs::Topic::NotifyNext) => {
-            let (execution_changed, _) =
-                serde_json_core::from_slice::<NextJobExecutionChanged<Jobs>>(&publish.payload)
-                    .map_err(drop)?;
-            let job = execution_changed.execution.ok_or(())?;
-            let ota_job = job.job_document.ok_or(())?.ota_job().ok_or(())?;
-            return Ok(OtaUpdate::JobUpdate(
-                job.job_id,
-                ota_job,
-                job.status_details,
-            ));
-        }
-        Some(jobs::Topic::DescribeAccepted(_)) => {
-            let (execution_changed, _) =
-                serde_json_core::from_slice::<DescribeJobExecutionResponse<Jobs>>(&publish.payload)
-                    .map_err(drop)?;
-            let job = execution_changed.execution.ok_or(())?;
-            let ota_job = job.job_document.ok_or(())?.ota_job().ok_or(())?;
-            return Ok(OtaUpdate::JobUpdate(
-                job.job_id,
-                ota_job,
-                job.status_details,
-            ));
-        }
-        _ => {}
+    if let Some(message) =
+        JobMessage::<Jobs>::from_slice(publish.topic_name.as_str(), &publish.payload)
+            .map_err(drop)?
+    {
+        let job = message.execution().ok_or(())?;
+        let ota_job = job.job_document.ok_or(())?.ota_job().ok_or(())?;
+        return Ok(OtaUpdate::JobUpdate(
+            job.job_id,
+            ota_job,
+            job.status_details
+                .as_ref()
+                .map(owned_status_details)
+                .transpose()
+                .map_err(drop)?,
+        ));
     }
 
     match ota::Topic::from_str(publish.topic_name.as_str()) {
         Some(ota::Topic::Data(_, _)) => {
-            return Ok(OtaUpdate::Data(&mut publish.payload));
+            return Ok(OtaUpdate::Data(&publish.payload));
         }
         _ => {}
     }
@@ -0,0 +1,178 @@
+//! Derive macros for [rustot](https://docs.rs/rustot), re-exported by it.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, Lit, Meta, NestedMeta};
+
+/// Derive `rustot::shadows::ShadowState` for a struct with named fields.
+///
+/// Generates `Partial<Name>`, a copy of the struct with every field wrapped
+/// in an `Option`, along with patching and diffing the state field by field.
+/// The fields must implement `Clone`, `PartialEq`, `Serialize` and
+/// `DeserializeOwned`. `#[serde(rename = "..")]` of the fields and
+/// `#[serde(rename_all = "..")]` of the struct carry over to the partial
+/// state.
+///
+/// The state of a named shadow is marked with `#[shadow(name = "..")]`:
+///
+/// ```ignore
+/// #[derive(Clone, Serialize, Deserialize, ShadowState)]
+/// #[shadow(name = "config")]
+/// pub struct Config {
+///     #[serde(rename = "intervalS")]
+///     pub interval_s: u32,
+///     pub led: bool,
+/// }
+/// ```
+#[proc_macro_derive(ShadowState, attributes(shadow))]
+pub fn derive_shadow_state(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    shadow_state(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn shadow_state(input: DeriveInput) -> Result<TokenStream2, Error> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "ShadowState cannot be derived for generic structs",
+        ));
+    }
+
+    let fields = match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "ShadowState can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "ShadowState can only be derived for structs",
+            ))
+        }
+    };
+
+    let vis = &input.vis;
+    let ident = &input.ident;
+    let partial = format_ident!("Partial{}", ident);
+    let name = match shadow_name(&input.attrs)? {
+        Some(name) => quote!(Some(#name)),
+        None => quote!(None),
+    };
+    let container_attrs = serde_attrs(&input.attrs, "rename_all")?;
+
+    let mut partial_fields = Vec::new();
+    let mut patches = Vec::new();
+    let mut diffs = Vec::new();
+    for field in fields {
+        let field_vis = &field.vis;
+        let field_ident = &field.ident;
+        let ty = &field.ty;
+        let attrs = serde_attrs(&field.attrs, "rename")?;
+
+        partial_fields.push(quote! {
+            #(#attrs)*
+            #[serde(default)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            #field_vis #field_ident: ::core::option::Option<#ty>
+        });
+        patches.push(quote! {
+            if let ::core::option::Option::Some(value) = partial.#field_ident {
+                self.#field_ident = value;
+            }
+        });
+        diffs.push(quote! {
+            #field_ident: if self.#field_ident != other.#field_ident {
+                ::core::option::Option::Some(::core::clone::Clone::clone(&other.#field_ident))
+            } else {
+                ::core::option::Option::None
+            }
+        });
+    }
+
+    let doc = format!(
+        "[`{}`] with all fields optional, generated by `#[derive(ShadowState)]`",
+        ident
+    );
+
+    Ok(quote! {
+        #[doc = #doc]
+        #[derive(Debug, Default, Clone, PartialEq, ::serde::Serialize, ::serde::Deserialize)]
+        #(#container_attrs)*
+        #vis struct #partial {
+            #(#partial_fields,)*
+        }
+
+        impl ::rustot::shadows::ShadowState for #ident {
+            const NAME: ::core::option::Option<&'static str> = #name;
+
+            type PartialState = #partial;
+
+            fn apply_patch(&mut self, partial: Self::PartialState) {
+                #(#patches)*
+            }
+
+            fn diff(&self, other: &Self) -> Self::PartialState {
+                #partial {
+                    #(#diffs,)*
+                }
+            }
+        }
+    })
+}
+
+/// The name of `#[shadow(name = "..")]`, if any
+fn shadow_name(attrs: &[Attribute]) -> Result<Option<String>, Error> {
+    let mut name = None;
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("shadow")) {
+        match attr.parse_meta()? {
+            Meta::List(list) => {
+                for nested in list.nested {
+                    match nested {
+                        NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("name") => {
+                            match nv.lit {
+                                Lit::Str(s) => name = Some(s.value()),
+                                lit => return Err(Error::new_spanned(lit, "expected a string")),
+                            }
+                        }
+                        nested => {
+                            return Err(Error::new_spanned(nested, "expected `name = \"..\"`"))
+                        }
+                    }
+                }
+            }
+            meta => {
+                return Err(Error::new_spanned(
+                    meta,
+                    "expected `#[shadow(name = \"..\")]`",
+                ))
+            }
+        }
+    }
+    Ok(name)
+}
+
+/// The `#[serde(key = "..")]` among the serde attributes, to carry over to
+/// the partial state
+fn serde_attrs(attrs: &[Attribute], key: &str) -> Result<Vec<TokenStream2>, Error> {
+    let mut carried = Vec::new();
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("serde")) {
+        if let Meta::List(list) = attr.parse_meta()? {
+            for nested in list.nested {
+                if let NestedMeta::Meta(Meta::NameValue(nv)) = nested {
+                    if nv.path.is_ident(key) {
+                        carried.push(quote!(#[serde(#nv)]));
+                    }
+                }
+            }
+        }
+    }
+    Ok(carried)
+}
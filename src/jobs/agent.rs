@@ -0,0 +1,2182 @@
+//! High level jobs agent, taking care of the jobs topic traffic.
+//!
+//! The agent claims the next pending job execution through
+//! StartNextPendingJobExecution, hands it to the application's
+//! [`JobHandler`], reports the outcome through UpdateJobExecution, and then
+//! claims the next job, until no job is pending. It then waits for a
+//! notification on `notify-next` before starting over.
+//!
+//! Updates are sent with the last known `versionNumber` of the job execution
+//! as `expectedVersion`. If the service rejects one with `VersionMismatch`,
+//! the agent describes the job to learn its current version, and sends the
+//! update again, unless the job reached a terminal state in the meantime.
+//!
+//! Each request is sent with its own client token, and responses are only
+//! acted upon if they answer a request of the agent still in flight.
+//!
+//! A final update rejected with a transient error, e.g. as the request was
+//! throttled, is sent again after a backoff, doubling from [`RETRY_WAIT_MS`]
+//! up to [`MAX_RETRY_WAIT_MS`], for at most [`MAX_RETRIES`] times.
+//!
+//! With a step timeout set through [`JobsAgent::with_step_timeout`], jobs are
+//! claimed with that timeout, and halfway through it the running job is kept
+//! alive with an IN_PROGRESS heartbeat resetting the timeout, unless the
+//! handler declines it in [`JobHandler::step_timeout`]. Progress updates reset
+//! the timeout as well.
+//!
+//! With a minimum update interval set through
+//! [`JobsAgent::with_min_update_interval`], progress reported less than that
+//! interval after the previous update of the running job is held back, and
+//! only the latest progress held back is sent once the interval elapsed.
+//! Final updates are never delayed, and supersede any progress held back.
+//!
+//! Job documents are deserialized in place, so jobs of any size are handled.
+//! The raw document of the running job can also be kept by the agent, up to a
+//! capacity chosen through [`JobsAgent::with_job_document`], e.g. a few
+//! hundred bytes on small sensors, or several kilobytes on gateways.
+//!
+//! A job the device can't apply right away, e.g. outside of its maintenance
+//! window, can be put off through [`JobsAgent::defer`]. The job is kept
+//! IN_PROGRESS, with the delay as a reschedule hint in its status details
+//! under [`RESCHEDULE_KEY`], and once the delay expired the agent describes it
+//! again and hands it to [`JobHandler::handle`] once more.
+//!
+//! With a request timeout set through [`JobsAgent::with_request_timeout`],
+//! requests the agent can't move on without, i.e. claiming the next job,
+//! describing the job and reporting its outcome, are given up on once no
+//! response arrived in time: [`JobsAgent::timer_callback`] returns
+//! [`JobError::Timeout`], and the application can send the request again
+//! through [`JobsAgent::retry`], e.g. after reconnecting. A response arriving
+//! late is still handled.
+//!
+//! The job in progress can be made to survive resets by giving the agent an
+//! [`ExecutionStorage`], see [`storage`](super::storage).
+//!
+//! The backoff, the heartbeats, the update interval and deferrals all run on
+//! the timer given to the agent, and [`JobsAgent::timer_callback`] is to be
+//! called on its expiry, or periodically.
+//!
+//! ```ignore
+//! struct Handler;
+//!
+//! impl<'a> JobHandler<Jobs<'a>> for Handler {
+//!     fn handle(&mut self, execution: &JobExecution<'_, Jobs<'a>>) -> JobOutcome {
+//!         match execution.job_document {
+//!             Some(Jobs::Reboot(_)) => JobOutcome::Pending,
+//!             _ => JobOutcome::Rejected,
+//!         }
+//!     }
+//! }
+//!
+//! let mut agent = JobsAgent::new(&mqtt_client, Handler, timer).with_step_timeout(10);
+//! agent.init()?;
+//!
+//! loop {
+//!     if let Ok(Notification::Publish(publish)) = mqtt_eventloop.yield_event(&mut network) {
+//!         agent.handle_message::<Jobs>(publish.topic_name.as_str(), &publish.payload)?;
+//!     }
+//!     agent.timer_callback::<Jobs>()?;
+//! }
+//! ```
+
+use core::fmt::Write;
+
+use embedded_hal::timer;
+use mqttrust::{Mqtt, QoS};
+use serde::Deserialize;
+
+use crate::ota::clock::Clock;
+
+use super::{
+    client_token::{ClientToken, ClientTokens},
+    data_types::{ErrorCode, JobExecution, JobStatus},
+    event::{JobsEvent, RawJobsEvent},
+    message::raw_job_document,
+    pending::PendingSnapshot,
+    storage::{ExecutionStorage, StoredExecution},
+    JobError, JobMessage, Jobs, StatusDetails, Topic, MAX_JOB_ID_LEN,
+};
+
+/// Wait before the first retry of a final update
+pub const RETRY_WAIT_MS: u32 = 1000;
+/// Upper bound of the wait between retries of a final update
+pub const MAX_RETRY_WAIT_MS: u32 = 32000;
+/// Number of times a final update is retried before giving up on it
+pub const MAX_RETRIES: u8 = 5;
+/// Status details key of the delay in milliseconds after which a deferred job
+/// is picked up again, see [`JobsAgent::defer`]
+pub const RESCHEDULE_KEY: &str = "rescheduleInMs";
+
+/// Outcome of a job, as reported by a [`JobHandler`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum JobOutcome {
+    /// The job is still running. Its outcome is to be reported through
+    /// [`JobsAgent::complete`].
+    Pending,
+    Succeeded,
+    Failed,
+    /// The device can not run the job, e.g. as it does not know the job
+    /// document.
+    Rejected,
+}
+
+/// Application specific processing of jobs, with job documents deserialized
+/// into `J`.
+pub trait JobHandler<J> {
+    /// Start the job execution claimed by the agent
+    fn handle(&mut self, execution: &JobExecution<'_, J>) -> JobOutcome;
+
+    /// The running job is no longer pending on the service side, e.g. as it
+    /// was canceled, and should be stopped.
+    fn cancel(&mut self, _job_id: &str) {}
+
+    /// Half the step timeout of the running job elapsed. Returns whether the
+    /// agent is to reset the timeout with a heartbeat. Otherwise, the job
+    /// times out unless completed or updated with progress in time.
+    fn step_timeout(&mut self, _job_id: &str) -> bool {
+        true
+    }
+
+    /// Resume the job execution left running before a reset, see
+    /// [`JobsAgent::with_storage`]. By default the job is reported as failed,
+    /// as whatever it was doing was interrupted.
+    fn resume(&mut self, _execution: &JobExecution<'_, J>) -> JobOutcome {
+        JobOutcome::Failed
+    }
+}
+
+/// State of the [`JobsAgent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum JobsState {
+    /// Not subscribed to the jobs topics yet
+    Init,
+    /// Waiting for the response to a StartNextPendingJobExecution request
+    Requesting,
+    /// No job pending, waiting for a notification
+    Idle,
+    /// A job is being processed by the handler
+    Running,
+    /// The outcome of the job was sent, waiting for the service to accept it,
+    /// or for the backoff of a retry to expire
+    Updating,
+    /// An update was rejected with a version mismatch, waiting for the
+    /// current state of the job execution
+    Reconciling,
+    /// Waiting for the current state of the job execution stored before a
+    /// reset
+    Resuming,
+    /// The running job was put off by the application, waiting for the delay
+    /// to expire, or for the current state of the job execution once it did
+    Deferred,
+}
+
+/// Requests of the agent waiting for a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Request {
+    StartNext,
+    Progress,
+    Heartbeat,
+    FinalUpdate,
+    Defer,
+    Describe,
+}
+
+/// Agent processing the jobs of the thing. The raw job document of the
+/// running job is kept if at most `D` bytes, see
+/// [`JobsAgent::with_job_document`].
+pub struct JobsAgent<'a, M: Mqtt, H, T, const D: usize = 0> {
+    mqtt: &'a M,
+    handler: H,
+    /// Heartbeat timer while running, backoff timer of retries while updating
+    timer: T,
+    state: JobsState,
+    job_id: heapless::String<MAX_JOB_ID_LEN>,
+    execution_number: Option<i64>,
+    version_number: Option<i64>,
+    /// Final status of the job, kept until accepted by the service
+    final_update: Option<(JobStatus, Option<StatusDetails>)>,
+    requests: ClientTokens<Request, 4>,
+    step_timeout_in_minutes: Option<i64>,
+    min_update_interval_ms: Option<u32>,
+    /// Set while the timer runs the minimum interval after an update
+    throttled: bool,
+    /// Latest progress reported during the minimum update interval
+    coalesced: Option<StatusDetails>,
+    /// Retries of the final update sent so far
+    retries: u8,
+    timer_started: bool,
+    storage: Option<&'a mut dyn ExecutionStorage>,
+    /// Set while the current job is written to storage
+    stored: bool,
+    job_document: heapless::Vec<u8, D>,
+    clock: Option<&'a dyn Clock>,
+    request_timeout_ms: u32,
+    /// Time by which the response to the request awaited is due
+    deadline: Option<u64>,
+    pending: PendingSnapshot,
+}
+
+impl<'a, M: Mqtt, H, T> JobsAgent<'a, M, H, T>
+where
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+{
+    pub fn new(mqtt: &'a M, handler: H, timer: T) -> Self {
+        Self {
+            mqtt,
+            handler,
+            timer,
+            state: JobsState::Init,
+            job_id: heapless::String::new(),
+            execution_number: None,
+            version_number: None,
+            final_update: None,
+            requests: ClientTokens::new(),
+            step_timeout_in_minutes: None,
+            min_update_interval_ms: None,
+            throttled: false,
+            coalesced: None,
+            retries: 0,
+            timer_started: false,
+            storage: None,
+            stored: false,
+            job_document: heapless::Vec::new(),
+            clock: None,
+            request_timeout_ms: 0,
+            deadline: None,
+            pending: PendingSnapshot::new(),
+        }
+    }
+}
+
+impl<'a, M: Mqtt, H, T, const D: usize> JobsAgent<'a, M, H, T, D>
+where
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+{
+    /// Keep the raw job document of the running job, if at most `K` bytes,
+    /// see [`Self::job_document`]
+    pub fn with_job_document<const K: usize>(self) -> JobsAgent<'a, M, H, T, K> {
+        JobsAgent {
+            mqtt: self.mqtt,
+            handler: self.handler,
+            timer: self.timer,
+            state: self.state,
+            job_id: self.job_id,
+            execution_number: self.execution_number,
+            version_number: self.version_number,
+            final_update: self.final_update,
+            requests: self.requests,
+            step_timeout_in_minutes: self.step_timeout_in_minutes,
+            min_update_interval_ms: self.min_update_interval_ms,
+            throttled: self.throttled,
+            coalesced: self.coalesced,
+            retries: self.retries,
+            timer_started: self.timer_started,
+            storage: self.storage,
+            stored: self.stored,
+            job_document: heapless::Vec::new(),
+            clock: self.clock,
+            request_timeout_ms: self.request_timeout_ms,
+            deadline: self.deadline,
+            pending: self.pending,
+        }
+    }
+
+    /// Persist the job in progress to `storage`, and pick it up again on the
+    /// first [`Self::init`] after a reset.
+    pub fn with_storage(self, storage: &'a mut dyn ExecutionStorage) -> Self {
+        Self {
+            storage: Some(storage),
+            ..self
+        }
+    }
+
+    /// Claim jobs with a step timeout of `minutes`, and keep them alive with
+    /// heartbeats while running.
+    pub fn with_step_timeout(self, minutes: i64) -> Self {
+        Self {
+            step_timeout_in_minutes: Some(minutes),
+            ..self
+        }
+    }
+
+    /// Send updates of a running job at most every `ms` milliseconds,
+    /// coalescing the progress reported in between.
+    pub fn with_min_update_interval(self, ms: u32) -> Self {
+        Self {
+            min_update_interval_ms: Some(ms),
+            ..self
+        }
+    }
+
+    /// Give up on responses not received within `ms` milliseconds, as told
+    /// by `clock`, see [`Self::retry`]
+    pub fn with_request_timeout(self, clock: &'a dyn Clock, ms: u32) -> Self {
+        Self {
+            clock: Some(clock),
+            request_timeout_ms: ms,
+            ..self
+        }
+    }
+
+    pub fn state(&self) -> JobsState {
+        self.state
+    }
+
+    pub fn handler(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// Id of the job being processed, if any
+    pub fn job_id(&self) -> Option<&str> {
+        match self.state {
+            JobsState::Running
+            | JobsState::Updating
+            | JobsState::Reconciling
+            | JobsState::Deferred => Some(self.job_id.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Execution number of the job being processed, if known. A job deployed
+    /// again to the thing keeps its id, but gets a new execution number.
+    pub fn execution_number(&self) -> Option<i64> {
+        self.job_id().and(self.execution_number)
+    }
+
+    /// Pending job executions of the thing, as last notified on
+    /// `$aws/things/{thingName}/jobs/notify`
+    pub fn pending_jobs(&self) -> &PendingSnapshot {
+        &self.pending
+    }
+
+    /// Raw job document of the job being processed, e.g. to parse documents
+    /// `J` can't describe once the handler left the job pending. `None` if the
+    /// document didn't fit the capacity given to
+    /// [`Self::with_job_document`].
+    pub fn job_document(&self) -> Option<&[u8]> {
+        match self.job_id() {
+            Some(_) if !self.job_document.is_empty() => Some(&self.job_document[..]),
+            _ => None,
+        }
+    }
+
+    /// Subscribe to the jobs topics, and claim the next pending job, if any.
+    /// Should be called again on reconnection.
+    pub fn init(&mut self) -> Result<(), JobError> {
+        Jobs::subscribe::<8>()
+            .topic(Topic::Notify, QoS::AtLeastOnce)
+            .topic(Topic::NotifyNext, QoS::AtLeastOnce)
+            .topic(Topic::StartNextAccepted, QoS::AtLeastOnce)
+            .topic(Topic::StartNextRejected, QoS::AtLeastOnce)
+            .topic(Topic::UpdateAccepted("+"), QoS::AtLeastOnce)
+            .topic(Topic::UpdateRejected("+"), QoS::AtLeastOnce)
+            .topic(Topic::DescribeAccepted("+"), QoS::AtLeastOnce)
+            .topic(Topic::DescribeRejected("+"), QoS::AtLeastOnce)
+            .send(self.mqtt)?;
+
+        let stored = match (self.state, self.storage.as_mut()) {
+            (JobsState::Init, Some(storage)) => storage.load()?,
+            _ => None,
+        };
+
+        match (self.state, stored) {
+            // Keep processing the current job, the service will still have it
+            // as the next job.
+            (JobsState::Running, _) => Ok(()),
+            (_, Some(stored)) => {
+                self.requests.clear();
+                self.describe_stored(stored)
+            }
+            _ => {
+                self.requests.clear();
+                self.request_next()
+            }
+        }
+    }
+
+    /// Handle a message received on any of the jobs topics, returning the
+    /// notable event it resulted in, if any. Messages on other topics are
+    /// ignored.
+    pub fn handle_message<'b, J>(
+        &mut self,
+        topic: &'b str,
+        payload: &'b [u8],
+    ) -> Result<Option<JobsEvent>, JobError>
+    where
+        J: Deserialize<'b>,
+        H: JobHandler<J>,
+    {
+        let message = match JobMessage::<J>::from_slice(topic, payload)? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+
+        if let JobMessage::Notify(ref message) = message {
+            self.pending.notified(message);
+        }
+
+        let request = message
+            .client_token()
+            .and_then(|client_token| self.requests.response(client_token));
+        if matches!(
+            request,
+            Some(Request::StartNext | Request::FinalUpdate | Request::Describe)
+        ) {
+            self.deadline = None;
+        }
+
+        let event = match (self.state, request, message) {
+            (JobsState::Running | JobsState::Deferred, _, JobMessage::Notify(message))
+                if !message
+                    .jobs()
+                    .any(|job| self.is_running(job.job_id, job.execution_number)) =>
+            {
+                self.canceled::<J>()?;
+                Some(JobsEvent::JobCanceled)
+            }
+            (_, _, JobMessage::Notify(_)) => Some(JobsEvent::PendingListChanged),
+            (JobsState::Idle, _, JobMessage::NotifyNext(message))
+                if message.execution.is_some() =>
+            {
+                self.request_next()?;
+                None
+            }
+            (JobsState::Running | JobsState::Deferred, _, JobMessage::NotifyNext(message))
+                if !message.execution.as_ref().map_or(false, |execution| {
+                    self.is_running(Some(execution.job_id), execution.execution_number)
+                }) =>
+            {
+                self.canceled::<J>()?;
+                Some(JobsEvent::JobCanceled)
+            }
+            (
+                JobsState::Requesting,
+                Some(Request::StartNext),
+                JobMessage::StartNextAccepted(response),
+            ) => match response.execution {
+                Some(execution) => {
+                    self.run(execution, false)?;
+                    Some(JobsEvent::JobReceived)
+                }
+                None => {
+                    self.state = JobsState::Idle;
+                    None
+                }
+            },
+            (
+                JobsState::Requesting,
+                Some(Request::StartNext),
+                JobMessage::Rejected(Topic::StartNextRejected, _),
+            ) => {
+                self.state = JobsState::Idle;
+                None
+            }
+            (state, Some(request), JobMessage::UpdateAccepted(job_id, response))
+                if job_id == self.job_id.as_str() =>
+            {
+                self.version_number = match response.execution_state {
+                    Some(execution_state) => Some(execution_state.version_number),
+                    None => self.version_number.map(|version| version + 1),
+                };
+
+                if state == JobsState::Updating && request == Request::FinalUpdate {
+                    self.final_update = None;
+                    self.request_next()?
+                }
+                Some(JobsEvent::UpdateAccepted)
+            }
+            (state, Some(request), JobMessage::Rejected(Topic::UpdateRejected(job_id), error))
+                if job_id == self.job_id.as_str() =>
+            {
+                // Rejections of updates the job moved on from, e.g. a progress
+                // update rejected after the final update was sent, are ignored.
+                let latest = match state {
+                    JobsState::Running => {
+                        request == Request::Progress || request == Request::Heartbeat
+                    }
+                    JobsState::Updating => request == Request::FinalUpdate,
+                    _ => false,
+                };
+
+                if latest && error.code == ErrorCode::VersionMismatch {
+                    crate::rustot_log!(debug, "Version mismatch on job {}", job_id);
+                    self.describe()?
+                } else if latest
+                    && state == JobsState::Updating
+                    && error.code.is_transient()
+                    && self.retries < MAX_RETRIES
+                {
+                    crate::rustot_log!(debug, "Update of job {} rejected, retrying", job_id);
+                    self.schedule_retry()?
+                } else if latest && state == JobsState::Updating {
+                    // Nothing more can be done for this job, e.g. as it was
+                    // canceled in the meantime.
+                    crate::rustot_log!(warn, "Update of job {} rejected", job_id);
+                    self.final_update = None;
+                    self.request_next()?
+                }
+                Some(JobsEvent::UpdateRejected { code: error.code })
+            }
+            (
+                JobsState::Reconciling,
+                Some(Request::Describe),
+                JobMessage::DescribeAccepted(job_id, response),
+            ) if job_id == self.job_id.as_str() => self.reconcile(
+                response
+                    .execution
+                    .map(|execution| (execution.status, execution.version_number)),
+            )?,
+            (
+                JobsState::Reconciling,
+                Some(Request::Describe),
+                JobMessage::Rejected(Topic::DescribeRejected(job_id), _),
+            ) if job_id == self.job_id.as_str() => self.reconcile(None)?,
+            (
+                JobsState::Resuming,
+                Some(Request::Describe),
+                JobMessage::DescribeAccepted(job_id, response),
+            ) if job_id == self.job_id.as_str() => match response.execution {
+                Some(execution)
+                    if execution.status == JobStatus::Queued
+                        || execution.status == JobStatus::InProgress =>
+                {
+                    self.resume(execution)?
+                }
+                _ => {
+                    crate::rustot_log!(info, "Stored job {} has ended", job_id);
+                    self.request_next()?;
+                    None
+                }
+            },
+            (
+                JobsState::Resuming,
+                Some(Request::Describe),
+                JobMessage::Rejected(Topic::DescribeRejected(job_id), _),
+            ) if job_id == self.job_id.as_str() => {
+                self.request_next()?;
+                None
+            }
+            (
+                JobsState::Deferred,
+                Some(Request::Describe),
+                JobMessage::DescribeAccepted(job_id, response),
+            ) if job_id == self.job_id.as_str() => match response.execution {
+                Some(execution)
+                    if execution.status == JobStatus::Queued
+                        || execution.status == JobStatus::InProgress =>
+                {
+                    self.run(execution, false)?;
+                    Some(JobsEvent::JobReceived)
+                }
+                _ => {
+                    self.canceled::<J>()?;
+                    Some(JobsEvent::JobCanceled)
+                }
+            },
+            (
+                JobsState::Deferred,
+                Some(Request::Describe),
+                JobMessage::Rejected(Topic::DescribeRejected(job_id), _),
+            ) if job_id == self.job_id.as_str() => {
+                self.canceled::<J>()?;
+                Some(JobsEvent::JobCanceled)
+            }
+            _ => None,
+        };
+
+        if event == Some(JobsEvent::JobReceived) {
+            self.keep_job_document(payload);
+        }
+        Ok(event)
+    }
+
+    /// Like [`Self::handle_message`], also giving back the topic and payload of
+    /// the message, for the application to access the raw job document. Returns
+    /// `None` for messages on other topics only.
+    pub fn handle_message_raw<'b, J>(
+        &mut self,
+        topic: &'b str,
+        payload: &'b [u8],
+    ) -> Result<Option<RawJobsEvent<'b>>, JobError>
+    where
+        J: Deserialize<'b>,
+        H: JobHandler<J>,
+    {
+        if Topic::from_str(topic).is_none() {
+            return Ok(None);
+        }
+
+        let event = self.handle_message::<J>(topic, payload)?;
+        Ok(Some(RawJobsEvent {
+            event,
+            topic,
+            payload,
+        }))
+    }
+
+    /// Send the heartbeat or the progress held back of the running job,
+    /// resend the final update once the backoff of a retry expired, or pick up
+    /// a deferred job again. To be called on expiry of the timer, or
+    /// periodically.
+    ///
+    /// Returns [`JobError::Timeout`] once the response to a request is
+    /// overdue, see [`Self::with_request_timeout`].
+    pub fn timer_callback<J>(&mut self) -> Result<(), JobError>
+    where
+        H: JobHandler<J>,
+    {
+        if let (Some(clock), Some(deadline)) = (self.clock, self.deadline) {
+            if clock.now_ms() >= deadline {
+                crate::rustot_log!(warn, "No response in {:?}", self.state);
+                self.deadline = None;
+                return Err(JobError::Timeout);
+            }
+        }
+
+        if !self.timer_started || self.timer.wait().is_err() {
+            return Ok(());
+        }
+        self.timer_started = false;
+
+        if self.state == JobsState::Deferred {
+            crate::rustot_log!(info, "Picking up deferred job {}", self.job_id.as_str());
+            return self.describe_with_document();
+        }
+
+        if self.state == JobsState::Running && core::mem::take(&mut self.throttled) {
+            return match self.coalesced.take() {
+                Some(status_details) => self.send_progress(&status_details),
+                None => self.start_heartbeat(self.min_update_interval_ms.unwrap_or(0)),
+            };
+        }
+
+        if self.state == JobsState::Running {
+            if self.handler.step_timeout(self.job_id.as_str()) {
+                return self.heartbeat();
+            }
+            crate::rustot_log!(
+                warn,
+                "Job {} about to reach its step timeout",
+                self.job_id.as_str()
+            );
+            return Ok(());
+        }
+
+        match self.final_update.take() {
+            Some((status, status_details)) if self.state == JobsState::Updating => {
+                let result =
+                    self.send_update(Request::FinalUpdate, status, status_details.as_ref());
+                self.final_update = Some((status, status_details));
+                result
+            }
+            final_update => {
+                self.final_update = final_update;
+                Ok(())
+            }
+        }
+    }
+
+    /// Send the request the agent is waiting for the response to again, e.g.
+    /// after it timed out. Does nothing if the agent is not waiting for a
+    /// response.
+    pub fn retry(&mut self) -> Result<(), JobError> {
+        match self.state {
+            JobsState::Requesting => self.request_next(),
+            JobsState::Reconciling => self.describe(),
+            JobsState::Resuming => self.describe_with_document(),
+            // Still waiting for the delay to expire otherwise
+            JobsState::Deferred if !self.timer_started => self.describe_with_document(),
+            // Still waiting for the backoff of a retry to expire otherwise
+            JobsState::Updating if !self.timer_started => match self.final_update.take() {
+                Some((status, status_details)) => {
+                    let result =
+                        self.send_update(Request::FinalUpdate, status, status_details.as_ref());
+                    self.final_update = Some((status, status_details));
+                    result
+                }
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Report progress of the running job. Within the minimum update
+    /// interval, the progress is sent once the interval elapsed, unless
+    /// superseded by another report.
+    pub fn report_progress(&mut self, status_details: &StatusDetails) -> Result<(), JobError> {
+        if self.state != JobsState::Running {
+            return Ok(());
+        }
+
+        if self.throttled {
+            self.coalesced = Some(status_details.clone());
+            return Ok(());
+        }
+        self.send_progress(status_details)
+    }
+
+    /// Report the outcome of the running job, e.g. after the handler returned
+    /// [`JobOutcome::Pending`]. Does nothing if no job is running.
+    pub fn complete(
+        &mut self,
+        outcome: JobOutcome,
+        status_details: Option<&StatusDetails>,
+    ) -> Result<(), JobError> {
+        let status = match outcome {
+            JobOutcome::Pending => return Ok(()),
+            JobOutcome::Succeeded => JobStatus::Succeeded,
+            JobOutcome::Failed => JobStatus::Failed,
+            JobOutcome::Rejected => JobStatus::Rejected,
+        };
+
+        match self.state {
+            JobsState::Running | JobsState::Deferred => {}
+            // Sent once the job is reconciled
+            JobsState::Reconciling if self.final_update.is_none() => {
+                self.persist(status)?;
+                self.final_update = Some((status, status_details.cloned()));
+                return Ok(());
+            }
+            _ => return Ok(()),
+        }
+
+        self.stop_timer();
+        self.coalesced = None;
+        self.persist(status)?;
+        self.send_update(Request::FinalUpdate, status, status_details)?;
+        self.final_update = Some((status, status_details.cloned()));
+        self.state = JobsState::Updating;
+        Ok(())
+    }
+
+    /// Put off the running job for `delay_ms` milliseconds, e.g. as it can
+    /// only be applied in a maintenance window. The job is reported
+    /// IN_PROGRESS with `status_details`, along with the delay under
+    /// [`RESCHEDULE_KEY`], and handed to [`JobHandler::handle`] again once the
+    /// delay expired, unless it ended on the service side in the meantime.
+    ///
+    /// With a step timeout, the step timer of the job is stopped until the job
+    /// is picked up again. Does nothing if no job is running.
+    pub fn defer(
+        &mut self,
+        delay_ms: u32,
+        status_details: Option<&StatusDetails>,
+    ) -> Result<(), JobError> {
+        if self.state != JobsState::Running {
+            return Ok(());
+        }
+
+        let mut delay = heapless::String::new();
+        write!(delay, "{}", delay_ms).map_err(|_| JobError::Overflow)?;
+        let mut status_details = status_details.cloned().unwrap_or_default();
+        status_details
+            .insert(heapless::String::from(RESCHEDULE_KEY), delay)
+            .map_err(|_| JobError::Overflow)?;
+
+        crate::rustot_log!(
+            info,
+            "Deferring job {} for {} ms",
+            self.job_id.as_str(),
+            delay_ms
+        );
+        self.stop_timer();
+        self.coalesced = None;
+        self.send_update(Request::Defer, JobStatus::InProgress, Some(&status_details))?;
+        self.start_timer(delay_ms)?;
+        self.state = JobsState::Deferred;
+        Ok(())
+    }
+
+    /// Hand `execution` to the handler, to be started, or resumed after a
+    /// reset.
+    fn run<J>(&mut self, execution: JobExecution<'_, J>, resumed: bool) -> Result<(), JobError>
+    where
+        H: JobHandler<J>,
+    {
+        self.job_id.clear();
+        if execution.job_id.len() >= MAX_JOB_ID_LEN {
+            self.state = JobsState::Idle;
+            return Err(JobError::Overflow);
+        }
+        self.job_id.push_str(execution.job_id).ok();
+        self.execution_number = execution.execution_number;
+        self.version_number = Some(execution.version_number);
+        self.final_update = None;
+        self.retries = 0;
+        self.state = JobsState::Running;
+
+        self.start_heartbeat(0)?;
+        let outcome = if resumed {
+            crate::rustot_log!(info, "Resuming job {}", execution.job_id);
+            self.handler.resume(&execution)
+        } else {
+            crate::rustot_log!(info, "Starting job {}", execution.job_id);
+            self.persist(JobStatus::InProgress)?;
+            self.handler.handle(&execution)
+        };
+
+        match outcome {
+            JobOutcome::Pending => Ok(()),
+            outcome => self.complete(outcome, None),
+        }
+    }
+
+    /// Describe the job stored before a reset, to learn whether it is still
+    /// to be worked on.
+    fn describe_stored(&mut self, stored: StoredExecution) -> Result<(), JobError> {
+        crate::rustot_log!(info, "Describing stored job {}", stored.job_id.as_str());
+        self.job_id = stored.job_id;
+        self.execution_number = stored.execution_number;
+        self.version_number = None;
+        self.final_update = match stored.status {
+            JobStatus::Queued | JobStatus::InProgress => None,
+            status => Some((status, None)),
+        };
+        self.stored = true;
+
+        self.describe_with_document()?;
+        self.state = JobsState::Resuming;
+        Ok(())
+    }
+
+    /// Pick up the stored job, still active on the service side. An outcome
+    /// stored before the reset is reported again, otherwise the job is
+    /// handed to the handler.
+    fn resume<J>(&mut self, execution: JobExecution<'_, J>) -> Result<Option<JobsEvent>, JobError>
+    where
+        H: JobHandler<J>,
+    {
+        match self.final_update.take() {
+            Some((status, status_details)) => {
+                self.version_number = Some(execution.version_number);
+                self.retries = 0;
+                self.send_update(Request::FinalUpdate, status, status_details.as_ref())?;
+                self.final_update = Some((status, status_details));
+                self.state = JobsState::Updating;
+                Ok(None)
+            }
+            None => {
+                self.run(execution, true)?;
+                Ok(Some(JobsEvent::JobReceived))
+            }
+        }
+    }
+
+    /// Continue the job from its current state on the service side, after a
+    /// version mismatch. `None` if the job no longer exists.
+    fn reconcile(
+        &mut self,
+        execution: Option<(JobStatus, i64)>,
+    ) -> Result<Option<JobsEvent>, JobError> {
+        match execution {
+            Some((status, version_number))
+                if status == JobStatus::Queued || status == JobStatus::InProgress =>
+            {
+                self.version_number = Some(version_number);
+
+                match self.final_update.take() {
+                    Some((final_status, status_details)) => {
+                        self.stop_timer();
+                        self.send_update(
+                            Request::FinalUpdate,
+                            final_status,
+                            status_details.as_ref(),
+                        )?;
+                        self.final_update = Some((final_status, status_details));
+                        self.state = JobsState::Updating;
+                    }
+                    None => {
+                        self.stop_timer();
+                        self.state = JobsState::Running;
+                        match self.coalesced.take() {
+                            Some(status_details) => self.send_progress(&status_details)?,
+                            // The step timeout may have been due while
+                            // reconciling
+                            None if self.step_timeout_in_minutes.is_some() => self.heartbeat()?,
+                            None => {}
+                        }
+                    }
+                }
+                Ok(None)
+            }
+            _ => {
+                crate::rustot_log!(
+                    info,
+                    "Job {} ended on the service side",
+                    self.job_id.as_str()
+                );
+                let event = match self.final_update.take() {
+                    Some(_) => None,
+                    None => {
+                        self.handler.cancel(self.job_id.as_str());
+                        Some(JobsEvent::JobCanceled)
+                    }
+                };
+                self.request_next()?;
+                Ok(event)
+            }
+        }
+    }
+
+    fn send_update(
+        &mut self,
+        request: Request,
+        status: JobStatus,
+        status_details: Option<&StatusDetails>,
+    ) -> Result<(), JobError> {
+        // Downgrade progress updates to QoS 0, a lost one is superseded by the
+        // next.
+        let qos = match request {
+            Request::Progress => QoS::AtMostOnce,
+            _ => QoS::AtLeastOnce,
+        };
+        let step_timeout_in_minutes = match request {
+            Request::Progress | Request::Heartbeat => self.step_timeout_in_minutes,
+            // Stop the step timer, the job is not worked on until picked up
+            // again
+            Request::Defer => self.step_timeout_in_minutes.map(|_| -1),
+            _ => None,
+        };
+
+        let client_token = self.client_token(request)?;
+        let update = Jobs::update(self.job_id.as_str(), status).client_token(client_token.as_str());
+        let update = match self.version_number {
+            Some(version_number) => update.expected_version(version_number),
+            None => update,
+        };
+        let update = match self.execution_number {
+            Some(execution_number) => update.execution_number(execution_number),
+            None => update,
+        };
+        let update = match step_timeout_in_minutes {
+            Some(minutes) => update.step_timeout_in_minutes(minutes),
+            None => update,
+        };
+        match status_details {
+            Some(status_details) => update.status_details(status_details),
+            None => update,
+        }
+        .send(self.mqtt, qos)?;
+
+        if request == Request::FinalUpdate {
+            self.await_response();
+        }
+        Ok(())
+    }
+
+    /// Start the request timeout, if any, of the request just sent
+    fn await_response(&mut self) {
+        self.deadline = self
+            .clock
+            .map(|clock| clock.now_ms() + self.request_timeout_ms as u64);
+    }
+
+    fn heartbeat(&mut self) -> Result<(), JobError> {
+        crate::rustot_log!(debug, "Heartbeat of job {}", self.job_id.as_str());
+        self.send_update(Request::Heartbeat, JobStatus::InProgress, None)?;
+        self.updated()
+    }
+
+    fn send_progress(&mut self, status_details: &StatusDetails) -> Result<(), JobError> {
+        self.send_update(
+            Request::Progress,
+            JobStatus::InProgress,
+            Some(status_details),
+        )?;
+        self.updated()
+    }
+
+    /// An update of the running job was sent, hold back the next one for the
+    /// minimum update interval, if any
+    fn updated(&mut self) -> Result<(), JobError> {
+        match self.min_update_interval_ms {
+            Some(ms) => {
+                self.start_timer(ms)?;
+                self.throttled = true;
+                Ok(())
+            }
+            None => self.start_heartbeat(0),
+        }
+    }
+
+    /// (Re)start the heartbeat timer for half the step timeout, if any, of
+    /// which `elapsed_ms` already elapsed
+    fn start_heartbeat(&mut self, elapsed_ms: u32) -> Result<(), JobError> {
+        if let Some(minutes) = self.step_timeout_in_minutes {
+            let wait_ms = (minutes.max(0) as u64 * 30_000).min(u32::MAX as u64) as u32;
+            self.start_timer(wait_ms.saturating_sub(elapsed_ms))?;
+        }
+        Ok(())
+    }
+
+    fn schedule_retry(&mut self) -> Result<(), JobError> {
+        let wait_ms = (RETRY_WAIT_MS << self.retries).min(MAX_RETRY_WAIT_MS);
+        self.start_timer(wait_ms)?;
+        self.retries += 1;
+        Ok(())
+    }
+
+    fn start_timer(&mut self, ms: u32) -> Result<(), JobError> {
+        self.timer.start(ms).map_err(|_| JobError::Timer)?;
+        self.timer_started = true;
+        Ok(())
+    }
+
+    fn stop_timer(&mut self) {
+        self.throttled = false;
+        if self.timer_started {
+            self.timer_started = false;
+            self.timer.cancel().ok();
+        }
+    }
+
+    fn describe(&mut self) -> Result<(), JobError> {
+        let client_token = self.client_token(Request::Describe)?;
+        let describe = Jobs::describe()
+            .job_id(self.job_id.as_str())
+            .client_token(client_token.as_str());
+        match self.execution_number {
+            Some(execution_number) => describe.execution_number(execution_number),
+            None => describe,
+        }
+        .send(self.mqtt, QoS::AtLeastOnce)?;
+
+        self.await_response();
+        self.state = JobsState::Reconciling;
+        Ok(())
+    }
+
+    /// Describe the current job along with its job document, to hand it to
+    /// the handler again if still pending
+    fn describe_with_document(&mut self) -> Result<(), JobError> {
+        let client_token = self.client_token(Request::Describe)?;
+        let describe = Jobs::describe()
+            .job_id(self.job_id.as_str())
+            .include_job_document()
+            .client_token(client_token.as_str());
+        match self.execution_number {
+            Some(execution_number) => describe.execution_number(execution_number),
+            None => describe,
+        }
+        .send(self.mqtt, QoS::AtLeastOnce)?;
+        self.await_response();
+        Ok(())
+    }
+
+    /// Whether `job_id` is the running job. Executions of the same job with
+    /// another execution number are not, as the job was deployed again.
+    fn is_running(&self, job_id: Option<&str>, execution_number: Option<i64>) -> bool {
+        job_id == Some(self.job_id.as_str())
+            && match (execution_number, self.execution_number) {
+                (Some(number), Some(running)) => number == running,
+                _ => true,
+            }
+    }
+
+    /// The running job was canceled or removed in the cloud
+    fn canceled<J>(&mut self) -> Result<(), JobError>
+    where
+        H: JobHandler<J>,
+    {
+        crate::rustot_log!(info, "Job {} canceled", self.job_id.as_str());
+        self.handler.cancel(self.job_id.as_str());
+        self.request_next()
+    }
+
+    /// Copy the raw job document of the job just received, if it fits `D`
+    fn keep_job_document(&mut self, payload: &[u8]) {
+        self.job_document.clear();
+        if D == 0 {
+            return;
+        }
+
+        if let Some(document) = raw_job_document(payload) {
+            if self.job_document.extend_from_slice(document).is_err() {
+                crate::rustot_log!(
+                    warn,
+                    "Job document of {} bytes too long to keep",
+                    document.len()
+                );
+            }
+        }
+    }
+
+    fn request_next(&mut self) -> Result<(), JobError> {
+        self.stop_timer();
+        self.coalesced = None;
+        self.job_document.clear();
+        self.forget()?;
+
+        let client_token = self.client_token(Request::StartNext)?;
+        let start_next = Jobs::start_next().client_token(client_token.as_str());
+        match self.step_timeout_in_minutes {
+            Some(minutes) => start_next.step_timeout_in_minutes(minutes),
+            None => start_next,
+        }
+        .send(self.mqtt, QoS::AtLeastOnce)?;
+
+        self.await_response();
+        self.state = JobsState::Requesting;
+        Ok(())
+    }
+
+    /// Store the current job with `status`, if the agent has a storage
+    fn persist(&mut self, status: JobStatus) -> Result<(), JobError> {
+        let execution = StoredExecution {
+            job_id: self.job_id.clone(),
+            status,
+            execution_number: self.execution_number,
+        };
+        if let Some(storage) = self.storage.as_mut() {
+            storage.store(&execution)?;
+            self.stored = true;
+        }
+        Ok(())
+    }
+
+    /// Erase the stored job, once done with it
+    fn forget(&mut self) -> Result<(), JobError> {
+        if self.stored {
+            self.stored = false;
+            if let Some(storage) = self.storage.as_mut() {
+                storage.clear()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn client_token(&mut self, request: Request) -> Result<ClientToken, JobError> {
+        self.requests.request(self.mqtt.client_id(), request)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mqttrust::{encoding::v4::decode_slice, Packet};
+
+    use super::*;
+    use crate::{
+        ota::clock::ClockTimer,
+        test::{jobs::MockJobs, MockClock, MockMqtt},
+    };
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Document<'a> {
+        operation: &'a str,
+    }
+
+    #[derive(Default)]
+    struct Handler {
+        outcome: Option<JobOutcome>,
+        handled: Vec<String>,
+        canceled: Vec<String>,
+    }
+
+    impl<'a> JobHandler<Document<'a>> for Handler {
+        fn handle(&mut self, execution: &JobExecution<'_, Document<'a>>) -> JobOutcome {
+            assert_eq!(
+                execution.job_document,
+                Some(Document {
+                    operation: "reboot"
+                })
+            );
+            self.handled.push(execution.job_id.to_string());
+            self.outcome.unwrap_or(JobOutcome::Succeeded)
+        }
+
+        fn cancel(&mut self, job_id: &str) {
+            self.canceled.push(job_id.to_string());
+        }
+    }
+
+    /// Topics and payloads of the messages published so far
+    fn published(mqtt: &MockMqtt) -> Vec<(String, String)> {
+        mqtt.tx
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Publish(p)) => Some((
+                    p.topic_name.to_string(),
+                    core::str::from_utf8(p.payload).unwrap().to_string(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn start_next_accepted(job_id: &str, client_token: &str) -> String {
+        format!(
+            r#"{{
+                "clientToken": "{}",
+                "timestamp": 1587381778,
+                "execution": {{
+                    "jobId": "{}",
+                    "status": "IN_PROGRESS",
+                    "queuedAt": 1587036256,
+                    "lastUpdatedAt": 1587381778,
+                    "versionNumber": 2,
+                    "jobDocument": {{ "operation": "reboot" }}
+                }}
+            }}"#,
+            client_token, job_id
+        )
+    }
+
+    const START_NEXT: &str = "$aws/things/test_client/jobs/start-next";
+    const START_NEXT_ACCEPTED: &str = "$aws/things/test_client/jobs/start-next/accepted";
+    const NOTIFY: &str = "$aws/things/test_client/jobs/notify";
+    const NOTIFY_NEXT: &str = "$aws/things/test_client/jobs/notify-next";
+
+    #[test]
+    fn runs_jobs_until_none_pending() {
+        let mqtt = MockMqtt::new();
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler::default(),
+            ClockTimer::new(MockClock::default()),
+        );
+
+        agent.init().unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                START_NEXT.to_string(),
+                r#"{"clientToken":"0:test_client"}"#.to_string()
+            )]
+        );
+        assert_eq!(agent.state(), JobsState::Requesting);
+
+        let payload = start_next_accepted("job-1", "0:test_client");
+        assert_eq!(
+            agent
+                .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+                .unwrap(),
+            Some(JobsEvent::JobReceived)
+        );
+        assert_eq!(agent.handler().handled, vec!["job-1"]);
+        assert_eq!(agent.state(), JobsState::Updating);
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/update".to_string(),
+                r#"{"expectedVersion":2,"status":"SUCCEEDED","clientToken":"1:test_client"}"#
+                    .to_string()
+            )]
+        );
+
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/accepted",
+                br#"{"clientToken":"1:test_client","timestamp":1587381779}"#,
+            )
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Requesting);
+        assert_eq!(published(&mqtt)[0].0, START_NEXT);
+
+        // No more jobs pending
+        agent
+            .handle_message::<Document>(
+                START_NEXT_ACCEPTED,
+                br#"{"clientToken":"2:test_client","timestamp":1587381780}"#,
+            )
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Idle);
+        assert_eq!(agent.job_id(), None);
+        assert_eq!(
+            agent
+                .handle_message::<Document>(
+                    "$aws/things/test_client/jobs/notify",
+                    br#"{"timestamp":1587381781}"#,
+                )
+                .unwrap(),
+            Some(JobsEvent::PendingListChanged)
+        );
+
+        // Unrelated topics are left to the application
+        assert_eq!(
+            agent
+                .handle_message::<Document>("$aws/things/test_client/streams/s/data/cbor", b"")
+                .unwrap(),
+            None
+        );
+
+        // A new job is queued
+        agent
+            .handle_message::<Document>(
+                NOTIFY_NEXT,
+                br#"{
+                    "timestamp": 1587381790,
+                    "execution": {
+                        "jobId": "job-2",
+                        "status": "QUEUED",
+                        "queuedAt": 1587381790,
+                        "lastUpdatedAt": 1587381790,
+                        "versionNumber": 1
+                    }
+                }"#,
+            )
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Requesting);
+        assert_eq!(published(&mqtt)[0].0, START_NEXT);
+    }
+
+    #[test]
+    fn pending_job_completed_or_canceled() {
+        let mqtt = MockMqtt::new();
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler {
+                outcome: Some(JobOutcome::Pending),
+                ..Handler::default()
+            },
+            ClockTimer::new(MockClock::default()),
+        );
+
+        agent.init().unwrap();
+        let payload = start_next_accepted("job-1", "0:test_client");
+        agent
+            .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Running);
+        assert_eq!(agent.job_id(), Some("job-1"));
+        published(&mqtt);
+
+        // The running job is still next
+        let payload = payload.replace("\"clientToken\": \"0:test_client\",", "");
+        agent
+            .handle_message::<Document>(NOTIFY_NEXT, payload.as_bytes())
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Running);
+
+        let mut status_details = StatusDetails::new();
+        status_details
+            .insert(
+                heapless::String::from("error"),
+                heapless::String::from("E42"),
+            )
+            .unwrap();
+        agent
+            .complete(JobOutcome::Failed, Some(&status_details))
+            .unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/update".to_string(),
+                r#"{"expectedVersion":2,"status":"FAILED","statusDetails":{"error":"E42"},"clientToken":"1:test_client"}"#
+                    .to_string()
+            )]
+        );
+
+        assert_eq!(
+            agent
+                .handle_message::<Document>(
+                    "$aws/things/test_client/jobs/job-1/update/rejected",
+                    br#"{"code":"InvalidStateTransition","message":"Job is canceled","clientToken":"1:test_client","timestamp":1587381779}"#,
+                )
+                .unwrap(),
+            Some(JobsEvent::UpdateRejected {
+                code: ErrorCode::InvalidStateTransition
+            })
+        );
+        assert_eq!(agent.state(), JobsState::Requesting);
+
+        let payload = start_next_accepted("job-2", "2:test_client");
+        agent
+            .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap();
+        assert_eq!(agent.job_id(), Some("job-2"));
+
+        // Job canceled, nothing else pending
+        assert_eq!(
+            agent
+                .handle_message::<Document>(NOTIFY_NEXT, br#"{"timestamp":1587381790}"#)
+                .unwrap(),
+            Some(JobsEvent::JobCanceled)
+        );
+        assert_eq!(agent.handler().canceled, vec!["job-2"]);
+        assert_eq!(agent.state(), JobsState::Requesting);
+    }
+
+    #[test]
+    fn mock_service() {
+        let mqtt = MockMqtt::new();
+        let mut service = MockJobs::new();
+        service.queue("job-1", r#"{ "operation": "reboot" }"#);
+        service.queue("job-2", r#"{ "operation": "reboot" }"#);
+        service.reject_next_update("VersionMismatch");
+
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler::default(),
+            ClockTimer::new(MockClock::default()),
+        );
+        agent.init().unwrap();
+
+        loop {
+            let responses = service.serve(&mqtt);
+            if responses.is_empty() {
+                break;
+            }
+            for response in responses {
+                agent
+                    .handle_message::<Document>(&response.topic, &response.payload)
+                    .unwrap();
+            }
+        }
+
+        assert_eq!(agent.state(), JobsState::Idle);
+        assert_eq!(agent.handler().handled, vec!["job-1", "job-2"]);
+        for job in service.jobs.iter() {
+            assert_eq!(job.status, JobStatus::Succeeded);
+            assert_eq!(job.updates, 1);
+        }
+    }
+
+    #[test]
+    fn job_document_kept() {
+        let mqtt = MockMqtt::new();
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler {
+                outcome: Some(JobOutcome::Pending),
+                ..Handler::default()
+            },
+            ClockTimer::new(MockClock::default()),
+        )
+        .with_job_document::<32>();
+
+        agent.init().unwrap();
+        assert_eq!(agent.job_document(), None);
+        let payload = start_next_accepted("job-1", "0:test_client");
+        agent
+            .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap();
+        assert_eq!(
+            agent.job_document(),
+            Some(&br#"{ "operation": "reboot" }"#[..])
+        );
+
+        agent.complete(JobOutcome::Succeeded, None).unwrap();
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/accepted",
+                br#"{"clientToken":"1:test_client","timestamp":1587381779}"#,
+            )
+            .unwrap();
+        assert_eq!(agent.job_document(), None);
+
+        // Too long to keep
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler::default(),
+            ClockTimer::new(MockClock::default()),
+        )
+        .with_job_document::<8>();
+        agent.init().unwrap();
+        agent
+            .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap();
+        assert_eq!(agent.job_document(), None);
+    }
+
+    #[test]
+    fn raw_message() {
+        let mqtt = MockMqtt::new();
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler::default(),
+            ClockTimer::new(MockClock::default()),
+        );
+
+        agent.init().unwrap();
+        let payload = start_next_accepted("job-1", "0:test_client");
+        let raw = agent
+            .handle_message_raw::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(raw.event, Some(JobsEvent::JobReceived));
+        assert_eq!(raw.topic, START_NEXT_ACCEPTED);
+        assert_eq!(
+            raw.job_document(),
+            Some(&br#"{ "operation": "reboot" }"#[..])
+        );
+
+        assert_eq!(
+            agent
+                .handle_message_raw::<Document>("$aws/things/test_client/shadow/update", b"{}")
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn canceled_on_notify() {
+        let mqtt = MockMqtt::new();
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler {
+                outcome: Some(JobOutcome::Pending),
+                ..Handler::default()
+            },
+            ClockTimer::new(MockClock::default()),
+        );
+
+        agent.init().unwrap();
+        let payload = start_next_accepted("job-1", "0:test_client");
+        agent
+            .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap();
+        published(&mqtt);
+
+        // Another job queued, the running job is still in progress
+        assert_eq!(
+            agent
+                .handle_message::<Document>(
+                    NOTIFY,
+                    br#"{
+                        "timestamp": 1587381785,
+                        "jobs": {
+                            "IN_PROGRESS": [{ "jobId": "job-1", "queuedAt": 1587381778, "lastUpdatedAt": 1587381779, "executionNumber": 1, "versionNumber": 2 }],
+                            "QUEUED": [{ "jobId": "job-2", "queuedAt": 1587381784, "lastUpdatedAt": 1587381784, "executionNumber": 1, "versionNumber": 1 }]
+                        }
+                    }"#,
+                )
+                .unwrap(),
+            Some(JobsEvent::PendingListChanged)
+        );
+        assert_eq!(agent.job_id(), Some("job-1"));
+        assert!(published(&mqtt).is_empty());
+
+        // The running job is gone from the list
+        assert_eq!(
+            agent
+                .handle_message::<Document>(
+                    NOTIFY,
+                    br#"{
+                        "timestamp": 1587381790,
+                        "jobs": {
+                            "QUEUED": [{ "jobId": "job-2", "queuedAt": 1587381784, "lastUpdatedAt": 1587381784, "executionNumber": 1, "versionNumber": 1 }]
+                        }
+                    }"#,
+                )
+                .unwrap(),
+            Some(JobsEvent::JobCanceled)
+        );
+        assert_eq!(agent.handler().canceled, vec!["job-1"]);
+        assert_eq!(agent.state(), JobsState::Requesting);
+        assert_eq!(published(&mqtt)[0].0, START_NEXT);
+    }
+
+    #[test]
+    fn deployed_again_while_running() {
+        let mqtt = MockMqtt::new();
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler {
+                outcome: Some(JobOutcome::Pending),
+                ..Handler::default()
+            },
+            ClockTimer::new(MockClock::default()),
+        );
+
+        agent.init().unwrap();
+        let payload = start_next_accepted("job-1", "0:test_client").replace(
+            r#""versionNumber": 2"#,
+            r#""executionNumber": 1, "versionNumber": 2"#,
+        );
+        agent
+            .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap();
+        assert_eq!(agent.execution_number(), Some(1));
+        published(&mqtt);
+
+        agent.report_progress(&StatusDetails::new()).unwrap();
+        assert_eq!(
+            published(&mqtt)[0].1,
+            r#"{"executionNumber":1,"expectedVersion":2,"status":"IN_PROGRESS","statusDetails":{},"clientToken":"1:test_client"}"#
+        );
+
+        // The service no longer has the running execution, but a new one of
+        // the same job
+        assert_eq!(
+            agent
+                .handle_message::<Document>(
+                    NOTIFY_NEXT,
+                    br#"{
+                        "timestamp": 1587381790,
+                        "execution": {
+                            "jobId": "job-1",
+                            "status": "QUEUED",
+                            "queuedAt": 1587381789,
+                            "lastUpdatedAt": 1587381789,
+                            "executionNumber": 2,
+                            "versionNumber": 1,
+                            "jobDocument": { "operation": "reboot" }
+                        }
+                    }"#,
+                )
+                .unwrap(),
+            Some(JobsEvent::JobCanceled)
+        );
+        assert_eq!(agent.handler().canceled, vec!["job-1"]);
+        assert_eq!(agent.state(), JobsState::Requesting);
+    }
+
+    #[test]
+    fn version_mismatch_reconciled() {
+        let mqtt = MockMqtt::new();
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler {
+                outcome: Some(JobOutcome::Pending),
+                ..Handler::default()
+            },
+            ClockTimer::new(MockClock::default()),
+        );
+
+        agent.init().unwrap();
+        let payload = start_next_accepted("job-1", "0:test_client");
+        agent
+            .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap();
+        published(&mqtt);
+
+        let mut status_details = StatusDetails::new();
+        status_details
+            .insert(heapless::String::from("step"), heapless::String::from("1"))
+            .unwrap();
+        agent.report_progress(&status_details).unwrap();
+        assert_eq!(
+            published(&mqtt)[0].1,
+            r#"{"expectedVersion":2,"status":"IN_PROGRESS","statusDetails":{"step":"1"},"clientToken":"1:test_client"}"#
+        );
+
+        // Accepted updates bump the version
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/accepted",
+                br#"{"clientToken":"1:test_client","timestamp":1587381779}"#,
+            )
+            .unwrap();
+        agent.complete(JobOutcome::Succeeded, None).unwrap();
+        assert_eq!(
+            published(&mqtt)[0].1,
+            r#"{"expectedVersion":3,"status":"SUCCEEDED","clientToken":"2:test_client"}"#
+        );
+
+        // Responses are only handled once
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/accepted",
+                br#"{"clientToken":"1:test_client","timestamp":1587381779}"#,
+            )
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Updating);
+
+        // The job was updated elsewhere in the meantime
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/rejected",
+                br#"{"code":"VersionMismatch","message":"Version mismatch","clientToken":"2:test_client","timestamp":1587381780}"#,
+            )
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Reconciling);
+        assert_eq!(
+            published(&mqtt)[0].0,
+            "$aws/things/test_client/jobs/job-1/get"
+        );
+
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/get/accepted",
+                br#"{
+                    "clientToken": "3:test_client",
+                    "timestamp": 1587381781,
+                    "execution": {
+                        "jobId": "job-1",
+                        "status": "IN_PROGRESS",
+                        "queuedAt": 1587036256,
+                        "lastUpdatedAt": 1587381780,
+                        "versionNumber": 5
+                    }
+                }"#,
+            )
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Updating);
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/update".to_string(),
+                r#"{"expectedVersion":5,"status":"SUCCEEDED","clientToken":"4:test_client"}"#
+                    .to_string()
+            )]
+        );
+
+        // Canceled while reconciling
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/rejected",
+                br#"{"code":"VersionMismatch","message":"Version mismatch","clientToken":"4:test_client","timestamp":1587381782}"#,
+            )
+            .unwrap();
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/get/accepted",
+                br#"{
+                    "clientToken": "5:test_client",
+                    "timestamp": 1587381783,
+                    "execution": {
+                        "jobId": "job-1",
+                        "status": "CANCELED",
+                        "queuedAt": 1587036256,
+                        "lastUpdatedAt": 1587381782,
+                        "versionNumber": 6
+                    }
+                }"#,
+            )
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Requesting);
+        assert_eq!(published(&mqtt)[0].0, START_NEXT);
+    }
+
+    #[test]
+    fn throttled_update_retried() {
+        let mqtt = MockMqtt::new();
+        let clock = MockClock::default();
+        let mut agent = JobsAgent::new(&mqtt, Handler::default(), ClockTimer::new(&clock));
+
+        agent.init().unwrap();
+        let payload = start_next_accepted("job-1", "0:test_client");
+        agent
+            .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap();
+        published(&mqtt);
+
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/rejected",
+                br#"{"code":"RequestThrottled","message":"Rate exceeded","clientToken":"1:test_client","timestamp":1587381779}"#,
+            )
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Updating);
+
+        // Resent once the backoff expired
+        clock.set(RETRY_WAIT_MS as u64 - 1);
+        agent.timer_callback::<Document>().unwrap();
+        assert!(published(&mqtt).is_empty());
+
+        clock.set(RETRY_WAIT_MS as u64);
+        agent.timer_callback::<Document>().unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/update".to_string(),
+                r#"{"expectedVersion":2,"status":"SUCCEEDED","clientToken":"2:test_client"}"#
+                    .to_string()
+            )]
+        );
+
+        // The backoff doubles on every retry
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/rejected",
+                br#"{"code":"ServiceUnavailable","message":"Service unavailable","clientToken":"2:test_client","timestamp":1587381780}"#,
+            )
+            .unwrap();
+        clock.set(RETRY_WAIT_MS as u64 * 2);
+        agent.timer_callback::<Document>().unwrap();
+        assert!(published(&mqtt).is_empty());
+
+        clock.set(RETRY_WAIT_MS as u64 * 3);
+        agent.timer_callback::<Document>().unwrap();
+        assert_eq!(published(&mqtt).len(), 1);
+
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/accepted",
+                br#"{"clientToken":"3:test_client","timestamp":1587381781}"#,
+            )
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Requesting);
+        assert_eq!(published(&mqtt)[0].0, START_NEXT);
+    }
+
+    #[test]
+    fn step_timeout_heartbeats() {
+        let mqtt = MockMqtt::new();
+        let clock = MockClock::default();
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler {
+                outcome: Some(JobOutcome::Pending),
+                ..Handler::default()
+            },
+            ClockTimer::new(&clock),
+        )
+        .with_step_timeout(10);
+
+        agent.init().unwrap();
+        assert_eq!(
+            published(&mqtt)[0].1,
+            r#"{"stepTimeoutInMinutes":10,"clientToken":"0:test_client"}"#
+        );
+
+        let payload = start_next_accepted("job-1", "0:test_client");
+        agent
+            .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap();
+        assert!(published(&mqtt).is_empty());
+
+        // Halfway through the step timeout
+        clock.set(299_999);
+        agent.timer_callback::<Document>().unwrap();
+        assert!(published(&mqtt).is_empty());
+
+        clock.set(300_000);
+        agent.timer_callback::<Document>().unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/update".to_string(),
+                r#"{"expectedVersion":2,"status":"IN_PROGRESS","stepTimeoutInMinutes":10,"clientToken":"1:test_client"}"#
+                    .to_string()
+            )]
+        );
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/accepted",
+                br#"{"clientToken":"1:test_client","timestamp":1587381779}"#,
+            )
+            .unwrap();
+
+        // No more heartbeats once the job is completed
+        agent.complete(JobOutcome::Succeeded, None).unwrap();
+        assert_eq!(
+            published(&mqtt)[0].1,
+            r#"{"expectedVersion":3,"status":"SUCCEEDED","clientToken":"2:test_client"}"#
+        );
+        clock.set(600_000);
+        agent.timer_callback::<Document>().unwrap();
+        assert!(published(&mqtt).is_empty());
+        assert_eq!(agent.state(), JobsState::Updating);
+    }
+
+    #[test]
+    fn progress_coalesced() {
+        let mqtt = MockMqtt::new();
+        let clock = MockClock::default();
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler {
+                outcome: Some(JobOutcome::Pending),
+                ..Handler::default()
+            },
+            ClockTimer::new(&clock),
+        )
+        .with_min_update_interval(1000);
+
+        agent.init().unwrap();
+        let payload = start_next_accepted("job-1", "0:test_client");
+        agent
+            .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap();
+        published(&mqtt);
+
+        let progress = |percentage: &str| {
+            let mut status_details = StatusDetails::new();
+            status_details
+                .insert(
+                    heapless::String::from("progress"),
+                    heapless::String::from(percentage),
+                )
+                .unwrap();
+            status_details
+        };
+
+        agent.report_progress(&progress("10%")).unwrap();
+        clock.set(400);
+        agent.report_progress(&progress("20%")).unwrap();
+        agent.report_progress(&progress("30%")).unwrap();
+        agent.timer_callback::<Document>().unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/update".to_string(),
+                r#"{"expectedVersion":2,"status":"IN_PROGRESS","statusDetails":{"progress":"10%"},"clientToken":"1:test_client"}"#
+                    .to_string()
+            )]
+        );
+
+        // Only the latest progress is sent once the interval elapsed
+        clock.set(1000);
+        agent.timer_callback::<Document>().unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/update".to_string(),
+                r#"{"expectedVersion":2,"status":"IN_PROGRESS","statusDetails":{"progress":"30%"},"clientToken":"2:test_client"}"#
+                    .to_string()
+            )]
+        );
+
+        // The final update is not held back, and supersedes the progress
+        clock.set(1500);
+        agent.report_progress(&progress("90%")).unwrap();
+        agent.complete(JobOutcome::Succeeded, None).unwrap();
+        clock.set(2000);
+        agent.timer_callback::<Document>().unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/update".to_string(),
+                r#"{"expectedVersion":2,"status":"SUCCEEDED","clientToken":"3:test_client"}"#
+                    .to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn request_timed_out() {
+        let mqtt = MockMqtt::new();
+        let clock = MockClock::default();
+        let mut agent = JobsAgent::new(&mqtt, Handler::default(), ClockTimer::new(&clock))
+            .with_request_timeout(&clock, 5000);
+
+        agent.init().unwrap();
+        published(&mqtt);
+
+        clock.set(4999);
+        agent.timer_callback::<Document>().unwrap();
+        clock.set(5000);
+        assert_eq!(agent.timer_callback::<Document>(), Err(JobError::Timeout));
+        // Only reported once
+        agent.timer_callback::<Document>().unwrap();
+        assert_eq!(agent.state(), JobsState::Requesting);
+
+        agent.retry().unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                START_NEXT.to_string(),
+                r#"{"clientToken":"1:test_client"}"#.to_string()
+            )]
+        );
+
+        // The final update times out as well
+        let payload = start_next_accepted("job-1", "1:test_client");
+        agent
+            .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Updating);
+        published(&mqtt);
+
+        clock.set(9999);
+        agent.timer_callback::<Document>().unwrap();
+        clock.set(10_000);
+        assert_eq!(agent.timer_callback::<Document>(), Err(JobError::Timeout));
+        agent.retry().unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/update".to_string(),
+                r#"{"expectedVersion":2,"status":"SUCCEEDED","clientToken":"3:test_client"}"#
+                    .to_string()
+            )]
+        );
+
+        // Late responses are still handled
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/accepted",
+                br#"{"clientToken":"2:test_client","timestamp":1587381779}"#,
+            )
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Requesting);
+    }
+
+    #[test]
+    fn deferred_job_picked_up_again() {
+        let mqtt = MockMqtt::new();
+        let clock = MockClock::default();
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler {
+                outcome: Some(JobOutcome::Pending),
+                ..Handler::default()
+            },
+            ClockTimer::new(&clock),
+        )
+        .with_step_timeout(10);
+
+        agent.init().unwrap();
+        let payload = start_next_accepted("job-1", "0:test_client");
+        agent
+            .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+            .unwrap();
+        published(&mqtt);
+
+        agent.defer(3_600_000, None).unwrap();
+        assert_eq!(agent.state(), JobsState::Deferred);
+        assert_eq!(agent.job_id(), Some("job-1"));
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/update".to_string(),
+                r#"{"expectedVersion":2,"status":"IN_PROGRESS","statusDetails":{"rescheduleInMs":"3600000"},"stepTimeoutInMinutes":-1,"clientToken":"1:test_client"}"#
+                    .to_string()
+            )]
+        );
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/accepted",
+                br#"{"clientToken":"1:test_client","timestamp":1587381779}"#,
+            )
+            .unwrap();
+
+        // No heartbeats while deferred
+        clock.set(3_599_999);
+        agent.timer_callback::<Document>().unwrap();
+        assert!(published(&mqtt).is_empty());
+
+        clock.set(3_600_000);
+        agent.timer_callback::<Document>().unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/get".to_string(),
+                r#"{"includeJobDocument":true,"clientToken":"2:test_client"}"#.to_string()
+            )]
+        );
+
+        let payload = start_next_accepted("job-1", "2:test_client")
+            .replace("\"versionNumber\": 2", "\"versionNumber\": 3");
+        assert_eq!(
+            agent
+                .handle_message::<Document>(
+                    "$aws/things/test_client/jobs/job-1/get/accepted",
+                    payload.as_bytes(),
+                )
+                .unwrap(),
+            Some(JobsEvent::JobReceived)
+        );
+        assert_eq!(agent.handler().handled, vec!["job-1", "job-1"]);
+        assert_eq!(agent.state(), JobsState::Running);
+
+        // Canceled while deferred
+        agent.defer(1000, None).unwrap();
+        assert_eq!(
+            agent
+                .handle_message::<Document>(NOTIFY_NEXT, br#"{"timestamp":1587381790}"#)
+                .unwrap(),
+            Some(JobsEvent::JobCanceled)
+        );
+        assert_eq!(agent.handler().canceled, vec!["job-1"]);
+        assert_eq!(agent.state(), JobsState::Requesting);
+    }
+
+    #[derive(Default)]
+    struct MemStorage(Option<StoredExecution>);
+
+    impl ExecutionStorage for MemStorage {
+        fn store(&mut self, execution: &StoredExecution) -> Result<(), JobError> {
+            self.0 = Some(execution.clone());
+            Ok(())
+        }
+
+        fn load(&mut self) -> Result<Option<StoredExecution>, JobError> {
+            Ok(self.0.clone())
+        }
+
+        fn clear(&mut self) -> Result<(), JobError> {
+            self.0 = None;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn job_resumed_after_reset() {
+        let mut storage = MemStorage::default();
+
+        {
+            let mqtt = MockMqtt::new();
+            let mut agent = JobsAgent::new(
+                &mqtt,
+                Handler {
+                    outcome: Some(JobOutcome::Pending),
+                    ..Handler::default()
+                },
+                ClockTimer::new(MockClock::default()),
+            )
+            .with_storage(&mut storage);
+
+            agent.init().unwrap();
+            let payload = start_next_accepted("job-1", "0:test_client");
+            agent
+                .handle_message::<Document>(START_NEXT_ACCEPTED, payload.as_bytes())
+                .unwrap();
+        }
+        assert_eq!(
+            storage.0,
+            Some(StoredExecution {
+                job_id: heapless::String::from("job-1"),
+                status: JobStatus::InProgress,
+                execution_number: None,
+            })
+        );
+
+        // The device resets while running the job
+        let mqtt = MockMqtt::new();
+        let mut agent = JobsAgent::new(
+            &mqtt,
+            Handler::default(),
+            ClockTimer::new(MockClock::default()),
+        )
+        .with_storage(&mut storage);
+
+        agent.init().unwrap();
+        assert_eq!(agent.state(), JobsState::Resuming);
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/get".to_string(),
+                r#"{"includeJobDocument":true,"clientToken":"0:test_client"}"#.to_string()
+            )]
+        );
+
+        // Still in progress, and reported as failed by the default handler
+        let payload = start_next_accepted("job-1", "0:test_client")
+            .replace("\"versionNumber\": 2", "\"versionNumber\": 3");
+        assert_eq!(
+            agent
+                .handle_message::<Document>(
+                    "$aws/things/test_client/jobs/job-1/get/accepted",
+                    payload.as_bytes(),
+                )
+                .unwrap(),
+            Some(JobsEvent::JobReceived)
+        );
+        assert!(agent.handler().handled.is_empty());
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/test_client/jobs/job-1/update".to_string(),
+                r#"{"expectedVersion":3,"status":"FAILED","clientToken":"1:test_client"}"#
+                    .to_string()
+            )]
+        );
+
+        agent
+            .handle_message::<Document>(
+                "$aws/things/test_client/jobs/job-1/update/accepted",
+                br#"{"clientToken":"1:test_client","timestamp":1587381779}"#,
+            )
+            .unwrap();
+        assert_eq!(agent.state(), JobsState::Requesting);
+        drop(agent);
+        assert_eq!(storage.0, None);
+    }
+}
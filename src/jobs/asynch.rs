@@ -0,0 +1,471 @@
+//! Async variant of the [`JobsClient`], for use from async executors such as
+//! embassy.
+//!
+//! The requests of [`AsyncJobsClient`] return futures that resolve once the
+//! response to the request arrives on its `accepted` or `rejected` topic, or
+//! fail with [`JobError::Timeout`] if it doesn't arrive in time. Messages
+//! received from the MQTT client are handed to
+//! [`AsyncJobsClient::handle_message`], e.g. from the task polling the MQTT
+//! connection:
+//!
+//! ```ignore
+//! let jobs = AsyncJobsClient::<_, _, 1024>::new(JobsClient::new(&mqtt), timer, 5000);
+//! jobs.subscribe()?;
+//!
+//! // Receiving task
+//! if !jobs.handle_message(topic, payload)? {
+//!     // Notifications, or responses nobody waits for
+//! }
+//!
+//! // Application task
+//! let response = jobs.start_next().await?;
+//! if let Some(execution) = response.message::<Command>()?.execution() {
+//!     let status = run(execution.job_document).await;
+//!     jobs.update(execution.job_id, status, None).await?;
+//! }
+//! ```
+//!
+//! A single request is awaited at a time. Another request sent before the
+//! response to the previous one arrived, or the previous one timed out, fails
+//! with [`JobError::Busy`] without being sent.
+//!
+//! The future of a request is woken by [`AsyncJobsClient::handle_message`],
+//! which is also when its timeout is checked. Without any message arriving,
+//! [`AsyncJobsClient::check_timeout`] is to be called periodically.
+
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use embedded_hal::timer;
+use mqttrust::Mqtt;
+use serde::{de::IgnoredAny, Deserialize};
+
+use super::{
+    client::JobsClient,
+    client_token::ClientToken,
+    data_types::{ErrorResponse, JobStatus},
+    JobError, JobMessage, StatusDetails, MAX_JOB_ID_LEN, MAX_THING_NAME_LEN,
+};
+
+/// Response to a request of the [`AsyncJobsClient`], holding a copy of the
+/// message of up to `L` bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response<const L: usize> {
+    topic: heapless::String<{ MAX_THING_NAME_LEN + MAX_JOB_ID_LEN + 34 }>,
+    payload: heapless::Vec<u8, L>,
+}
+
+impl<const L: usize> Response<L> {
+    fn new(topic: &str, payload: &[u8]) -> Result<Self, JobError> {
+        let mut response = Self {
+            topic: heapless::String::new(),
+            payload: heapless::Vec::new(),
+        };
+        response
+            .topic
+            .push_str(topic)
+            .map_err(|_| JobError::Overflow)?;
+        response
+            .payload
+            .extend_from_slice(payload)
+            .map_err(|_| JobError::Overflow)?;
+        Ok(response)
+    }
+
+    pub fn topic(&self) -> &str {
+        self.topic.as_str()
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Deserialize the response, with job documents deserialized into `J`
+    pub fn message<'b, J: Deserialize<'b>>(&'b self) -> Result<JobMessage<'b, J>, JobError> {
+        JobMessage::from_slice(self.topic.as_str(), &self.payload)?.ok_or(JobError::Encoding)
+    }
+
+    /// The error the request was rejected with, `None` if it was accepted
+    pub fn error(&self) -> Option<ErrorResponse<'_>> {
+        match self.message::<IgnoredAny>() {
+            Ok(JobMessage::Rejected(_, error)) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// [`JobsClient`] whose requests resolve to their response, keeping responses
+/// of up to `L` bytes, and tracking up to `N` job executions
+pub struct AsyncJobsClient<'a, M: Mqtt, T, const L: usize = 1024, const N: usize = 1> {
+    client: RefCell<JobsClient<'a, M, N>>,
+    timer: RefCell<T>,
+    timeout_ms: u32,
+    /// Client token of the request awaiting its response
+    awaiting: RefCell<Option<ClientToken>>,
+    response: RefCell<Option<Result<Response<L>, JobError>>>,
+    /// Waker of the future awaiting the response
+    waker: RefCell<Option<Waker>>,
+}
+
+impl<'a, M: Mqtt, T, const L: usize, const N: usize> AsyncJobsClient<'a, M, T, L, N>
+where
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+{
+    /// Wrap `client`, waiting at most `timeout_ms` for each response
+    pub fn new(client: JobsClient<'a, M, N>, timer: T, timeout_ms: u32) -> Self {
+        Self {
+            client: RefCell::new(client),
+            timer: RefCell::new(timer),
+            timeout_ms,
+            awaiting: RefCell::new(None),
+            response: RefCell::new(None),
+            waker: RefCell::new(None),
+        }
+    }
+
+    /// Get back the underlying synchronous client
+    pub fn into_inner(self) -> JobsClient<'a, M, N> {
+        self.client.into_inner()
+    }
+
+    /// Subscribe to the notifications, and the responses of all requests
+    pub fn subscribe(&self) -> Result<(), JobError> {
+        self.client.borrow().subscribe()
+    }
+
+    pub fn unsubscribe(&self) -> Result<(), JobError> {
+        self.client.borrow().unsubscribe()
+    }
+
+    /// Get the pending job executions of the thing
+    pub async fn get_pending(&self) -> Result<Response<L>, JobError> {
+        self.request(|client| client.get_pending())?.await
+    }
+
+    /// Describe the job execution `job_id`, including its job document, or
+    /// the next pending job execution if `None`
+    pub async fn describe(&self, job_id: Option<&str>) -> Result<Response<L>, JobError> {
+        self.request(|client| client.describe(job_id))?.await
+    }
+
+    /// Claim the next pending job execution, moving it to IN_PROGRESS
+    pub async fn start_next(&self) -> Result<Response<L>, JobError> {
+        self.request(|client| client.start_next())?.await
+    }
+
+    /// Update the status of the job execution `job_id`, see
+    /// [`JobsClient::update`]
+    pub async fn update(
+        &self,
+        job_id: &str,
+        status: JobStatus,
+        status_details: Option<&StatusDetails>,
+    ) -> Result<Response<L>, JobError> {
+        self.request(|client| client.update(job_id, status, status_details))?
+            .await
+    }
+
+    /// Handle a message received on any topic. Returns whether it was the
+    /// response to the request being awaited, which is then resolved with it.
+    pub fn handle_message(&self, topic: &str, payload: &[u8]) -> Result<bool, JobError> {
+        let message = self
+            .client
+            .borrow_mut()
+            .handle_message::<IgnoredAny>(topic, payload);
+
+        let client_token = match message {
+            Ok(Some(message)) => message.client_token(),
+            Ok(None) => None,
+            Err(e) => return Err(e),
+        };
+
+        let awaited = matches!(
+            (client_token, self.awaiting.borrow().as_ref()),
+            (Some(client_token), Some(awaited)) if client_token == awaited.as_str()
+        );
+        if awaited {
+            *self.response.borrow_mut() = Some(Response::new(topic, payload));
+        }
+
+        // The awaiting future checks its timeout when woken, so it is woken
+        // by any message
+        self.wake();
+        Ok(awaited)
+    }
+
+    /// Wake the future awaiting a response once its timeout expired, to be
+    /// called periodically if no messages arrive
+    pub fn check_timeout(&self) {
+        self.wake();
+    }
+
+    fn wake(&self) {
+        let waker = self.waker.borrow_mut().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Send a request through `send`, unless another request is awaiting
+    /// its response, and wait for the response carrying the client token
+    /// returned by `send`
+    fn request(
+        &self,
+        send: impl FnOnce(&mut JobsClient<'a, M, N>) -> Result<ClientToken, JobError>,
+    ) -> Result<ResponseFuture<'_, 'a, M, T, L, N>, JobError> {
+        if self.awaiting.borrow().is_some() {
+            return Err(JobError::Busy);
+        }
+
+        let client_token = send(&mut self.client.borrow_mut())?;
+        self.timer
+            .borrow_mut()
+            .start(self.timeout_ms)
+            .map_err(|_| JobError::Timer)?;
+        *self.awaiting.borrow_mut() = Some(client_token);
+        *self.response.borrow_mut() = None;
+
+        Ok(ResponseFuture { jobs: self })
+    }
+}
+
+/// Future of the response to the request of an [`AsyncJobsClient`], freeing
+/// the client for the next request once resolved or dropped
+struct ResponseFuture<'c, 'a, M: Mqtt, T, const L: usize, const N: usize>
+where
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+{
+    jobs: &'c AsyncJobsClient<'a, M, T, L, N>,
+}
+
+impl<'c, 'a, M: Mqtt, T, const L: usize, const N: usize> Future
+    for ResponseFuture<'c, 'a, M, T, L, N>
+where
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+{
+    type Output = Result<Response<L>, JobError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let jobs = self.jobs;
+        if let Some(result) = jobs.response.borrow_mut().take() {
+            return Poll::Ready(result);
+        }
+        if jobs.timer.borrow_mut().wait().is_ok() {
+            return Poll::Ready(Err(JobError::Timeout));
+        }
+
+        *jobs.waker.borrow_mut() = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<'c, 'a, M: Mqtt, T, const L: usize, const N: usize> Drop for ResponseFuture<'c, 'a, M, T, L, N>
+where
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+{
+    fn drop(&mut self) {
+        self.jobs.awaiting.borrow_mut().take();
+        self.jobs.response.borrow_mut().take();
+        self.jobs.waker.borrow_mut().take();
+        self.jobs.timer.borrow_mut().cancel().ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::task::{RawWaker, RawWakerVTable, Waker};
+
+    use super::*;
+    use crate::{
+        ota::clock::ClockTimer,
+        test::{MockClock, MockMqtt},
+    };
+
+    fn noop_raw_waker() -> RawWaker {
+        fn no_op(_: *const ()) {}
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw_waker()
+        }
+
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, no_op, no_op, no_op);
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+
+    fn poll<F: Future>(fut: Pin<&mut F>) -> Poll<F::Output> {
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        fut.poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn resolved_by_response() {
+        let mqtt = MockMqtt::new();
+        let clock = MockClock::default();
+        let jobs = AsyncJobsClient::<_, _, 512>::new(
+            JobsClient::new(&mqtt),
+            ClockTimer::new(&clock),
+            1000,
+        );
+
+        let mut start_next = jobs.start_next();
+        let mut start_next = unsafe { Pin::new_unchecked(&mut start_next) };
+        assert!(poll(start_next.as_mut()).is_pending());
+
+        // Other messages are left to the application
+        assert!(!jobs
+            .handle_message(
+                "$aws/things/test_client/jobs/notify-next",
+                br#"{"timestamp":1587381778}"#,
+            )
+            .unwrap());
+        assert!(poll(start_next.as_mut()).is_pending());
+
+        let payload = br#"{
+            "clientToken": "0:test_client",
+            "timestamp": 1587381778,
+            "execution": {
+                "jobId": "job-1",
+                "status": "IN_PROGRESS",
+                "queuedAt": 1587036256,
+                "lastUpdatedAt": 1587381778,
+                "versionNumber": 2
+            }
+        }"#;
+        assert!(jobs
+            .handle_message("$aws/things/test_client/jobs/start-next/accepted", payload)
+            .unwrap());
+
+        let response = match poll(start_next.as_mut()) {
+            Poll::Ready(response) => response.unwrap(),
+            Poll::Pending => panic!("Response not resolved"),
+        };
+        assert_eq!(response.error(), None);
+        let execution = response.message::<()>().unwrap().execution().unwrap();
+        assert_eq!(execution.job_id, "job-1");
+    }
+
+    #[test]
+    fn rejected_and_timed_out() {
+        let mqtt = MockMqtt::new();
+        let clock = MockClock::default();
+        let jobs = AsyncJobsClient::<_, _, 512>::new(
+            JobsClient::new(&mqtt),
+            ClockTimer::new(&clock),
+            1000,
+        );
+
+        let mut update = jobs.update("job-1", JobStatus::Succeeded, None);
+        let mut update = unsafe { Pin::new_unchecked(&mut update) };
+        assert!(poll(update.as_mut()).is_pending());
+        assert!(jobs
+            .handle_message(
+                "$aws/things/test_client/jobs/job-1/update/rejected",
+                br#"{"code":"InvalidStateTransition","message":"","clientToken":"0:test_client","timestamp":1587381778}"#,
+            )
+            .unwrap());
+        let response = match poll(update.as_mut()) {
+            Poll::Ready(response) => response.unwrap(),
+            Poll::Pending => panic!("Response not resolved"),
+        };
+        assert_eq!(
+            response.error().map(|error| error.code),
+            Some(crate::jobs::JobsErrorCode::InvalidStateTransition)
+        );
+
+        let mut describe = jobs.describe(Some("job-1"));
+        let mut describe = unsafe { Pin::new_unchecked(&mut describe) };
+        assert!(poll(describe.as_mut()).is_pending());
+        clock.set(1000);
+        assert!(matches!(
+            poll(describe.as_mut()),
+            Poll::Ready(Err(JobError::Timeout))
+        ));
+    }
+
+    #[test]
+    fn one_request_at_a_time() {
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        };
+
+        struct Flag(AtomicBool);
+
+        impl std::task::Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let mqtt = MockMqtt::new();
+        let clock = MockClock::default();
+        let jobs = AsyncJobsClient::<_, _, 512>::new(
+            JobsClient::new(&mqtt),
+            ClockTimer::new(&clock),
+            1000,
+        );
+
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut get_pending = jobs.get_pending();
+        let mut get_pending = unsafe { Pin::new_unchecked(&mut get_pending) };
+        assert!(get_pending.as_mut().poll(&mut cx).is_pending());
+
+        // A second request is refused without being sent, leaving the first
+        // one waiting
+        mqtt.tx.borrow_mut().clear();
+        let mut start_next = jobs.start_next();
+        let mut start_next = unsafe { Pin::new_unchecked(&mut start_next) };
+        assert!(matches!(
+            poll(start_next.as_mut()),
+            Poll::Ready(Err(JobError::Busy))
+        ));
+        assert!(mqtt.tx.borrow_mut().is_empty());
+
+        // Any message wakes the waiting future, to check its timeout
+        assert!(!flag.0.load(Ordering::SeqCst));
+        jobs.handle_message(
+            "$aws/things/test_client/jobs/notify-next",
+            br#"{"timestamp":1587381778}"#,
+        )
+        .unwrap();
+        assert!(flag.0.swap(false, Ordering::SeqCst));
+        assert!(get_pending.as_mut().poll(&mut cx).is_pending());
+
+        clock.set(1000);
+        jobs.check_timeout();
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert!(matches!(
+            get_pending.as_mut().poll(&mut cx),
+            Poll::Ready(Err(JobError::Timeout))
+        ));
+    }
+
+    #[test]
+    fn dropped_request_frees_client() {
+        let mqtt = MockMqtt::new();
+        let clock = MockClock::default();
+        let jobs = AsyncJobsClient::<_, _, 512>::new(
+            JobsClient::new(&mqtt),
+            ClockTimer::new(&clock),
+            1000,
+        );
+
+        {
+            let mut get_pending = jobs.get_pending();
+            let get_pending = unsafe { Pin::new_unchecked(&mut get_pending) };
+            assert!(poll(get_pending).is_pending());
+        }
+
+        let mut describe = jobs.describe(None);
+        let describe = unsafe { Pin::new_unchecked(&mut describe) };
+        assert!(poll(describe).is_pending());
+    }
+}
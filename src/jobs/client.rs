@@ -0,0 +1,456 @@
+//! Compact client of the jobs MQTT API.
+//!
+//! For devices that only need remote command execution, [`JobsClient`] wraps
+//! the request builders of this module behind a handful of methods, each
+//! sending its request with a fresh client token. It has no dependency on the
+//! OTA agent, and leaves the processing of jobs entirely to the application:
+//!
+//! ```ignore
+//! let mut jobs = JobsClient::builder(&mqtt_client)
+//!     .notify_qos(QoS::AtMostOnce)
+//!     .max_document_len(1024)
+//!     .executions::<2>()
+//!     .build();
+//! jobs.subscribe()?;
+//! jobs.start_next()?;
+//!
+//! match jobs.handle_message::<Command>(topic, payload)? {
+//!     Some(JobMessage::StartNextAccepted(StartNextPendingJobExecutionResponse {
+//!         execution: Some(execution),
+//!         ..
+//!     })) => {
+//!         let status = run(execution.job_document);
+//!         jobs.update(execution.job_id, status, None)?;
+//!     }
+//!     Some(JobMessage::NotifyNext(_)) => jobs.start_next().map(drop)?,
+//!     _ => {}
+//! }
+//! ```
+//!
+//! The job executions claimed or described through the client are tracked,
+//! see [`JobExecutions`], so that updates are sent with the version the
+//! execution was last seen at as their expected version. The pending job
+//! executions of the thing, as last listed on `notify` or in response to
+//! GetPendingJobExecutions, are available through [`JobsClient::pending_jobs`].
+//!
+//! [`JobsAgent`](super::agent::JobsAgent) builds on the same requests to
+//! drive the whole job life cycle instead.
+
+use mqttrust::{Mqtt, QoS};
+use serde::Deserialize;
+
+use super::{
+    client_token::{client_token, ClientToken},
+    data_types::JobStatus,
+    executions::JobExecutions,
+    pending::PendingSnapshot,
+    subscribe::Subscribe,
+    unsubscribe::Unsubscribe,
+    JobError, JobMessage, Jobs, StatusDetails, Topic,
+};
+
+/// Configuration of a [`JobsClient`] tracking up to `N` job executions, see
+/// [`JobsClient::builder`]
+pub struct JobsClientBuilder<'a, M: Mqtt, const N: usize = 1> {
+    mqtt: &'a M,
+    config: Config,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Config {
+    notify_qos: QoS,
+    response_qos: QoS,
+    request_qos: QoS,
+    max_document_len: usize,
+    include_job_document: bool,
+    include_job_execution_state: bool,
+}
+
+impl<'a, M: Mqtt, const N: usize> JobsClientBuilder<'a, M, N> {
+    /// QoS of the subscriptions to `notify` and `notify-next`
+    pub fn notify_qos(mut self, qos: QoS) -> Self {
+        self.config.notify_qos = qos;
+        self
+    }
+
+    /// QoS of the subscriptions to the `accepted` and `rejected` topics
+    pub fn response_qos(mut self, qos: QoS) -> Self {
+        self.config.response_qos = qos;
+        self
+    }
+
+    /// QoS the requests are published with
+    pub fn request_qos(mut self, qos: QoS) -> Self {
+        self.config.request_qos = qos;
+        self
+    }
+
+    /// Reject messages larger than `len` bytes, job document included, with
+    /// [`JobError::Overflow`] instead of deserializing them
+    pub fn max_document_len(mut self, len: usize) -> Self {
+        self.config.max_document_len = len;
+        self
+    }
+
+    /// Whether describe responses include the job document, which is the
+    /// default. Devices that only follow the status of their jobs can leave it
+    /// out, and save on the buffer the response is received in.
+    ///
+    /// StartNextPendingJobExecution responses always include the job
+    /// document, see [`Self::max_document_len`].
+    pub fn include_job_document(mut self, include: bool) -> Self {
+        self.config.include_job_document = include;
+        self
+    }
+
+    /// Whether update responses include the execution state, i.e. the status
+    /// and version of the job execution after the update. Off by default, as
+    /// the version is tracked without it.
+    pub fn include_job_execution_state(mut self, include: bool) -> Self {
+        self.config.include_job_execution_state = include;
+        self
+    }
+
+    /// Track up to `K` job executions at once
+    pub fn executions<const K: usize>(self) -> JobsClientBuilder<'a, M, K> {
+        JobsClientBuilder {
+            mqtt: self.mqtt,
+            config: self.config,
+        }
+    }
+
+    pub fn build(self) -> JobsClient<'a, M, N> {
+        JobsClient {
+            mqtt: self.mqtt,
+            config: self.config,
+            request_cnt: 0,
+            executions: JobExecutions::new(),
+            pending: PendingSnapshot::new(),
+        }
+    }
+}
+
+pub struct JobsClient<'a, M: Mqtt, const N: usize = 1> {
+    mqtt: &'a M,
+    config: Config,
+    request_cnt: u32,
+    executions: JobExecutions<N>,
+    pending: PendingSnapshot,
+}
+
+impl<'a, M: Mqtt> JobsClient<'a, M> {
+    /// Client with all topics at QoS 1, tracking a single job execution
+    pub fn new(mqtt: &'a M) -> Self {
+        Self::builder(mqtt).build()
+    }
+
+    pub fn builder(mqtt: &'a M) -> JobsClientBuilder<'a, M> {
+        JobsClientBuilder {
+            mqtt,
+            config: Config {
+                notify_qos: QoS::AtLeastOnce,
+                response_qos: QoS::AtLeastOnce,
+                request_qos: QoS::AtLeastOnce,
+                max_document_len: usize::MAX,
+                include_job_document: true,
+                include_job_execution_state: false,
+            },
+        }
+    }
+}
+
+impl<'a, M: Mqtt, const N: usize> JobsClient<'a, M, N> {
+    /// Subscribe to the notifications, and the responses of all requests
+    pub fn subscribe(&self) -> Result<(), JobError> {
+        let Config {
+            notify_qos,
+            response_qos,
+            ..
+        } = self.config;
+
+        Subscribe::all(notify_qos, response_qos).send(self.mqtt)
+    }
+
+    pub fn unsubscribe(&self) -> Result<(), JobError> {
+        Unsubscribe::all().send(self.mqtt)
+    }
+
+    /// Job executions claimed or described through the client, and still
+    /// active
+    pub fn executions(&self) -> &JobExecutions<N> {
+        &self.executions
+    }
+
+    /// Pending job executions of the thing, as last listed by the service
+    pub fn pending_jobs(&self) -> &PendingSnapshot {
+        &self.pending
+    }
+
+    /// Get the pending job executions of the thing. Returns the client token
+    /// of the request.
+    pub fn get_pending(&mut self) -> Result<ClientToken, JobError> {
+        let client_token = self.client_token()?;
+        Jobs::get_pending()
+            .client_token(client_token.as_str())
+            .send(self.mqtt, self.config.request_qos)?;
+        Ok(client_token)
+    }
+
+    /// Describe the job execution `job_id`, or the next pending job execution
+    /// if `None`, including its job document unless configured otherwise.
+    /// Tracked job executions are described by their execution number.
+    pub fn describe(&mut self, job_id: Option<&str>) -> Result<ClientToken, JobError> {
+        let client_token = self.client_token()?;
+        let describe = Jobs::describe().client_token(client_token.as_str());
+        let describe = if self.config.include_job_document {
+            describe.include_job_document()
+        } else {
+            describe
+        };
+        let execution_number = job_id
+            .and_then(|job_id| self.executions.get(job_id))
+            .and_then(|execution| execution.execution_number());
+        match (job_id, execution_number) {
+            (Some(job_id), Some(execution_number)) => {
+                describe.job_id(job_id).execution_number(execution_number)
+            }
+            (Some(job_id), None) => describe.job_id(job_id),
+            (None, _) => describe.next(),
+        }
+        .send(self.mqtt, self.config.request_qos)?;
+        Ok(client_token)
+    }
+
+    /// Claim the next pending job execution, moving it to IN_PROGRESS
+    pub fn start_next(&mut self) -> Result<ClientToken, JobError> {
+        let client_token = self.client_token()?;
+        Jobs::start_next()
+            .client_token(client_token.as_str())
+            .send(self.mqtt, self.config.request_qos)?;
+        Ok(client_token)
+    }
+
+    /// Update the status of the job execution `job_id`, expecting the version
+    /// it was last seen at if tracked
+    pub fn update(
+        &mut self,
+        job_id: &str,
+        status: JobStatus,
+        status_details: Option<&StatusDetails>,
+    ) -> Result<ClientToken, JobError> {
+        let client_token = self.client_token()?;
+        let update = match self.executions.update(job_id, status) {
+            Some(update) => update,
+            None => Jobs::update(job_id, status),
+        }
+        .client_token(client_token.as_str());
+        let update = if self.config.include_job_execution_state {
+            update.include_job_execution_state()
+        } else {
+            update
+        };
+        match status_details {
+            Some(status_details) => update.status_details(status_details),
+            None => update,
+        }
+        .send(self.mqtt, self.config.request_qos)?;
+        Ok(client_token)
+    }
+
+    /// Deserialize a message received on any of the jobs topics, and track
+    /// the job executions it describes. Returns `None` for messages on other
+    /// topics.
+    pub fn handle_message<'b, J: Deserialize<'b>>(
+        &mut self,
+        topic: &'b str,
+        payload: &'b [u8],
+    ) -> Result<Option<JobMessage<'b, J>>, JobError> {
+        if payload.len() > self.config.max_document_len && Topic::from_str(topic).is_some() {
+            return Err(JobError::Overflow);
+        }
+
+        let message = match JobMessage::from_slice(topic, payload)? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+
+        self.executions.handle_message(&message);
+        match message {
+            JobMessage::Notify(ref message) => self.pending.notified(message),
+            JobMessage::GetPendingAccepted(ref response) => self.pending.listed(response),
+            _ => {}
+        }
+
+        Ok(Some(message))
+    }
+
+    fn client_token(&mut self) -> Result<ClientToken, JobError> {
+        let client_token = client_token(self.request_cnt, self.mqtt.client_id())?;
+        self.request_cnt = self.request_cnt.wrapping_add(1);
+        Ok(client_token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mqttrust::{encoding::v4::decode_slice, Packet};
+
+    use super::*;
+    use crate::test::MockMqtt;
+
+    fn published(mqtt: &MockMqtt) -> Vec<(String, String)> {
+        mqtt.tx
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Publish(p)) => Some((
+                    p.topic_name.to_string(),
+                    core::str::from_utf8(p.payload).unwrap().to_string(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn requests() {
+        let mqtt = MockMqtt::new();
+        let mut jobs = JobsClient::new(&mqtt);
+
+        jobs.subscribe().unwrap();
+        assert_eq!(mqtt.tx.borrow_mut().drain(..).count(), 2);
+
+        assert_eq!(jobs.start_next().unwrap().as_str(), "0:test_client");
+        jobs.describe(None).unwrap();
+        jobs.update("job-1", JobStatus::Succeeded, None).unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![
+                (
+                    "$aws/things/test_client/jobs/start-next".to_string(),
+                    r#"{"clientToken":"0:test_client"}"#.to_string()
+                ),
+                (
+                    "$aws/things/test_client/jobs/$next/get".to_string(),
+                    r#"{"includeJobDocument":true,"clientToken":"1:test_client"}"#.to_string()
+                ),
+                (
+                    "$aws/things/test_client/jobs/job-1/update".to_string(),
+                    r#"{"status":"SUCCEEDED","clientToken":"2:test_client"}"#.to_string()
+                ),
+            ]
+        );
+
+        let message = jobs
+            .handle_message::<()>(
+                "$aws/things/test_client/jobs/job-1/update/accepted",
+                br#"{"clientToken":"2:test_client","timestamp":1587381779}"#,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.client_token(), Some("2:test_client"));
+
+        jobs.handle_message::<()>(
+            "$aws/things/test_client/jobs/notify",
+            br#"{"timestamp":1587381780,"jobs":{"QUEUED":[{"jobId":"job-2","queuedAt":1587381780,"versionNumber":1}]}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            jobs.pending_jobs()
+                .queued()
+                .next()
+                .map(|job| job.job_id.as_str()),
+            Some("job-2")
+        );
+        assert!(jobs
+            .handle_message::<()>("$aws/things/test_client/shadow/update", b"{}")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn builder() {
+        let mqtt = MockMqtt::new();
+        let mut jobs = JobsClient::builder(&mqtt)
+            .notify_qos(QoS::AtMostOnce)
+            .max_document_len(512)
+            .executions::<2>()
+            .build();
+
+        jobs.subscribe().unwrap();
+        let qos: Vec<_> = mqtt
+            .tx
+            .borrow_mut()
+            .drain(..)
+            .flat_map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Subscribe(s)) => s.topics().map(|t| t.qos).collect::<Vec<_>>(),
+                _ => vec![],
+            })
+            .collect();
+        assert_eq!(
+            qos[..3],
+            [QoS::AtMostOnce, QoS::AtMostOnce, QoS::AtLeastOnce]
+        );
+
+        // Started jobs are updated with their expected version
+        jobs.start_next().unwrap();
+        jobs.handle_message::<()>(
+            "$aws/things/test_client/jobs/start-next/accepted",
+            br#"{
+                "clientToken": "0:test_client",
+                "timestamp": 1587381778,
+                "execution": {
+                    "jobId": "job-1",
+                    "status": "IN_PROGRESS",
+                    "queuedAt": 1587036256,
+                    "lastUpdatedAt": 1587381778,
+                    "versionNumber": 2
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(jobs.executions().len(), 1);
+        published(&mqtt);
+
+        jobs.update("job-1", JobStatus::Succeeded, None).unwrap();
+        assert_eq!(
+            published(&mqtt)[0].1,
+            r#"{"expectedVersion":2,"status":"SUCCEEDED","clientToken":"1:test_client"}"#
+        );
+
+        let payload = format!(
+            r#"{{"timestamp":1587381779,"padding":"{}"}}"#,
+            "a".repeat(512)
+        );
+        assert_eq!(
+            jobs.handle_message::<()>("$aws/things/test_client/jobs/notify", payload.as_bytes()),
+            Err(JobError::Overflow)
+        );
+    }
+
+    #[test]
+    fn include_flags() {
+        let mqtt = MockMqtt::new();
+        let mut jobs = JobsClient::builder(&mqtt)
+            .include_job_document(false)
+            .include_job_execution_state(true)
+            .build();
+
+        jobs.describe(Some("job-1")).unwrap();
+        jobs.update("job-1", JobStatus::InProgress, None).unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![
+                (
+                    "$aws/things/test_client/jobs/job-1/get".to_string(),
+                    r#"{"clientToken":"0:test_client"}"#.to_string()
+                ),
+                (
+                    "$aws/things/test_client/jobs/job-1/update".to_string(),
+                    r#"{"includeJobExecutionState":true,"status":"IN_PROGRESS","clientToken":"1:test_client"}"#
+                        .to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Correlation of jobs responses with the requests they answer.
+//!
+//! The accepted and rejected topics of a jobs API are shared by all requests
+//! of that API, so with several requests in flight, e.g. a progress update
+//! followed by the final update of a job, the client token reflected in the
+//! responses is the only way to tell which request a response belongs to.
+
+use core::fmt::Write;
+
+use super::{JobError, MAX_CLIENT_TOKEN_LEN};
+
+pub type ClientToken = heapless::String<MAX_CLIENT_TOKEN_LEN>;
+
+/// Generates unique client tokens on the form `{requestNumber}:{thingName}`,
+/// and keeps track of up to `N` requests in flight, identified by `R`.
+///
+/// ```ignore
+/// let client_token = requests.request(mqtt.client_id(), Request::Progress)?;
+/// Jobs::update(job_id, JobStatus::InProgress)
+///     .client_token(client_token.as_str())
+///     .send(mqtt, QoS::AtLeastOnce)?;
+///
+/// // On `$aws/things/{thingName}/jobs/{jobId}/update/accepted`
+/// match message.client_token().and_then(|token| requests.response(token)) {
+///     Some(Request::Progress) => {}
+///     ...
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ClientTokens<R, const N: usize> {
+    request_cnt: u32,
+    in_flight: heapless::Vec<(u32, R), N>,
+}
+
+impl<R, const N: usize> Default for ClientTokens<R, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R, const N: usize> ClientTokens<R, N> {
+    pub const fn new() -> Self {
+        Self {
+            request_cnt: 0,
+            in_flight: heapless::Vec::new(),
+        }
+    }
+
+    /// Register `request` as in flight, and get the client token to send it
+    /// with. If `N` requests are in flight already, the oldest one is
+    /// forgotten, and its response will no longer be matched.
+    pub fn request(&mut self, client_id: &str, request: R) -> Result<ClientToken, JobError> {
+        let request_number = self.request_cnt;
+        let client_token = client_token(request_number, client_id)?;
+
+        self.request_cnt = self.request_cnt.wrapping_add(1);
+
+        if self.in_flight.is_full() {
+            let oldest = self
+                .in_flight
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, (number, _))| request_number.wrapping_sub(*number))
+                .map(|(i, _)| i);
+            if let Some(oldest) = oldest {
+                self.in_flight.swap_remove(oldest);
+            }
+        }
+        self.in_flight.push((request_number, request)).ok();
+
+        Ok(client_token)
+    }
+
+    /// Take the request answered by a response carrying `client_token`.
+    /// Returns `None` for tokens that were not generated here, or whose
+    /// request is no longer in flight, e.g. a duplicate response.
+    pub fn response(&mut self, client_token: &str) -> Option<R> {
+        let request_number = client_token.splitn(2, ':').next()?.parse::<u32>().ok()?;
+
+        let i = self
+            .in_flight
+            .iter()
+            .position(|(number, _)| *number == request_number)?;
+        Some(self.in_flight.swap_remove(i).1)
+    }
+
+    /// Number of requests waiting for a response
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Forget all requests in flight, e.g. on reconnection
+    pub fn clear(&mut self) {
+        self.in_flight.clear();
+    }
+}
+
+/// Client token of request number `request_number`
+pub(crate) fn client_token(request_number: u32, client_id: &str) -> Result<ClientToken, JobError> {
+    let mut client_token = ClientToken::new();
+    client_token
+        .write_fmt(format_args!("{}:{}", request_number, client_id))
+        .map_err(|_| JobError::Overflow)?;
+    Ok(client_token)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Request {
+        Describe,
+        Update,
+    }
+
+    #[test]
+    fn correlate_responses() {
+        let mut requests = ClientTokens::<Request, 2>::new();
+
+        let describe = requests.request("test_client", Request::Describe).unwrap();
+        let update = requests.request("test_client", Request::Update).unwrap();
+        assert_eq!(describe.as_str(), "0:test_client");
+        assert_eq!(update.as_str(), "1:test_client");
+        assert_eq!(requests.in_flight(), 2);
+
+        // Responses may arrive in any order, but only once
+        assert_eq!(requests.response(&update), Some(Request::Update));
+        assert_eq!(requests.response(&update), None);
+        assert_eq!(requests.response(&describe), Some(Request::Describe));
+        assert_eq!(requests.response("token_of_someone_else"), None);
+        assert_eq!(requests.in_flight(), 0);
+    }
+
+    #[test]
+    fn oldest_request_dropped() {
+        let mut requests = ClientTokens::<Request, 2>::new();
+
+        let first = requests.request("test_client", Request::Update).unwrap();
+        let second = requests.request("test_client", Request::Update).unwrap();
+        let third = requests.request("test_client", Request::Describe).unwrap();
+
+        assert_eq!(requests.response(&first), None);
+        assert_eq!(requests.response(&third), Some(Request::Describe));
+        assert_eq!(requests.response(&second), Some(Request::Update));
+    }
+}
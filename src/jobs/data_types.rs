@@ -1,7 +1,7 @@
-use heapless::{String, Vec};
+use heapless::Vec;
 use serde::{Deserialize, Serialize};
 
-use super::{StatusDetails, MAX_JOB_ID_LEN, MAX_PENDING_JOBS, MAX_RUNNING_JOBS};
+use super::{StatusDetailsRef, MAX_PENDING_JOBS, MAX_RUNNING_JOBS};
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
@@ -22,7 +22,19 @@ pub enum JobStatus {
     Removed,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+/// Documented error codes of the jobs API, given in the `code` of an
+/// [`ErrorResponse`]. New codes may be added as the service documents them.
+///
+/// ```ignore
+/// match error.code {
+///     JobsErrorCode::TerminalStateReached => abort(job_id),
+///     code if code.is_transient() => retry_later(),
+///     _ => {}
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+#[non_exhaustive]
 pub enum ErrorCode {
     /// The request was sent to a topic in the AWS IoT Jobs namespace that does
     /// not map to any API.
@@ -50,11 +62,24 @@ pub enum ErrorCode {
     InternalError,
     /// The request was throttled.
     RequestThrottled,
+    /// The service is temporarily unavailable.
+    ServiceUnavailable,
     /// Occurs when a command to describe a job is performed on a job that is in
     /// a terminal state.
     TerminalStateReached,
 }
 
+impl ErrorCode {
+    /// Whether the request may succeed when sent again later, as the error is
+    /// on the service side rather than with the request itself.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::InternalError | Self::RequestThrottled | Self::ServiceUnavailable
+        )
+    }
+}
+
 /// Topic (accepted): $aws/things/{thingName}/jobs/{jobId}/get/accepted \
 /// Topic (rejected): $aws/things/{thingName}/jobs/{jobId}/get/rejected
 #[derive(Debug, PartialEq, Deserialize)]
@@ -79,11 +104,13 @@ pub struct GetPendingJobExecutionsResponse<'a> {
     /// A list of JobExecutionSummary objects with status IN_PROGRESS.
     #[serde(rename = "inProgressJobs")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub in_progress_jobs: Option<Vec<JobExecutionSummary, MAX_RUNNING_JOBS>>,
+    #[serde(borrow)]
+    pub in_progress_jobs: Option<Vec<JobExecutionSummary<'a>, MAX_RUNNING_JOBS>>,
     /// A list of JobExecutionSummary objects with status QUEUED.
     #[serde(rename = "queuedJobs")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub queued_jobs: Option<Vec<JobExecutionSummary, MAX_PENDING_JOBS>>,
+    #[serde(borrow)]
+    pub queued_jobs: Option<Vec<JobExecutionSummary<'a>, MAX_PENDING_JOBS>>,
     /// The time, in seconds since the epoch, when the message was sent.
     #[serde(rename = "timestamp")]
     pub timestamp: i64,
@@ -91,9 +118,26 @@ pub struct GetPendingJobExecutionsResponse<'a> {
     /// arbitrary value here and it is reflected in the response.
     #[serde(rename = "clientToken")]
     pub client_token: &'a str,
+    /// Token to request the next page of pending job executions with, if the
+    /// lists were truncated to the requested number of results.
+    #[serde(rename = "nextToken")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_token: Option<&'a str>,
+}
+
+impl<'a> GetPendingJobExecutionsResponse<'a> {
+    /// All job executions in the response, the ones in progress first
+    pub fn jobs(&self) -> impl Iterator<Item = &JobExecutionSummary<'a>> {
+        self.in_progress_jobs
+            .iter()
+            .flatten()
+            .chain(self.queued_jobs.iter().flatten())
+    }
 }
 
-/// Contains data about a job execution.
+/// Contains data about a job execution. As for all responses of the jobs API,
+/// fields unknown to this crate are ignored, so payloads carrying fields added
+/// to the service later on still deserialize.
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct JobExecution<'a, J> {
     /// The estimated number of seconds that remain before the job execution
@@ -131,11 +175,12 @@ pub struct JobExecution<'a, J> {
     /// "FAILED", "SUCCESS", "CANCELED", "REJECTED", or "REMOVED".
     #[serde(rename = "status")]
     pub status: JobStatus,
-    // / A collection of name/value pairs that describe the status of the job
-    // execution.
+    /// A collection of name/value pairs that describe the status of the job
+    /// execution, borrowed from the message.
     #[serde(rename = "statusDetails")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub status_details: Option<StatusDetails>,
+    #[serde(borrow)]
+    pub status_details: Option<StatusDetailsRef<'a>>,
     // The name of the thing that is executing the job.
     #[serde(rename = "thingName")]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -148,16 +193,17 @@ pub struct JobExecution<'a, J> {
 
 /// Contains data about the state of a job execution.
 #[derive(Debug, PartialEq, Deserialize)]
-pub struct JobExecutionState {
+pub struct JobExecutionState<'a> {
     /// The status of the job execution. Can be one of: "QUEUED", "IN_PROGRESS",
     /// "FAILED", "SUCCESS", "CANCELED", "REJECTED", or "REMOVED".
     #[serde(rename = "status")]
     pub status: JobStatus,
     /// A collection of name/value pairs that describe the status of the job
-    /// execution.
+    /// execution, borrowed from the message.
     #[serde(rename = "statusDetails")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub status_details: Option<StatusDetails>,
+    #[serde(borrow)]
+    pub status_details: Option<StatusDetailsRef<'a>>,
     // The version of the job execution. Job execution versions are incremented
     // each time they are updated by a device.
     #[serde(rename = "versionNumber")]
@@ -166,7 +212,7 @@ pub struct JobExecutionState {
 
 /// Contains a subset of information about a job execution.
 #[derive(Debug, Clone, PartialEq, Deserialize)]
-pub struct JobExecutionSummary {
+pub struct JobExecutionSummary<'a> {
     /// A number that identifies a particular job execution on a particular
     /// device.
     #[serde(rename = "executionNumber")]
@@ -175,7 +221,8 @@ pub struct JobExecutionSummary {
     /// The unique identifier you assigned to this job when it was created.
     #[serde(rename = "jobId")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub job_id: Option<String<MAX_JOB_ID_LEN>>,
+    #[serde(borrow)]
+    pub job_id: Option<&'a str>,
     /// The time, in seconds since the epoch, when the job execution was last
     /// updated.
     #[serde(rename = "lastUpdatedAt")]
@@ -221,7 +268,8 @@ pub struct UpdateJobExecutionResponse<'a, J> {
     /// A JobExecutionState object.
     #[serde(rename = "executionState")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub execution_state: Option<JobExecutionState>,
+    #[serde(borrow)]
+    pub execution_state: Option<JobExecutionState<'a>>,
     /// The contents of the Job Documents.
     #[serde(rename = "jobDocument")]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -240,16 +288,29 @@ pub struct UpdateJobExecutionResponse<'a, J> {
 ///
 /// Topic: $aws/things/{thingName}/jobs/notify
 #[derive(Debug, Clone, PartialEq, Deserialize)]
-pub struct JobExecutionsChanged {
+pub struct JobExecutionsChanged<'a> {
     /// A list of JobExecutionSummary objects with status IN_PROGRESS.
     #[serde(rename = "jobs")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub jobs: Option<Jobs>,
+    #[serde(borrow)]
+    pub jobs: Option<Jobs<'a>>,
     /// The time, in seconds since the epoch, when the message was sent.
     #[serde(rename = "timestamp")]
     pub timestamp: i64,
 }
 
+impl<'a> JobExecutionsChanged<'a> {
+    /// All pending job executions listed, the ones in progress first
+    pub fn jobs(&self) -> impl Iterator<Item = &JobExecutionSummary<'a>> {
+        self.jobs.iter().flat_map(|jobs| {
+            jobs.in_progress
+                .iter()
+                .flatten()
+                .chain(jobs.queued.iter().flatten())
+        })
+    }
+}
+
 /// Sent whenever there is a change to which job execution is next on the list
 /// of pending job executions for a thing, as defined for DescribeJobExecution
 /// with jobId $next. This message is not sent when the next job's execution
@@ -274,24 +335,26 @@ pub struct NextJobExecutionChanged<'a, J> {
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
-pub struct Jobs {
+pub struct Jobs<'a> {
     /// Queued jobs.
     #[serde(rename = "QUEUED")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub queued: Option<Vec<JobExecutionSummary, MAX_RUNNING_JOBS>>,
+    #[serde(borrow)]
+    pub queued: Option<Vec<JobExecutionSummary<'a>, MAX_RUNNING_JOBS>>,
     /// In-progress jobs.
     #[serde(rename = "IN_PROGRESS")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub in_progress: Option<Vec<JobExecutionSummary, MAX_RUNNING_JOBS>>,
+    #[serde(borrow)]
+    pub in_progress: Option<Vec<JobExecutionSummary<'a>, MAX_RUNNING_JOBS>>,
 }
 
 /// Contains information about an error that occurred during an AWS IoT Jobs
 /// service operation.
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct ErrorResponse<'a> {
-    code: ErrorCode,
+    pub code: ErrorCode,
     /// An error message string.
-    message: &'a str,
+    pub message: &'a str,
     /// A client token used to correlate requests and responses. Enter an
     /// arbitrary value here and it is reflected in the response.
     #[serde(rename = "clientToken")]
@@ -303,12 +366,29 @@ pub struct ErrorResponse<'a> {
     /// A JobExecutionState object.
     #[serde(rename = "executionState")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub execution_state: Option<JobExecutionState>,
+    #[serde(borrow)]
+    pub execution_state: Option<JobExecutionState<'a>>,
+}
+
+impl<'a> ErrorResponse<'a> {
+    /// Whether the request was rejected because the job execution is no
+    /// longer active, e.g. as it was canceled or removed in the cloud.
+    pub fn terminal_state(&self) -> bool {
+        self.code == ErrorCode::TerminalStateReached
+            || matches!(
+                self.execution_state,
+                Some(JobExecutionState {
+                    status: JobStatus::Canceled | JobStatus::Removed,
+                    ..
+                })
+            )
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::jobs::{owned_status_details, JobError};
     use heapless::Vec;
     use serde_json_core::from_slice;
 
@@ -395,6 +475,7 @@ mod test {
                 queued_jobs: None,
                 timestamp: 1587381778,
                 client_token: "0:client_name",
+                next_token: None,
             }
         );
 
@@ -417,7 +498,7 @@ mod test {
         queued_jobs
             .push(JobExecutionSummary {
                 execution_number: Some(1),
-                job_id: Some(String::from("test")),
+                job_id: Some("test"),
                 last_updated_at: Some(1587036256),
                 queued_at: Some(1587036256),
                 started_at: None,
@@ -434,6 +515,7 @@ mod test {
                 queued_jobs: Some(queued_jobs),
                 timestamp: 1587381778,
                 client_token: "0:client_name",
+                next_token: None,
             }
         );
     }
@@ -486,4 +568,169 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn deserialize_borrowed_status_details() {
+        let payload = br#"{
+                "jobId": "job-with-a-rather-long-id",
+                "status": "IN_PROGRESS",
+                "statusDetails": { "lastError": "checksum of the downloaded image mismatches" },
+                "queuedAt": 1587036256,
+                "lastUpdatedAt": 1587036260,
+                "versionNumber": 3
+            }"#;
+
+        let (execution, _) = from_slice::<JobExecution<JobDetails>>(payload).unwrap();
+
+        let status_details = execution.status_details.unwrap();
+        assert_eq!(
+            status_details.get("lastError"),
+            Some(&"checksum of the downloaded image mismatches")
+        );
+        // Too long for the owned status details
+        assert_eq!(
+            owned_status_details(&status_details),
+            Err(JobError::Overflow)
+        );
+    }
+
+    #[test]
+    fn error_response_terminal_state() {
+        let payload = br#"{
+                "code": "InvalidStateTransition",
+                "message": "Job execution is canceled",
+                "timestamp": 1587381778,
+                "executionState": {
+                    "status": "CANCELED",
+                    "versionNumber": 3
+                }
+            }"#;
+
+        let (response, _) = from_slice::<ErrorResponse>(payload).unwrap();
+        assert!(response.terminal_state());
+
+        let payload = br#"{
+                "code": "RequestThrottled",
+                "message": "Rate exceeded",
+                "timestamp": 1587381778
+            }"#;
+
+        let (response, _) = from_slice::<ErrorResponse>(payload).unwrap();
+        assert!(!response.terminal_state());
+    }
+
+    #[test]
+    fn error_response_codes() {
+        let codes = [
+            ("InvalidTopic", ErrorCode::InvalidTopic),
+            ("InvalidJson", ErrorCode::InvalidJson),
+            ("InvalidRequest", ErrorCode::InvalidRequest),
+            ("InvalidStateTransition", ErrorCode::InvalidStateTransition),
+            ("ResourceNotFound", ErrorCode::ResourceNotFound),
+            ("VersionMismatch", ErrorCode::VersionMismatch),
+            ("InternalError", ErrorCode::InternalError),
+            ("RequestThrottled", ErrorCode::RequestThrottled),
+            ("ServiceUnavailable", ErrorCode::ServiceUnavailable),
+            ("TerminalStateReached", ErrorCode::TerminalStateReached),
+        ];
+
+        for (name, code) in codes.iter() {
+            let payload = format!(
+                r#"{{"code":"{}","message":"Rejected","clientToken":"3:client_name","timestamp":1587381778}}"#,
+                name
+            );
+            let (response, _) = from_slice::<ErrorResponse>(payload.as_bytes()).unwrap();
+            assert_eq!(
+                response,
+                ErrorResponse {
+                    code: *code,
+                    message: "Rejected",
+                    client_token: Some("3:client_name"),
+                    timestamp: 1587381778,
+                    execution_state: None,
+                }
+            );
+        }
+
+        assert!(
+            from_slice::<ErrorResponse>(br#"{"code":"Unknown","message":"","timestamp":0}"#)
+                .is_err()
+        );
+    }
+    #[test]
+    fn unknown_fields_ignored() {
+        // Fields the service may add in the future, of any shape
+        let payload = br#"{
+            "timestamp": 1587471560,
+            "futureFlag": true,
+            "execution": {
+                "jobId": "mini",
+                "status": "IN_PROGRESS",
+                "queuedAt": 1587471559,
+                "lastUpdatedAt": 1587471559,
+                "versionNumber": 2,
+                "schedulingConfig": { "endBehavior": "STOP_ROLLOUT", "windows": [{ "durationInMinutes": 30 }] },
+                "retryAttempt": 1,
+                "destinationPackageVersions": ["arn:aws:iot:eu-west-1:1:package/a/version/1"],
+                "jobDocument": { "test_job": { "operation": "test", "somerandomkey": "random_value" } },
+                "statusReason": null
+            },
+            "trailer": "x"
+        }"#;
+
+        let (response, _) = from_slice::<NextJobExecutionChanged<JobDetails>>(payload).unwrap();
+        let execution = response.execution.unwrap();
+        assert_eq!(execution.job_id, "mini");
+        assert_eq!(execution.version_number, 2);
+        assert_eq!(
+            execution.job_document,
+            Some(JobDetails::TestJob(TestJob {
+                operation: "test",
+                somerandomkey: "random_value"
+            }))
+        );
+        // The raw document is still available, including fields unknown to `J`
+        assert_eq!(
+            crate::jobs::message::raw_job_document(payload),
+            Some(
+                &br#"{ "test_job": { "operation": "test", "somerandomkey": "random_value" } }"#[..]
+            )
+        );
+
+        let (response, _) = from_slice::<UpdateJobExecutionResponse<()>>(
+            br#"{
+                "executionState": { "status": "SUCCEEDED", "versionNumber": 3, "statusReason": { "code": "OK" } },
+                "clientToken": "1:client_name",
+                "timestamp": 1587471561,
+                "requestId": "c1b0"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(response.execution_state.unwrap().version_number, 3);
+
+        let (response, _) = from_slice::<ErrorResponse>(
+            br#"{
+                "code": "VersionMismatch",
+                "message": "Version mismatch",
+                "timestamp": 1587471562,
+                "details": { "expected": 2 },
+                "executionState": { "status": "IN_PROGRESS", "versionNumber": 4, "retryAttempt": 0 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(response.code, ErrorCode::VersionMismatch);
+        assert_eq!(response.execution_state.unwrap().version_number, 4);
+
+        let (response, _) = from_slice::<JobExecutionsChanged>(
+            br#"{
+                "timestamp": 1587471563,
+                "jobs": { "IN_PROGRESS": [{ "jobId": "mini", "versionNumber": 2, "retryAttempt": 1 }], "SCHEDULED": [] }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.jobs().next().and_then(|job| job.job_id),
+            Some("mini")
+        );
+    }
 }
@@ -1,9 +1,16 @@
 use mqttrust::{Mqtt, QoS};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::jobs::JobTopic;
 
-use super::{JobError, MAX_CLIENT_TOKEN_LEN, MAX_JOB_ID_LEN, MAX_THING_NAME_LEN};
+use super::{
+    data_types::{DescribeJobExecutionResponse, ErrorResponse},
+    JobError, Topic, MAX_CLIENT_TOKEN_LEN, MAX_JOB_ID_LEN, MAX_THING_NAME_LEN, NEXT_JOB_ID,
+};
+
+/// Longest request payload, besides the client token: an `executionNumber` of
+/// up to 20 characters, and `includeJobDocument`.
+const MAX_REQUEST_LEN: usize = 83;
 
 /// Gets detailed information about a job execution.
 ///
@@ -53,11 +60,23 @@ impl<'a> Describe<'a> {
         }
     }
 
+    /// Describe the job execution `job_id`. [`NEXT_JOB_ID`] is the same as
+    /// [`Self::next`].
     pub fn job_id(self, job_id: &'a str) -> Self {
         assert!(job_id.len() < MAX_JOB_ID_LEN);
 
         Self {
-            job_id: Some(job_id),
+            job_id: Some(job_id).filter(|job_id| *job_id != NEXT_JOB_ID),
+            ..self
+        }
+    }
+
+    /// Describe the next pending job execution, QUEUED or IN_PROGRESS, which
+    /// is the default. Lets single job devices fetch their job without
+    /// listing the pending job executions first.
+    pub fn next(self) -> Self {
+        Self {
+            job_id: None,
             ..self
         }
     }
@@ -82,7 +101,7 @@ impl<'a> Describe<'a> {
     ) -> Result<
         (
             heapless::String<{ MAX_THING_NAME_LEN + MAX_JOB_ID_LEN + 22 }>,
-            heapless::Vec<u8, { MAX_CLIENT_TOKEN_LEN + 2 }>,
+            heapless::Vec<u8, { MAX_CLIENT_TOKEN_LEN + MAX_REQUEST_LEN }>,
         ),
         JobError,
     > {
@@ -103,12 +122,52 @@ impl<'a> Describe<'a> {
     }
 
     pub fn send<M: Mqtt>(self, mqtt: &M, qos: QoS) -> Result<(), JobError> {
-        let (topic, payload) = self.topic_payload(mqtt.client_id())?;
+        self.send_for(mqtt, mqtt.client_id(), qos)
+    }
+
+    /// Send the request on behalf of `thing_name`, e.g. a thing proxied by a
+    /// gateway, rather than the thing of the MQTT client
+    pub fn send_for<M: Mqtt>(self, mqtt: &M, thing_name: &str, qos: QoS) -> Result<(), JobError> {
+        let (topic, payload) = self.topic_payload(thing_name)?;
 
         mqtt.publish(topic.as_str(), &payload, qos)?;
 
         Ok(())
     }
+
+    /// Parse the response to describing `job_id`, or the next pending job if
+    /// `None`, as received on `topic`. Returns `None` for messages on any
+    /// other topic, including responses for other jobs.
+    ///
+    /// Both the `get/accepted` and `get/rejected` topics of the job have to
+    /// be subscribed to receive the response.
+    pub fn response<'b, J: Deserialize<'b>>(
+        job_id: Option<&str>,
+        topic: &'b str,
+        payload: &'b [u8],
+    ) -> Result<Option<DescribeResponse<'b, J>>, JobError> {
+        let job_id = job_id.unwrap_or(NEXT_JOB_ID);
+        let response = match Topic::from_str(topic) {
+            Some(Topic::DescribeAccepted(id)) if id == job_id => {
+                serde_json_core::from_slice(payload).map(|(r, _)| DescribeResponse::Accepted(r))
+            }
+            Some(Topic::DescribeRejected(id)) if id == job_id => {
+                serde_json_core::from_slice(payload).map(|(r, _)| DescribeResponse::Rejected(r))
+            }
+            _ => return Ok(None),
+        };
+
+        response.map(Some).map_err(|_| JobError::Encoding)
+    }
+}
+
+/// Response to a [`Describe`] request
+#[derive(Debug, PartialEq)]
+pub enum DescribeResponse<'a, J> {
+    /// Topic: $aws/things/{thingName}/jobs/{jobId}/get/accepted
+    Accepted(DescribeJobExecutionResponse<'a, J>),
+    /// Topic: $aws/things/{thingName}/jobs/{jobId}/get/rejected
+    Rejected(ErrorResponse<'a>),
 }
 
 #[cfg(test)]
@@ -166,4 +225,100 @@ mod test {
             "$aws/things/test_client/jobs/test_job_id/get"
         );
     }
+
+    #[test]
+    fn topic_next() {
+        let (topic, _) = Describe::new()
+            .job_id("test_job_id")
+            .next()
+            .topic_payload("test_client")
+            .unwrap();
+        assert_eq!(topic.as_str(), "$aws/things/test_client/jobs/$next/get");
+
+        let (topic, _) = Describe::new()
+            .job_id(NEXT_JOB_ID)
+            .topic_payload("test_client")
+            .unwrap();
+        assert_eq!(topic.as_str(), "$aws/things/test_client/jobs/$next/get");
+    }
+
+    #[test]
+    fn topic_payload_long_client_token() {
+        let client_token = [b'a'; MAX_CLIENT_TOKEN_LEN - 1];
+        let client_token = core::str::from_utf8(&client_token).unwrap();
+
+        let (_, payload) = Describe::new()
+            .include_job_document()
+            .execution_number(i64::MIN)
+            .job_id("test_job_id")
+            .client_token(client_token)
+            .topic_payload("test_client")
+            .unwrap();
+
+        assert!(payload.ends_with(br#"a"}"#));
+    }
+
+    #[test]
+    fn parse_response() {
+        use crate::jobs::data_types::{ErrorCode, JobStatus};
+
+        let payload = br#"{
+                "clientToken": "0:test_client",
+                "timestamp": 1587381778,
+                "execution": {
+                    "jobId": "test_job_id",
+                    "status": "IN_PROGRESS",
+                    "queuedAt": 1587036256,
+                    "lastUpdatedAt": 1587036256,
+                    "versionNumber": 3,
+                    "executionNumber": 2,
+                    "jobDocument": { "operation": "reboot" }
+                }
+            }"#;
+        let topic = "$aws/things/test_client/jobs/test_job_id/get/accepted";
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Document<'a> {
+            operation: &'a str,
+        }
+
+        match Describe::response::<Document>(Some("test_job_id"), topic, payload) {
+            Ok(Some(DescribeResponse::Accepted(response))) => {
+                let execution = response.execution.unwrap();
+                assert_eq!(execution.status, JobStatus::InProgress);
+                assert_eq!(execution.execution_number, Some(2));
+                assert_eq!(
+                    execution.job_document,
+                    Some(Document {
+                        operation: "reboot"
+                    })
+                );
+            }
+            _ => panic!("Expected an accepted response"),
+        }
+
+        // Responses for other jobs are not picked up
+        assert_eq!(
+            Describe::response::<Document>(Some("other_job"), topic, payload),
+            Ok(None)
+        );
+        assert_eq!(
+            Describe::response::<Document>(None, topic, payload),
+            Ok(None)
+        );
+
+        let payload = br#"{
+                "code": "ResourceNotFound",
+                "message": "Job execution not found",
+                "timestamp": 1587381778
+            }"#;
+        let topic = "$aws/things/test_client/jobs/$next/get/rejected";
+
+        match Describe::response::<Document>(None, topic, payload) {
+            Ok(Some(DescribeResponse::Rejected(response))) => {
+                assert_eq!(response.code, ErrorCode::ResourceNotFound);
+            }
+            _ => panic!("Expected a rejected response"),
+        }
+    }
 }
@@ -0,0 +1,76 @@
+//! Jobs agent events, for superloop applications.
+//!
+//! [`JobsAgent::handle_message`] returns a [`JobsEvent`] whenever a message
+//! changes anything the application may want to act upon, so all jobs
+//! activity can be routed through a single match:
+//!
+//! ```ignore
+//! match agent.handle_message::<Jobs>(topic, payload)? {
+//!     Some(JobsEvent::JobReceived) => display.show(agent.job_id()),
+//!     Some(JobsEvent::UpdateRejected { code }) => telemetry.report(code),
+//!     Some(JobsEvent::PendingListChanged) => refresh_pending_jobs(),
+//!     _ => {}
+//! }
+//! ```
+//!
+//! Applications with job documents the typed `J` can't describe get the raw
+//! message along with the event from [`JobsAgent::handle_message_raw`], and
+//! parse the document themselves, e.g. with a handler leaving the job
+//! [`Pending`](super::agent::JobOutcome::Pending):
+//!
+//! ```ignore
+//! if let Some(raw) = agent.handle_message_raw::<IgnoredAny>(topic, payload)? {
+//!     if raw.event == Some(JobsEvent::JobReceived) {
+//!         let outcome = run_script(raw.job_document());
+//!         agent.complete(outcome, None)?;
+//!     }
+//! }
+//! ```
+//!
+//! [`JobsAgent::handle_message`]: super::agent::JobsAgent::handle_message
+//! [`JobsAgent::handle_message_raw`]: super::agent::JobsAgent::handle_message_raw
+
+use super::{data_types::ErrorCode, message::raw_job_document};
+
+/// Notable outcome of a jobs message handled by the agent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum JobsEvent {
+    /// A job was claimed and handed to the handler. Its id is available
+    /// through [`JobsAgent::job_id`] until it is completed.
+    ///
+    /// [`JobsAgent::job_id`]: super::agent::JobsAgent::job_id
+    JobReceived,
+    /// The running job ended on the service side, e.g. as it was canceled,
+    /// and the handler was told to stop it.
+    JobCanceled,
+    /// An update of the current job was accepted
+    UpdateAccepted,
+    /// An update of the current job was rejected. Rejections with a version
+    /// mismatch, or a transient error, are followed by the agent retrying.
+    UpdateRejected { code: ErrorCode },
+    /// A job was added to, or removed from, the pending job executions of the
+    /// thing, as notified on `$aws/things/{thingName}/jobs/notify`. A
+    /// notification no longer listing the running job is a
+    /// [`JobsEvent::JobCanceled`] instead. The pending job executions are
+    /// available through [`JobsAgent::pending_jobs`] either way.
+    ///
+    /// [`JobsAgent::pending_jobs`]: super::agent::JobsAgent::pending_jobs
+    PendingListChanged,
+}
+
+/// Message handled by the agent on one of the jobs topics, along with the
+/// event it resulted in, if any
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawJobsEvent<'a> {
+    pub event: Option<JobsEvent>,
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+}
+
+impl<'a> RawJobsEvent<'a> {
+    /// Raw JSON of the job document carried by the message, if any
+    pub fn job_document(&self) -> Option<&'a [u8]> {
+        raw_job_document(self.payload)
+    }
+}
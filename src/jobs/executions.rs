@@ -0,0 +1,448 @@
+use super::{
+    data_types::{
+        DescribeJobExecutionResponse, ErrorResponse, JobExecution, JobExecutionSummary, JobStatus,
+        StartNextPendingJobExecutionResponse, UpdateJobExecutionResponse,
+    },
+    update::Update,
+    JobError, JobMessage, Topic, MAX_JOB_ID_LEN,
+};
+
+/// Tracks up to `N` job executions of the device running concurrently, e.g.
+/// diagnostics and a configuration change alongside an OTA job, each with its
+/// own status and version.
+///
+/// Updates are created with the version the job execution was last seen at
+/// as their expected version, and the version is kept up to date from the
+/// update responses, independently for every job. They also carry the
+/// execution number of the job execution, if known, so that a job deployed
+/// again under the same job id is not mistaken for the previous execution.
+///
+/// ```ignore
+/// executions.track(&execution)?;
+/// if let Some(update) = executions.update(execution.job_id, JobStatus::InProgress) {
+///     update.send(mqtt, QoS::AtLeastOnce)?;
+/// }
+///
+/// match JobMessage::<Jobs>::from_slice(topic, payload)? {
+///     Some(JobMessage::UpdateAccepted(job_id, response)) => executions.accepted(job_id, &response),
+///     Some(JobMessage::Rejected(Topic::UpdateRejected(job_id), error)) => {
+///         executions.rejected(job_id, &error)
+///     }
+///     _ => {}
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct JobExecutions<const N: usize> {
+    executions: heapless::Vec<TrackedExecution, N>,
+}
+
+/// Job execution tracked by [`JobExecutions`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackedExecution {
+    job_id: heapless::String<MAX_JOB_ID_LEN>,
+    execution_number: Option<i64>,
+    status: JobStatus,
+    version_number: i64,
+    /// Status sent in an update waiting for a response
+    requested: Option<JobStatus>,
+}
+
+impl TrackedExecution {
+    pub fn job_id(&self) -> &str {
+        self.job_id.as_str()
+    }
+
+    pub fn execution_number(&self) -> Option<i64> {
+        self.execution_number
+    }
+
+    /// Status of the job execution, as last accepted by the service
+    pub fn status(&self) -> JobStatus {
+        self.status
+    }
+
+    pub fn version_number(&self) -> i64 {
+        self.version_number
+    }
+}
+
+impl<const N: usize> JobExecutions<N> {
+    pub const fn new() -> Self {
+        Self {
+            executions: heapless::Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.executions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.executions.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TrackedExecution> {
+        self.executions.iter()
+    }
+
+    pub fn get(&self, job_id: &str) -> Option<&TrackedExecution> {
+        self.executions
+            .iter()
+            .find(|execution| execution.job_id.as_str() == job_id)
+    }
+
+    /// Track a job execution as described by the service, e.g. in response
+    /// to StartNextPendingJobExecution or DescribeJobExecution. Executions in
+    /// a terminal state are no longer tracked.
+    ///
+    /// Returns [`JobError::Overflow`] if `N` other executions are tracked
+    /// already.
+    pub fn track<J>(&mut self, execution: &JobExecution<'_, J>) -> Result<(), JobError> {
+        self.set(
+            execution.job_id,
+            execution.execution_number,
+            execution.status,
+            execution.version_number,
+        )
+    }
+
+    /// Track a pending job execution with `status`, as listed by
+    /// GetPendingJobExecutions. Summaries without a job id or version are
+    /// ignored.
+    pub fn track_summary(
+        &mut self,
+        summary: &JobExecutionSummary<'_>,
+        status: JobStatus,
+    ) -> Result<(), JobError> {
+        match (summary.job_id, summary.version_number) {
+            (Some(job_id), Some(version_number)) => {
+                self.set(job_id, summary.execution_number, status, version_number)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Request moving the tracked job `job_id` to `status`. Returns `None` if
+    /// the job is not tracked.
+    pub fn update(&mut self, job_id: &str, status: JobStatus) -> Option<Update<'_>> {
+        let execution = self
+            .executions
+            .iter_mut()
+            .find(|execution| execution.job_id.as_str() == job_id)?;
+
+        execution.requested = Some(status);
+        let update = Update::new(execution.job_id.as_str(), status)
+            .expected_version(execution.version_number);
+        Some(match execution.execution_number {
+            Some(execution_number) => update.execution_number(execution_number),
+            None => update,
+        })
+    }
+
+    /// Handle a response on the `update/accepted` topic of `job_id`. Jobs
+    /// updated to a terminal state are no longer tracked.
+    pub fn accepted<J>(&mut self, job_id: &str, response: &UpdateJobExecutionResponse<'_, J>) {
+        let i = match self.position(job_id) {
+            Some(i) => i,
+            None => return,
+        };
+
+        let execution = &mut self.executions[i];
+        let requested = execution.requested.take();
+        match response.execution_state {
+            Some(ref state) => {
+                execution.status = state.status;
+                execution.version_number = state.version_number;
+            }
+            None => {
+                execution.status = requested.unwrap_or(execution.status);
+                execution.version_number += 1;
+            }
+        }
+
+        if !active(execution.status) {
+            self.executions.swap_remove(i);
+        }
+    }
+
+    /// Handle a response on the `update/rejected` topic of `job_id`. The
+    /// current state of the job execution given along with the error, e.g.
+    /// on a version mismatch, is tracked from then on, and jobs no longer
+    /// active on the service side are no longer tracked.
+    pub fn rejected(&mut self, job_id: &str, error: &ErrorResponse<'_>) {
+        let i = match self.position(job_id) {
+            Some(i) => i,
+            None => return,
+        };
+
+        let execution = &mut self.executions[i];
+        execution.requested = None;
+        if let Some(ref state) = error.execution_state {
+            execution.status = state.status;
+            execution.version_number = state.version_number;
+        }
+
+        if error.terminal_state() || !active(execution.status) {
+            self.executions.swap_remove(i);
+        }
+    }
+
+    /// Track the job executions described by `message`, as received on any
+    /// of the jobs topics: claimed and described executions are tracked, and
+    /// update responses handled as by [`Self::accepted`] and
+    /// [`Self::rejected`].
+    pub fn handle_message<J>(&mut self, message: &JobMessage<'_, J>) {
+        match message {
+            JobMessage::StartNextAccepted(StartNextPendingJobExecutionResponse {
+                execution: Some(execution),
+                ..
+            })
+            | JobMessage::DescribeAccepted(
+                _,
+                DescribeJobExecutionResponse {
+                    execution: Some(execution),
+                    ..
+                },
+            ) => {
+                if self.track(execution).is_err() {
+                    crate::rustot_log!(warn, "Job {} not tracked", execution.job_id);
+                }
+            }
+            JobMessage::UpdateAccepted(job_id, response) => self.accepted(job_id, response),
+            JobMessage::Rejected(Topic::UpdateRejected(job_id), error) => {
+                self.rejected(job_id, error)
+            }
+            _ => {}
+        }
+    }
+
+    /// Stop tracking `job_id`, e.g. as it was canceled
+    pub fn remove(&mut self, job_id: &str) -> Option<TrackedExecution> {
+        let i = self.position(job_id)?;
+        Some(self.executions.swap_remove(i))
+    }
+
+    /// Track `job_id` at `version_number`. A new execution number replaces
+    /// the execution tracked before, along with any update it was waiting
+    /// for.
+    fn set(
+        &mut self,
+        job_id: &str,
+        execution_number: Option<i64>,
+        status: JobStatus,
+        version_number: i64,
+    ) -> Result<(), JobError> {
+        let i = self.position(job_id);
+
+        if !active(status) {
+            if let Some(i) = i {
+                self.executions.swap_remove(i);
+            }
+            return Ok(());
+        }
+
+        match i {
+            Some(i) => {
+                let execution = &mut self.executions[i];
+                if execution_number.is_some() && execution_number != execution.execution_number {
+                    execution.execution_number = execution_number;
+                    execution.requested = None;
+                }
+                execution.status = status;
+                execution.version_number = version_number;
+            }
+            None => {
+                let mut id = heapless::String::new();
+                id.push_str(job_id).map_err(|_| JobError::Overflow)?;
+
+                self.executions
+                    .push(TrackedExecution {
+                        job_id: id,
+                        execution_number,
+                        status,
+                        version_number,
+                        requested: None,
+                    })
+                    .map_err(|_| JobError::Overflow)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn position(&self, job_id: &str) -> Option<usize> {
+        self.executions
+            .iter()
+            .position(|execution| execution.job_id.as_str() == job_id)
+    }
+}
+
+fn active(status: JobStatus) -> bool {
+    matches!(status, JobStatus::Queued | JobStatus::InProgress)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jobs::JobMessage;
+
+    fn execution(job_id: &str, status: &str, version_number: i64) -> String {
+        format!(
+            r#"{{
+                "clientToken": "0:test_client",
+                "timestamp": 1587381778,
+                "execution": {{
+                    "jobId": "{}",
+                    "status": "{}",
+                    "queuedAt": 1587036256,
+                    "lastUpdatedAt": 1587381778,
+                    "versionNumber": {}
+                }}
+            }}"#,
+            job_id, status, version_number
+        )
+    }
+
+    fn track<const N: usize>(
+        executions: &mut JobExecutions<N>,
+        payload: &str,
+    ) -> Result<(), JobError> {
+        let execution = JobMessage::<()>::from_slice(
+            "$aws/things/test_client/jobs/start-next/accepted",
+            payload.as_bytes(),
+        )
+        .unwrap()
+        .unwrap()
+        .execution()
+        .unwrap();
+        executions.track(&execution)
+    }
+
+    fn update_payload<const N: usize>(
+        executions: &mut JobExecutions<N>,
+        job_id: &str,
+        status: JobStatus,
+    ) -> heapless::Vec<u8, 512> {
+        executions
+            .update(job_id, status)
+            .unwrap()
+            .topic_payload("test_client")
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn independent_versions() {
+        let mut executions = JobExecutions::<2>::new();
+
+        track(&mut executions, &execution("diagnostics", "IN_PROGRESS", 3)).unwrap();
+        track(&mut executions, &execution("config", "QUEUED", 1)).unwrap();
+        assert_eq!(
+            track(&mut executions, &execution("ota", "QUEUED", 1)),
+            Err(JobError::Overflow)
+        );
+        assert_eq!(executions.len(), 2);
+
+        assert_eq!(
+            update_payload(&mut executions, "config", JobStatus::InProgress),
+            br#"{"expectedVersion":1,"status":"IN_PROGRESS"}"#
+        );
+        let response = serde_json_core::from_slice(
+            br#"{"clientToken":"1:test_client","timestamp":1587381779}"#,
+        )
+        .unwrap()
+        .0;
+        executions.accepted::<()>("config", &response);
+
+        let config = executions.get("config").unwrap();
+        assert_eq!(config.status(), JobStatus::InProgress);
+        assert_eq!(config.version_number(), 2);
+        assert_eq!(executions.get("diagnostics").unwrap().version_number(), 3);
+
+        // Completed jobs are no longer tracked
+        assert_eq!(
+            update_payload(&mut executions, "diagnostics", JobStatus::Succeeded),
+            br#"{"expectedVersion":3,"status":"SUCCEEDED"}"#
+        );
+        executions.accepted::<()>("diagnostics", &response);
+        assert!(executions.get("diagnostics").is_none());
+        assert_eq!(executions.len(), 1);
+
+        track(&mut executions, &execution("ota", "QUEUED", 1)).unwrap();
+        assert_eq!(executions.len(), 2);
+    }
+
+    #[test]
+    fn deployed_again() {
+        let mut executions = JobExecutions::<1>::new();
+        let payload = |execution_number: i64, version_number: i64| {
+            format!(
+                r#"{{
+                    "clientToken": "0:test_client",
+                    "timestamp": 1587381778,
+                    "execution": {{
+                        "jobId": "config",
+                        "status": "QUEUED",
+                        "queuedAt": 1587036256,
+                        "lastUpdatedAt": 1587381778,
+                        "executionNumber": {},
+                        "versionNumber": {}
+                    }}
+                }}"#,
+                execution_number, version_number
+            )
+        };
+
+        track(&mut executions, &payload(1, 3)).unwrap();
+        assert_eq!(
+            update_payload(&mut executions, "config", JobStatus::InProgress),
+            br#"{"executionNumber":1,"expectedVersion":3,"status":"IN_PROGRESS"}"#
+        );
+
+        // The same job id with a new execution number replaces the execution
+        track(&mut executions, &payload(2, 1)).unwrap();
+        let config = executions.get("config").unwrap();
+        assert_eq!(config.execution_number(), Some(2));
+        assert_eq!(config.version_number(), 1);
+        assert_eq!(
+            update_payload(&mut executions, "config", JobStatus::InProgress),
+            br#"{"executionNumber":2,"expectedVersion":1,"status":"IN_PROGRESS"}"#
+        );
+    }
+
+    #[test]
+    fn rejected_update() {
+        let mut executions = JobExecutions::<2>::new();
+        track(&mut executions, &execution("config", "IN_PROGRESS", 2)).unwrap();
+        track(&mut executions, &execution("diagnostics", "IN_PROGRESS", 4)).unwrap();
+
+        // The current state is given along with a version mismatch
+        let error = serde_json_core::from_slice(
+            br#"{
+                "code": "VersionMismatch",
+                "message": "Version mismatch",
+                "timestamp": 1587381779,
+                "executionState": { "status": "IN_PROGRESS", "versionNumber": 5 }
+            }"#,
+        )
+        .unwrap()
+        .0;
+        executions.rejected("config", &error);
+        assert_eq!(
+            update_payload(&mut executions, "config", JobStatus::Succeeded),
+            br#"{"expectedVersion":5,"status":"SUCCEEDED"}"#
+        );
+
+        let error = serde_json_core::from_slice(
+            br#"{
+                "code": "TerminalStateReached",
+                "message": "Job is canceled",
+                "timestamp": 1587381780
+            }"#,
+        )
+        .unwrap()
+        .0;
+        executions.rejected("diagnostics", &error);
+        assert!(executions.get("diagnostics").is_none());
+        assert!(executions.get("config").is_some());
+    }
+}
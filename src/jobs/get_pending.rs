@@ -3,7 +3,18 @@ use serde::Serialize;
 
 use crate::jobs::JobTopic;
 
-use super::{JobError, MAX_CLIENT_TOKEN_LEN, MAX_THING_NAME_LEN};
+use super::{
+    data_types::{GetPendingJobExecutionsResponse, JobExecutionSummary},
+    JobError, MAX_CLIENT_TOKEN_LEN, MAX_PENDING_JOBS, MAX_RUNNING_JOBS, MAX_THING_NAME_LEN,
+};
+
+/// Maximum length of the token used to request the next page of pending job
+/// executions.
+pub const MAX_NEXT_TOKEN_LEN: usize = 256;
+
+/// Length of a request with all fields set, but the client token and next
+/// token, i.e. `{"clientToken":"","maxResults":255,"nextToken":""}`
+const MAX_REQUEST_LEN: usize = 50;
 
 /// Gets the list of all jobs for a thing that are not in a terminal state.
 ///
@@ -15,11 +26,23 @@ pub struct GetPendingJobExecutionsRequest<'a> {
     #[serde(rename = "clientToken")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub client_token: Option<&'a str>,
+    /// The maximum number of job executions to return in each of the
+    /// `inProgressJobs` and `queuedJobs` lists.
+    #[serde(rename = "maxResults")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_results: Option<u8>,
+    /// The token returned with the previous page of results, to get the next
+    /// one.
+    #[serde(rename = "nextToken")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_token: Option<&'a str>,
 }
 
 #[derive(Default)]
 pub struct GetPending<'a> {
     client_token: Option<&'a str>,
+    max_results: Option<u8>,
+    next_token: Option<&'a str>,
 }
 
 impl<'a> GetPending<'a> {
@@ -32,6 +55,23 @@ impl<'a> GetPending<'a> {
 
         Self {
             client_token: Some(client_token),
+            ..self
+        }
+    }
+
+    pub fn max_results(self, max_results: u8) -> Self {
+        Self {
+            max_results: Some(max_results),
+            ..self
+        }
+    }
+
+    pub fn next_token(self, next_token: &'a str) -> Self {
+        assert!(next_token.len() <= MAX_NEXT_TOKEN_LEN);
+
+        Self {
+            next_token: Some(next_token),
+            ..self
         }
     }
 
@@ -41,12 +81,14 @@ impl<'a> GetPending<'a> {
     ) -> Result<
         (
             heapless::String<{ MAX_THING_NAME_LEN + 21 }>,
-            heapless::Vec<u8, { MAX_CLIENT_TOKEN_LEN + 2 }>,
+            heapless::Vec<u8, { MAX_CLIENT_TOKEN_LEN + MAX_NEXT_TOKEN_LEN + MAX_REQUEST_LEN }>,
         ),
         JobError,
     > {
         let payload = serde_json_core::to_vec(&&GetPendingJobExecutionsRequest {
             client_token: self.client_token,
+            max_results: self.max_results,
+            next_token: self.next_token,
         })
         .map_err(|_| JobError::Encoding)?;
 
@@ -54,7 +96,13 @@ impl<'a> GetPending<'a> {
     }
 
     pub fn send<M: Mqtt>(self, mqtt: &M, qos: QoS) -> Result<(), JobError> {
-        let (topic, payload) = self.topic_payload(mqtt.client_id())?;
+        self.send_for(mqtt, mqtt.client_id(), qos)
+    }
+
+    /// Send the request on behalf of `thing_name`, e.g. a thing proxied by a
+    /// gateway, rather than the thing of the MQTT client
+    pub fn send_for<M: Mqtt>(self, mqtt: &M, thing_name: &str, qos: QoS) -> Result<(), JobError> {
+        let (topic, payload) = self.topic_payload(thing_name)?;
 
         mqtt.publish(topic.as_str(), &payload, qos)?;
 
@@ -62,6 +110,79 @@ impl<'a> GetPending<'a> {
     }
 }
 
+/// Enumerates the pending job executions of a thing one page at a time, so
+/// that each response fits the fixed capacity of
+/// [`GetPendingJobExecutionsResponse`].
+///
+/// ```ignore
+/// let mut pending = PendingJobs::new();
+///
+/// while let Some(request) = pending.request() {
+///     request.client_token(client_token).send(mqtt, QoS::AtLeastOnce)?;
+///
+///     let response = wait_for_get_accepted()?;
+///     for job in pending.page(&response)? {
+///         list.push(job.job_id);
+///     }
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct PendingJobs {
+    next_token: Option<heapless::String<MAX_NEXT_TOKEN_LEN>>,
+    done: bool,
+}
+
+impl PendingJobs {
+    /// Number of results requested per page, as many as the response lists
+    /// can hold.
+    pub const PAGE_SIZE: u8 = if MAX_PENDING_JOBS < MAX_RUNNING_JOBS {
+        MAX_PENDING_JOBS as u8
+    } else {
+        MAX_RUNNING_JOBS as u8
+    };
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request for the next page, or `None` once all pages were received
+    pub fn request(&self) -> Option<GetPending<'_>> {
+        if self.done {
+            return None;
+        }
+
+        let request = GetPending::new().max_results(Self::PAGE_SIZE);
+        Some(match self.next_token {
+            Some(ref next_token) => request.next_token(next_token.as_str()),
+            None => request,
+        })
+    }
+
+    /// Handle the response to the last request, returning the job executions
+    /// of the page.
+    pub fn page<'r, 'a>(
+        &mut self,
+        response: &'r GetPendingJobExecutionsResponse<'a>,
+    ) -> Result<impl Iterator<Item = &'r JobExecutionSummary<'a>>, JobError> {
+        self.next_token = match response.next_token {
+            Some(next_token) => {
+                let mut token = heapless::String::new();
+                token.push_str(next_token).map_err(|_| JobError::Overflow)?;
+                Some(token)
+            }
+            None => None,
+        };
+        self.done = self.next_token.is_none();
+
+        Ok(response.jobs())
+    }
+
+    /// Whether all pages were received
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -71,6 +192,8 @@ mod test {
     fn serialize_requests() {
         let req = GetPendingJobExecutionsRequest {
             client_token: Some("test_client:token_pending"),
+            max_results: None,
+            next_token: None,
         };
         assert_eq!(
             &to_string::<_, 512>(&req).unwrap(),
@@ -89,4 +212,95 @@ mod test {
 
         assert_eq!(topic.as_str(), "$aws/things/test_client/jobs/get");
     }
+
+    #[test]
+    fn topic_payload_pagination() {
+        let client_token = "0:".to_string() + &"a".repeat(MAX_THING_NAME_LEN);
+        let next_token = "n".repeat(MAX_NEXT_TOKEN_LEN);
+
+        let (_, payload) = GetPending::new()
+            .client_token(&client_token)
+            .max_results(255)
+            .next_token(&next_token)
+            .topic_payload("test_client")
+            .unwrap();
+
+        assert_eq!(
+            payload,
+            format!(
+                r#"{{"clientToken":"{}","maxResults":255,"nextToken":"{}"}}"#,
+                client_token, next_token
+            )
+            .as_bytes()
+        );
+    }
+
+    #[test]
+    fn paginate() {
+        let mut pending = PendingJobs::new();
+
+        let (_, payload) = pending
+            .request()
+            .unwrap()
+            .topic_payload("test_client")
+            .unwrap();
+        assert_eq!(payload, br#"{"maxResults":1}"#);
+
+        let response = br#"{
+                "clientToken": "0:test_client",
+                "timestamp": 1587381778,
+                "inProgressJobs": [
+                    {
+                        "jobId": "job-1",
+                        "lastUpdatedAt": 1587036256,
+                        "queuedAt": 1587036256,
+                        "startedAt": 1587036256,
+                        "versionNumber": 2
+                    }
+                ],
+                "queuedJobs": [
+                    {
+                        "jobId": "job-2",
+                        "lastUpdatedAt": 1587036257,
+                        "queuedAt": 1587036257,
+                        "versionNumber": 1
+                    }
+                ],
+                "nextToken": "page-2"
+            }"#;
+        let (response, _) =
+            serde_json_core::from_slice::<GetPendingJobExecutionsResponse>(response).unwrap();
+        let jobs: Vec<_> = pending
+            .page(&response)
+            .unwrap()
+            .map(|job| job.job_id.unwrap())
+            .collect();
+        assert_eq!(jobs, ["job-1", "job-2"]);
+        assert!(!pending.is_done());
+
+        let (_, payload) = pending
+            .request()
+            .unwrap()
+            .topic_payload("test_client")
+            .unwrap();
+        assert_eq!(payload, br#"{"maxResults":1,"nextToken":"page-2"}"#);
+
+        let response = br#"{
+                "clientToken": "1:test_client",
+                "timestamp": 1587381779,
+                "queuedJobs": [
+                    {
+                        "jobId": "job-3",
+                        "lastUpdatedAt": 1587036258,
+                        "queuedAt": 1587036258,
+                        "versionNumber": 1
+                    }
+                ]
+            }"#;
+        let (response, _) =
+            serde_json_core::from_slice::<GetPendingJobExecutionsResponse>(response).unwrap();
+        assert_eq!(pending.page(&response).unwrap().count(), 1);
+        assert!(pending.is_done());
+        assert!(pending.request().is_none());
+    }
 }
@@ -0,0 +1,354 @@
+use serde::Deserialize;
+
+use super::{
+    data_types::{
+        DescribeJobExecutionResponse, ErrorResponse, GetPendingJobExecutionsResponse, JobExecution,
+        JobExecutionsChanged, NextJobExecutionChanged, StartNextPendingJobExecutionResponse,
+        UpdateJobExecutionResponse,
+    },
+    JobError, Topic,
+};
+
+/// Message received on one of the jobs topics, with job documents
+/// deserialized into the application specific type `J`, e.g. an enum of all
+/// the job documents the device knows how to process.
+///
+/// ```ignore
+/// #[derive(Deserialize)]
+/// enum Jobs<'a> {
+///     #[serde(rename = "afr_ota")]
+///     #[serde(borrow)]
+///     Ota(OtaJob<'a>),
+///     #[serde(rename = "reboot")]
+///     Reboot(Reboot),
+/// }
+///
+/// match JobMessage::<Jobs>::from_slice(topic, payload)? {
+///     Some(message) => match message.execution() {
+///         Some(JobExecution {
+///             job_id,
+///             job_document: Some(Jobs::Reboot(reboot)),
+///             ..
+///         }) => reboot_in(job_id, reboot.delay),
+///         _ => {}
+///     },
+///     // Not a jobs topic
+///     None => {}
+/// }
+/// ```
+#[derive(Debug, PartialEq)]
+pub enum JobMessage<'a, J> {
+    /// `$aws/things/{thingName}/jobs/notify`
+    Notify(JobExecutionsChanged<'a>),
+    /// `$aws/things/{thingName}/jobs/notify-next`
+    NotifyNext(NextJobExecutionChanged<'a, J>),
+    /// `$aws/things/{thingName}/jobs/get/accepted`
+    GetPendingAccepted(GetPendingJobExecutionsResponse<'a>),
+    /// `$aws/things/{thingName}/jobs/start-next/accepted`
+    StartNextAccepted(StartNextPendingJobExecutionResponse<'a, J>),
+    /// `$aws/things/{thingName}/jobs/{jobId}/get/accepted`
+    DescribeAccepted(&'a str, DescribeJobExecutionResponse<'a, J>),
+    /// `$aws/things/{thingName}/jobs/{jobId}/update/accepted`
+    UpdateAccepted(&'a str, UpdateJobExecutionResponse<'a, J>),
+    /// Any of the `rejected` topics, along with the topic the response was
+    /// received on.
+    Rejected(Topic<'a>, ErrorResponse<'a>),
+}
+
+impl<'a, J: Deserialize<'a>> JobMessage<'a, J> {
+    /// Deserialize a message received on `topic`. Returns `None` for topics
+    /// other than the jobs topics.
+    pub fn from_slice(topic: &'a str, payload: &'a [u8]) -> Result<Option<Self>, JobError> {
+        let topic = match Topic::from_str(topic) {
+            Some(topic) => topic,
+            None => return Ok(None),
+        };
+
+        Ok(Some(match topic {
+            Topic::Notify => Self::Notify(Self::deserialize(payload)?),
+            Topic::NotifyNext => Self::NotifyNext(Self::deserialize(payload)?),
+            Topic::GetAccepted => Self::GetPendingAccepted(Self::deserialize(payload)?),
+            Topic::StartNextAccepted => Self::StartNextAccepted(Self::deserialize(payload)?),
+            Topic::DescribeAccepted(job_id) => {
+                Self::DescribeAccepted(job_id, Self::deserialize(payload)?)
+            }
+            Topic::UpdateAccepted(job_id) => {
+                Self::UpdateAccepted(job_id, Self::deserialize(payload)?)
+            }
+            Topic::GetRejected
+            | Topic::StartNextRejected
+            | Topic::DescribeRejected(_)
+            | Topic::UpdateRejected(_) => {
+                let response = Self::deserialize(payload)?;
+                Self::Rejected(topic, response)
+            }
+        }))
+    }
+
+    fn deserialize<T: Deserialize<'a>>(payload: &'a [u8]) -> Result<T, JobError> {
+        serde_json_core::from_slice(payload)
+            .map(|(value, _)| value)
+            .map_err(|_| JobError::Encoding)
+    }
+}
+
+impl<'a, J> JobMessage<'a, J> {
+    /// Job execution carried by the message, for notify-next, start-next and
+    /// describe responses.
+    pub fn execution(self) -> Option<JobExecution<'a, J>> {
+        match self {
+            Self::NotifyNext(message) => message.execution,
+            Self::StartNextAccepted(response) => response.execution,
+            Self::DescribeAccepted(_, response) => response.execution,
+            _ => None,
+        }
+    }
+
+    /// Client token reflected in a response, to correlate it with its
+    /// request. `None` for notifications.
+    pub fn client_token(&self) -> Option<&'a str> {
+        match self {
+            Self::Notify(_) | Self::NotifyNext(_) => None,
+            Self::GetPendingAccepted(response) => Some(response.client_token),
+            Self::StartNextAccepted(response) => Some(response.client_token),
+            Self::DescribeAccepted(_, response) => Some(response.client_token),
+            Self::UpdateAccepted(_, response) => Some(response.client_token),
+            Self::Rejected(_, response) => response.client_token,
+        }
+    }
+}
+
+/// Raw JSON of the `jobDocument` carried by the payload of a jobs message, for
+/// job documents to be parsed by the application itself rather than through
+/// `J`. Only the top level of the message and the objects directly within it,
+/// i.e. its `execution`, are searched.
+///
+/// ```ignore
+/// if let Some(document) = raw_job_document(payload) {
+///     let command = my_parser::parse(document)?;
+/// }
+/// ```
+pub fn raw_job_document(payload: &[u8]) -> Option<&[u8]> {
+    const KEY: &[u8] = br#""jobDocument""#;
+
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < payload.len() {
+        match payload[i] {
+            b'"' => {
+                let end = string_end(payload, i)?;
+                let colon = skip_whitespace(payload, end);
+                if depth <= 2 && &payload[i..end] == KEY && payload.get(colon) == Some(&b':') {
+                    let start = skip_whitespace(payload, colon + 1);
+                    return Some(&payload[start..value_end(payload, start)?]);
+                }
+                i = end;
+                continue;
+            }
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Index just past the string starting at `start`
+fn string_end(payload: &[u8], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    while i < payload.len() {
+        match payload[i] {
+            b'\\' => i += 2,
+            b'"' => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Index just past the value starting at `start`
+fn value_end(payload: &[u8], start: usize) -> Option<usize> {
+    match payload.get(start)? {
+        b'"' => string_end(payload, start),
+        b'{' | b'[' => {
+            let mut depth = 0usize;
+            let mut i = start;
+            while i < payload.len() {
+                match payload[i] {
+                    b'"' => {
+                        i = string_end(payload, i)?;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(i + 1);
+                        }
+                    }
+                    _ => {}
+                }
+                i += 1;
+            }
+            None
+        }
+        _ => payload[start..]
+            .iter()
+            .position(|c| matches!(c, b',' | b'}' | b']') || c.is_ascii_whitespace())
+            .map(|len| start + len),
+    }
+}
+
+fn skip_whitespace(payload: &[u8], start: usize) -> usize {
+    payload[start..]
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .map_or(payload.len(), |len| start + len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jobs::data_types::{ErrorCode, JobStatus};
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Reboot {
+        delay: u32,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Run<'a> {
+        command: &'a str,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum TestJobs<'a> {
+        #[serde(rename = "reboot")]
+        Reboot(Reboot),
+        #[serde(rename = "run")]
+        #[serde(borrow)]
+        Run(Run<'a>),
+    }
+
+    #[test]
+    fn typed_job_documents() {
+        let payload = br#"{
+                "timestamp": 1587471560,
+                "execution": {
+                    "jobId": "mini",
+                    "status": "QUEUED",
+                    "queuedAt": 1587471559,
+                    "lastUpdatedAt": 1587471559,
+                    "versionNumber": 1,
+                    "jobDocument": { "reboot": { "delay": 5 } }
+                }
+            }"#;
+
+        let message =
+            JobMessage::<TestJobs>::from_slice("$aws/things/thing/jobs/notify-next", payload)
+                .unwrap()
+                .unwrap();
+        let execution = message.execution().unwrap();
+        assert_eq!(execution.job_id, "mini");
+        assert_eq!(execution.status, JobStatus::Queued);
+        assert_eq!(
+            execution.job_document,
+            Some(TestJobs::Reboot(Reboot { delay: 5 }))
+        );
+
+        let payload = br#"{
+                "clientToken": "0:thing",
+                "timestamp": 1587471560,
+                "execution": {
+                    "jobId": "run-job",
+                    "status": "IN_PROGRESS",
+                    "queuedAt": 1587471559,
+                    "lastUpdatedAt": 1587471559,
+                    "versionNumber": 2,
+                    "jobDocument": { "run": { "command": "selftest" } }
+                }
+            }"#;
+
+        let message = JobMessage::<TestJobs>::from_slice(
+            "$aws/things/thing/jobs/run-job/get/accepted",
+            payload,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(matches!(
+            message,
+            JobMessage::DescribeAccepted("run-job", _)
+        ));
+        assert_eq!(
+            message.execution().unwrap().job_document,
+            Some(TestJobs::Run(Run {
+                command: "selftest"
+            }))
+        );
+    }
+
+    #[test]
+    fn rejected_and_other_topics() {
+        let payload = br#"{
+                "code": "TerminalStateReached",
+                "message": "Job is canceled",
+                "timestamp": 1587381778
+            }"#;
+
+        match JobMessage::<TestJobs>::from_slice(
+            "$aws/things/thing/jobs/run-job/update/rejected",
+            payload,
+        ) {
+            Ok(Some(JobMessage::Rejected(Topic::UpdateRejected("run-job"), response))) => {
+                assert_eq!(response.code, ErrorCode::TerminalStateReached);
+                assert!(response.terminal_state());
+            }
+            _ => panic!("Expected a rejected update"),
+        }
+
+        assert_eq!(
+            JobMessage::<TestJobs>::from_slice(
+                "$aws/things/thing/streams/stream/data/cbor",
+                payload
+            ),
+            Ok(None)
+        );
+        assert_eq!(
+            JobMessage::<TestJobs>::from_slice("$aws/things/thing/jobs/notify-next", b"{"),
+            Err(JobError::Encoding)
+        );
+    }
+
+    #[test]
+    fn raw_job_documents() {
+        let payload = br#"{
+                "clientToken": "0:thing",
+                "execution": {
+                    "jobId": "run-job",
+                    "status": "IN_PROGRESS",
+                    "statusDetails": { "note": "not the \"jobDocument\": {}" },
+                    "jobDocument": { "script": ["echo }", { "jobDocument": 1 }] },
+                    "versionNumber": 2
+                }
+            }"#;
+        assert_eq!(
+            raw_job_document(payload),
+            Some(&br#"{ "script": ["echo }", { "jobDocument": 1 }] }"#[..])
+        );
+
+        assert_eq!(
+            raw_job_document(br#"{"jobDocument":"reboot","timestamp":1587381779}"#),
+            Some(&br#""reboot""#[..])
+        );
+        assert_eq!(
+            raw_job_document(br#"{"timestamp":1587381779, "jobDocument": 42}"#),
+            Some(&b"42"[..])
+        );
+        assert_eq!(
+            raw_job_document(br#"{"clientToken":"0:thing","timestamp":1587381779}"#),
+            None
+        );
+        assert_eq!(raw_job_document(br#"{"jobDocument": {"#), None);
+    }
+}
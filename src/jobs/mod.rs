@@ -97,10 +97,23 @@
 //!
 //! The status of the job execution that is first in the list changes to a
 //! terminal status and is removed from the list.
+pub mod agent;
+pub mod asynch;
+pub mod client;
+pub mod client_token;
 pub mod data_types;
 pub mod describe;
+pub mod event;
+pub mod executions;
 pub mod get_pending;
+pub mod message;
+pub mod next;
+pub mod pending;
+pub mod progress;
+pub mod proxy;
+pub mod registry;
 pub mod start_next;
+pub mod storage;
 pub mod subscribe;
 pub mod unsubscribe;
 pub mod update;
@@ -111,23 +124,81 @@ use self::{
     data_types::JobStatus, describe::Describe, get_pending::GetPending, start_next::StartNext,
     subscribe::Subscribe, unsubscribe::Unsubscribe, update::Update,
 };
+pub use agent::{JobHandler, JobOutcome, JobsAgent};
+pub use asynch::AsyncJobsClient;
+pub use client::{JobsClient, JobsClientBuilder};
+pub use client_token::ClientTokens;
+pub use data_types::ErrorCode as JobsErrorCode;
+pub use event::{JobsEvent, RawJobsEvent};
+pub use executions::JobExecutions;
+pub use message::{raw_job_document, JobMessage};
+pub use next::{NextJob, NextJobEvent};
+pub use pending::{PendingJob, PendingSnapshot};
+pub use progress::Progress;
+pub use proxy::JobsProxy;
+pub use registry::{JobRegistry, OperationDocument, OperationHandler};
+pub use storage::{ExecutionStorage, StoredExecution};
 pub use subscribe::Topic;
 
 /// https://docs.aws.amazon.com/iot/latest/apireference/API_DescribeThing.html
 pub const MAX_THING_NAME_LEN: usize = 128;
 pub const MAX_CLIENT_TOKEN_LEN: usize = MAX_THING_NAME_LEN + 10;
 pub const MAX_JOB_ID_LEN: usize = 64;
+/// Job id standing for the next pending job execution of the thing, in
+/// DescribeJobExecution requests
+pub const NEXT_JOB_ID: &str = "$next";
 pub const MAX_STREAM_ID_LEN: usize = MAX_JOB_ID_LEN;
 pub const MAX_PENDING_JOBS: usize = 1;
 pub const MAX_RUNNING_JOBS: usize = 1;
 
-pub type StatusDetails = heapless::FnvIndexMap<heapless::String<15>, heapless::String<11>, 4>;
+/// Maximum number of status details of a job execution. OTA jobs alone report
+/// up to 9 of them (`updated_by`, `self_test`, `receivedBlocks`,
+/// `totalBlocks`, `failure`, `failed_block`, `reason`, `pal_error` and
+/// `pal_code`), and the index maps need a power of two.
+pub const MAX_STATUS_DETAILS: usize = 16;
+
+pub type StatusDetails =
+    heapless::FnvIndexMap<heapless::String<15>, heapless::String<11>, MAX_STATUS_DETAILS>;
+
+/// Status details as received from the service, borrowing its keys and values
+/// from the message rather than copying them
+pub type StatusDetailsRef<'a> = heapless::FnvIndexMap<&'a str, &'a str, MAX_STATUS_DETAILS>;
+
+/// Copy received status details, e.g. to report them back along with an
+/// update of the job execution.
+///
+/// Fails with [`JobError::Overflow`] if a key or value is too long for
+/// [`StatusDetails`].
+pub fn owned_status_details(details: &StatusDetailsRef<'_>) -> Result<StatusDetails, JobError> {
+    let mut owned = StatusDetails::new();
+    for (key, value) in details.iter() {
+        let mut k = heapless::String::new();
+        k.push_str(key).map_err(|_| JobError::Overflow)?;
+        let mut v = heapless::String::new();
+        v.push_str(value).map_err(|_| JobError::Overflow)?;
+        owned.insert(k, v).map_err(|_| JobError::Overflow)?;
+    }
+    Ok(owned)
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum JobError {
     Overflow,
     Encoding,
     Mqtt(mqttrust::MqttError),
+    Storage,
+    Timer,
+    /// The thing name used as client ID is empty, too long, or contains
+    /// characters that are not allowed in a thing name
+    InvalidThingName,
+    /// No response to a request arrived in time
+    Timeout,
+    /// Another request of an
+    /// [`AsyncJobsClient`](asynch::AsyncJobsClient) is awaiting its response
+    Busy,
+    /// The thing is not one of the things proxied by a
+    /// [`JobsProxy`](proxy::JobsProxy)
+    UnknownThing,
 }
 
 impl From<mqttrust::MqttError> for JobError {
@@ -166,6 +237,8 @@ impl<'a> JobTopic<'a> {
     }
 
     pub fn format<const L: usize>(&self, client_id: &str) -> Result<heapless::String<L>, JobError> {
+        validate_thing_name(client_id)?;
+
         let mut topic_path = heapless::String::new();
         match self {
             Self::GetNext => topic_path.write_fmt(format_args!(
@@ -253,6 +326,23 @@ impl<'a> JobTopic<'a> {
     }
 }
 
+/// Check that `thing_name` is a valid thing name, i.e. 1 to
+/// [`MAX_THING_NAME_LEN`] characters of `[a-zA-Z0-9:_-]`. Anything else would
+/// end up in topics that never match, or in wildcards or extra levels within
+/// the topics of the thing.
+pub fn validate_thing_name(thing_name: &str) -> Result<(), JobError> {
+    let valid = !thing_name.is_empty()
+        && thing_name.len() <= MAX_THING_NAME_LEN
+        && thing_name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b':' || c == b'_' || c == b'-');
+
+    if !valid {
+        return Err(JobError::InvalidThingName);
+    }
+    Ok(())
+}
+
 pub struct Jobs;
 
 impl Jobs {
@@ -0,0 +1,326 @@
+use super::{
+    data_types::{JobExecution, JobStatus, NextJobExecutionChanged},
+    update::Update,
+    MAX_JOB_ID_LEN,
+};
+
+/// Tracks the next pending job execution of the device, as reported on the
+/// `notify-next` topic, or in response to StartNextPendingJobExecution and
+/// DescribeJobExecution with jobId `$next`.
+///
+/// The service repeats the details of the next job on every notification and
+/// on reconnection, so the tracker filters out anything that does not change
+/// the job to work on, and makes sure a QUEUED job is moved to IN_PROGRESS
+/// only once. A job deployed again keeps its job id, but comes with a new
+/// execution number, and is reported as a new job.
+///
+/// ```ignore
+/// if let Some(JobMessage::NotifyNext(message)) = JobMessage::<Jobs>::from_slice(topic, payload)? {
+///     match next_job.notify(message) {
+///         Some(NextJobEvent::Queued(execution)) => {
+///             if let Some(update) = next_job.start() {
+///                 update.send(mqtt, QoS::AtLeastOnce)?;
+///             }
+///             run(execution.job_id, execution.job_document);
+///         }
+///         Some(NextJobEvent::InProgress(execution)) => resume(execution),
+///         Some(NextJobEvent::Removed) => abort(),
+///         None => {}
+///     }
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct NextJob {
+    current: Option<TrackedJob>,
+}
+
+#[derive(Debug)]
+struct TrackedJob {
+    job_id: heapless::String<MAX_JOB_ID_LEN>,
+    execution_number: Option<i64>,
+    status: JobStatus,
+    version_number: i64,
+}
+
+/// Change of the next job execution, as seen by [`NextJob`]
+#[derive(Debug, PartialEq)]
+pub enum NextJobEvent<'a, J> {
+    /// A new job is next in the queue, and is yet to be started through
+    /// [`NextJob::start`].
+    Queued(JobExecution<'a, J>),
+    /// The next job is already in progress, e.g. when it was started before
+    /// a reboot, or through StartNextPendingJobExecution.
+    InProgress(JobExecution<'a, J>),
+    /// The tracked job is no longer next, e.g. as it reached a terminal state
+    /// or was canceled, and no other job is pending.
+    Removed,
+}
+
+impl NextJob {
+    pub const fn new() -> Self {
+        Self { current: None }
+    }
+
+    /// Id of the tracked job, if any
+    pub fn job_id(&self) -> Option<&str> {
+        self.current.as_ref().map(|job| job.job_id.as_str())
+    }
+
+    /// Execution number of the tracked job, if known
+    pub fn execution_number(&self) -> Option<i64> {
+        self.current.as_ref().and_then(|job| job.execution_number)
+    }
+
+    /// Status of the tracked job, if any
+    pub fn status(&self) -> Option<JobStatus> {
+        self.current.as_ref().map(|job| job.status)
+    }
+
+    /// Handle a message received on the `notify-next` topic
+    pub fn notify<'a, J>(
+        &mut self,
+        message: NextJobExecutionChanged<'a, J>,
+    ) -> Option<NextJobEvent<'a, J>> {
+        self.update(message.execution)
+    }
+
+    /// Track the next job execution, as given by any of the `notify-next`,
+    /// `start-next/accepted` or `$next/get/accepted` messages. Returns `None`
+    /// if the next job is still the one being tracked.
+    pub fn update<'a, J>(
+        &mut self,
+        execution: Option<JobExecution<'a, J>>,
+    ) -> Option<NextJobEvent<'a, J>> {
+        let execution = match execution {
+            Some(execution) => execution,
+            None => return self.current.take().map(|_| NextJobEvent::Removed),
+        };
+
+        if let Some(ref job) = self.current {
+            // Notifications sent before the job was started may arrive after
+            // the update moving it to IN_PROGRESS.
+            let same_execution = match (job.execution_number, execution.execution_number) {
+                (Some(tracked), Some(number)) => tracked == number,
+                _ => true,
+            };
+            if job.job_id.as_str() == execution.job_id
+                && same_execution
+                && (job.status == execution.status || execution.status == JobStatus::Queued)
+            {
+                return None;
+            }
+        }
+
+        match execution.status {
+            JobStatus::Queued | JobStatus::InProgress
+                if execution.job_id.len() < MAX_JOB_ID_LEN =>
+            {
+                let mut job_id = heapless::String::new();
+                job_id.push_str(execution.job_id).ok()?;
+
+                self.current = Some(TrackedJob {
+                    job_id,
+                    execution_number: execution.execution_number,
+                    status: execution.status,
+                    version_number: execution.version_number,
+                });
+
+                Some(match execution.status {
+                    JobStatus::Queued => NextJobEvent::Queued(execution),
+                    _ => NextJobEvent::InProgress(execution),
+                })
+            }
+            _ => self.current.take().map(|_| NextJobEvent::Removed),
+        }
+    }
+
+    /// Request moving the tracked job from QUEUED to IN_PROGRESS. Returns
+    /// `None` if there is no queued job, or it was already started.
+    ///
+    /// The update expects the version the job was last reported with, so it
+    /// is rejected if the job execution changed in the meantime.
+    pub fn start(&mut self) -> Option<Update<'_>> {
+        match self.current {
+            Some(ref mut job) if job.status == JobStatus::Queued => {
+                job.status = JobStatus::InProgress;
+                let update = Update::new(job.job_id.as_str(), JobStatus::InProgress)
+                    .expected_version(job.version_number);
+                Some(match job.execution_number {
+                    Some(execution_number) => update.execution_number(execution_number),
+                    None => update,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jobs::JobMessage;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Document<'a> {
+        operation: &'a str,
+    }
+
+    fn notify_next(payload: &[u8]) -> NextJobExecutionChanged<'_, Document<'_>> {
+        match JobMessage::from_slice("$aws/things/test_client/jobs/notify-next", payload) {
+            Ok(Some(JobMessage::NotifyNext(message))) => message,
+            _ => panic!("Expected a notify-next message"),
+        }
+    }
+
+    #[test]
+    fn queued_job_started_once() {
+        let mut next_job = NextJob::new();
+
+        let queued = br#"{
+                "timestamp": 1587471560,
+                "execution": {
+                    "jobId": "job-1",
+                    "status": "QUEUED",
+                    "queuedAt": 1587471559,
+                    "lastUpdatedAt": 1587471559,
+                    "versionNumber": 1,
+                    "jobDocument": { "operation": "reboot" }
+                }
+            }"#;
+
+        match next_job.notify(notify_next(queued)) {
+            Some(NextJobEvent::Queued(execution)) => {
+                assert_eq!(execution.job_id, "job-1");
+                assert_eq!(
+                    execution.job_document,
+                    Some(Document {
+                        operation: "reboot"
+                    })
+                );
+            }
+            _ => panic!("Expected a queued job"),
+        }
+        assert_eq!(next_job.job_id(), Some("job-1"));
+
+        let (topic, payload) = next_job
+            .start()
+            .unwrap()
+            .topic_payload("test_client")
+            .unwrap();
+        assert_eq!(topic.as_str(), "$aws/things/test_client/jobs/job-1/update");
+        assert_eq!(payload, br#"{"expectedVersion":1,"status":"IN_PROGRESS"}"#);
+        assert_eq!(next_job.status(), Some(JobStatus::InProgress));
+        assert!(next_job.start().is_none());
+
+        // Repeated notifications of the same job are filtered out
+        assert_eq!(next_job.notify(notify_next(queued)), None);
+        let in_progress = br#"{
+                "timestamp": 1587471570,
+                "execution": {
+                    "jobId": "job-1",
+                    "status": "IN_PROGRESS",
+                    "queuedAt": 1587471559,
+                    "lastUpdatedAt": 1587471569,
+                    "versionNumber": 2
+                }
+            }"#;
+        assert_eq!(next_job.notify(notify_next(in_progress)), None);
+
+        // Job completed, nothing else pending
+        assert_eq!(
+            next_job.notify(notify_next(br#"{"timestamp": 1587471580}"#)),
+            Some(NextJobEvent::Removed)
+        );
+        assert_eq!(next_job.job_id(), None);
+        assert_eq!(
+            next_job.notify(notify_next(br#"{"timestamp": 1587471590}"#)),
+            None
+        );
+    }
+
+    #[test]
+    fn in_progress_job_replaced() {
+        let mut next_job = NextJob::new();
+
+        let in_progress = br#"{
+                "timestamp": 1587471560,
+                "execution": {
+                    "jobId": "job-1",
+                    "status": "IN_PROGRESS",
+                    "queuedAt": 1587471559,
+                    "startedAt": 1587471559,
+                    "lastUpdatedAt": 1587471559,
+                    "versionNumber": 3
+                }
+            }"#;
+        assert!(matches!(
+            next_job.notify(notify_next(in_progress)),
+            Some(NextJobEvent::InProgress(_))
+        ));
+        assert!(next_job.start().is_none());
+
+        let queued = br#"{
+                "timestamp": 1587471570,
+                "execution": {
+                    "jobId": "job-2",
+                    "status": "QUEUED",
+                    "queuedAt": 1587471565,
+                    "lastUpdatedAt": 1587471565,
+                    "versionNumber": 1
+                }
+            }"#;
+        match next_job.notify(notify_next(queued)) {
+            Some(NextJobEvent::Queued(execution)) => assert_eq!(execution.job_id, "job-2"),
+            _ => panic!("Expected a queued job"),
+        }
+        assert_eq!(next_job.job_id(), Some("job-2"));
+        assert_eq!(next_job.status(), Some(JobStatus::Queued));
+    }
+
+    #[test]
+    fn deployed_again() {
+        let mut next_job = NextJob::new();
+        let queued = |execution_number: i64| {
+            format!(
+                r#"{{
+                    "timestamp": 1587471560,
+                    "execution": {{
+                        "jobId": "job-1",
+                        "status": "QUEUED",
+                        "queuedAt": 1587471559,
+                        "lastUpdatedAt": 1587471559,
+                        "executionNumber": {},
+                        "versionNumber": 1
+                    }}
+                }}"#,
+                execution_number
+            )
+        };
+
+        let first = queued(1);
+        assert!(matches!(
+            next_job.notify(notify_next(first.as_bytes())),
+            Some(NextJobEvent::Queued(_))
+        ));
+        let (_, payload) = next_job
+            .start()
+            .unwrap()
+            .topic_payload("test_client")
+            .unwrap();
+        assert_eq!(
+            payload,
+            br#"{"executionNumber":1,"expectedVersion":1,"status":"IN_PROGRESS"}"#
+        );
+        assert_eq!(next_job.notify(notify_next(first.as_bytes())), None);
+
+        // The job is deployed again, under the same job id
+        let second = queued(2);
+        assert!(matches!(
+            next_job.notify(notify_next(second.as_bytes())),
+            Some(NextJobEvent::Queued(_))
+        ));
+        assert_eq!(next_job.execution_number(), Some(2));
+        assert!(next_job.start().is_some());
+    }
+}
@@ -0,0 +1,216 @@
+//! Snapshot of the pending job executions of the thing.
+//!
+//! Both the `notify` messages and the responses to GetPendingJobExecutions
+//! list all job executions of the thing that are QUEUED or IN_PROGRESS.
+//! [`PendingSnapshot`] keeps the most recent of these lists, e.g. for the
+//! application to display or log its outstanding work:
+//!
+//! ```ignore
+//! if jobs.handle_message::<Command>(topic, payload)?.is_some() {
+//!     for job in jobs.pending_jobs().iter() {
+//!         log::info!("{} {:?} since {:?}", job.job_id, job.status, job.queued_at);
+//!     }
+//! }
+//! ```
+
+use super::{
+    data_types::{
+        GetPendingJobExecutionsResponse, JobExecutionSummary, JobExecutionsChanged, JobStatus,
+    },
+    MAX_JOB_ID_LEN, MAX_PENDING_JOBS, MAX_RUNNING_JOBS,
+};
+
+/// Number of job executions a notification or response lists at most
+pub const MAX_LISTED_JOBS: usize = MAX_RUNNING_JOBS + MAX_PENDING_JOBS;
+
+/// Pending job execution, as listed by the service
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingJob {
+    pub job_id: heapless::String<MAX_JOB_ID_LEN>,
+    /// QUEUED or IN_PROGRESS
+    pub status: JobStatus,
+    pub execution_number: Option<i64>,
+    pub version_number: Option<i64>,
+    /// Time, in seconds since the epoch, the job execution was queued at
+    pub queued_at: Option<i64>,
+}
+
+/// Pending job executions of the thing, as of the latest list received
+#[derive(Debug, Default)]
+pub struct PendingSnapshot {
+    jobs: heapless::Vec<PendingJob, MAX_LISTED_JOBS>,
+    /// Time, in seconds since the epoch, the list was sent at
+    timestamp: Option<i64>,
+    truncated: bool,
+}
+
+impl PendingSnapshot {
+    pub const fn new() -> Self {
+        Self {
+            jobs: heapless::Vec::new(),
+            timestamp: None,
+            truncated: false,
+        }
+    }
+
+    /// All pending job executions, the ones in progress first
+    pub fn iter(&self) -> impl Iterator<Item = &PendingJob> {
+        self.jobs.iter()
+    }
+
+    pub fn in_progress(&self) -> impl Iterator<Item = &PendingJob> {
+        self.iter()
+            .filter(|job| job.status == JobStatus::InProgress)
+    }
+
+    pub fn queued(&self) -> impl Iterator<Item = &PendingJob> {
+        self.iter().filter(|job| job.status == JobStatus::Queued)
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Time, in seconds since the epoch, the snapshot was sent at by the
+    /// service. `None` until a list was received.
+    pub fn timestamp(&self) -> Option<i64> {
+        self.timestamp
+    }
+
+    /// Whether the snapshot is the first page only of the response to a
+    /// GetPendingJobExecutions request with `maxResults`
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Take the pending job executions from a message on the `notify` topic
+    pub fn notified(&mut self, message: &JobExecutionsChanged<'_>) {
+        let (in_progress, queued) = match message.jobs {
+            Some(ref jobs) => (jobs.in_progress.as_deref(), jobs.queued.as_deref()),
+            None => (None, None),
+        };
+        self.replace(message.timestamp, in_progress, queued, false);
+    }
+
+    /// Take the pending job executions from a response on the `get/accepted`
+    /// topic
+    pub fn listed(&mut self, response: &GetPendingJobExecutionsResponse<'_>) {
+        self.replace(
+            response.timestamp,
+            response.in_progress_jobs.as_deref(),
+            response.queued_jobs.as_deref(),
+            response.next_token.is_some(),
+        );
+    }
+
+    /// Replace the snapshot, unless it is more recent than `timestamp`, e.g.
+    /// as messages arrived out of order. Summaries without a job id, or with
+    /// one longer than [`MAX_JOB_ID_LEN`], are ignored.
+    fn replace(
+        &mut self,
+        timestamp: i64,
+        in_progress: Option<&[JobExecutionSummary<'_>]>,
+        queued: Option<&[JobExecutionSummary<'_>]>,
+        truncated: bool,
+    ) {
+        if self.timestamp.map_or(false, |latest| latest > timestamp) {
+            return;
+        }
+
+        self.jobs.clear();
+        let summaries = in_progress
+            .into_iter()
+            .flatten()
+            .map(|summary| (summary, JobStatus::InProgress))
+            .chain(
+                queued
+                    .into_iter()
+                    .flatten()
+                    .map(|summary| (summary, JobStatus::Queued)),
+            );
+        for (summary, status) in summaries {
+            let mut job_id = heapless::String::new();
+            if let Some(Ok(())) = summary.job_id.map(|id| job_id.push_str(id)) {
+                self.jobs
+                    .push(PendingJob {
+                        job_id,
+                        status,
+                        execution_number: summary.execution_number,
+                        version_number: summary.version_number,
+                        queued_at: summary.queued_at,
+                    })
+                    .ok();
+            }
+        }
+        self.timestamp = Some(timestamp);
+        self.truncated = truncated;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn notify(payload: &[u8]) -> JobExecutionsChanged<'_> {
+        serde_json_core::from_slice(payload).unwrap().0
+    }
+
+    #[test]
+    fn snapshot_of_latest_list() {
+        let mut pending = PendingSnapshot::new();
+        assert_eq!(pending.timestamp(), None);
+
+        pending.notified(&notify(
+            br#"{
+                "timestamp": 1587381785,
+                "jobs": {
+                    "IN_PROGRESS": [{ "jobId": "job-1", "queuedAt": 1587381778, "lastUpdatedAt": 1587381779, "executionNumber": 1, "versionNumber": 2 }],
+                    "QUEUED": [{ "jobId": "job-2", "queuedAt": 1587381784, "lastUpdatedAt": 1587381784, "executionNumber": 1, "versionNumber": 1 }]
+                }
+            }"#,
+        ));
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending.timestamp(), Some(1587381785));
+        assert_eq!(
+            pending.in_progress().next(),
+            Some(&PendingJob {
+                job_id: heapless::String::from("job-1"),
+                status: JobStatus::InProgress,
+                execution_number: Some(1),
+                version_number: Some(2),
+                queued_at: Some(1587381778),
+            })
+        );
+        assert_eq!(
+            pending.queued().map(|job| job.job_id.as_str()).next(),
+            Some("job-2")
+        );
+
+        // Lists older than the snapshot are ignored
+        pending.notified(&notify(br#"{"timestamp":1587381780}"#));
+        assert_eq!(pending.len(), 2);
+
+        let response: GetPendingJobExecutionsResponse = serde_json_core::from_slice(
+            br#"{
+                "clientToken": "0:test_client",
+                "timestamp": 1587381790,
+                "queuedJobs": [{ "jobId": "job-2", "queuedAt": 1587381784, "versionNumber": 1 }],
+                "nextToken": "abc"
+            }"#,
+        )
+        .unwrap()
+        .0;
+        pending.listed(&response);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.in_progress().count(), 0);
+        assert!(pending.is_truncated());
+
+        pending.notified(&notify(br#"{"timestamp":1587381795}"#));
+        assert!(pending.is_empty());
+        assert!(!pending.is_truncated());
+    }
+}
@@ -0,0 +1,178 @@
+//! Progress reporting of long running jobs through `statusDetails`.
+//!
+//! The progress of a job is reported with the standard keys below, so that
+//! all jobs of a fleet show up consistently in the console:
+//!
+//! - `progress`: completion percentage, e.g. `"42%"`
+//! - `step`: name of the current step, e.g. `"download"`
+//! - `currentStep` and `totalSteps`: position of the current step
+//!
+//! ```ignore
+//! let mut status_details = StatusDetails::new();
+//! Progress::steps(2, 5).step("verify").write(&mut status_details)?;
+//! agent.report_progress(&status_details)?;
+//! ```
+
+use core::fmt::Write;
+
+use super::{JobError, StatusDetails};
+
+pub const PROGRESS: &str = "progress";
+pub const STEP: &str = "step";
+pub const CURRENT_STEP: &str = "currentStep";
+pub const TOTAL_STEPS: &str = "totalSteps";
+
+/// Progress of a job, to be written into its `statusDetails`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress<'a> {
+    percentage: u8,
+    steps: Option<(u32, u32)>,
+    step: Option<&'a str>,
+}
+
+impl<'a> Progress<'a> {
+    /// Progress of `done` out of `total` units of work, e.g. bytes
+    pub fn of(done: usize, total: usize) -> Self {
+        Self {
+            percentage: percentage(done as u64, total as u64),
+            steps: None,
+            step: None,
+        }
+    }
+
+    /// Progress through the steps of a job, with `current_step` counted from
+    /// 1 up to `total_steps`. The percentage is that of the steps completed.
+    pub fn steps(current_step: u32, total_steps: u32) -> Self {
+        Self {
+            percentage: percentage(current_step.saturating_sub(1) as u64, total_steps as u64),
+            steps: Some((current_step, total_steps)),
+            step: None,
+        }
+    }
+
+    /// Name the current step
+    pub fn step(self, step: &'a str) -> Self {
+        Self {
+            step: Some(step),
+            ..self
+        }
+    }
+
+    pub fn percentage(&self) -> u8 {
+        self.percentage
+    }
+
+    /// Set the progress keys of `status_details`, leaving any other keys
+    /// untouched. Keys not set by this progress are removed.
+    pub fn write(&self, status_details: &mut StatusDetails) -> Result<(), JobError> {
+        insert_fmt(
+            status_details,
+            PROGRESS,
+            format_args!("{}%", self.percentage),
+        )?;
+
+        match self.steps {
+            Some((current_step, total_steps)) => {
+                insert_fmt(
+                    status_details,
+                    CURRENT_STEP,
+                    format_args!("{}", current_step),
+                )?;
+                insert_fmt(status_details, TOTAL_STEPS, format_args!("{}", total_steps))?;
+            }
+            None => {
+                status_details.remove(&heapless::String::from(CURRENT_STEP));
+                status_details.remove(&heapless::String::from(TOTAL_STEPS));
+            }
+        }
+
+        match self.step {
+            Some(step) => insert_fmt(status_details, STEP, format_args!("{}", step)),
+            None => {
+                status_details.remove(&heapless::String::from(STEP));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Percentage of `done` out of `total`, rounded down and capped at 100. An
+/// empty job is complete.
+fn percentage(done: u64, total: u64) -> u8 {
+    match total {
+        0 => 100,
+        _ => core::cmp::min(done * 100 / total, 100) as u8,
+    }
+}
+
+fn insert_fmt(
+    status_details: &mut StatusDetails,
+    key: &str,
+    args: core::fmt::Arguments<'_>,
+) -> Result<(), JobError> {
+    let mut value = heapless::String::new();
+    value.write_fmt(args).map_err(|_| JobError::Overflow)?;
+
+    status_details
+        .insert(heapless::String::from(key), value)
+        .map_err(|_| JobError::Overflow)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn get<'a>(status_details: &'a StatusDetails, key: &str) -> Option<&'a str> {
+        status_details
+            .get(&heapless::String::from(key))
+            .map(|value| value.as_str())
+    }
+
+    #[test]
+    fn percentage_of_work() {
+        assert_eq!(Progress::of(0, 200).percentage(), 0);
+        assert_eq!(Progress::of(85, 200).percentage(), 42);
+        assert_eq!(Progress::of(200, 200).percentage(), 100);
+        assert_eq!(Progress::of(300, 200).percentage(), 100);
+        assert_eq!(Progress::of(0, 0).percentage(), 100);
+        assert_eq!(Progress::steps(1, 4).percentage(), 0);
+        assert_eq!(Progress::steps(3, 4).percentage(), 50);
+    }
+
+    #[test]
+    fn write_status_details() {
+        let mut status_details = StatusDetails::new();
+        status_details
+            .insert(
+                heapless::String::from("reason"),
+                heapless::String::from("user"),
+            )
+            .unwrap();
+
+        Progress::steps(2, 5)
+            .step("verify")
+            .write(&mut status_details)
+            .unwrap();
+
+        assert_eq!(get(&status_details, PROGRESS), Some("20%"));
+        assert_eq!(get(&status_details, CURRENT_STEP), Some("2"));
+        assert_eq!(get(&status_details, TOTAL_STEPS), Some("5"));
+        assert_eq!(get(&status_details, STEP), Some("verify"));
+        assert_eq!(get(&status_details, "reason"), Some("user"));
+
+        Progress::of(3, 4).write(&mut status_details).unwrap();
+        assert_eq!(get(&status_details, PROGRESS), Some("75%"));
+        assert_eq!(get(&status_details, CURRENT_STEP), None);
+        assert_eq!(get(&status_details, STEP), None);
+        assert_eq!(status_details.len(), 2);
+
+        // Values are limited in length
+        assert_eq!(
+            Progress::of(1, 2)
+                .step("decompress-and-verify")
+                .write(&mut status_details),
+            Err(JobError::Overflow)
+        );
+    }
+}
@@ -0,0 +1,356 @@
+//! Jobs of several things, handled over a single MQTT connection.
+//!
+//! A gateway with child things behind it, e.g. sensors connected over BLE or
+//! Modbus, would otherwise need one [`JobsClient`](super::client::JobsClient)
+//! per thing. [`JobsProxy`] subscribes to the jobs topics of every thing added
+//! to it, sends requests on behalf of any of them, and tracks the job
+//! executions of each thing separately:
+//!
+//! ```ignore
+//! let mut jobs = JobsProxy::<_, 8>::new(&mqtt_client);
+//! jobs.add_thing(mqtt_client.client_id())?;
+//! for sensor in sensors.iter() {
+//!     jobs.add_thing(sensor.thing_name())?;
+//!     jobs.start_next(sensor.thing_name())?;
+//! }
+//!
+//! match jobs.handle_message::<Command>(topic, payload)? {
+//!     Some((thing_name, JobMessage::StartNextAccepted(StartNextPendingJobExecutionResponse {
+//!         execution: Some(execution),
+//!         ..
+//!     }))) => {
+//!         let status = sensors.run(thing_name, execution.job_document);
+//!         jobs.update(thing_name, execution.job_id, status, None)?;
+//!     }
+//!     Some((thing_name, JobMessage::NotifyNext(_))) => jobs.start_next(thing_name).map(drop)?,
+//!     _ => {}
+//! }
+//! ```
+//!
+//! The things need to be allowed to act on each others topics by the policy
+//! of the gateway's certificate.
+
+use mqttrust::{Mqtt, QoS};
+use serde::Deserialize;
+
+use super::{
+    client_token::{client_token, ClientToken},
+    data_types::JobStatus,
+    executions::JobExecutions,
+    subscribe::Subscribe,
+    unsubscribe::Unsubscribe,
+    validate_thing_name, JobError, JobMessage, Jobs, StatusDetails, Topic, MAX_THING_NAME_LEN,
+};
+
+/// Thing proxied by a [`JobsProxy`], along with its job executions
+#[derive(Debug)]
+pub struct ProxiedThing<const N: usize> {
+    thing_name: heapless::String<MAX_THING_NAME_LEN>,
+    executions: JobExecutions<N>,
+}
+
+impl<const N: usize> ProxiedThing<N> {
+    pub fn thing_name(&self) -> &str {
+        self.thing_name.as_str()
+    }
+
+    /// Job executions of the thing claimed or described through the proxy,
+    /// and still active
+    pub fn executions(&self) -> &JobExecutions<N> {
+        &self.executions
+    }
+}
+
+/// Jobs client for up to `T` things, tracking up to `N` job executions of
+/// each
+pub struct JobsProxy<'a, M: Mqtt, const T: usize, const N: usize = 1> {
+    mqtt: &'a M,
+    qos: QoS,
+    request_cnt: u32,
+    things: heapless::Vec<ProxiedThing<N>, T>,
+}
+
+impl<'a, M: Mqtt, const T: usize, const N: usize> JobsProxy<'a, M, T, N> {
+    /// Proxy with all topics at QoS 1, and no things yet
+    pub fn new(mqtt: &'a M) -> Self {
+        Self {
+            mqtt,
+            qos: QoS::AtLeastOnce,
+            request_cnt: 0,
+            things: heapless::Vec::new(),
+        }
+    }
+
+    /// QoS of the subscriptions and requests
+    pub fn with_qos(self, qos: QoS) -> Self {
+        Self { qos, ..self }
+    }
+
+    /// Subscribe to the jobs topics of `thing_name`, and handle its jobs from
+    /// then on. Adding a thing again only subscribes again, e.g. on
+    /// reconnection, keeping its tracked job executions.
+    ///
+    /// Returns [`JobError::Overflow`] if `T` things are proxied already.
+    pub fn add_thing(&mut self, thing_name: &str) -> Result<(), JobError> {
+        validate_thing_name(thing_name)?;
+
+        if self.position(thing_name).is_none() {
+            let mut name = heapless::String::new();
+            name.push_str(thing_name).map_err(|_| JobError::Overflow)?;
+            self.things
+                .push(ProxiedThing {
+                    thing_name: name,
+                    executions: JobExecutions::new(),
+                })
+                .map_err(|_| JobError::Overflow)?;
+        }
+
+        Subscribe::all(self.qos, self.qos).send_for(self.mqtt, thing_name)
+    }
+
+    /// Unsubscribe from the jobs topics of `thing_name`, e.g. as the child
+    /// thing disconnected from the gateway, and forget its job executions
+    pub fn remove_thing(&mut self, thing_name: &str) -> Result<(), JobError> {
+        let i = self.position(thing_name).ok_or(JobError::UnknownThing)?;
+
+        // The thing is kept if unsubscribing fails, its messages still
+        // arriving
+        Unsubscribe::all().send_for(self.mqtt, thing_name)?;
+        self.things.swap_remove(i);
+        Ok(())
+    }
+
+    pub fn things(&self) -> impl Iterator<Item = &ProxiedThing<N>> {
+        self.things.iter()
+    }
+
+    pub fn thing(&self, thing_name: &str) -> Option<&ProxiedThing<N>> {
+        self.things
+            .iter()
+            .find(|thing| thing.thing_name.as_str() == thing_name)
+    }
+
+    /// Get the pending job executions of `thing_name`. Returns the client
+    /// token of the request.
+    pub fn get_pending(&mut self, thing_name: &str) -> Result<ClientToken, JobError> {
+        self.position(thing_name).ok_or(JobError::UnknownThing)?;
+        let client_token = self.client_token(thing_name)?;
+        Jobs::get_pending()
+            .client_token(client_token.as_str())
+            .send_for(self.mqtt, thing_name, self.qos)?;
+        Ok(client_token)
+    }
+
+    /// Describe the job execution `job_id` of `thing_name`, or its next
+    /// pending job execution if `None`, including the job document
+    pub fn describe(
+        &mut self,
+        thing_name: &str,
+        job_id: Option<&str>,
+    ) -> Result<ClientToken, JobError> {
+        let i = self.position(thing_name).ok_or(JobError::UnknownThing)?;
+        let client_token = self.client_token(thing_name)?;
+        let describe = Jobs::describe()
+            .include_job_document()
+            .client_token(client_token.as_str());
+        let execution_number = job_id
+            .and_then(|job_id| self.things[i].executions.get(job_id))
+            .and_then(|execution| execution.execution_number());
+        match (job_id, execution_number) {
+            (Some(job_id), Some(execution_number)) => {
+                describe.job_id(job_id).execution_number(execution_number)
+            }
+            (Some(job_id), None) => describe.job_id(job_id),
+            (None, _) => describe.next(),
+        }
+        .send_for(self.mqtt, thing_name, self.qos)?;
+        Ok(client_token)
+    }
+
+    /// Claim the next pending job execution of `thing_name`, moving it to
+    /// IN_PROGRESS
+    pub fn start_next(&mut self, thing_name: &str) -> Result<ClientToken, JobError> {
+        self.position(thing_name).ok_or(JobError::UnknownThing)?;
+        let client_token = self.client_token(thing_name)?;
+        Jobs::start_next()
+            .client_token(client_token.as_str())
+            .send_for(self.mqtt, thing_name, self.qos)?;
+        Ok(client_token)
+    }
+
+    /// Update the status of the job execution `job_id` of `thing_name`,
+    /// expecting the version it was last seen at if tracked
+    pub fn update(
+        &mut self,
+        thing_name: &str,
+        job_id: &str,
+        status: JobStatus,
+        status_details: Option<&StatusDetails>,
+    ) -> Result<ClientToken, JobError> {
+        let i = self.position(thing_name).ok_or(JobError::UnknownThing)?;
+        let client_token = self.client_token(thing_name)?;
+        let update = match self.things[i].executions.update(job_id, status) {
+            Some(update) => update,
+            None => Jobs::update(job_id, status),
+        }
+        .client_token(client_token.as_str());
+        match status_details {
+            Some(status_details) => update.status_details(status_details),
+            None => update,
+        }
+        .send_for(self.mqtt, thing_name, self.qos)?;
+        Ok(client_token)
+    }
+
+    /// Deserialize a message received on the jobs topics of any of the
+    /// proxied things, and track the job executions it describes. Returns the
+    /// name of the thing along with the message, or `None` for messages on
+    /// other topics, including the jobs topics of things not proxied.
+    pub fn handle_message<'b, J: Deserialize<'b>>(
+        &mut self,
+        topic: &'b str,
+        payload: &'b [u8],
+    ) -> Result<Option<(&'b str, JobMessage<'b, J>)>, JobError> {
+        let (thing_name, i) = match Topic::thing_name(topic)
+            .and_then(|thing_name| Some((thing_name, self.position(thing_name)?)))
+        {
+            Some(thing) => thing,
+            None => return Ok(None),
+        };
+
+        let message = match JobMessage::from_slice(topic, payload)? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+
+        self.things[i].executions.handle_message(&message);
+
+        Ok(Some((thing_name, message)))
+    }
+
+    fn position(&self, thing_name: &str) -> Option<usize> {
+        self.things
+            .iter()
+            .position(|thing| thing.thing_name.as_str() == thing_name)
+    }
+
+    /// Client token of the next request, on behalf of `thing_name`
+    fn client_token(&mut self, thing_name: &str) -> Result<ClientToken, JobError> {
+        let client_token = client_token(self.request_cnt, thing_name)?;
+        self.request_cnt = self.request_cnt.wrapping_add(1);
+        Ok(client_token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mqttrust::{encoding::v4::decode_slice, Packet};
+
+    use super::*;
+    use crate::test::MockMqtt;
+
+    fn published(mqtt: &MockMqtt) -> Vec<(String, String)> {
+        mqtt.tx
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Publish(p)) => Some((
+                    p.topic_name.to_string(),
+                    core::str::from_utf8(p.payload).unwrap().to_string(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn subscribed(mqtt: &MockMqtt) -> Vec<String> {
+        mqtt.tx
+            .borrow_mut()
+            .drain(..)
+            .flat_map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Subscribe(s)) => s
+                    .topics()
+                    .map(|t| t.topic_path.to_string())
+                    .collect::<Vec<_>>(),
+                _ => vec![],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn jobs_of_several_things() {
+        let mqtt = MockMqtt::new();
+        let mut jobs = JobsProxy::<_, 2>::new(&mqtt);
+
+        jobs.add_thing("test_client").unwrap();
+        jobs.add_thing("sensor-1").unwrap();
+        let topics = subscribed(&mqtt);
+        assert_eq!(topics.len(), 20);
+        assert_eq!(topics[0], "$aws/things/test_client/jobs/notify");
+        assert_eq!(topics[10], "$aws/things/sensor-1/jobs/notify");
+
+        assert_eq!(jobs.add_thing("sensor-2"), Err(JobError::Overflow));
+        assert_eq!(jobs.start_next("sensor-2"), Err(JobError::UnknownThing));
+
+        jobs.start_next("sensor-1").unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![(
+                "$aws/things/sensor-1/jobs/start-next".to_string(),
+                r#"{"clientToken":"0:sensor-1"}"#.to_string()
+            )]
+        );
+
+        let (thing_name, _) = jobs
+            .handle_message::<()>(
+                "$aws/things/sensor-1/jobs/start-next/accepted",
+                br#"{
+                    "clientToken": "0:sensor-1",
+                    "timestamp": 1587381778,
+                    "execution": {
+                        "jobId": "job-1",
+                        "status": "IN_PROGRESS",
+                        "queuedAt": 1587036256,
+                        "lastUpdatedAt": 1587381778,
+                        "versionNumber": 2
+                    }
+                }"#,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(thing_name, "sensor-1");
+        assert_eq!(jobs.thing("sensor-1").unwrap().executions().len(), 1);
+        assert!(jobs.thing("test_client").unwrap().executions().is_empty());
+
+        // Executions are tracked per thing
+        jobs.update("sensor-1", "job-1", JobStatus::Succeeded, None)
+            .unwrap();
+        jobs.update("test_client", "job-1", JobStatus::Succeeded, None)
+            .unwrap();
+        assert_eq!(
+            published(&mqtt),
+            vec![
+                (
+                    "$aws/things/sensor-1/jobs/job-1/update".to_string(),
+                    r#"{"expectedVersion":2,"status":"SUCCEEDED","clientToken":"1:sensor-1"}"#
+                        .to_string()
+                ),
+                (
+                    "$aws/things/test_client/jobs/job-1/update".to_string(),
+                    r#"{"status":"SUCCEEDED","clientToken":"2:test_client"}"#.to_string()
+                ),
+            ]
+        );
+
+        // Messages of things not proxied are left alone
+        jobs.remove_thing("sensor-1").unwrap();
+        assert!(jobs
+            .handle_message::<()>(
+                "$aws/things/sensor-1/jobs/notify",
+                br#"{"timestamp":1587381779}"#
+            )
+            .unwrap()
+            .is_none());
+        assert_eq!(jobs.things().count(), 1);
+    }
+}
@@ -0,0 +1,310 @@
+//! Dispatching of jobs by operation.
+//!
+//! Devices usually support a handful of simple jobs, each with a job document
+//! on the form
+//!
+//! ```json
+//! { "operation": "set-log-level", "parameters": { "level": "debug" } }
+//! ```
+//!
+//! A [`JobRegistry`] holds one [`OperationHandler`] per operation, and is used
+//! as the [`JobHandler`] of a [`JobsAgent`], which reports the outcome of each
+//! handler back to AWS IoT Jobs:
+//!
+//! ```ignore
+//! let registry = JobRegistry::<3>::new()
+//!     .handler("reboot", &mut reboot)?
+//!     .handler("set-log-level", &mut log_level)?
+//!     .handler("run-diagnostics", &mut diagnostics)?;
+//!
+//! let mut agent = JobsAgent::new(&mqtt_client, registry, timer);
+//! agent.init()?;
+//! // ...
+//! agent.handle_message::<OperationDocument>(topic, payload)?;
+//! ```
+//!
+//! [`JobsAgent`]: super::agent::JobsAgent
+
+use serde::Deserialize;
+
+use super::{
+    agent::{JobHandler, JobOutcome},
+    data_types::JobExecution,
+    JobError,
+};
+
+pub const MAX_PARAMETERS: usize = 8;
+
+/// Parameters of an operation, given as an object of string values
+pub type Parameters<'a> = heapless::FnvIndexMap<&'a str, &'a str, MAX_PARAMETERS>;
+
+/// Job document naming the operation to run
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct OperationDocument<'a> {
+    pub operation: &'a str,
+    #[serde(borrow)]
+    pub parameters: Option<Parameters<'a>>,
+}
+
+/// Handler of the jobs of a single operation
+pub trait OperationHandler {
+    /// Run the job `job_id`. If [`JobOutcome::Pending`] is returned, the
+    /// outcome is to be reported through
+    /// [`JobsAgent::complete`](super::agent::JobsAgent::complete).
+    fn run(&mut self, job_id: &str, parameters: Option<&Parameters<'_>>) -> JobOutcome;
+
+    /// The pending job `job_id` is no longer to be run, e.g. as it was
+    /// canceled.
+    fn cancel(&mut self, _job_id: &str) {}
+}
+
+/// Set of handlers, keyed by the operation they run. Jobs of unknown
+/// operations are rejected.
+pub struct JobRegistry<'a, const N: usize> {
+    handlers: heapless::Vec<(&'a str, &'a mut dyn OperationHandler), N>,
+    /// Handler of the job left pending, if any
+    pending: Option<usize>,
+}
+
+impl<'a, const N: usize> Default for JobRegistry<'a, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, const N: usize> JobRegistry<'a, N> {
+    pub fn new() -> Self {
+        Self {
+            handlers: heapless::Vec::new(),
+            pending: None,
+        }
+    }
+
+    /// Register `handler` for the jobs of `operation`, replacing any handler
+    /// registered for it before. Fails with [`JobError::Overflow`] if `N`
+    /// handlers are registered already.
+    pub fn handler(
+        mut self,
+        operation: &'a str,
+        handler: &'a mut dyn OperationHandler,
+    ) -> Result<Self, JobError> {
+        match self.position(operation) {
+            Some(i) => self.handlers[i].1 = handler,
+            None => self
+                .handlers
+                .push((operation, handler))
+                .map_err(|_| JobError::Overflow)?,
+        }
+        Ok(self)
+    }
+
+    fn position(&self, operation: &str) -> Option<usize> {
+        self.handlers.iter().position(|(op, _)| *op == operation)
+    }
+}
+
+impl<'a, 'd, const N: usize> JobHandler<OperationDocument<'d>> for JobRegistry<'a, N> {
+    fn handle(&mut self, execution: &JobExecution<'_, OperationDocument<'d>>) -> JobOutcome {
+        let document = match execution.job_document {
+            Some(ref document) => document,
+            None => return JobOutcome::Rejected,
+        };
+
+        let i = match self.position(document.operation) {
+            Some(i) => i,
+            None => {
+                crate::rustot_log!(warn, "Unknown job operation {}", document.operation);
+                return JobOutcome::Rejected;
+            }
+        };
+
+        let outcome = self.handlers[i]
+            .1
+            .run(execution.job_id, document.parameters.as_ref());
+        self.pending = match outcome {
+            JobOutcome::Pending => Some(i),
+            _ => None,
+        };
+        outcome
+    }
+
+    fn cancel(&mut self, job_id: &str) {
+        if let Some(i) = self.pending.take() {
+            self.handlers[i].1.cancel(job_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        jobs::{agent::JobsAgent, JobMessage},
+        ota::clock::ClockTimer,
+        test::{MockClock, MockMqtt},
+    };
+
+    #[derive(Default)]
+    struct Recorder {
+        outcome: Option<JobOutcome>,
+        runs: Vec<(String, Vec<(String, String)>)>,
+        canceled: Vec<String>,
+    }
+
+    impl OperationHandler for Recorder {
+        fn run(&mut self, job_id: &str, parameters: Option<&Parameters<'_>>) -> JobOutcome {
+            let parameters = parameters
+                .into_iter()
+                .flatten()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            self.runs.push((job_id.to_string(), parameters));
+            self.outcome.unwrap_or(JobOutcome::Succeeded)
+        }
+
+        fn cancel(&mut self, job_id: &str) {
+            self.canceled.push(job_id.to_string());
+        }
+    }
+
+    fn execution(payload: &[u8]) -> JobExecution<'_, OperationDocument<'_>> {
+        JobMessage::from_slice("$aws/things/test_client/jobs/notify-next", payload)
+            .unwrap()
+            .unwrap()
+            .execution()
+            .unwrap()
+    }
+
+    fn job(job_id: &str, document: &str) -> String {
+        format!(
+            r#"{{
+                "timestamp": 1587471560,
+                "execution": {{
+                    "jobId": "{}",
+                    "status": "QUEUED",
+                    "queuedAt": 1587471559,
+                    "lastUpdatedAt": 1587471559,
+                    "versionNumber": 1,
+                    "jobDocument": {}
+                }}
+            }}"#,
+            job_id, document
+        )
+    }
+
+    #[test]
+    fn dispatch_by_operation() {
+        let mut reboot = Recorder::default();
+        let mut log_level = Recorder {
+            outcome: Some(JobOutcome::Failed),
+            ..Recorder::default()
+        };
+
+        {
+            let mut registry = JobRegistry::<2>::new()
+                .handler("reboot", &mut reboot)
+                .unwrap()
+                .handler("set-log-level", &mut log_level)
+                .unwrap();
+
+            let payload = job(
+                "job-1",
+                r#"{ "operation": "set-log-level", "parameters": { "level": "debug" } }"#,
+            );
+            assert_eq!(
+                registry.handle(&execution(payload.as_bytes())),
+                JobOutcome::Failed
+            );
+
+            let payload = job("job-2", r#"{ "operation": "reboot" }"#);
+            assert_eq!(
+                registry.handle(&execution(payload.as_bytes())),
+                JobOutcome::Succeeded
+            );
+
+            let payload = job("job-3", r#"{ "operation": "format-disk" }"#);
+            assert_eq!(
+                registry.handle(&execution(payload.as_bytes())),
+                JobOutcome::Rejected
+            );
+        }
+
+        assert_eq!(reboot.runs, vec![("job-2".to_string(), vec![])]);
+        assert_eq!(
+            log_level.runs,
+            vec![(
+                "job-1".to_string(),
+                vec![("level".to_string(), "debug".to_string())]
+            )]
+        );
+    }
+
+    #[test]
+    fn registry_full() {
+        let mut reboot = Recorder::default();
+        let mut log_level = Recorder::default();
+        let mut diagnostics = Recorder::default();
+
+        let registry = JobRegistry::<1>::new()
+            .handler("reboot", &mut reboot)
+            .unwrap();
+
+        // Replacing a handler takes no room
+        let registry = registry.handler("reboot", &mut log_level).unwrap();
+        assert_eq!(
+            registry.handler("run-diagnostics", &mut diagnostics).err(),
+            Some(JobError::Overflow)
+        );
+    }
+
+    #[test]
+    fn pending_job_through_agent() {
+        let mut diagnostics = Recorder {
+            outcome: Some(JobOutcome::Pending),
+            ..Recorder::default()
+        };
+
+        {
+            let mqtt = MockMqtt::new();
+            let registry = JobRegistry::<1>::new()
+                .handler("run-diagnostics", &mut diagnostics)
+                .unwrap();
+            let mut agent = JobsAgent::new(&mqtt, registry, ClockTimer::new(MockClock::default()));
+
+            agent.init().unwrap();
+            let payload = format!(
+                r#"{{
+                    "clientToken": "0:test_client",
+                    "timestamp": 1587381778,
+                    "execution": {{
+                        "jobId": "job-1",
+                        "status": "IN_PROGRESS",
+                        "queuedAt": 1587036256,
+                        "lastUpdatedAt": 1587381778,
+                        "versionNumber": 2,
+                        "jobDocument": {}
+                    }}
+                }}"#,
+                r#"{ "operation": "run-diagnostics" }"#
+            );
+            agent
+                .handle_message::<OperationDocument>(
+                    "$aws/things/test_client/jobs/start-next/accepted",
+                    payload.as_bytes(),
+                )
+                .unwrap();
+            assert_eq!(agent.job_id(), Some("job-1"));
+
+            // Canceled, with nothing else pending
+            agent
+                .handle_message::<OperationDocument>(
+                    "$aws/things/test_client/jobs/notify-next",
+                    br#"{"timestamp":1587381790}"#,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(diagnostics.runs.len(), 1);
+        assert_eq!(diagnostics.canceled, vec!["job-1"]);
+    }
+}
@@ -1,9 +1,12 @@
 use mqttrust::{Mqtt, QoS};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::jobs::JobTopic;
 
-use super::{JobError, MAX_CLIENT_TOKEN_LEN, MAX_THING_NAME_LEN};
+use super::{
+    data_types::{ErrorResponse, StartNextPendingJobExecutionResponse},
+    JobError, StatusDetails, Topic, MAX_CLIENT_TOKEN_LEN, MAX_THING_NAME_LEN,
+};
 
 /// Gets and starts the next pending job execution for a thing (status
 /// IN_PROGRESS or QUEUED).
@@ -29,21 +32,21 @@ use super::{JobError, MAX_CLIENT_TOKEN_LEN, MAX_THING_NAME_LEN};
 /// Topic: $aws/things/{thingName}/jobs/start-next
 #[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct StartNextPendingJobExecutionRequest<'a> {
-    // / A collection of name/value pairs that describe the status of the job
-    // execution. If not specified, the statusDetails are unchanged.
-    // #[serde(rename = "statusDetails")] #[serde(skip_serializing_if =
-    // "Option::is_none")] pub status_details:
-    // Option<::std::collections::HashMap<String, String>>, Specifies the amount
-    // of time this device has to finish execution of this job. If the job
-    // execution status is not set to a terminal state before this timer
-    // expires, or before the timer is reset (by calling
-    // <code>UpdateJobExecution</code>, setting the status to
-    // <code>IN_PROGRESS</code> and specifying a new timeout value in field
-    // <code>stepTimeoutInMinutes</code>) the job execution status will be
-    // automatically set to <code>TIMED_OUT</code>. Note that setting this
-    // timeout has no effect on that job execution timeout which may have been
-    // specified when the job was created (<code>CreateJob</code> using field
-    // <code>timeoutConfig</code>).
+    /// A collection of name/value pairs that describe the status of the job
+    /// execution. If not specified, the statusDetails are unchanged.
+    #[serde(rename = "statusDetails")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_details: Option<&'a StatusDetails>,
+    /// Specifies the amount of time this device has to finish execution of
+    /// this job. If the job execution status is not set to a terminal state
+    /// before this timer expires, or before the timer is reset (by calling
+    /// <code>UpdateJobExecution</code>, setting the status to
+    /// <code>IN_PROGRESS</code> and specifying a new timeout value in field
+    /// <code>stepTimeoutInMinutes</code>) the job execution status will be
+    /// automatically set to <code>TIMED_OUT</code>. Note that setting this
+    /// timeout has no effect on that job execution timeout which may have been
+    /// specified when the job was created (<code>CreateJob</code> using field
+    /// <code>timeoutConfig</code>).
     #[serde(rename = "stepTimeoutInMinutes")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub step_timeout_in_minutes: Option<i64>,
@@ -57,6 +60,7 @@ pub struct StartNextPendingJobExecutionRequest<'a> {
 #[derive(Default)]
 pub struct StartNext<'a> {
     client_token: Option<&'a str>,
+    status_details: Option<&'a StatusDetails>,
     step_timeout_in_minutes: Option<i64>,
 }
 
@@ -74,6 +78,14 @@ impl<'a> StartNext<'a> {
         }
     }
 
+    /// Status details to set, if the next pending job execution is QUEUED
+    pub fn status_details(self, status_details: &'a StatusDetails) -> Self {
+        Self {
+            status_details: Some(status_details),
+            ..self
+        }
+    }
+
     pub fn step_timeout_in_minutes(self, step_timeout_in_minutes: i64) -> Self {
         Self {
             step_timeout_in_minutes: Some(step_timeout_in_minutes),
@@ -87,11 +99,12 @@ impl<'a> StartNext<'a> {
     ) -> Result<
         (
             heapless::String<{ MAX_THING_NAME_LEN + 28 }>,
-            heapless::Vec<u8, { MAX_CLIENT_TOKEN_LEN + 2 }>,
+            heapless::Vec<u8, 512>,
         ),
         JobError,
     > {
         let payload = serde_json_core::to_vec(&StartNextPendingJobExecutionRequest {
+            status_details: self.status_details,
             step_timeout_in_minutes: self.step_timeout_in_minutes,
             client_token: self.client_token,
         })
@@ -101,12 +114,47 @@ impl<'a> StartNext<'a> {
     }
 
     pub fn send<M: Mqtt>(self, mqtt: &M, qos: QoS) -> Result<(), JobError> {
-        let (topic, payload) = self.topic_payload(mqtt.client_id())?;
+        self.send_for(mqtt, mqtt.client_id(), qos)
+    }
+
+    /// Send the request on behalf of `thing_name`, e.g. a thing proxied by a
+    /// gateway, rather than the thing of the MQTT client
+    pub fn send_for<M: Mqtt>(self, mqtt: &M, thing_name: &str, qos: QoS) -> Result<(), JobError> {
+        let (topic, payload) = self.topic_payload(thing_name)?;
 
         mqtt.publish(topic.as_str(), &payload, qos)?;
 
         Ok(())
     }
+
+    /// Parse the response to a [`StartNext`] request, as received on
+    /// `topic`. Returns `None` for messages on any other topic.
+    ///
+    /// The accepted response carries no execution if no job is pending.
+    pub fn response<'b, J: Deserialize<'b>>(
+        topic: &'b str,
+        payload: &'b [u8],
+    ) -> Result<Option<StartNextResponse<'b, J>>, JobError> {
+        let response =
+            match Topic::from_str(topic) {
+                Some(Topic::StartNextAccepted) => serde_json_core::from_slice(payload)
+                    .map(|(r, _)| StartNextResponse::Accepted(r)),
+                Some(Topic::StartNextRejected) => serde_json_core::from_slice(payload)
+                    .map(|(r, _)| StartNextResponse::Rejected(r)),
+                _ => return Ok(None),
+            };
+
+        response.map(Some).map_err(|_| JobError::Encoding)
+    }
+}
+
+/// Response to a [`StartNext`] request
+#[derive(Debug, PartialEq)]
+pub enum StartNextResponse<'a, J> {
+    /// Topic: $aws/things/{thingName}/jobs/start-next/accepted
+    Accepted(StartNextPendingJobExecutionResponse<'a, J>),
+    /// Topic: $aws/things/{thingName}/jobs/start-next/rejected
+    Rejected(ErrorResponse<'a>),
 }
 
 #[cfg(test)]
@@ -118,6 +166,7 @@ mod test {
     fn serialize_requests() {
         let req = StartNextPendingJobExecutionRequest {
             client_token: Some("test_client:token_next_pending"),
+            status_details: None,
             step_timeout_in_minutes: Some(50),
         };
         assert_eq!(
@@ -126,6 +175,7 @@ mod test {
         );
         let req_none = StartNextPendingJobExecutionRequest {
             client_token: Some("test_client:token_next_pending"),
+            status_details: None,
             step_timeout_in_minutes: None,
         };
         assert_eq!(
@@ -149,4 +199,87 @@ mod test {
 
         assert_eq!(topic.as_str(), "$aws/things/test_client/jobs/start-next");
     }
+
+    #[test]
+    fn topic_payload_status_details() {
+        let mut status_details = StatusDetails::new();
+        status_details
+            .insert(
+                heapless::String::from("progress"),
+                heapless::String::from("claimed"),
+            )
+            .unwrap();
+
+        let (_, payload) = StartNext::new()
+            .status_details(&status_details)
+            .step_timeout_in_minutes(10)
+            .topic_payload("test_client")
+            .unwrap();
+
+        assert_eq!(
+            payload,
+            br#"{"statusDetails":{"progress":"claimed"},"stepTimeoutInMinutes":10}"#
+        );
+    }
+
+    #[test]
+    fn parse_response() {
+        use crate::jobs::data_types::JobStatus;
+
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Document<'a> {
+            operation: &'a str,
+        }
+
+        let payload = br#"{
+                "clientToken": "0:test_client",
+                "timestamp": 1587381778,
+                "execution": {
+                    "jobId": "test_job_id",
+                    "status": "IN_PROGRESS",
+                    "queuedAt": 1587036256,
+                    "startedAt": 1587381778,
+                    "lastUpdatedAt": 1587381778,
+                    "versionNumber": 2,
+                    "executionNumber": 1,
+                    "jobDocument": { "operation": "reboot" }
+                }
+            }"#;
+
+        match StartNext::response::<Document>(
+            "$aws/things/test_client/jobs/start-next/accepted",
+            payload,
+        ) {
+            Ok(Some(StartNextResponse::Accepted(response))) => {
+                let execution = response.execution.unwrap();
+                assert_eq!(execution.job_id, "test_job_id");
+                assert_eq!(execution.status, JobStatus::InProgress);
+                assert_eq!(execution.started_at, Some(1587381778));
+                assert_eq!(
+                    execution.job_document,
+                    Some(Document {
+                        operation: "reboot"
+                    })
+                );
+            }
+            _ => panic!("Expected an accepted response"),
+        }
+
+        // No job pending
+        let payload = br#"{"clientToken":"0:test_client","timestamp":1587381778}"#;
+        match StartNext::response::<Document>(
+            "$aws/things/test_client/jobs/start-next/accepted",
+            payload,
+        ) {
+            Ok(Some(StartNextResponse::Accepted(response))) => {
+                assert!(response.execution.is_none())
+            }
+            _ => panic!("Expected an accepted response"),
+        }
+
+        assert_eq!(
+            StartNext::response::<Document>("$aws/things/test_client/jobs/notify-next", payload),
+            Ok(None)
+        );
+    }
 }
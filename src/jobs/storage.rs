@@ -0,0 +1,45 @@
+//! Persistence of the job execution in progress across resets.
+//!
+//! A device resetting in the middle of a job would by default leave the job
+//! IN_PROGRESS until it times out, if ever. With an [`ExecutionStorage`] set
+//! through [`JobsAgent::with_storage`], the agent stores the job it is
+//! processing, and on boot describes that job before claiming any other: a
+//! job still in progress is handed to [`JobHandler::resume`], and an outcome
+//! not yet accepted by the service is reported again.
+//!
+//! The same storage keeps track of the OTA job in progress, when the OTA agent
+//! runs on its own, through [`PersistentJobs`].
+//!
+//! [`PersistentJobs`]: crate::ota::control_interface::persistent::PersistentJobs
+//! [`JobsAgent::with_storage`]: super::agent::JobsAgent::with_storage
+//! [`JobHandler::resume`]: super::agent::JobHandler::resume
+
+use serde::{Deserialize, Serialize};
+
+use super::{data_types::JobStatus, JobError, MAX_JOB_ID_LEN};
+
+/// Job execution in progress, as persisted by an [`ExecutionStorage`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredExecution {
+    pub job_id: heapless::String<MAX_JOB_ID_LEN>,
+    /// IN_PROGRESS while the job is running, and its final status once
+    /// reported
+    pub status: JobStatus,
+    /// Number of the execution, if known
+    pub execution_number: Option<i64>,
+}
+
+/// Non-volatile storage of the job execution in progress.
+///
+/// Written when a job is started and when its outcome is reported, and
+/// cleared once the job is done with.
+pub trait ExecutionStorage {
+    /// Persist `execution`, replacing any previously stored execution
+    fn store(&mut self, execution: &StoredExecution) -> Result<(), JobError>;
+
+    /// Load the stored execution, if any
+    fn load(&mut self) -> Result<Option<StoredExecution>, JobError>;
+
+    /// Erase the stored execution
+    fn clear(&mut self) -> Result<(), JobError>;
+}
@@ -2,9 +2,7 @@ use mqttrust::{Mqtt, QoS, SubscribeTopic};
 
 use crate::jobs::JobError;
 
-use super::{
-    JobTopic, {MAX_JOB_ID_LEN, MAX_THING_NAME_LEN},
-};
+use super::{JobTopic, MAX_JOB_ID_LEN};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Topic<'a> {
@@ -55,6 +53,13 @@ impl<'a> Topic<'a> {
             _ => return None,
         })
     }
+
+    /// Name of the thing a jobs topic belongs to. Returns `None` for any
+    /// other topic.
+    pub fn thing_name(s: &'a str) -> Option<&'a str> {
+        Self::from_str(s)?;
+        s.splitn(4, '/').nth(2)
+    }
 }
 
 impl<'a> From<&Topic<'a>> for JobTopic<'a> {
@@ -74,11 +79,41 @@ impl<'a> From<&Topic<'a>> for JobTopic<'a> {
     }
 }
 
+/// The notifications, and the responses to the requests of any job, as
+/// subscribed to by [`JobsClient`](super::client::JobsClient) and
+/// [`JobsProxy`](super::proxy::JobsProxy)
+pub(crate) const ALL_TOPICS: [Topic<'static>; 10] = [
+    Topic::Notify,
+    Topic::NotifyNext,
+    Topic::GetAccepted,
+    Topic::GetRejected,
+    Topic::StartNextAccepted,
+    Topic::StartNextRejected,
+    Topic::DescribeAccepted("+"),
+    Topic::DescribeRejected("+"),
+    Topic::UpdateAccepted("+"),
+    Topic::UpdateRejected("+"),
+];
+
 #[derive(Default)]
 pub struct Subscribe<'a, const N: usize> {
     topics: heapless::Vec<(Topic<'a>, QoS), N>,
 }
 
+impl Subscribe<'static, 10> {
+    /// Subscription to [`ALL_TOPICS`], the notifications at `notify_qos` and
+    /// the responses at `response_qos`
+    pub(crate) fn all(notify_qos: QoS, response_qos: QoS) -> Self {
+        ALL_TOPICS.iter().fold(Self::new(), |subscribe, topic| {
+            let qos = match topic {
+                Topic::Notify | Topic::NotifyNext => notify_qos,
+                _ => response_qos,
+            };
+            subscribe.topic(topic.clone(), qos)
+        })
+    }
+}
+
 impl<'a, const N: usize> Subscribe<'a, N> {
     pub fn new() -> Self {
         Self::default()
@@ -107,21 +142,24 @@ impl<'a, const N: usize> Subscribe<'a, N> {
         self,
         client_id: &str,
     ) -> Result<heapless::Vec<(heapless::String<256>, QoS), N>, JobError> {
-        assert!(client_id.len() <= MAX_THING_NAME_LEN);
-        Ok(self
-            .topics
+        self.topics
             .iter()
-            .map(|(topic, qos)| {
-                (
-                    JobTopic::from(topic).format::<256>(client_id).unwrap(),
-                    *qos,
-                )
-            })
-            .collect())
+            .map(|(topic, qos)| Ok((JobTopic::from(topic).format(client_id)?, *qos)))
+            .collect()
     }
 
     pub fn send<M: Mqtt>(self, mqtt: &M) -> Result<(), JobError> {
-        let topic_paths = self.topics(mqtt.client_id())?;
+        self.send_for(mqtt, mqtt.client_id())
+    }
+
+    /// Subscribe to the topics of `thing_name`, e.g. a thing proxied by a
+    /// gateway, rather than the thing of the MQTT client
+    pub fn send_for<M: Mqtt>(self, mqtt: &M, thing_name: &str) -> Result<(), JobError> {
+        if self.topics.is_empty() {
+            return Ok(());
+        }
+
+        let topic_paths = self.topics(thing_name)?;
 
         let topics: heapless::Vec<_, N> = topic_paths
             .iter()
@@ -146,7 +184,7 @@ mod tests {
 
     use super::*;
 
-    use crate::test::MockMqtt;
+    use crate::{jobs::MAX_THING_NAME_LEN, test::MockMqtt};
 
     #[test]
     fn splits_subscribe_all() {
@@ -235,4 +273,32 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn empty_subscribe_not_sent() {
+        let mqtt = &MockMqtt::new();
+
+        Subscribe::<2>::new().send(mqtt).unwrap();
+        assert!(mqtt.tx.borrow_mut().is_empty());
+    }
+
+    #[test]
+    fn invalid_thing_name() {
+        let subscribe = || Subscribe::<1>::new().topic(Topic::NotifyNext, QoS::AtLeastOnce);
+
+        assert!(subscribe().topics("my-thing:01_a").is_ok());
+        assert_eq!(subscribe().topics(""), Err(JobError::InvalidThingName));
+        assert_eq!(
+            subscribe().topics("my/thing"),
+            Err(JobError::InvalidThingName)
+        );
+        assert_eq!(
+            subscribe().topics("thing+"),
+            Err(JobError::InvalidThingName)
+        );
+        assert_eq!(
+            subscribe().topics(&"a".repeat(MAX_THING_NAME_LEN + 1)),
+            Err(JobError::InvalidThingName)
+        );
+    }
 }
@@ -3,8 +3,8 @@ use mqttrust::Mqtt;
 use crate::jobs::JobTopic;
 
 use super::{
-    subscribe::Topic,
-    JobError, {MAX_JOB_ID_LEN, MAX_THING_NAME_LEN},
+    subscribe::{Topic, ALL_TOPICS},
+    JobError, MAX_JOB_ID_LEN,
 };
 
 #[derive(Default)]
@@ -12,6 +12,15 @@ pub struct Unsubscribe<'a, const N: usize> {
     topics: heapless::Vec<Topic<'a>, N>,
 }
 
+impl Unsubscribe<'static, 10> {
+    /// Unsubscription from [`ALL_TOPICS`]
+    pub(crate) fn all() -> Self {
+        ALL_TOPICS.iter().fold(Self::new(), |unsubscribe, topic| {
+            unsubscribe.topic(topic.clone())
+        })
+    }
+}
+
 impl<'a, const N: usize> Unsubscribe<'a, N> {
     pub fn new() -> Self {
         Self::default()
@@ -39,8 +48,6 @@ impl<'a, const N: usize> Unsubscribe<'a, N> {
         self,
         client_id: &str,
     ) -> Result<heapless::Vec<heapless::String<256>, N>, JobError> {
-        assert!(client_id.len() <= MAX_THING_NAME_LEN);
-
         self.topics
             .iter()
             .map(|topic| JobTopic::from(topic).format(client_id))
@@ -48,7 +55,17 @@ impl<'a, const N: usize> Unsubscribe<'a, N> {
     }
 
     pub fn send<M: Mqtt>(self, mqtt: &M) -> Result<(), JobError> {
-        let topic_paths = self.topics(mqtt.client_id())?;
+        self.send_for(mqtt, mqtt.client_id())
+    }
+
+    /// Unsubscribe from the topics of `thing_name`, see
+    /// [`Subscribe::send_for`](super::subscribe::Subscribe::send_for)
+    pub fn send_for<M: Mqtt>(self, mqtt: &M, thing_name: &str) -> Result<(), JobError> {
+        if self.topics.is_empty() {
+            return Ok(());
+        }
+
+        let topic_paths = self.topics(thing_name)?;
         let topics: heapless::Vec<_, N> = topic_paths.iter().map(|s| s.as_str()).collect();
 
         for t in topics.chunks(5) {
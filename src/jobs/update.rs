@@ -15,7 +15,7 @@ use super::JobError;
 ///
 /// Topic: $aws/things/{thingName}/jobs/{jobId}/update
 #[derive(Debug, PartialEq, Serialize)]
-pub struct UpdateJobExecutionRequest<'a> {
+pub struct UpdateJobExecutionRequest<'a, S = StatusDetails> {
     /// Optional. A number that identifies a particular job execution on a
     /// particular device.
     #[serde(rename = "executionNumber")]
@@ -45,11 +45,11 @@ pub struct UpdateJobExecutionRequest<'a> {
     /// REJECTED). This must be specified on every update.
     #[serde(rename = "status")]
     pub status: JobStatus,
-    // /  Optional. A collection of name/value pairs that describe the status of
-    // the job execution. If not specified, the statusDetails are unchanged.
+    /// Optional. A collection of name/value pairs that describe the status of
+    /// the job execution. If not specified, the statusDetails are unchanged.
     #[serde(rename = "statusDetails")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub status_details: Option<&'a StatusDetails>,
+    pub status_details: Option<&'a S>,
     // Specifies the amount of time this device has to finish execution of this
     // job. If the job execution status is not set to a terminal state before
     // this timer expires, or before the timer is reset (by again calling
@@ -70,11 +70,28 @@ pub struct UpdateJobExecutionRequest<'a> {
     pub client_token: Option<&'a str>,
 }
 
-pub struct Update<'a> {
+/// Builder for an [`UpdateJobExecutionRequest`].
+///
+/// Status details default to the [`StatusDetails`] map, but can be any type
+/// serializing to a JSON object of string values, e.g.
+///
+/// ```ignore
+/// #[derive(Serialize)]
+/// struct Progress<'a> {
+///     step: &'a str,
+///     #[serde(rename = "lastError")]
+///     last_error: &'a str,
+/// }
+///
+/// Jobs::update(job_id, JobStatus::InProgress)
+///     .status_details(&Progress { step: "download", last_error: "none" })
+///     .send(mqtt, QoS::AtLeastOnce)?;
+/// ```
+pub struct Update<'a, S = StatusDetails> {
     job_id: &'a str,
     status: JobStatus,
     client_token: Option<&'a str>,
-    status_details: Option<&'a StatusDetails>,
+    status_details: Option<&'a S>,
     include_job_document: bool,
     execution_number: Option<i64>,
     include_job_execution_state: bool,
@@ -98,7 +115,9 @@ impl<'a> Update<'a> {
             step_timeout_in_minutes: None,
         }
     }
+}
 
+impl<'a, S: Serialize> Update<'a, S> {
     pub fn client_token(self, client_token: &'a str) -> Self {
         assert!(client_token.len() < MAX_CLIENT_TOKEN_LEN);
 
@@ -108,10 +127,19 @@ impl<'a> Update<'a> {
         }
     }
 
-    pub fn status_details(self, status_details: &'a StatusDetails) -> Self {
-        Self {
+    /// Status details to report along with the status, replacing any details
+    /// of a previous update.
+    pub fn status_details<T: Serialize>(self, status_details: &'a T) -> Update<'a, T> {
+        Update {
+            job_id: self.job_id,
+            status: self.status,
+            client_token: self.client_token,
             status_details: Some(status_details),
-            ..self
+            include_job_document: self.include_job_document,
+            execution_number: self.execution_number,
+            include_job_execution_state: self.include_job_execution_state,
+            expected_version: self.expected_version,
+            step_timeout_in_minutes: self.step_timeout_in_minutes,
         }
     }
 
@@ -176,7 +204,13 @@ impl<'a> Update<'a> {
     }
 
     pub fn send<M: Mqtt>(self, mqtt: &M, qos: QoS) -> Result<(), JobError> {
-        let (topic, payload) = self.topic_payload(mqtt.client_id())?;
+        self.send_for(mqtt, mqtt.client_id(), qos)
+    }
+
+    /// Send the request on behalf of `thing_name`, e.g. a thing proxied by a
+    /// gateway, rather than the thing of the MQTT client
+    pub fn send_for<M: Mqtt>(self, mqtt: &M, thing_name: &str, qos: QoS) -> Result<(), JobError> {
+        let (topic, payload) = self.topic_payload(thing_name)?;
 
         mqtt.publish(topic.as_str(), &payload, qos)?;
 
@@ -191,7 +225,7 @@ mod test {
 
     #[test]
     fn serialize_requests() {
-        let req = UpdateJobExecutionRequest {
+        let req: UpdateJobExecutionRequest = UpdateJobExecutionRequest {
             client_token: Some("test_client:token_update"),
             step_timeout_in_minutes: Some(50),
             execution_number: Some(5),
@@ -226,4 +260,58 @@ mod test {
             "$aws/things/test_client/jobs/test_job_id/update"
         );
     }
+
+    #[test]
+    fn status_details_map() {
+        let mut status_details = StatusDetails::new();
+        status_details
+            .insert(
+                heapless::String::from("step"),
+                heapless::String::from("download"),
+            )
+            .unwrap();
+        status_details
+            .insert(
+                heapless::String::from("progress"),
+                heapless::String::from("42"),
+            )
+            .unwrap();
+
+        let (_, payload) = Update::new("test_job_id", JobStatus::InProgress)
+            .status_details(&status_details)
+            .topic_payload("test_client")
+            .unwrap();
+
+        assert_eq!(
+            payload,
+            br#"{"status":"IN_PROGRESS","statusDetails":{"step":"download","progress":"42"}}"#
+        );
+    }
+
+    #[test]
+    fn status_details_struct() {
+        #[derive(Serialize)]
+        struct Diagnostics<'a> {
+            step: &'a str,
+            #[serde(rename = "lastError")]
+            last_error: &'a str,
+            #[serde(rename = "bootCount")]
+            boot_count: &'a str,
+        }
+
+        let (_, payload) = Update::new("test_job_id", JobStatus::Failed)
+            .client_token("test_client:token_update")
+            .status_details(&Diagnostics {
+                step: "verify",
+                last_error: "signature mismatch",
+                boot_count: "3",
+            })
+            .topic_payload("test_client")
+            .unwrap();
+
+        assert_eq!(
+            payload,
+            br#"{"status":"FAILED","statusDetails":{"step":"verify","lastError":"signature mismatch","bootCount":"3"},"clientToken":"test_client:token_update"}"#
+        );
+    }
 }
@@ -1,8 +1,13 @@
 #![cfg_attr(not(test), no_std)]
 
+// Lets the code generated by `rustot-derive` refer to `::rustot` within the
+// crate itself
+extern crate self as rustot;
+
 pub mod jobs;
 pub mod ota;
 pub mod provisioning;
+pub mod shadows;
 
 #[cfg(test)]
 pub mod test;
@@ -1,3 +1,5 @@
+use core::ops::Range;
+
 use embedded_hal::timer;
 use embedded_hal::timer::nb::CountDown;
 
@@ -5,11 +7,20 @@ use super::{
     builder::{self, NoTimer},
     control_interface::ControlInterface,
     data_interface::{DataInterface, NoInterface},
-    encoding::json::OtaJob,
+    encoding::json::{AbortReason, JobDocument, OtaJob},
+    error::OtaError,
+    event::{AgentEvent, Transition},
     pal::OtaPal,
     state::{Error, Events, JobEventData, SmContext, StateMachine, States},
+    statistics::Statistics,
+};
+#[cfg(feature = "ota_mqtt_data")]
+use super::{data_interface::mqtt::Topic, split::MessageConsumer};
+use crate::jobs::{
+    data_types::{ErrorResponse, NextJobExecutionChanged},
+    StatusDetails, Topic as JobsTopic,
 };
-use crate::{jobs::StatusDetails, rustot_log};
+use crate::rustot_log;
 
 // OTA Agent driving the FSM of an OTA update
 pub struct OtaAgent<'a, C, DP, DS, T, ST, PAL>
@@ -77,7 +88,7 @@ where
     PAL: OtaPal,
 {
     pub fn init(&mut self) {
-        self.state.process_event(Events::Start).ok();
+        self.transition(Events::Start).ok();
     }
 
     pub fn job_update(
@@ -86,66 +97,368 @@ where
         ota_document: &OtaJob,
         status_details: Option<&StatusDetails>,
     ) -> Result<&States, Error> {
-        self.state
-            .process_event(Events::ReceivedJobDocument(JobEventData {
+        self.transition(Events::ReceivedJobDocument(JobEventData {
+            job_name,
+            ota_document,
+            job_document: None,
+            status_details,
+        }))
+    }
+
+    /// Handle a raw `jobDocument`, as received from AWS IoT Jobs.
+    ///
+    /// OTA jobs are processed as by [`Self::job_update`], while any other job
+    /// is forwarded to the [`CustomJobHandler`] set through
+    /// [`OtaAgentBuilder::custom_job_handler`], leaving the state of the OTA
+    /// agent untouched.
+    ///
+    /// [`CustomJobHandler`]: super::custom_job::CustomJobHandler
+    /// [`OtaAgentBuilder::custom_job_handler`]: builder::OtaAgentBuilder::custom_job_handler
+    pub fn handle_job_document(
+        &mut self,
+        job_name: &str,
+        job_document: &[u8],
+        status_details: Option<&StatusDetails>,
+    ) -> Result<&States, Error> {
+        let document = JobDocument::from_slice(job_document).map_err(Error::GuardFailed)?;
+
+        if let Some(ref ota_document) = document.ota {
+            return self.transition(Events::ReceivedJobDocument(JobEventData {
                 job_name,
                 ota_document,
+                job_document: Some(job_document),
                 status_details,
-            }))
+            }));
+        }
+
+        match self.state.context_mut().custom_job_handler {
+            Some(ref mut handler) => handler
+                .handle_job(job_name, job_document, status_details)
+                .map_err(Error::GuardFailed)?,
+            None => {
+                rustot_log!(warn, "No handler for non-OTA job {}", job_name);
+                return Err(Error::GuardFailed(OtaError::InvalidFile));
+            }
+        }
+
+        Ok(self.state())
     }
 
     pub fn timer_callback(&mut self) -> Result<(), Error> {
+        match self.poll_timers() {
+            Ok(()) | Err(nb::Error::WouldBlock) => Ok(()),
+            Err(nb::Error::Other(e)) => Err(e),
+        }
+    }
+
+    /// Process `event` in the state machine, logging the transition taken
+    /// and reporting it to the [`TransitionHook`], if any.
+    ///
+    /// [`TransitionHook`]: super::event::TransitionHook
+    pub(crate) fn transition(&mut self, event: Events<'_>) -> Result<&States, Error> {
+        let from = self.state.state().name();
+        let trigger = event.name();
+        if let Err(e) = self.state.process_event(event) {
+            rustot_log!(debug, "{:?} rejected {:?}", from, trigger);
+            return Err(e);
+        }
+
+        let transition = Transition {
+            from,
+            to: self.state.state().name(),
+            trigger,
+        };
+        if transition.from != transition.to {
+            rustot_log!(
+                info,
+                "{:?} -> {:?} on {:?}",
+                transition.from,
+                transition.to,
+                transition.trigger
+            );
+        } else {
+            rustot_log!(
+                trace,
+                "{:?} -> {:?} on {:?}",
+                transition.from,
+                transition.to,
+                transition.trigger
+            );
+        }
+        if let Some(ref mut hook) = self.state.context_mut().transition_hook {
+            hook.on_transition(&transition);
+        }
+
+        Ok(self.state())
+    }
+
+    /// Check both the request timer and the self-test timer, handling
+    /// whichever has expired. Returns `WouldBlock` if neither timer has fired
+    /// yet.
+    pub(crate) fn poll_timers(&mut self) -> nb::Result<(), Error> {
         let ctx = self.state.context_mut();
         if ctx.request_timer.wait().is_ok() {
-            return self.state.process_event(Events::RequestTimer).map(drop);
+            return self
+                .transition(Events::RequestTimer)
+                .map(drop)
+                .map_err(nb::Error::Other);
         }
 
         if let Some(ref mut self_test_timer) = ctx.self_test_timer {
             if self_test_timer.wait().is_ok() {
-                rustot_log!(
-                    error,
-                    "Self test failed to complete within {} ms",
-                    ctx.config.self_test_timeout_ms
-                );
-                ctx.pal.reset_device().ok();
+                return ctx
+                    .self_test_timeout()
+                    .map_err(|e| nb::Error::Other(Error::GuardFailed(e)));
             }
         }
-        Ok(())
+        Err(nb::Error::WouldBlock)
     }
 
-    pub fn process_event(&mut self) -> Result<&States, Error> {
+    /// Process the next queued event, returning the [`AgentEvent`] raised
+    /// since the last call, if any.
+    pub fn process_event(&mut self) -> Result<Option<AgentEvent>, Error> {
         if let Some(event) = self.state.context_mut().events.dequeue() {
-            self.state.process_event(event)?;
+            self.transition(event)?;
         }
-        Ok(self.state())
+        Ok(self.take_event())
+    }
+
+    /// Handle a message received on the data topic of the active file,
+    /// returning the [`AgentEvent`] raised by it, if any.
+    ///
+    /// The file block is decoded in place, and handed on to the PAL as a
+    /// slice of `payload`, so the receive buffer of the MQTT client can be
+    /// passed in directly, without copying it first.
+    pub fn handle_message(&mut self, payload: &[u8]) -> Result<Option<AgentEvent>, Error> {
+        self.transition(Events::ReceivedFileBlock(payload))?;
+        Ok(self.take_event())
     }
 
-    pub fn handle_message(&mut self, payload: &mut [u8]) -> Result<&States, Error> {
-        self.state.process_event(Events::ReceivedFileBlock(payload))
+    /// Handle the messages queued in `messages` on stream data topics, as by
+    /// [`Self::handle_message`], followed by the next queued agent event.
+    /// Returns the latest [`AgentEvent`] raised, if any.
+    ///
+    /// Handling stops at the first message on any other topic, e.g. a job
+    /// update, which is left in the queue for the application to
+    /// [`dequeue`](MessageConsumer::dequeue).
+    #[cfg(feature = "ota_mqtt_data")]
+    pub fn process<const N: usize, const Q: usize>(
+        &mut self,
+        messages: &mut MessageConsumer<'_, N, Q>,
+    ) -> Result<Option<AgentEvent>, Error> {
+        let prefix = self.state.context().config.stream_topic_prefix;
+        let is_data = |topic: &str| {
+            let topic = match prefix {
+                Some(prefix) => Topic::from_str_with_prefix(topic, prefix),
+                None => Topic::from_str(topic),
+            };
+            matches!(topic, Some(Topic::Data(..)))
+        };
+
+        let mut event = None;
+        while messages.peek().map_or(false, |m| is_data(m.topic())) {
+            if let Some(message) = messages.dequeue() {
+                event = self.handle_message(message.payload())?.or(event);
+            }
+        }
+        Ok(self.process_event()?.or(event))
+    }
+
+    /// Take the latest [`AgentEvent`], e.g. the
+    /// [`AgentEvent::Failed`] event of a call that returned an error, or an
+    /// event raised during [`Self::timer_callback`].
+    pub fn take_event(&mut self) -> Option<AgentEvent> {
+        self.state.context_mut().agent_event.take()
     }
 
     pub fn check_for_update(&mut self) -> Result<&States, Error> {
-        self.state.process_event(Events::RequestJobDocument)
+        self.transition(Events::RequestJobDocument)
+    }
+
+    /// Abort the OTA update in progress, reporting the job as failed with
+    /// `reason` in its status details.
+    pub fn abort(&mut self, reason: AbortReason) -> Result<&States, Error> {
+        self.transition(Events::UserAbort(reason))
     }
 
-    pub fn abort(&mut self) -> Result<&States, Error> {
-        self.state.process_event(Events::UserAbort)
+    /// Abort the transfer of `job_name`, after the job was canceled in the
+    /// cloud.
+    ///
+    /// Cancellation is signalled by a `notify-next` message without an
+    /// execution, or by an update or describe request of the job being
+    /// rejected with a terminal state (see
+    /// [`ErrorResponse::terminal_state`]), both of which are detected by
+    /// [`Self::handle_job_message`]. The PAL is asked to abort the file in
+    /// progress, and the agent returns to waiting for the next job. Nothing
+    /// happens if `job_name` is not the job in progress.
+    pub fn job_canceled(&mut self, job_name: &str) -> Result<&States, Error> {
+        if self.active_job().map_or(true, |active| active != job_name) {
+            return Ok(self.state());
+        }
+
+        self.transition(Events::JobCanceled)
     }
 
+    /// Handle a message received on one of the jobs topics, ignoring messages
+    /// on any other topic.
+    ///
+    /// A `notify-next` message without an execution, or an update or describe
+    /// request of the job in progress rejected with a terminal state, means
+    /// that the job was canceled in the cloud, and aborts the transfer as by
+    /// [`Self::job_canceled`]. Job documents carried by the messages are to be
+    /// handed to [`Self::handle_job_document`].
+    pub fn handle_job_message(&mut self, topic: &str, payload: &[u8]) -> Result<&States, Error> {
+        match JobsTopic::from_str(topic) {
+            Some(JobsTopic::NotifyNext) => {
+                let (message, _) =
+                    serde_json_core::from_slice::<NextJobExecutionChanged<JobDocument>>(payload)
+                        .map_err(|_| Error::GuardFailed(OtaError::Encoding))?;
+
+                if message.execution.is_some() {
+                    return Ok(self.state());
+                }
+
+                match self.active_job().map(heapless::String::<64>::from) {
+                    Some(job_name) => self.job_canceled(job_name.as_str()),
+                    None => Ok(self.state()),
+                }
+            }
+            Some(JobsTopic::UpdateRejected(job_name) | JobsTopic::DescribeRejected(job_name)) => {
+                let (error, _) = serde_json_core::from_slice::<ErrorResponse>(payload)
+                    .map_err(|_| Error::GuardFailed(OtaError::Encoding))?;
+
+                if error.terminal_state() {
+                    self.job_canceled(job_name)
+                } else {
+                    Ok(self.state())
+                }
+            }
+            _ => Ok(self.state()),
+        }
+    }
+
+    /// Name of the job whose transfer is in progress, if any
+    fn active_job(&self) -> Option<&str> {
+        match self.state() {
+            States::CreatingFile
+            | States::RequestingFileBlock
+            | States::WaitingForFileBlock
+            | States::Suspended => self
+                .state
+                .context()
+                .active_interface
+                .as_ref()
+                .map(|interface| interface.file_ctx().job_name.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Accept the image under test, when configured to require an explicit
+    /// accept through [`OtaAgentBuilder::explicit_accept`].
+    ///
+    /// [`OtaAgentBuilder::explicit_accept`]: builder::OtaAgentBuilder::explicit_accept
+    pub fn accept_image(&mut self) -> Result<&States, Error> {
+        self.transition(Events::AcceptImage)
+    }
+
+    /// Suspend the OTA agent, e.g. during high priority work or on low
+    /// battery.
+    ///
+    /// A file transfer in progress is paused rather than failed: the data
+    /// topics are unsubscribed and the request timer is stopped, while the
+    /// file context is kept such that [`Self::resume`] can continue the
+    /// transfer with the blocks still missing. The self-test timer, if
+    /// running, is left untouched, as it guards against running an
+    /// unconfirmed image indefinitely.
     pub fn suspend(&mut self) -> Result<&States, Error> {
         // Stop the request timer
         self.state.context_mut().request_timer.cancel().ok();
 
         // Send event to OTA agent task.
-        self.state.process_event(Events::Suspend)
+        self.transition(Events::Suspend)
     }
 
+    /// Resume a suspended OTA agent, continuing a suspended file transfer if
+    /// any, or otherwise requesting the job document.
     pub fn resume(&mut self) -> Result<&States, Error> {
+        let event = if self.state.context().suspended_transfer {
+            Events::ResumeTransfer
+        } else {
+            Events::Resume
+        };
+
         // Send event to OTA agent task
-        self.state.process_event(Events::Resume)
+        self.transition(event)
+    }
+
+    /// Request `blocks` of the file being received again, e.g. after they
+    /// failed to read back from flash, on top of the blocks still missing.
+    ///
+    /// The blocks are marked as missing, and requested right away. They have
+    /// to fit a single window of [`Bitmap::WINDOW`] blocks, see
+    /// [`FileContext::mark_missing`]. The PAL has to accept writing the blocks
+    /// once more.
+    ///
+    /// [`Bitmap::WINDOW`]: super::encoding::Bitmap::WINDOW
+    /// [`FileContext::mark_missing`]: super::encoding::FileContext::mark_missing
+    pub fn request_blocks(&mut self, blocks: Range<usize>) -> Result<&States, Error> {
+        if !matches!(
+            self.state(),
+            States::RequestingFileBlock | States::WaitingForFileBlock
+        ) {
+            return Err(Error::InvalidEvent);
+        }
+
+        let ctx = self.state.context_mut();
+        let block_size = ctx.config.block_size;
+        ctx.active_interface
+            .as_mut()
+            .ok_or(Error::GuardFailed(OtaError::InvalidInterface))?
+            .mut_file_ctx()
+            .mark_missing(blocks, block_size)
+            .map_err(Error::GuardFailed)?;
+
+        self.transition(Events::RequestFileBlock)
+    }
+
+    /// Complete the file received last, once the PAL is done closing it in
+    /// the background, returning the [`AgentEvent`] raised, if any.
+    ///
+    /// Only applies after [`OtaPal::close_file`] returned
+    /// [`OtaPalError::WouldBlock`]. Nothing happens while
+    /// [`OtaPal::poll_close`] still returns [`OtaPalError::WouldBlock`], so
+    /// this can be called repeatedly until the file is complete.
+    ///
+    /// [`OtaPal::close_file`]: super::pal::OtaPal::close_file
+    /// [`OtaPal::poll_close`]: super::pal::OtaPal::poll_close
+    /// [`OtaPalError::WouldBlock`]: super::pal::OtaPalError::WouldBlock
+    pub fn finalize_file(&mut self) -> Result<Option<AgentEvent>, Error> {
+        if !self.state.context().close_pending {
+            return Err(Error::InvalidEvent);
+        }
+
+        self.transition(Events::FinalizeFile)?;
+        Ok(self.take_event())
     }
 
     pub fn state(&self) -> &States {
         self.state.state()
     }
+
+    /// Raw document of the active job, including any fields unknown to the
+    /// agent. Only available for jobs handled through
+    /// [`Self::handle_job_document`], and only if the document fits the
+    /// buffer set by [`OtaAgentBuilder::job_document_buffer`].
+    ///
+    /// [`OtaAgentBuilder::job_document_buffer`]: builder::OtaAgentBuilder::job_document_buffer
+    pub fn job_document(&self) -> Option<&[u8]> {
+        let context = self.state.context();
+        let len = context.job_document_len?;
+        context.job_document_buf.as_deref().map(|buf| &buf[..len])
+    }
+
+    /// Statistics of the file transfer currently in progress
+    pub fn statistics(&self) -> &Statistics {
+        &self.state.context().statistics
+    }
 }
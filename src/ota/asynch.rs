@@ -0,0 +1,325 @@
+//! Async wrapper around the OTA agent, for use from async executors such as
+//! embassy.
+//!
+//! The wrapper drives the very same state machine as [`OtaAgent`], but instead
+//! of requiring the application to repeatedly call `process_event` and
+//! `timer_callback`, the events enqueued by the state machine are processed
+//! within the returned futures. [`AsyncOtaAgent::timer_callback`] waits for a
+//! timer of the agent to expire, sleeping on a delay of the executor in
+//! between checking the timers:
+//!
+//! ```ignore
+//! let mut agent = AsyncOtaAgent::new(agent);
+//! agent.init().await?;
+//! loop {
+//!     agent
+//!         .timer_callback(|| Timer::after(Duration::from_millis(100)))
+//!         .await?;
+//! }
+//! ```
+
+use core::future::Future;
+
+use embedded_hal::timer;
+
+use super::{
+    agent::OtaAgent,
+    control_interface::ControlInterface,
+    data_interface::DataInterface,
+    encoding::json::{AbortReason, OtaJob},
+    event::AgentEvent,
+    pal::OtaPal,
+    state::{Error, Events, States},
+    statistics::Statistics,
+};
+use crate::jobs::StatusDetails;
+
+// Async OTA Agent driving the FSM of an OTA update
+pub struct AsyncOtaAgent<'a, C, DP, DS, T, ST, PAL>
+where
+    C: ControlInterface,
+    DP: DataInterface,
+    DS: DataInterface,
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+    ST: timer::nb::CountDown + timer::nb::Cancel,
+    ST::Time: From<u32>,
+    PAL: OtaPal,
+{
+    agent: OtaAgent<'a, C, DP, DS, T, ST, PAL>,
+}
+
+impl<'a, C, DP, DS, T, ST, PAL> From<OtaAgent<'a, C, DP, DS, T, ST, PAL>>
+    for AsyncOtaAgent<'a, C, DP, DS, T, ST, PAL>
+where
+    C: ControlInterface,
+    DP: DataInterface,
+    DS: DataInterface,
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+    ST: timer::nb::CountDown + timer::nb::Cancel,
+    ST::Time: From<u32>,
+    PAL: OtaPal,
+{
+    fn from(agent: OtaAgent<'a, C, DP, DS, T, ST, PAL>) -> Self {
+        Self { agent }
+    }
+}
+
+/// Public interface of the async OTA Agent
+impl<'a, C, DP, DS, T, ST, PAL> AsyncOtaAgent<'a, C, DP, DS, T, ST, PAL>
+where
+    C: ControlInterface,
+    DP: DataInterface,
+    DS: DataInterface,
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+    ST: timer::nb::CountDown + timer::nb::Cancel,
+    ST::Time: From<u32>,
+    PAL: OtaPal,
+{
+    pub fn new(agent: OtaAgent<'a, C, DP, DS, T, ST, PAL>) -> Self {
+        Self { agent }
+    }
+
+    /// Get back the underlying synchronous agent
+    pub fn into_inner(self) -> OtaAgent<'a, C, DP, DS, T, ST, PAL> {
+        self.agent
+    }
+
+    pub async fn init(&mut self) -> Result<&States, Error> {
+        self.agent.init();
+        self.process_events().await
+    }
+
+    pub async fn job_update(
+        &mut self,
+        job_name: &str,
+        ota_document: &OtaJob<'_>,
+        status_details: Option<&StatusDetails>,
+    ) -> Result<&States, Error> {
+        self.agent
+            .job_update(job_name, ota_document, status_details)?;
+        self.process_events().await
+    }
+
+    pub async fn handle_job_document(
+        &mut self,
+        job_name: &str,
+        job_document: &[u8],
+        status_details: Option<&StatusDetails>,
+    ) -> Result<&States, Error> {
+        self.agent
+            .handle_job_document(job_name, job_document, status_details)?;
+        self.process_events().await
+    }
+
+    /// Wait until either the request timer or the self-test timer expires,
+    /// and handle the expiry, including any events it causes.
+    ///
+    /// The timers are checked each time the future returned by `sleep`
+    /// resolves, e.g. an `embassy_time::Timer`, leaving the task parked in
+    /// the executor in between. The timers are thus only as precise as the
+    /// delay slept on.
+    pub async fn timer_callback<S, F>(&mut self, mut sleep: S) -> Result<(), Error>
+    where
+        S: FnMut() -> F,
+        F: Future<Output = ()>,
+    {
+        loop {
+            match self.agent.poll_timers() {
+                Ok(()) => break,
+                Err(nb::Error::WouldBlock) => sleep().await,
+                Err(nb::Error::Other(e)) => return Err(e),
+            }
+        }
+        self.process_events().await.map(drop)
+    }
+
+    /// Process all events currently enqueued by the state machine.
+    pub async fn process_events(&mut self) -> Result<&States, Error> {
+        while let Some(event) = self.agent.state.context_mut().events.dequeue() {
+            self.agent.transition(event)?;
+        }
+        Ok(self.agent.state())
+    }
+
+    /// Handle a message received on the data topic of the active file,
+    /// including any events it causes, returning the latest [`AgentEvent`].
+    pub async fn handle_message(&mut self, payload: &[u8]) -> Result<Option<AgentEvent>, Error> {
+        self.agent.transition(Events::ReceivedFileBlock(payload))?;
+        self.process_events().await?;
+        Ok(self.agent.take_event())
+    }
+
+    /// Complete the file received last, once the PAL is done closing it in
+    /// the background. See [`OtaAgent::finalize_file`].
+    pub async fn finalize_file(&mut self) -> Result<Option<AgentEvent>, Error> {
+        let event = self.agent.finalize_file()?;
+        self.process_events().await?;
+        Ok(self.agent.take_event().or(event))
+    }
+
+    pub async fn check_for_update(&mut self) -> Result<&States, Error> {
+        self.agent.check_for_update()?;
+        self.process_events().await
+    }
+
+    pub async fn abort(&mut self, reason: AbortReason) -> Result<&States, Error> {
+        self.agent.abort(reason)?;
+        self.process_events().await
+    }
+
+    pub async fn accept_image(&mut self) -> Result<&States, Error> {
+        self.agent.accept_image()?;
+        self.process_events().await
+    }
+
+    pub async fn suspend(&mut self) -> Result<&States, Error> {
+        self.agent.suspend()?;
+        self.process_events().await
+    }
+
+    pub async fn resume(&mut self) -> Result<&States, Error> {
+        self.agent.resume()?;
+        self.process_events().await
+    }
+
+    pub fn state(&self) -> &States {
+        self.agent.state()
+    }
+
+    /// Take the latest [`AgentEvent`], see [`OtaAgent::take_event`]
+    pub fn take_event(&mut self) -> Option<AgentEvent> {
+        self.agent.take_event()
+    }
+
+    pub fn statistics(&self) -> &Statistics {
+        self.agent.statistics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::test::mock::{MockPal, MockTimer};
+    use crate::test::MockMqtt;
+    use core::pin::Pin;
+    use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    fn noop_raw_waker() -> RawWaker {
+        fn no_op(_: *const ()) {}
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw_waker()
+        }
+
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, no_op, no_op, no_op);
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+
+    /// Waker of a task that is not to be woken
+    fn panicking_raw_waker() -> RawWaker {
+        fn wake(_: *const ()) {
+            panic!("Woken while waiting for a timer");
+        }
+        fn clone(_: *const ()) -> RawWaker {
+            panicking_raw_waker()
+        }
+        fn drop(_: *const ()) {}
+
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+
+    fn block_on<F: Future>(mut fut: F) -> F::Output {
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = unsafe { Pin::new_unchecked(&mut fut) };
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return out;
+            }
+        }
+    }
+
+    /// Timer expiring once, on the `n`th call to `wait`
+    struct CountdownTimer(u32);
+
+    impl timer::nb::CountDown for CountdownTimer {
+        type Error = ();
+
+        type Time = u32;
+
+        fn start<T>(&mut self, _count: T) -> Result<(), Self::Error>
+        where
+            T: Into<Self::Time>,
+        {
+            Ok(())
+        }
+
+        fn wait(&mut self) -> nb::Result<(), Self::Error> {
+            match self.0 {
+                0 => Err(nb::Error::WouldBlock),
+                1 => {
+                    self.0 = 0;
+                    Ok(())
+                }
+                _ => {
+                    self.0 -= 1;
+                    Err(nb::Error::WouldBlock)
+                }
+            }
+        }
+    }
+
+    impl timer::nb::Cancel for CountdownTimer {
+        fn cancel(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn processes_enqueued_events() {
+        let mqtt = MockMqtt::new();
+
+        let agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal::new())
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .build();
+        let mut agent = AsyncOtaAgent::from(agent);
+
+        // `Start` enqueues `RequestJobDocument`, which should be handled
+        // without any further calls from the application.
+        assert!(matches!(block_on(agent.init()), Ok(&States::WaitingForJob)));
+        assert_eq!(agent.agent.state.context().events.len(), 0);
+
+        // Subscribe & publish for the job document
+        assert_eq!(mqtt.tx.borrow_mut().len(), 2);
+    }
+
+    #[test]
+    fn timer_callback_sleeps_until_expiry() {
+        let mqtt = MockMqtt::new();
+
+        let agent = OtaAgent::builder(&mqtt, &mqtt, CountdownTimer(u32::MAX), MockPal::new())
+            .with_self_test_timeout(CountdownTimer(3), 16000)
+            .build();
+        let mut agent = AsyncOtaAgent::from(agent);
+
+        let mut sleeps = 0;
+        block_on(agent.timer_callback(|| {
+            sleeps += 1;
+            core::future::ready(())
+        }))
+        .unwrap();
+
+        // Checked thrice, sleeping in between
+        assert_eq!(sleeps, 2);
+
+        // The task is parked while sleeping, rather than waking itself up
+        let waker = unsafe { Waker::from_raw(panicking_raw_waker()) };
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = agent.timer_callback(core::future::pending);
+        let fut = unsafe { Pin::new_unchecked(&mut fut) };
+        assert!(fut.poll(&mut cx).is_pending());
+    }
+}
@@ -0,0 +1,127 @@
+//! Backoff of unanswered requests.
+//!
+//! Each request without a response doubles the wait before the next retry, up
+//! to [`OtaAgentBuilder::max_request_wait_ms`]. With an [`Rng`] set through
+//! [`OtaAgentBuilder::rng`], every wait is additionally randomized between
+//! half and the full delay, such that a fleet of devices coming back after an
+//! outage does not retry in lockstep.
+//!
+//! Independently, [`OtaAgentBuilder::max_blocks_per_second`] paces the
+//! download itself: each window of blocks is only requested once the previous
+//! one is due at the configured rate.
+//!
+//! [`OtaAgentBuilder::max_request_wait_ms`]: super::builder::OtaAgentBuilder::max_request_wait_ms
+//! [`OtaAgentBuilder::rng`]: super::builder::OtaAgentBuilder::rng
+//! [`OtaAgentBuilder::max_blocks_per_second`]: super::builder::OtaAgentBuilder::max_blocks_per_second
+
+use super::config::Config;
+
+/// Source of randomness for the request jitter. Does not need to be
+/// cryptographically secure.
+pub trait Rng {
+    fn next_u32(&mut self) -> u32;
+}
+
+/// Time to wait for a response to a request, given the number of requests
+/// already sent without a response.
+pub(crate) fn request_wait_ms(
+    config: &Config,
+    momentum: u8,
+    rng: Option<&mut (dyn Rng + '_)>,
+) -> u32 {
+    let cap = config.max_request_wait_ms.max(config.request_wait_ms);
+    let delay = config
+        .request_wait_ms
+        .checked_shl(momentum as u32)
+        .filter(|delay| delay >> momentum == config.request_wait_ms)
+        .map_or(cap, |delay| delay.min(cap));
+
+    match rng {
+        Some(rng) => delay - delay / 2 + rng.next_u32() % (delay / 2 + 1),
+        None => delay,
+    }
+}
+
+/// Time to hold off before requesting the next window, after receiving
+/// `blocks` blocks, or `None` if the download is not rate limited.
+pub(crate) fn throttle_ms(config: &Config, blocks: usize) -> Option<u32> {
+    config
+        .max_blocks_per_second
+        .filter(|rate| *rate > 0)
+        .map(|rate| (blocks as u32).saturating_mul(1000) / rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct SeqRng(core::ops::Range<u32>);
+
+    impl Rng for SeqRng {
+        fn next_u32(&mut self) -> u32 {
+            self.0.next().unwrap()
+        }
+    }
+
+    fn config(request_wait_ms: u32, max_request_wait_ms: u32) -> Config {
+        Config {
+            request_wait_ms,
+            max_request_wait_ms,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn fixed_interval_by_default() {
+        let config = Config::default();
+        for momentum in 0..4 {
+            assert_eq!(request_wait_ms(&config, momentum, None), 8000);
+        }
+    }
+
+    #[test]
+    fn exponential_backoff_capped() {
+        let config = config(1000, 5000);
+        let waits: Vec<u32> = (0..5)
+            .map(|momentum| request_wait_ms(&config, momentum, None))
+            .collect();
+
+        assert_eq!(waits, vec![1000, 2000, 4000, 5000, 5000]);
+
+        // Shifting out all bits saturates at the cap
+        assert_eq!(request_wait_ms(&config(1000, u32::MAX), 40, None), u32::MAX);
+        assert_eq!(request_wait_ms(&config(1000, u32::MAX), 30, None), u32::MAX);
+    }
+
+    #[test]
+    fn jitter_within_bounds() {
+        let config = config(1000, 60000);
+        let mut rng = SeqRng(0..u32::MAX);
+
+        for momentum in 0..8 {
+            let delay = request_wait_ms(&config, momentum, None);
+            for _ in 0..100 {
+                let wait = request_wait_ms(&config, momentum, Some(&mut rng));
+                assert!(wait >= delay / 2 && wait <= delay);
+            }
+        }
+    }
+
+    #[test]
+    fn throttle() {
+        assert_eq!(throttle_ms(&Config::default(), 31), None);
+
+        let config = Config {
+            max_blocks_per_second: Some(4),
+            ..Config::default()
+        };
+        assert_eq!(throttle_ms(&config, 31), Some(7750));
+        assert_eq!(throttle_ms(&config, 1), Some(250));
+
+        let config = Config {
+            max_blocks_per_second: Some(0),
+            ..Config::default()
+        };
+        assert_eq!(throttle_ms(&config, 31), None);
+    }
+}
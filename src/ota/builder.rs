@@ -1,11 +1,20 @@
 use embedded_hal::timer;
 
 use crate::ota::{
-    config::Config,
+    backoff::Rng,
+    clock::Clock,
+    config::{Config, ConfigError},
     control_interface::ControlInterface,
-    data_interface::DataInterface,
+    custom_job::CustomJobHandler,
+    data_interface::{compression::Decompressor, DataInterface},
+    event::TransitionHook,
+    integrity::IntegrityCheck,
     pal::OtaPal,
+    self_test::SelfTest,
     state::{SmContext, StateMachine},
+    statistics::Statistics,
+    storage::BitmapStorage,
+    version_policy::VersionPolicy,
 };
 
 use super::{agent::OtaAgent, data_interface::NoInterface, pal::ImageState};
@@ -58,6 +67,16 @@ where
     pal: PAL,
     request_timer: T,
     self_test_timer: Option<ST>,
+    decompressor: Option<&'a mut dyn Decompressor>,
+    bitmap_storage: Option<&'a mut dyn BitmapStorage>,
+    custom_job_handler: Option<&'a mut dyn CustomJobHandler>,
+    rng: Option<&'a mut dyn Rng>,
+    progress_clock: Option<&'a dyn Clock>,
+    self_test: Option<&'a mut dyn SelfTest>,
+    integrity_check: Option<&'a mut dyn IntegrityCheck>,
+    transition_hook: Option<&'a mut dyn TransitionHook>,
+    version_policy: Option<&'a mut dyn VersionPolicy>,
+    job_document_buf: Option<&'a mut [u8]>,
     config: Config,
 }
 
@@ -80,6 +99,16 @@ where
             pal,
             request_timer,
             self_test_timer: None,
+            decompressor: None,
+            bitmap_storage: None,
+            custom_job_handler: None,
+            rng: None,
+            progress_clock: None,
+            self_test: None,
+            integrity_check: None,
+            transition_hook: None,
+            version_policy: None,
+            job_document_buf: None,
             config: Config::default(),
         }
     }
@@ -108,6 +137,16 @@ where
             pal: self.pal,
             request_timer: self.request_timer,
             self_test_timer: self.self_test_timer,
+            decompressor: self.decompressor,
+            bitmap_storage: self.bitmap_storage,
+            custom_job_handler: self.custom_job_handler,
+            rng: self.rng,
+            progress_clock: self.progress_clock,
+            self_test: self.self_test,
+            integrity_check: self.integrity_check,
+            transition_hook: self.transition_hook,
+            version_policy: self.version_policy,
+            job_document_buf: self.job_document_buf,
             config: self.config,
         }
     }
@@ -122,10 +161,30 @@ where
         }
     }
 
+    /// Number of times an unanswered file block request, or a failure to
+    /// start the transfer, is retried before the job is reported as failed
+    /// with a timeout. The count is reset on every block received.
+    pub fn max_block_retries(self, max_block_retries: u8) -> Self {
+        Self {
+            config: Config {
+                max_block_retries,
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    #[deprecated(note = "Renamed to `max_block_retries`")]
     pub fn max_request_momentum(self, max_request_momentum: u8) -> Self {
+        self.max_block_retries(max_request_momentum)
+    }
+
+    /// Number of times a job request that failed to be sent is retried
+    /// before the agent gives up and shuts down.
+    pub fn max_describe_retries(self, max_describe_retries: u8) -> Self {
         Self {
             config: Config {
-                max_request_momentum,
+                max_describe_retries,
                 ..self.config
             },
             ..self
@@ -152,6 +211,47 @@ where
         }
     }
 
+    /// Upper bound of the exponential backoff of unanswered requests. Each
+    /// retry doubles the wait, starting from `request_wait_ms`. Defaults to
+    /// `request_wait_ms`, retrying on a fixed interval.
+    pub fn max_request_wait_ms(self, max_request_wait_ms: u32) -> Self {
+        Self {
+            config: Config {
+                max_request_wait_ms,
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Limit the download to at most `max_blocks_per_second` file blocks per
+    /// second on average. Once a window of blocks is received, the next one
+    /// is only requested after the time the window is due at this rate, with
+    /// other traffic, e.g. application telemetry on a narrowband link, making
+    /// use of the remaining bandwidth. Unlimited by default.
+    pub fn max_blocks_per_second(self, max_blocks_per_second: u32) -> Self {
+        Self {
+            config: Config {
+                max_blocks_per_second: Some(max_blocks_per_second),
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Largest MQTT payload the client can receive, e.g. the size of its
+    /// receive buffer. Checked by [`Self::try_build`] against the block
+    /// size, such that the blocks requested from the streaming service fit.
+    pub fn max_payload_len(self, max_payload_len: usize) -> Self {
+        Self {
+            config: Config {
+                max_payload_len: Some(max_payload_len),
+                ..self.config
+            },
+            ..self
+        }
+    }
+
     pub fn status_update_frequency(self, status_update_frequency: u32) -> Self {
         Self {
             config: Config {
@@ -162,6 +262,8 @@ where
         }
     }
 
+    /// Accept any version of a new image, skipping the version check
+    /// entirely. See [`Self::version_policy`] for finer grained control.
     pub fn allow_downgrade(self) -> Self {
         Self {
             config: Config {
@@ -172,6 +274,164 @@ where
         }
     }
 
+    /// Prefix of the MQTT streaming service topics, replacing
+    /// `$aws/things/<thing name>`. Stream topics are formatted as
+    /// `<prefix>/streams/<stream name>/...`, e.g. to receive files from a
+    /// local broker or a staging environment mirroring the streaming service.
+    pub fn stream_topic_prefix(self, prefix: &'static str) -> Self {
+        Self {
+            config: Config {
+                stream_topic_prefix: Some(prefix),
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Require the application to explicitly accept a new image through
+    /// [`OtaAgent::accept_image`] while in self-test, rather than accepting it
+    /// as soon as the self-test job is received. Combined with
+    /// [`Self::with_self_test_timeout`], images that are not accepted in time
+    /// are rejected, and the device is reset to roll back.
+    pub fn explicit_accept(self) -> Self {
+        Self {
+            config: Config {
+                explicit_accept: true,
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Public key used to verify the `sig-sha256-ecdsa` signature of
+    /// received files, as a SEC1 encoded P-256 point. Takes precedence over
+    /// [`OtaPal::code_signing_public_key`].
+    #[cfg(feature = "ota_sign_verify")]
+    pub fn code_signing_key(self, code_signing_key: &'static [u8]) -> Self {
+        Self {
+            config: Config {
+                code_signing_key: Some(code_signing_key),
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Public key of the root CA issuing code signing certificates, as a
+    /// SEC1 encoded P-256 point. Certificates looked up through
+    /// [`OtaPal::code_signing_certificate`] must be signed by this key.
+    #[cfg(feature = "ota_sign_verify")]
+    pub fn code_signing_root_key(self, root_key: &'static [u8]) -> Self {
+        Self {
+            config: Config {
+                code_signing_root_key: Some(root_key),
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Decompressor used for files marked as compressed in the job document.
+    /// Compressed files are rejected if no matching decompressor is set.
+    pub fn decompressor(self, decompressor: &'a mut dyn Decompressor) -> Self {
+        Self {
+            decompressor: Some(decompressor),
+            ..self
+        }
+    }
+
+    /// Storage used to persist the download progress, allowing a download
+    /// interrupted by a reset to resume instead of restarting from block 0.
+    pub fn bitmap_storage(self, storage: &'a mut dyn BitmapStorage) -> Self {
+        Self {
+            bitmap_storage: Some(storage),
+            ..self
+        }
+    }
+
+    /// Buffer keeping the raw document of the active job, including any
+    /// fields unknown to the agent, available through
+    /// [`OtaAgent::job_document`]. Documents longer than the buffer are not
+    /// kept.
+    pub fn job_document_buffer(self, buf: &'a mut [u8]) -> Self {
+        Self {
+            job_document_buf: Some(buf),
+            ..self
+        }
+    }
+
+    /// Random number generator used to add jitter to request retries.
+    pub fn rng(self, rng: &'a mut dyn Rng) -> Self {
+        Self {
+            rng: Some(rng),
+            ..self
+        }
+    }
+
+    /// Report the download progress to the job at least every
+    /// `interval_ms`, as measured by `clock`, in addition to every
+    /// [`Self::status_update_frequency`] blocks. On slow links, this keeps the
+    /// progress shown by the AWS console and Fleet Hub moving even when the
+    /// block based updates are far apart.
+    pub fn progress_interval(self, clock: &'a dyn Clock, interval_ms: u32) -> Self {
+        Self {
+            progress_clock: Some(clock),
+            config: Config {
+                progress_interval_ms: Some(interval_ms),
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Application self test, deciding whether a newly activated image is
+    /// accepted or rejected. Takes precedence over [`Self::explicit_accept`].
+    pub fn self_test(self, self_test: &'a mut dyn SelfTest) -> Self {
+        Self {
+            self_test: Some(self_test),
+            ..self
+        }
+    }
+
+    /// Integrity check run over every received file, in addition to the
+    /// SHA-256 digest given by the job, if any.
+    pub fn integrity_check(self, check: &'a mut dyn IntegrityCheck) -> Self {
+        Self {
+            integrity_check: Some(check),
+            ..self
+        }
+    }
+
+    /// Hook notified of every transition of the agent state machine, e.g. to
+    /// record them for field debugging. Transitions are logged regardless.
+    pub fn transition_hook(self, hook: &'a mut dyn TransitionHook) -> Self {
+        Self {
+            transition_hook: Some(hook),
+            ..self
+        }
+    }
+
+    /// Policy checking the firmware version of images in self test. Defaults
+    /// to [`Increasing`], only accepting images newer than the firmware that
+    /// performed the update.
+    ///
+    /// [`Increasing`]: super::version_policy::Increasing
+    pub fn version_policy(self, policy: &'a mut dyn VersionPolicy) -> Self {
+        Self {
+            version_policy: Some(policy),
+            ..self
+        }
+    }
+
+    /// Handler of job documents that are not OTA jobs, passed to
+    /// [`OtaAgent::handle_job_document`].
+    pub fn custom_job_handler(self, handler: &'a mut dyn CustomJobHandler) -> Self {
+        Self {
+            custom_job_handler: Some(handler),
+            ..self
+        }
+    }
+
     pub fn with_self_test_timeout<NST>(
         self,
         timer: NST,
@@ -188,6 +448,16 @@ where
             pal: self.pal,
             request_timer: self.request_timer,
             self_test_timer: Some(timer),
+            decompressor: self.decompressor,
+            bitmap_storage: self.bitmap_storage,
+            custom_job_handler: self.custom_job_handler,
+            rng: self.rng,
+            progress_clock: self.progress_clock,
+            self_test: self.self_test,
+            integrity_check: self.integrity_check,
+            transition_hook: self.transition_hook,
+            version_policy: self.version_policy,
+            job_document_buf: self.job_document_buf,
             config: Config {
                 self_test_timeout_ms: timeout_ms,
                 ..self.config
@@ -195,6 +465,18 @@ where
         }
     }
 
+    /// Validate the configuration, and build the agent.
+    ///
+    /// Fails if the block size is not supported by the streaming service, or
+    /// does not fit [`Self::max_payload_len`], or if the self-test timeout of
+    /// [`Self::with_self_test_timeout`] does not exceed the request wait.
+    pub fn try_build(self) -> Result<OtaAgent<'a, C, DP, DS, T, ST, PAL>, ConfigError> {
+        self.config.validate(self.self_test_timer.is_some())?;
+        Ok(self.build())
+    }
+
+    /// Build the agent, without validating the configuration. See
+    /// [`Self::try_build`].
     pub fn build(self) -> OtaAgent<'a, C, DP, DS, T, ST, PAL> {
         OtaAgent {
             state: StateMachine::new(SmContext {
@@ -203,12 +485,33 @@ where
                 data_secondary: self.data_secondary,
                 data_primary: self.data_primary,
                 active_interface: None,
+                pending_files: heapless::Vec::new(),
+                suspended_transfer: false,
+                protocol_fallback: false,
+                pal_busy: false,
+                close_pending: false,
                 request_momentum: 0,
+                statistics: Statistics::default(),
                 request_timer: self.request_timer,
                 self_test_timer: self.self_test_timer,
                 pal: self.pal,
+                decompressor: self.decompressor,
+                bitmap_storage: self.bitmap_storage,
+                custom_job_handler: self.custom_job_handler,
+                rng: self.rng,
+                progress_clock: self.progress_clock,
+                last_progress_ms: 0,
+                self_test: self.self_test,
+                integrity_check: self.integrity_check,
+                transition_hook: self.transition_hook,
+                version_policy: self.version_policy,
+                job_document_buf: self.job_document_buf,
+                job_document_len: None,
+                #[cfg(feature = "ota_sha256")]
+                file_hasher: Default::default(),
                 config: self.config,
                 image_state: ImageState::Unknown,
+                agent_event: None,
             }),
         }
     }
@@ -0,0 +1,217 @@
+//! Validation of code signing certificates.
+//!
+//! Rather than compiling in the public key of the code signing certificate,
+//! devices can be provisioned with the public key of a root CA only. The
+//! signing certificate referenced by the `certfile` of the job document is
+//! then looked up through [`OtaPal::code_signing_certificate`], and checked
+//! to be issued by the root before its key is used to verify the file. This
+//! allows rotating signing certificates without reflashing devices.
+//!
+//! Only the subset of X.509 used by AWS code signing is supported: the
+//! certificate must be directly issued by the root, using ECDSA P-256 with
+//! SHA-256. The validity period is not checked.
+//!
+//! [`OtaPal::code_signing_certificate`]: super::pal::OtaPal::code_signing_certificate
+
+use p256::ecdsa::{signature::DigestVerifier, Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use super::error::OtaError;
+use crate::rustot_log;
+
+const SEQUENCE: u8 = 0x30;
+const BIT_STRING: u8 = 0x03;
+const VERSION: u8 = 0xA0;
+
+/// `AlgorithmIdentifier` of ecdsa-with-SHA256
+const ECDSA_WITH_SHA256: &[u8] = &[0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02];
+
+/// `AlgorithmIdentifier` of id-ecPublicKey on the prime256v1 curve
+const EC_PUBLIC_KEY_P256: &[u8] = &[
+    0x06, 0x07, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01, 0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D,
+    0x03, 0x01, 0x07,
+];
+
+/// Minimal reader of DER encoded data
+struct Der<'c> {
+    data: &'c [u8],
+}
+
+impl<'c> Der<'c> {
+    fn new(data: &'c [u8]) -> Self {
+        Self { data }
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    /// Read the next element, returning its tag, its contents and its
+    /// complete encoding
+    fn next(&mut self) -> Result<(u8, &'c [u8], &'c [u8]), OtaError> {
+        let (&tag, rest) = self.data.split_first().ok_or(OtaError::Encoding)?;
+        let (&first, rest) = rest.split_first().ok_or(OtaError::Encoding)?;
+
+        let (len, rest) = match first {
+            0x00..=0x7F => (first as usize, rest),
+            0x81 | 0x82 => {
+                let n = (first & 0x7F) as usize;
+                if rest.len() < n {
+                    return Err(OtaError::Encoding);
+                }
+                let len = rest[..n].iter().fold(0, |len, b| (len << 8) | *b as usize);
+                (len, &rest[n..])
+            }
+            _ => return Err(OtaError::Encoding),
+        };
+
+        if rest.len() < len {
+            return Err(OtaError::Encoding);
+        }
+
+        let header = self.data.len() - rest.len();
+        let element = &self.data[..header + len];
+        self.data = &rest[len..];
+        Ok((tag, &rest[..len], element))
+    }
+
+    /// Read the contents of the next element, which must be of type `tag`
+    fn expect(&mut self, tag: u8) -> Result<&'c [u8], OtaError> {
+        match self.next()? {
+            (t, contents, _) if t == tag => Ok(contents),
+            _ => Err(OtaError::Encoding),
+        }
+    }
+
+    /// Read the contents of the next element, which must be a BIT STRING
+    /// without unused bits
+    fn bit_string(&mut self) -> Result<&'c [u8], OtaError> {
+        match self.expect(BIT_STRING)?.split_first() {
+            Some((0, bits)) => Ok(bits),
+            _ => Err(OtaError::Encoding),
+        }
+    }
+}
+
+/// Check that the DER encoded X.509 `certificate` is signed by `root_key`,
+/// returning the SEC1 encoded public key of the certificate.
+pub(crate) fn signing_key<'c>(
+    certificate: &'c [u8],
+    root_key: &[u8],
+) -> Result<&'c [u8], OtaError> {
+    let mut cert = Der::new(Der::new(certificate).expect(SEQUENCE)?);
+
+    let (tag, tbs_contents, tbs) = cert.next()?;
+    if tag != SEQUENCE || cert.expect(SEQUENCE)? != ECDSA_WITH_SHA256 {
+        rustot_log!(error, "Unsupported code signing certificate");
+        return Err(OtaError::SignatureCheckFailed);
+    }
+    let signature = cert.bit_string()?;
+
+    let mut tbs_cert = Der::new(tbs_contents);
+    if tbs_cert.peek_tag() == Some(VERSION) {
+        tbs_cert.next()?;
+    }
+    // Skip serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        tbs_cert.next()?;
+    }
+
+    let mut public_key_info = Der::new(tbs_cert.expect(SEQUENCE)?);
+    if public_key_info.expect(SEQUENCE)? != EC_PUBLIC_KEY_P256 {
+        rustot_log!(error, "Code signing certificate is not a P-256 key");
+        return Err(OtaError::SignatureCheckFailed);
+    }
+    let public_key = public_key_info.bit_string()?;
+
+    let signature = Signature::from_der(signature).map_err(|_| OtaError::SignatureCheckFailed)?;
+    let root =
+        VerifyingKey::from_sec1_bytes(root_key).map_err(|_| OtaError::SignatureCheckFailed)?;
+
+    root.verify_digest(Sha256::new().chain_update(tbs), &signature)
+        .map_err(|_| {
+            rustot_log!(error, "Code signing certificate not issued by the root");
+            OtaError::SignatureCheckFailed
+        })?;
+
+    Ok(public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT_KEY: &[u8] = &[
+        4, 29, 250, 210, 213, 198, 226, 210, 73, 59, 44, 207, 227, 53, 110, 190, 166, 154, 127,
+        135, 42, 72, 10, 190, 152, 236, 90, 220, 154, 124, 169, 36, 161, 249, 18, 28, 160, 76, 2,
+        145, 217, 211, 180, 139, 158, 218, 204, 154, 4, 230, 66, 56, 188, 1, 184, 73, 129, 93, 197,
+        121, 22, 138, 223, 227, 187,
+    ];
+
+    const SIGNER_KEY: &[u8] = &[
+        4, 1, 105, 207, 154, 148, 236, 55, 168, 109, 53, 116, 218, 127, 172, 135, 1, 102, 149, 167,
+        83, 225, 104, 46, 228, 213, 111, 43, 252, 76, 57, 216, 16, 8, 185, 160, 45, 201, 184, 7,
+        192, 88, 143, 32, 237, 209, 221, 228, 206, 196, 39, 50, 243, 170, 236, 173, 224, 90, 102,
+        96, 85, 138, 37, 41, 100,
+    ];
+
+    const OTHER_KEY: &[u8] = &[
+        4, 210, 196, 130, 107, 112, 215, 47, 98, 137, 209, 149, 67, 35, 225, 224, 188, 151, 15,
+        131, 167, 41, 225, 248, 226, 56, 200, 52, 149, 87, 49, 44, 87, 184, 201, 165, 174, 137, 7,
+        244, 243, 152, 127, 134, 77, 10, 121, 185, 112, 225, 38, 25, 71, 204, 176, 123, 75, 200,
+        121, 103, 30, 29, 18, 12, 170,
+    ];
+
+    /// Certificate of `SIGNER_KEY`, issued by `ROOT_KEY`
+    const CERTIFICATE: &[u8] = &[
+        48, 130, 1, 26, 48, 129, 193, 160, 3, 2, 1, 2, 2, 1, 1, 48, 10, 6, 8, 42, 134, 72, 206, 61,
+        4, 3, 2, 48, 22, 49, 20, 48, 18, 6, 3, 85, 4, 3, 12, 11, 114, 117, 115, 116, 111, 116, 32,
+        114, 111, 111, 116, 48, 30, 23, 13, 50, 49, 48, 49, 48, 49, 48, 48, 48, 48, 48, 48, 90, 23,
+        13, 52, 49, 48, 49, 48, 49, 48, 48, 48, 48, 48, 48, 90, 48, 24, 49, 22, 48, 20, 6, 3, 85,
+        4, 3, 12, 13, 114, 117, 115, 116, 111, 116, 32, 115, 105, 103, 110, 101, 114, 48, 89, 48,
+        19, 6, 7, 42, 134, 72, 206, 61, 2, 1, 6, 8, 42, 134, 72, 206, 61, 3, 1, 7, 3, 66, 0, 4, 1,
+        105, 207, 154, 148, 236, 55, 168, 109, 53, 116, 218, 127, 172, 135, 1, 102, 149, 167, 83,
+        225, 104, 46, 228, 213, 111, 43, 252, 76, 57, 216, 16, 8, 185, 160, 45, 201, 184, 7, 192,
+        88, 143, 32, 237, 209, 221, 228, 206, 196, 39, 50, 243, 170, 236, 173, 224, 90, 102, 96,
+        85, 138, 37, 41, 100, 48, 10, 6, 8, 42, 134, 72, 206, 61, 4, 3, 2, 3, 72, 0, 48, 69, 2, 33,
+        0, 176, 230, 72, 176, 44, 55, 48, 123, 240, 241, 149, 82, 60, 91, 59, 233, 98, 90, 122, 82,
+        51, 248, 182, 125, 235, 226, 16, 139, 1, 174, 200, 223, 2, 32, 36, 163, 152, 76, 228, 105,
+        224, 96, 232, 176, 121, 180, 85, 210, 116, 172, 123, 33, 173, 227, 176, 159, 133, 23, 235,
+        208, 254, 29, 66, 49, 194, 129,
+    ];
+
+    #[test]
+    fn issued_by_root() {
+        assert_eq!(signing_key(CERTIFICATE, ROOT_KEY), Ok(SIGNER_KEY));
+    }
+
+    #[test]
+    fn other_root() {
+        assert_eq!(
+            signing_key(CERTIFICATE, OTHER_KEY),
+            Err(OtaError::SignatureCheckFailed)
+        );
+    }
+
+    #[test]
+    fn tampered_certificate() {
+        // Flip a bit in the subject common name
+        let mut certificate = CERTIFICATE.to_vec();
+        let subject = certificate.windows(6).position(|w| w == b"signer").unwrap();
+        certificate[subject] ^= 0x01;
+
+        assert_eq!(
+            signing_key(&certificate, ROOT_KEY),
+            Err(OtaError::SignatureCheckFailed)
+        );
+    }
+
+    #[test]
+    fn malformed_certificate() {
+        assert_eq!(
+            signing_key(&CERTIFICATE[..100], ROOT_KEY),
+            Err(OtaError::Encoding)
+        );
+        assert_eq!(signing_key(&[], ROOT_KEY), Err(OtaError::Encoding));
+    }
+}
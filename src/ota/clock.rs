@@ -0,0 +1,128 @@
+//! Timers driven by a monotonic clock.
+//!
+//! The agent uses `embedded-hal` count down timers for request retries and the
+//! self-test timeout. Most runtimes readily provide a monotonic time source
+//! instead, e.g. the RTIC monotonic, `embassy_time::Instant` or a SysTick
+//! counter. Implementing [`Clock`] for such a time source, and wrapping it in
+//! a [`ClockTimer`] for each timer, is all that is needed to drive the agent:
+//!
+//! ```ignore
+//! struct SysTick;
+//!
+//! impl Clock for SysTick {
+//!     fn now_ms(&self) -> u64 {
+//!         systick_millis()
+//!     }
+//! }
+//!
+//! let agent = OtaAgent::builder(&mqtt, &mqtt, ClockTimer::new(&SysTick), pal)
+//!     .with_self_test_timeout(ClockTimer::new(&SysTick), 16000)
+//!     .build();
+//! ```
+
+use core::convert::Infallible;
+
+use embedded_hal::timer;
+
+/// Monotonic time source
+pub trait Clock {
+    /// Milliseconds elapsed since an arbitrary, fixed point in time
+    fn now_ms(&self) -> u64;
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now_ms(&self) -> u64 {
+        (**self).now_ms()
+    }
+}
+
+/// One-shot count down timer in milliseconds, driven by a [`Clock`]
+pub struct ClockTimer<C: Clock> {
+    clock: C,
+    deadline: Option<u64>,
+}
+
+impl<C: Clock> ClockTimer<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            deadline: None,
+        }
+    }
+}
+
+impl<C: Clock> timer::nb::CountDown for ClockTimer<C> {
+    type Error = Infallible;
+
+    type Time = u32;
+
+    fn start<T>(&mut self, count: T) -> Result<(), Self::Error>
+    where
+        T: Into<Self::Time>,
+    {
+        let count: u32 = count.into();
+        self.deadline = Some(self.clock.now_ms() + count as u64);
+        Ok(())
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Self::Error> {
+        match self.deadline {
+            Some(deadline) if self.clock.now_ms() >= deadline => {
+                self.deadline = None;
+                Ok(())
+            }
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+impl<C: Clock> timer::nb::Cancel for ClockTimer<C> {
+    fn cancel(&mut self) -> Result<(), Self::Error> {
+        self.deadline = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+    use embedded_hal::timer::nb::{Cancel, CountDown};
+
+    use super::*;
+
+    struct MockClock(Cell<u64>);
+
+    impl Clock for MockClock {
+        fn now_ms(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn expires_once() {
+        let clock = MockClock(Cell::new(1000));
+        let mut timer = ClockTimer::new(&clock);
+
+        // Never fires before being started
+        assert_eq!(timer.wait(), Err(nb::Error::WouldBlock));
+
+        timer.start(500u32).unwrap();
+        clock.0.set(1499);
+        assert_eq!(timer.wait(), Err(nb::Error::WouldBlock));
+
+        clock.0.set(1500);
+        assert_eq!(timer.wait(), Ok(()));
+        assert_eq!(timer.wait(), Err(nb::Error::WouldBlock));
+    }
+
+    #[test]
+    fn cancel() {
+        let clock = MockClock(Cell::new(0));
+        let mut timer = ClockTimer::new(&clock);
+
+        timer.start(10u32).unwrap();
+        timer.cancel().unwrap();
+        clock.0.set(20);
+        assert_eq!(timer.wait(), Err(nb::Error::WouldBlock));
+    }
+}
@@ -1,25 +1,143 @@
 pub struct Config {
     pub(crate) block_size: usize,
-    pub(crate) max_request_momentum: u8,
+    pub(crate) max_block_retries: u8,
+    pub(crate) max_describe_retries: u8,
     pub(crate) activate_delay: u8,
     pub(crate) request_wait_ms: u32,
+    pub(crate) max_request_wait_ms: u32,
     pub(crate) status_update_frequency: u32,
     pub(crate) allow_downgrade: bool,
     pub(crate) unsubscribe_on_shutdown: bool,
     pub(crate) self_test_timeout_ms: u32,
+    pub(crate) explicit_accept: bool,
+    pub(crate) stream_topic_prefix: Option<&'static str>,
+    pub(crate) max_blocks_per_second: Option<u32>,
+    pub(crate) max_payload_len: Option<usize>,
+    pub(crate) progress_interval_ms: Option<u32>,
+    #[cfg(feature = "ota_sign_verify")]
+    pub(crate) code_signing_key: Option<&'static [u8]>,
+    #[cfg(feature = "ota_sign_verify")]
+    pub(crate) code_signing_root_key: Option<&'static [u8]>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             block_size: 256,
-            max_request_momentum: 3,
+            max_block_retries: 3,
+            max_describe_retries: 3,
             activate_delay: 5,
             request_wait_ms: 8000,
+            max_request_wait_ms: 8000,
             status_update_frequency: 24,
             allow_downgrade: false,
             unsubscribe_on_shutdown: true,
             self_test_timeout_ms: 16000,
+            explicit_accept: false,
+            stream_topic_prefix: None,
+            max_blocks_per_second: None,
+            max_payload_len: None,
+            progress_interval_ms: None,
+            #[cfg(feature = "ota_sign_verify")]
+            code_signing_key: None,
+            #[cfg(feature = "ota_sign_verify")]
+            code_signing_root_key: None,
         }
     }
 }
+
+/// Inconsistent configuration, as reported by
+/// [`OtaAgentBuilder::try_build`](super::builder::OtaAgentBuilder::try_build)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum ConfigError {
+    /// The block size is outside of the 256 bytes to 128 KiB supported by
+    /// the streaming service
+    BlockSize,
+    /// A block of the configured size, encoded as a streaming service
+    /// response, does not fit the maximum MQTT payload
+    BlockExceedsPayload,
+    /// The self-test timeout expires before the job request sent after a
+    /// reset is even retried
+    SelfTestTimeout,
+}
+
+impl Config {
+    /// Smallest and largest block size supported by the streaming service
+    const BLOCK_SIZE: core::ops::RangeInclusive<usize> = 256..=128 * 1024;
+
+    /// Upper bound of the CBOR encoding of a streaming service response
+    /// around its block payload: the map header and break, and the `f`, `i`,
+    /// `l` and `p` keys along with their value headers.
+    const BLOCK_FRAMING: usize = 2 + 4 + 3 * 7;
+
+    pub(crate) fn validate(&self, self_test_timer: bool) -> Result<(), ConfigError> {
+        if !Self::BLOCK_SIZE.contains(&self.block_size) {
+            return Err(ConfigError::BlockSize);
+        }
+
+        if let Some(max_payload_len) = self.max_payload_len {
+            if self.block_size + Self::BLOCK_FRAMING > max_payload_len {
+                return Err(ConfigError::BlockExceedsPayload);
+            }
+        }
+
+        if self_test_timer && self.self_test_timeout_ms <= self.request_wait_ms {
+            return Err(ConfigError::SelfTestTimeout);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_valid() {
+        assert_eq!(Config::default().validate(true), Ok(()));
+    }
+
+    #[test]
+    fn block_size() {
+        for (block_size, valid) in [(0, false), (255, false), (256, true), (131073, false)] {
+            let config = Config {
+                block_size,
+                ..Config::default()
+            };
+            assert_eq!(config.validate(false).is_ok(), valid);
+        }
+    }
+
+    #[test]
+    fn block_exceeds_payload() {
+        let config = Config {
+            block_size: 1024,
+            max_payload_len: Some(1024),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.validate(false),
+            Err(ConfigError::BlockExceedsPayload)
+        );
+
+        let config = Config {
+            max_payload_len: Some(1024 + Config::BLOCK_FRAMING),
+            ..config
+        };
+        assert_eq!(config.validate(false), Ok(()));
+    }
+
+    #[test]
+    fn self_test_timeout() {
+        let config = Config {
+            self_test_timeout_ms: 8000,
+            ..Config::default()
+        };
+        assert_eq!(config.validate(true), Err(ConfigError::SelfTestTimeout));
+
+        // Without a self-test timer, the timeout is never started
+        assert_eq!(config.validate(false), Ok(()));
+    }
+}
@@ -7,10 +7,16 @@ use super::{
 };
 
 pub mod mqtt;
+pub mod persistent;
+pub mod shared;
 
 // Interfaces required for OTA
 pub trait ControlInterface {
     fn request_job(&self) -> Result<(), OtaError>;
+    /// Request the execution of the job `job_name` rather than the next
+    /// pending job, e.g. to pick up a job that was in progress before a
+    /// reset.
+    fn describe_job(&self, job_name: &str) -> Result<(), OtaError>;
     fn update_job_status(
         &self,
         file_ctx: &mut FileContext,
@@ -16,29 +16,44 @@ use crate::ota::error::OtaError;
 // FIXME: This can cause unit-tests to sometimes fail, due to parallel execution
 static REQUEST_CNT: AtomicU32 = AtomicU32::new(0);
 
+/// Subscribe to job notifications, and describe the job `job_id`, or the
+/// next pending job if `None`.
+fn describe<T: mqttrust::Mqtt>(mqtt: &T, job_id: Option<&str>) -> Result<(), OtaError> {
+    // Subscribe to the OTA job notification topics
+    Jobs::subscribe::<1>()
+        .topic(Topic::NotifyNext, QoS::AtLeastOnce)
+        .send(mqtt)?;
+
+    let request_cnt = REQUEST_CNT.fetch_add(1, Ordering::Relaxed);
+
+    // Obtains a unique client token on the form
+    // `{requestNumber}:{thingName}`, and increments the request counter
+    let mut client_token = heapless::String::<MAX_CLIENT_TOKEN_LEN>::new();
+    client_token
+        .write_fmt(format_args!("{}:{}", request_cnt, mqtt.client_id()))
+        .map_err(|_| OtaError::Overflow)?;
+
+    let request = Jobs::describe().client_token(client_token.as_str());
+    match job_id {
+        Some(job_id) => request.job_id(job_id).include_job_document(),
+        None => request,
+    }
+    .send(mqtt, QoS::AtLeastOnce)?;
+
+    Ok(())
+}
+
 impl<T: mqttrust::Mqtt> ControlInterface for T {
     /// Check for next available OTA job from the job service by publishing a
     /// "get next job" message to the job service.
     fn request_job(&self) -> Result<(), OtaError> {
-        // Subscribe to the OTA job notification topics
-        Jobs::subscribe::<1>()
-            .topic(Topic::NotifyNext, QoS::AtLeastOnce)
-            .send(self)?;
-
-        let request_cnt = REQUEST_CNT.fetch_add(1, Ordering::Relaxed);
-
-        // Obtains a unique client token on the form
-        // `{requestNumber}:{thingName}`, and increments the request counter
-        let mut client_token = heapless::String::<MAX_CLIENT_TOKEN_LEN>::new();
-        client_token
-            .write_fmt(format_args!("{}:{}", request_cnt, self.client_id()))
-            .map_err(|_| OtaError::Overflow)?;
-
-        Jobs::describe()
-            .client_token(client_token.as_str())
-            .send(self, QoS::AtLeastOnce)?;
+        describe(self, None)
+    }
 
-        Ok(())
+    /// Check for a specific job by publishing a "describe job" message to the
+    /// job service.
+    fn describe_job(&self, job_name: &str) -> Result<(), OtaError> {
+        describe(self, Some(job_name))
     }
 
     /// Update the job status on the service side with progress or completion info
@@ -68,23 +83,35 @@ impl<T: mqttrust::Mqtt> ControlInterface for T {
             if file_ctx.blocks_remaining != 0
                 && received_blocks != 0
                 && received_blocks % config.status_update_frequency != 0
+                && !file_ctx.report_progress
             {
                 return Ok(());
             }
+            file_ctx.report_progress = false;
 
             // Don't override the progress on succeeded, nor on self-test
             // active. (Cases where progess counter is lost due to device
             // restarts)
             if status != JobStatus::Succeeded && reason != JobStatusReason::SelfTestActive {
-                let mut progress = heapless::String::new();
-                progress
-                    .write_fmt(format_args!("{}/{}", received_blocks, total_blocks))
-                    .map_err(|_| OtaError::Overflow)?;
-
+                // Replaced by the separate block counts
                 file_ctx
                     .status_details
-                    .insert(heapless::String::from("progress"), progress)
-                    .map_err(|_| OtaError::Overflow)?;
+                    .remove(&heapless::String::from("progress"));
+
+                for (key, blocks) in [
+                    ("receivedBlocks", received_blocks),
+                    ("totalBlocks", total_blocks),
+                ] {
+                    let mut value = heapless::String::new();
+                    value
+                        .write_fmt(format_args!("{}", blocks))
+                        .map_err(|_| OtaError::Overflow)?;
+
+                    file_ctx
+                        .status_details
+                        .insert(heapless::String::from(key), value)
+                        .map_err(|_| OtaError::Overflow)?;
+                }
             }
 
             // Downgrade Progress updates to QOS 0 to avoid overloading MQTT
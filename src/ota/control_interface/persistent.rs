@@ -0,0 +1,222 @@
+//! Tracking of the job in progress across power cycles.
+//!
+//! A device that resets in the middle of an update will by default ask for the
+//! next pending job after boot. Wrapping the control interface in
+//! [`PersistentJobs`] stores the name and status of the job in progress in an
+//! [`ExecutionStorage`], the same storage as used by a [`JobsAgent`], and
+//! makes the first job request after boot describe that job instead, such
+//! that the agent picks up reporting on it:
+//!
+//! ```ignore
+//! let control = PersistentJobs::new(&mqtt, &mut job_storage);
+//! let agent = OtaAgent::builder(&control, &mqtt, timer, pal).build();
+//! ```
+//!
+//! The response to the job description is published on the
+//! `$aws/things/{thing_name}/jobs/{job_id}/get/accepted` topic, and is to be
+//! handed to the agent just like the response for the next pending job.
+//!
+//! [`JobsAgent`]: crate::jobs::agent::JobsAgent
+
+use core::cell::{Cell, RefCell};
+
+use super::ControlInterface;
+use crate::jobs::{
+    data_types::JobStatus,
+    storage::{ExecutionStorage, StoredExecution},
+};
+use crate::ota::{
+    config::Config,
+    encoding::{json::JobStatusReason, FileContext},
+    error::OtaError,
+};
+use crate::rustot_log;
+
+/// [`ControlInterface`] wrapper persisting the job in progress
+pub struct PersistentJobs<'a, C: ControlInterface> {
+    control: &'a C,
+    storage: RefCell<&'a mut dyn ExecutionStorage>,
+    /// Job and status last written to storage
+    stored: RefCell<Option<StoredExecution>>,
+    /// Set once the stored job has been picked up after boot
+    resumed: Cell<bool>,
+}
+
+impl<'a, C: ControlInterface> PersistentJobs<'a, C> {
+    pub fn new(control: &'a C, storage: &'a mut dyn ExecutionStorage) -> Self {
+        Self {
+            control,
+            storage: RefCell::new(storage),
+            stored: RefCell::new(None),
+            resumed: Cell::new(false),
+        }
+    }
+
+    /// Store the reported `status` of `job_name`, clearing the storage once
+    /// the job is done. Only writes to storage if the status changed.
+    fn persist(&self, job_name: &str, status: JobStatus) -> Result<(), OtaError> {
+        let mut stored = self.stored.borrow_mut();
+        if matches!(&*stored, Some(job) if job.job_id == job_name && job.status == status) {
+            return Ok(());
+        }
+
+        let mut job = StoredExecution {
+            job_id: heapless::String::new(),
+            status,
+            execution_number: None,
+        };
+        job.job_id
+            .push_str(job_name)
+            .map_err(|_| OtaError::Overflow)?;
+
+        let mut storage = self.storage.borrow_mut();
+        match status {
+            JobStatus::Queued | JobStatus::InProgress => storage.store(&job)?,
+            _ => storage.clear()?,
+        }
+        *stored = Some(job);
+        Ok(())
+    }
+}
+
+impl<'a, C: ControlInterface> ControlInterface for PersistentJobs<'a, C> {
+    /// Describe the job in progress before the last reset, if any, or request
+    /// the next pending job otherwise.
+    fn request_job(&self) -> Result<(), OtaError> {
+        // Only resume once, as the job might have been canceled in the
+        // meantime
+        if !self.resumed.replace(true) {
+            if let Some(job) = self.storage.borrow_mut().load()? {
+                rustot_log!(info, "Resuming job {}", job.job_id.as_str());
+                let result = self.control.describe_job(job.job_id.as_str());
+                *self.stored.borrow_mut() = Some(job);
+                return result;
+            }
+        }
+        self.control.request_job()
+    }
+
+    fn describe_job(&self, job_name: &str) -> Result<(), OtaError> {
+        self.control.describe_job(job_name)
+    }
+
+    fn update_job_status(
+        &self,
+        file_ctx: &mut FileContext,
+        config: &Config,
+        status: JobStatus,
+        reason: JobStatusReason,
+    ) -> Result<(), OtaError> {
+        self.control
+            .update_job_status(file_ctx, config, status, reason)?;
+        self.persist(file_ctx.job_name.as_str(), status)
+    }
+
+    fn cleanup(&self) -> Result<(), OtaError> {
+        self.control.cleanup()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mqttrust::{encoding::v4::decode_slice, Packet};
+
+    use super::*;
+    use crate::jobs::JobError;
+    use crate::ota::test::test_file_ctx;
+    use crate::test::MockMqtt;
+
+    #[derive(Default)]
+    struct MemStorage {
+        job: Option<StoredExecution>,
+        writes: usize,
+    }
+
+    impl ExecutionStorage for MemStorage {
+        fn store(&mut self, job: &StoredExecution) -> Result<(), JobError> {
+            self.job = Some(job.clone());
+            self.writes += 1;
+            Ok(())
+        }
+
+        fn load(&mut self) -> Result<Option<StoredExecution>, JobError> {
+            Ok(self.job.clone())
+        }
+
+        fn clear(&mut self) -> Result<(), JobError> {
+            self.job = None;
+            self.writes += 1;
+            Ok(())
+        }
+    }
+
+    fn published_topics(mqtt: &MockMqtt) -> Vec<String> {
+        mqtt.tx
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Publish(p)) => Some(p.topic_name.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stores_status_changes() {
+        let mqtt = MockMqtt::new();
+        let config = Config::default();
+        let mut file_ctx = test_file_ctx(&config);
+        let mut storage = MemStorage::default();
+
+        let control = PersistentJobs::new(&mqtt, &mut storage);
+        for _ in 0..3 {
+            control
+                .update_job_status(
+                    &mut file_ctx,
+                    &config,
+                    JobStatus::InProgress,
+                    JobStatusReason::Receiving,
+                )
+                .unwrap();
+        }
+        control
+            .update_job_status(
+                &mut file_ctx,
+                &config,
+                JobStatus::Succeeded,
+                JobStatusReason::Accepted,
+            )
+            .unwrap();
+        drop(control);
+
+        assert_eq!(storage.writes, 2);
+        assert_eq!(storage.job, None);
+    }
+
+    #[test]
+    fn resumes_stored_job() {
+        let mqtt = MockMqtt::new();
+        let mut storage = MemStorage {
+            job: Some(StoredExecution {
+                job_id: heapless::String::from("Test-job"),
+                status: JobStatus::InProgress,
+                execution_number: None,
+            }),
+            writes: 0,
+        };
+
+        let control = PersistentJobs::new(&mqtt, &mut storage);
+        control.request_job().unwrap();
+        assert_eq!(
+            published_topics(&mqtt),
+            vec!["$aws/things/test_client/jobs/Test-job/get"]
+        );
+
+        // Any later request asks for the next pending job
+        control.request_job().unwrap();
+        assert_eq!(
+            published_topics(&mqtt),
+            vec!["$aws/things/test_client/jobs/$next/get"]
+        );
+    }
+}
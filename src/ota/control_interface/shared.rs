@@ -0,0 +1,60 @@
+//! Control interface for an OTA agent sharing the jobs topics with a
+//! [`JobsAgent`].
+//!
+//! The jobs agent subscribes to the jobs topics and claims every job of the
+//! thing, so the OTA agent is only to report the status of the OTA jobs handed
+//! to it through an [`OtaJobRouter`]. See [`job_router`] for the complete
+//! setup.
+//!
+//! [`JobsAgent`]: crate::jobs::agent::JobsAgent
+//! [`OtaJobRouter`]: crate::ota::job_router::OtaJobRouter
+//! [`job_router`]: crate::ota::job_router
+
+use super::ControlInterface;
+use crate::jobs::data_types::JobStatus;
+use crate::ota::{
+    config::Config,
+    encoding::{json::JobStatusReason, FileContext},
+    error::OtaError,
+};
+
+/// [`ControlInterface`] wrapper leaving subscriptions and job requests to the
+/// jobs agent, and only passing on job status updates
+pub struct SharedJobs<'a, C: ControlInterface> {
+    control: &'a C,
+}
+
+impl<'a, C: ControlInterface> SharedJobs<'a, C> {
+    pub fn new(control: &'a C) -> Self {
+        Self { control }
+    }
+}
+
+impl<'a, C: ControlInterface> ControlInterface for SharedJobs<'a, C> {
+    /// Nothing to request, the next job is claimed by the jobs agent
+    fn request_job(&self) -> Result<(), OtaError> {
+        Ok(())
+    }
+
+    /// Nothing to describe, the job in progress is claimed by the jobs agent
+    /// again after a reset
+    fn describe_job(&self, _job_name: &str) -> Result<(), OtaError> {
+        Ok(())
+    }
+
+    fn update_job_status(
+        &self,
+        file_ctx: &mut FileContext,
+        config: &Config,
+        status: JobStatus,
+        reason: JobStatusReason,
+    ) -> Result<(), OtaError> {
+        self.control
+            .update_job_status(file_ctx, config, status, reason)
+    }
+
+    /// The jobs topics stay subscribed to by the jobs agent
+    fn cleanup(&self) -> Result<(), OtaError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,30 @@
+//! Dispatching of job documents that are not OTA jobs.
+//!
+//! The OTA agent shares the AWS IoT Jobs subscription with any other jobs the
+//! device handles. Job documents passed to
+//! [`OtaAgent::handle_job_document`] that do not contain an `afr_ota` section
+//! are forwarded to the [`CustomJobHandler`] set on the builder.
+//!
+//! The other way around, a [`JobsAgent`] processing the application jobs can
+//! hand the OTA jobs to the OTA agent, see [`job_router`](super::job_router).
+//!
+//! [`JobsAgent`]: crate::jobs::agent::JobsAgent
+//! [`OtaAgent::handle_job_document`]: super::agent::OtaAgent::handle_job_document
+
+use super::error::OtaError;
+use crate::jobs::StatusDetails;
+
+/// Application handler of non-OTA jobs.
+pub trait CustomJobHandler {
+    /// Handle the job `job_name`, with `job_document` being the raw JSON job
+    /// document.
+    ///
+    /// Reporting the job execution status back to AWS IoT Jobs is the
+    /// responsibility of the handler.
+    fn handle_job(
+        &mut self,
+        job_name: &str,
+        job_document: &[u8],
+        status_details: Option<&StatusDetails>,
+    ) -> Result<(), OtaError>;
+}
@@ -0,0 +1,252 @@
+//! Decompression of compressed OTA files.
+//!
+//! Files marked with a `compression` in the job document have their blocks
+//! streamed through a [`Decompressor`] before being written by the PAL. As
+//! decompression is inherently sequential, blocks of compressed files are
+//! ingested strictly in order.
+
+use serde::Deserialize;
+
+use crate::ota::error::OtaError;
+
+/// Compression algorithm of a file, as indicated by the job document.
+///
+/// Job documents with files compressed by any other algorithm are rejected
+/// when parsed, as no decompressor exists for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Compression {
+    #[serde(rename = "heatshrink")]
+    Heatshrink,
+}
+
+/// Streaming decompressor used by the OTA agent for compressed files.
+pub trait Decompressor {
+    /// Compression algorithm handled by this decompressor
+    fn compression(&self) -> Compression;
+
+    /// Reset any internal state, in preparation of decompressing a new file
+    fn reset(&mut self);
+
+    /// Decompress the next chunk of compressed data, handing all produced
+    /// output to `sink`, in order.
+    fn decompress(
+        &mut self,
+        input: &[u8],
+        sink: &mut dyn FnMut(&[u8]) -> Result<(), OtaError>,
+    ) -> Result<(), OtaError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HeatshrinkState {
+    TagBit,
+    Literal,
+    BackrefIndex,
+    BackrefCount,
+}
+
+/// Streaming [heatshrink](https://github.com/atomicobject/heatshrink)
+/// decoder.
+///
+/// `WINDOW` is the size of the window buffer in bytes, and must be equal to
+/// `2^window_sz2` of the encoder. Keeping it a compile time constant bounds
+/// the RAM used for decompression.
+pub struct HeatshrinkDecoder<const WINDOW: usize> {
+    window: [u8; WINDOW],
+    head: usize,
+    lookahead_sz2: u8,
+    state: HeatshrinkState,
+    bits: u16,
+    bit_cnt: u8,
+    backref_index: u16,
+    output: heapless::Vec<u8, 64>,
+}
+
+impl<const WINDOW: usize> HeatshrinkDecoder<WINDOW> {
+    /// Create a new decoder, matching an encoder configured with
+    /// `window_sz2 = log2(WINDOW)` and `lookahead_sz2`.
+    ///
+    /// Fails with [`OtaError::InvalidFile`] if either is out of the range
+    /// supported by heatshrink.
+    pub fn new(lookahead_sz2: u8) -> Result<Self, OtaError> {
+        if !WINDOW.is_power_of_two() || WINDOW < 16 || WINDOW > (1 << 15) {
+            return Err(OtaError::InvalidFile);
+        }
+
+        if lookahead_sz2 < 3 || lookahead_sz2 as u32 >= WINDOW.trailing_zeros() {
+            return Err(OtaError::InvalidFile);
+        }
+
+        Ok(Self {
+            window: [0; WINDOW],
+            head: 0,
+            lookahead_sz2,
+            state: HeatshrinkState::TagBit,
+            bits: 0,
+            bit_cnt: 0,
+            backref_index: 0,
+            output: heapless::Vec::new(),
+        })
+    }
+
+    fn window_sz2(&self) -> u8 {
+        WINDOW.trailing_zeros() as u8
+    }
+
+    fn bits_needed(&self) -> u8 {
+        match self.state {
+            HeatshrinkState::TagBit => 1,
+            HeatshrinkState::Literal => 8,
+            HeatshrinkState::BackrefIndex => self.window_sz2(),
+            HeatshrinkState::BackrefCount => self.lookahead_sz2,
+        }
+    }
+
+    fn push(
+        &mut self,
+        byte: u8,
+        sink: &mut dyn FnMut(&[u8]) -> Result<(), OtaError>,
+    ) -> Result<(), OtaError> {
+        self.window[self.head % WINDOW] = byte;
+        self.head = self.head.wrapping_add(1);
+
+        if self.output.push(byte).is_err() {
+            self.flush(sink)?;
+            // Can't fail, as the buffer was just flushed
+            self.output.push(byte).ok();
+        }
+        Ok(())
+    }
+
+    fn flush(
+        &mut self,
+        sink: &mut dyn FnMut(&[u8]) -> Result<(), OtaError>,
+    ) -> Result<(), OtaError> {
+        if !self.output.is_empty() {
+            sink(&self.output)?;
+            self.output.clear();
+        }
+        Ok(())
+    }
+
+    /// Handle a complete value read from the bitstream, for the current state
+    fn step(
+        &mut self,
+        value: u16,
+        sink: &mut dyn FnMut(&[u8]) -> Result<(), OtaError>,
+    ) -> Result<(), OtaError> {
+        self.state = match self.state {
+            HeatshrinkState::TagBit if value == 1 => HeatshrinkState::Literal,
+            HeatshrinkState::TagBit => HeatshrinkState::BackrefIndex,
+            HeatshrinkState::Literal => {
+                self.push(value as u8, sink)?;
+                HeatshrinkState::TagBit
+            }
+            HeatshrinkState::BackrefIndex => {
+                self.backref_index = value + 1;
+                HeatshrinkState::BackrefCount
+            }
+            HeatshrinkState::BackrefCount => {
+                let offset = self.backref_index as usize;
+                for _ in 0..=value {
+                    let byte = self.window[self.head.wrapping_sub(offset) % WINDOW];
+                    self.push(byte, sink)?;
+                }
+                HeatshrinkState::TagBit
+            }
+        };
+        Ok(())
+    }
+}
+
+impl<const WINDOW: usize> Decompressor for HeatshrinkDecoder<WINDOW> {
+    fn compression(&self) -> Compression {
+        Compression::Heatshrink
+    }
+
+    fn reset(&mut self) {
+        self.window = [0; WINDOW];
+        self.head = 0;
+        self.state = HeatshrinkState::TagBit;
+        self.bits = 0;
+        self.bit_cnt = 0;
+        self.backref_index = 0;
+        self.output.clear();
+    }
+
+    fn decompress(
+        &mut self,
+        input: &[u8],
+        sink: &mut dyn FnMut(&[u8]) -> Result<(), OtaError>,
+    ) -> Result<(), OtaError> {
+        for byte in input {
+            for shift in (0..8).rev() {
+                self.bits = (self.bits << 1) | ((*byte >> shift) & 1) as u16;
+                self.bit_cnt += 1;
+
+                if self.bit_cnt == self.bits_needed() {
+                    let value = self.bits;
+                    self.bits = 0;
+                    self.bit_cnt = 0;
+                    self.step(value, sink)?;
+                }
+            }
+        }
+
+        self.flush(sink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decompress_all<D: Decompressor>(decoder: &mut D, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in chunks {
+            decoder
+                .decompress(chunk, &mut |data| {
+                    out.extend_from_slice(data);
+                    Ok(())
+                })
+                .unwrap();
+        }
+        out
+    }
+
+    // "abcabcabc", encoded with window_sz2 = 8 & lookahead_sz2 = 4
+    const ENCODED: &[u8] = &[176, 216, 172, 96, 37];
+
+    #[test]
+    fn heatshrink_literals_and_backref() {
+        let mut decoder = HeatshrinkDecoder::<256>::new(4).unwrap();
+        assert_eq!(decompress_all(&mut decoder, &[ENCODED]), b"abcabcabc");
+    }
+
+    #[test]
+    fn heatshrink_streaming() {
+        let mut decoder = HeatshrinkDecoder::<256>::new(4).unwrap();
+        let chunks: Vec<&[u8]> = ENCODED.chunks(1).collect();
+        assert_eq!(decompress_all(&mut decoder, &chunks), b"abcabcabc");
+
+        decoder.reset();
+        assert_eq!(decompress_all(&mut decoder, &[ENCODED]), b"abcabcabc");
+    }
+
+    #[test]
+    fn heatshrink_invalid_parameters() {
+        assert!(HeatshrinkDecoder::<100>::new(4).is_err());
+        assert!(HeatshrinkDecoder::<256>::new(2).is_err());
+        assert!(HeatshrinkDecoder::<256>::new(8).is_err());
+    }
+
+    #[test]
+    fn unsupported_compression() {
+        assert_eq!(
+            serde_json_core::from_str::<Compression>("\"heatshrink\"")
+                .unwrap()
+                .0,
+            Compression::Heatshrink
+        );
+        assert!(serde_json_core::from_str::<Compression>("\"gzip\"").is_err());
+    }
+}
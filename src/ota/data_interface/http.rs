@@ -1,3 +1,5 @@
+use core::ops::RangeInclusive;
+
 use crate::ota::{
     config::Config,
     data_interface::{DataInterface, FileBlock, Protocol},
@@ -5,38 +7,214 @@ use crate::ota::{
     error::OtaError,
 };
 
-pub struct HttpInterface {}
+/// Minimal HTTP client abstraction required by the HTTP data interface.
+///
+/// The OTA agent only ever issues ranged `GET` requests towards the
+/// (pre-signed) `update_data_url` of the file being downloaded. Implementors
+/// are expected to send the request along with a `Range: bytes=start-end`
+/// header, and hand the full response body to [`OtaAgent::handle_message`]
+/// once it has been received.
+///
+/// [`OtaAgent::handle_message`]: crate::ota::agent::OtaAgent::handle_message
+pub trait HttpClient {
+    type Error;
+
+    /// Issue a `GET` request to `url`, requesting the bytes in `range`.
+    fn get_range(&self, url: &str, range: RangeInclusive<usize>) -> Result<(), Self::Error>;
+}
+
+impl<'a, H: HttpClient> HttpClient for &'a H {
+    type Error = H::Error;
 
-impl HttpInterface {
-    pub fn new() -> Self {
-        Self {}
+    fn get_range(&self, url: &str, range: RangeInclusive<usize>) -> Result<(), Self::Error> {
+        (*self).get_range(url, range)
     }
 }
 
-impl DataInterface for HttpInterface {
+pub struct HttpInterface<H: HttpClient> {
+    client: H,
+}
+
+impl<H: HttpClient> HttpInterface<H> {
+    pub fn new(client: H) -> Self {
+        Self { client }
+    }
+
+    /// Absolute block index of the next missing block in the current bitmap
+    /// window.
+    fn next_block_id(file_ctx: &FileContext) -> Result<usize, OtaError> {
+        file_ctx
+            .bitmap
+            .first_index()
+            .map(|idx| file_ctx.block_offset as usize + idx)
+            .ok_or(OtaError::BlockOutOfRange)
+    }
+}
+
+impl<H: HttpClient> DataInterface for HttpInterface<H> {
     const PROTOCOL: Protocol = Protocol::Http;
 
-    fn init_file_transfer(&self, _file_ctx: &mut FileContext) -> Result<(), OtaError> {
+    fn init_file_transfer(
+        &self,
+        file_ctx: &mut FileContext,
+        _config: &Config,
+    ) -> Result<(), OtaError> {
+        // Nothing to subscribe to, but the transfer cannot succeed without an
+        // URL to download from.
+        if file_ctx.update_data_url.is_none() {
+            return Err(OtaError::InvalidFile);
+        }
         Ok(())
     }
 
+    /// Request the next missing file block using a ranged `GET` request
     fn request_file_block(
         &self,
-        _file_ctx: &mut FileContext,
-        _config: &Config,
+        file_ctx: &mut FileContext,
+        config: &Config,
     ) -> Result<(), OtaError> {
-        Ok(())
+        // Blocks are requested one at a time over HTTP
+        file_ctx.request_block_remaining = 1;
+
+        let url = file_ctx
+            .update_data_url
+            .as_ref()
+            .ok_or(OtaError::InvalidFile)?;
+
+        let start = Self::next_block_id(file_ctx)? * config.block_size;
+        let end = core::cmp::min(start + config.block_size, file_ctx.filesize) - 1;
+
+        self.client
+            .get_range(url.as_str(), start..=end)
+            .map_err(|_| OtaError::Http)
     }
 
+    /// The payload of a HTTP response is the raw content of the block that was
+    /// requested last.
     fn decode_file_block<'b>(
         &self,
-        _file_ctx: &mut FileContext,
-        _payload: &'b mut [u8],
+        file_ctx: &mut FileContext,
+        payload: &'b [u8],
     ) -> Result<FileBlock<'b>, OtaError> {
-        unimplemented!()
+        Ok(FileBlock {
+            client_token: None,
+            file_id: file_ctx.fileid,
+            block_size: payload.len(),
+            block_id: Self::next_block_id(file_ctx)?,
+            block_payload: payload,
+        })
     }
 
     fn cleanup(&self, _file_ctx: &mut FileContext, _config: &Config) -> Result<(), OtaError> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::ota::test::test_file_ctx;
+
+    #[derive(Default)]
+    struct MockHttpClient {
+        requests: RefCell<Vec<(String, RangeInclusive<usize>)>>,
+    }
+
+    impl HttpClient for MockHttpClient {
+        type Error = ();
+
+        fn get_range(&self, url: &str, range: RangeInclusive<usize>) -> Result<(), Self::Error> {
+            self.requests.borrow_mut().push((url.to_owned(), range));
+            Ok(())
+        }
+    }
+
+    fn http_file_ctx(config: &Config) -> FileContext {
+        let mut file_ctx = test_file_ctx(config);
+        file_ctx.update_data_url = Some(heapless::String::from("https://s3/ota?sig=abc"));
+        file_ctx
+    }
+
+    #[test]
+    fn protocol_fits() {
+        assert_eq!(
+            <HttpInterface<MockHttpClient> as DataInterface>::PROTOCOL,
+            Protocol::Http
+        );
+    }
+
+    #[test]
+    fn init_file_transfer_requires_url() {
+        let interface = HttpInterface::new(MockHttpClient::default());
+        let config = Config::default();
+
+        let mut file_ctx = test_file_ctx(&config);
+        assert_eq!(
+            interface.init_file_transfer(&mut file_ctx, &config),
+            Err(OtaError::InvalidFile)
+        );
+
+        let mut file_ctx = http_file_ctx(&config);
+        assert_eq!(interface.init_file_transfer(&mut file_ctx, &config), Ok(()));
+    }
+
+    #[test]
+    fn request_file_block_ranged_get() {
+        let client = MockHttpClient::default();
+        let interface = HttpInterface::new(&client);
+        let config = Config::default();
+
+        let mut file_ctx = http_file_ctx(&config);
+        file_ctx.block_offset = 2;
+        file_ctx.bitmap.set(0, false);
+
+        interface
+            .request_file_block(&mut file_ctx, &config)
+            .unwrap();
+
+        assert_eq!(file_ctx.request_block_remaining, 1);
+        assert_eq!(
+            client.requests.borrow().as_slice(),
+            &[(String::from("https://s3/ota?sig=abc"), 768..=1023)]
+        );
+    }
+
+    #[test]
+    fn request_last_file_block_is_truncated() {
+        let client = MockHttpClient::default();
+        let interface = HttpInterface::new(&client);
+        let config = Config::default();
+
+        let mut file_ctx = http_file_ctx(&config);
+        // 123456 bytes in 256 byte blocks leaves a final block of 64 bytes
+        file_ctx.block_offset = 482;
+
+        interface
+            .request_file_block(&mut file_ctx, &config)
+            .unwrap();
+
+        assert_eq!(
+            client.requests.borrow().as_slice(),
+            &[(String::from("https://s3/ota?sig=abc"), 123392..=123455)]
+        );
+    }
+
+    #[test]
+    fn decode_file_block_uses_requested_block() {
+        let interface = HttpInterface::new(MockHttpClient::default());
+        let config = Config::default();
+
+        let mut file_ctx = http_file_ctx(&config);
+        file_ctx.bitmap.set(0, false);
+
+        let payload = &mut [0xAAu8; 256];
+        let block = interface.decode_file_block(&mut file_ctx, payload).unwrap();
+
+        assert_eq!(block.block_id, 1);
+        assert_eq!(block.block_size, 256);
+        assert_eq!(block.file_id, 0);
+        assert!(block.validate(config.block_size, file_ctx.filesize));
+    }
+}
@@ -0,0 +1,360 @@
+//! Reference [`HttpClient`] on top of `embedded-nal` and `embedded-tls`.
+//!
+//! [`HttpsClient`] opens a TLS connection to the host of the pre-signed
+//! `update_data_url` for every requested block, issues the ranged `GET`
+//! request and reads the response body into a buffer owned by the
+//! application. Once [`HttpClient::get_range`] returns, the block is handed to
+//! the agent:
+//!
+//! ```ignore
+//! let client = HttpsClient::new(&mut stack, &mut rng, read_record, write_record, body);
+//! let http = HttpInterface::new(&client);
+//! let mut agent = OtaAgent::builder(&mqtt, &mqtt, timer, pal)
+//!     .data_secondary(&http)
+//!     .build();
+//!
+//! // After the agent requested a block
+//! agent.handle_message(&client.response())?;
+//! ```
+//!
+//! The TLS read record buffer must hold a full TLS record, i.e. 16640 bytes,
+//! unless the server is known to use smaller records. The server certificate
+//! is not verified: the integrity of the image is established by its code
+//! signature, which should always be enabled along with this client.
+
+use core::cell::{Ref, RefCell};
+use core::ops::RangeInclusive;
+
+use embedded_io::blocking::{Read, Write};
+use embedded_nal::{AddrType, Dns, SocketAddr, TcpClientStack};
+use embedded_tls::blocking::{Aes128GcmSha256, NoVerify, TlsConfig, TlsConnection, TlsContext};
+use rand_core::{CryptoRng, RngCore};
+
+use super::http::HttpClient;
+use crate::rustot_log;
+
+const HTTPS_PORT: u16 = 443;
+
+/// Maximum size of the status line and headers of a response
+const MAX_RESPONSE_HEAD_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum HttpsError {
+    /// The URL is not a valid `https://` URL
+    Url,
+    /// Host name lookup failed
+    Dns,
+    /// Opening, reading from or writing to the TCP socket failed
+    Network,
+    /// TLS handshake or record layer failure
+    Tls,
+    /// Server responded with a status other than `206 Partial Content`
+    Status(u16),
+    /// Malformed response
+    Response,
+    /// Request or response does not fit the buffers
+    Overflow,
+}
+
+/// Blocking [`embedded_io`] adapter for a connected `embedded-nal` socket
+struct NalStream<'s, T: TcpClientStack> {
+    stack: &'s mut T,
+    socket: &'s mut T::TcpSocket,
+}
+
+#[derive(Debug)]
+struct NalError;
+
+impl embedded_io::Error for NalError {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        embedded_io::ErrorKind::Other
+    }
+}
+
+impl<'s, T: TcpClientStack> embedded_io::Io for NalStream<'s, T> {
+    type Error = NalError;
+}
+
+impl<'s, T: TcpClientStack> Read for NalStream<'s, T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        nb::block!(self.stack.receive(self.socket, buf)).map_err(|_| NalError)
+    }
+}
+
+impl<'s, T: TcpClientStack> Write for NalStream<'s, T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        nb::block!(self.stack.send(self.socket, buf)).map_err(|_| NalError)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Split an `https://` URL into host, port and path (including the query)
+fn split_url(url: &str) -> Result<(&str, u16, &str), HttpsError> {
+    let rest = url.strip_prefix("https://").ok_or(HttpsError::Url)?;
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => rest.split_at(idx),
+        None => (rest, "/"),
+    };
+
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Ok((host, port.parse().map_err(|_| HttpsError::Url)?, path)),
+        None if !authority.is_empty() => Ok((authority, HTTPS_PORT, path)),
+        None => Err(HttpsError::Url),
+    }
+}
+
+/// Write the head of a ranged `GET` request into `buf`, returning its length
+fn request_head(
+    buf: &mut [u8],
+    host: &str,
+    path: &str,
+    range: &RangeInclusive<usize>,
+) -> Result<usize, HttpsError> {
+    use core::fmt::Write;
+
+    let mut head = heapless::String::<MAX_RESPONSE_HEAD_LEN>::new();
+    write!(
+        head,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nRange: bytes={}-{}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        range.start(),
+        range.end()
+    )
+    .map_err(|_| HttpsError::Overflow)?;
+
+    buf.get_mut(..head.len())
+        .ok_or(HttpsError::Overflow)?
+        .copy_from_slice(head.as_bytes());
+    Ok(head.len())
+}
+
+/// Parse the status line and headers of a response, returning the length of
+/// the head and the `Content-Length` of the body. `None` if the head is
+/// incomplete.
+fn parse_head(buf: &[u8]) -> Result<Option<(usize, usize)>, HttpsError> {
+    let head_len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(idx) => idx + 4,
+        None => return Ok(None),
+    };
+
+    let head = core::str::from_utf8(&buf[..head_len]).map_err(|_| HttpsError::Response)?;
+    let mut lines = head.split("\r\n");
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or(HttpsError::Response)?;
+    if status != 206 {
+        return Err(HttpsError::Status(status));
+    }
+
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .ok_or(HttpsError::Response)?;
+
+    Ok(Some((head_len, content_length)))
+}
+
+struct Inner<'a, T, R> {
+    stack: &'a mut T,
+    rng: &'a mut R,
+    read_record: &'a mut [u8],
+    write_record: &'a mut [u8],
+    body: &'a mut [u8],
+    body_len: usize,
+}
+
+/// Blocking HTTPS client for the HTTP data interface
+pub struct HttpsClient<'a, T, R> {
+    inner: RefCell<Inner<'a, T, R>>,
+}
+
+impl<'a, T, R> HttpsClient<'a, T, R>
+where
+    T: TcpClientStack + Dns,
+    R: CryptoRng + RngCore,
+{
+    /// Create a new client, using `read_record` and `write_record` as TLS
+    /// record buffers, and receiving response bodies into `body`, which must
+    /// fit a full block.
+    pub fn new(
+        stack: &'a mut T,
+        rng: &'a mut R,
+        read_record: &'a mut [u8],
+        write_record: &'a mut [u8],
+        body: &'a mut [u8],
+    ) -> Self {
+        Self {
+            inner: RefCell::new(Inner {
+                stack,
+                rng,
+                read_record,
+                write_record,
+                body,
+                body_len: 0,
+            }),
+        }
+    }
+
+    /// Body of the last response, to be passed to the agent
+    pub fn response(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.inner.borrow(), |inner| &inner.body[..inner.body_len])
+    }
+}
+
+impl<'a, T, R> Inner<'a, T, R>
+where
+    T: TcpClientStack + Dns,
+    R: CryptoRng + RngCore,
+{
+    fn get_range(&mut self, url: &str, range: RangeInclusive<usize>) -> Result<(), HttpsError> {
+        let (host, port, path) = split_url(url)?;
+        self.body_len = 0;
+
+        let ip = nb::block!(self.stack.get_host_by_name(host, AddrType::Either))
+            .map_err(|_| HttpsError::Dns)?;
+
+        let mut socket = self.stack.socket().map_err(|_| HttpsError::Network)?;
+        let result = match nb::block!(self.stack.connect(&mut socket, SocketAddr::new(ip, port))) {
+            Ok(()) => self.exchange(&mut socket, host, path, &range),
+            Err(_) => Err(HttpsError::Network),
+        };
+        self.stack.close(socket).ok();
+
+        result
+    }
+
+    /// Perform the TLS handshake, send the request and receive the body over
+    /// the connected `socket`.
+    fn exchange(
+        &mut self,
+        socket: &mut T::TcpSocket,
+        host: &str,
+        path: &str,
+        range: &RangeInclusive<usize>,
+    ) -> Result<(), HttpsError> {
+        let stream = NalStream {
+            stack: &mut *self.stack,
+            socket,
+        };
+        let mut tls: TlsConnection<_, Aes128GcmSha256> =
+            TlsConnection::new(stream, &mut *self.read_record, &mut *self.write_record);
+
+        let config = TlsConfig::new().with_server_name(host);
+        tls.open::<_, NoVerify>(TlsContext::new(&config, &mut *self.rng))
+            .map_err(|_| HttpsError::Tls)?;
+
+        let mut head = [0u8; MAX_RESPONSE_HEAD_LEN];
+        let len = request_head(&mut head, host, path, range)?;
+        tls.write_all(&head[..len]).map_err(|_| HttpsError::Tls)?;
+        tls.flush().map_err(|_| HttpsError::Tls)?;
+
+        // Read until the end of the response head. Any part of the body read
+        // along with it is moved to the body buffer.
+        let mut received = 0;
+        let (head_len, content_length) = loop {
+            let n = tls
+                .read(head.get_mut(received..).ok_or(HttpsError::Overflow)?)
+                .map_err(|_| HttpsError::Tls)?;
+            if n == 0 {
+                return Err(HttpsError::Response);
+            }
+            received += n;
+
+            if let Some(parsed) = parse_head(&head[..received])? {
+                break parsed;
+            }
+        };
+
+        let body = self
+            .body
+            .get_mut(..content_length)
+            .ok_or(HttpsError::Overflow)?;
+        let prefix = core::cmp::min(received - head_len, content_length);
+        body[..prefix].copy_from_slice(&head[head_len..head_len + prefix]);
+        tls.read_exact(&mut body[prefix..])
+            .map_err(|_| HttpsError::Response)?;
+
+        tls.close().ok();
+        self.body_len = content_length;
+        Ok(())
+    }
+}
+
+impl<'a, T, R> HttpClient for HttpsClient<'a, T, R>
+where
+    T: TcpClientStack + Dns,
+    R: CryptoRng + RngCore,
+{
+    type Error = HttpsError;
+
+    fn get_range(&self, url: &str, range: RangeInclusive<usize>) -> Result<(), Self::Error> {
+        let result = self.inner.borrow_mut().get_range(url, range);
+        if let Err(e) = result {
+            rustot_log!(error, "HTTPS request failed: {:?}", e);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_urls() {
+        assert_eq!(
+            split_url("https://bucket.s3.amazonaws.com/image.bin?X-Amz-Signature=abc"),
+            Ok((
+                "bucket.s3.amazonaws.com",
+                443,
+                "/image.bin?X-Amz-Signature=abc"
+            ))
+        );
+        assert_eq!(
+            split_url("https://localhost:8443"),
+            Ok(("localhost", 8443, "/"))
+        );
+        assert_eq!(split_url("http://localhost/image"), Err(HttpsError::Url));
+        assert_eq!(split_url("https://host:port/"), Err(HttpsError::Url));
+    }
+
+    #[test]
+    fn request() {
+        let buf = &mut [0u8; 128];
+        let len = request_head(buf, "host", "/image?sig=abc", &(256..=511)).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &b"GET /image?sig=abc HTTP/1.1\r\nHost: host\r\nRange: bytes=256-511\r\nConnection: close\r\n\r\n"[..]
+        );
+
+        assert_eq!(
+            request_head(&mut [0u8; 16], "host", "/", &(0..=1)),
+            Err(HttpsError::Overflow)
+        );
+    }
+
+    #[test]
+    fn response_head() {
+        let response = b"HTTP/1.1 206 Partial Content\r\ncontent-length: 4\r\nContent-Range: bytes 0-3/10\r\n\r\nbody";
+        assert_eq!(parse_head(&response[..40]), Ok(None));
+        assert_eq!(parse_head(response), Ok(Some((response.len() - 4, 4))));
+
+        assert_eq!(
+            parse_head(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n"),
+            Err(HttpsError::Status(403))
+        );
+        assert_eq!(
+            parse_head(b"HTTP/1.1 206 Partial Content\r\n\r\n"),
+            Err(HttpsError::Response)
+        );
+    }
+}
@@ -1,5 +1,8 @@
+pub mod compression;
 #[cfg(feature = "ota_http_data")]
 pub mod http;
+#[cfg(feature = "ota_https_data")]
+pub mod https;
 #[cfg(feature = "ota_mqtt_data")]
 pub mod mqtt;
 
@@ -15,6 +18,9 @@ pub enum Protocol {
     Mqtt,
     #[serde(rename = "HTTP")]
     Http,
+    /// Protocol not known to this version of the agent, never selected
+    #[serde(other)]
+    Unknown,
 }
 
 #[derive(Debug)]
@@ -44,7 +50,11 @@ impl<'a> FileBlock<'a> {
 pub trait DataInterface {
     const PROTOCOL: Protocol;
 
-    fn init_file_transfer(&self, file_ctx: &mut FileContext) -> Result<(), OtaError>;
+    fn init_file_transfer(
+        &self,
+        file_ctx: &mut FileContext,
+        config: &Config,
+    ) -> Result<(), OtaError>;
     fn request_file_block(
         &self,
         file_ctx: &mut FileContext,
@@ -53,7 +63,7 @@ pub trait DataInterface {
     fn decode_file_block<'a>(
         &self,
         file_ctx: &mut FileContext,
-        payload: &'a mut [u8],
+        payload: &'a [u8],
     ) -> Result<FileBlock<'a>, OtaError>;
     fn cleanup(&self, file_ctx: &mut FileContext, config: &Config) -> Result<(), OtaError>;
 }
@@ -63,7 +73,11 @@ pub struct NoInterface;
 impl DataInterface for NoInterface {
     const PROTOCOL: Protocol = Protocol::Mqtt;
 
-    fn init_file_transfer(&self, _file_ctx: &mut FileContext) -> Result<(), OtaError> {
+    fn init_file_transfer(
+        &self,
+        _file_ctx: &mut FileContext,
+        _config: &Config,
+    ) -> Result<(), OtaError> {
         unreachable!()
     }
 
@@ -78,7 +92,7 @@ impl DataInterface for NoInterface {
     fn decode_file_block<'a>(
         &self,
         _file_ctx: &mut FileContext,
-        _payload: &'a mut [u8],
+        _payload: &'a [u8],
     ) -> Result<FileBlock<'a>, OtaError> {
         unreachable!()
     }
@@ -49,22 +49,43 @@ pub enum Topic<'a> {
 
 impl<'a> Topic<'a> {
     pub fn from_str(s: &'a str) -> Option<Self> {
-        let tt = s.splitn(8, '/').collect::<heapless::Vec<&str, 8>>();
+        let tt = s.splitn(5, '/').collect::<heapless::Vec<&str, 5>>();
+        match (tt.get(0), tt.get(1), tt.get(2), tt.get(3), tt.get(4)) {
+            (Some(&"$aws"), Some(&"things"), Some(_), Some(&"streams"), Some(stream)) => {
+                Self::from_stream(stream)
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse a stream topic below a custom topic prefix, as set through
+    /// [`OtaAgentBuilder::stream_topic_prefix`]. Topics below the default
+    /// prefix are still recognized.
+    ///
+    /// [`OtaAgentBuilder::stream_topic_prefix`]: crate::ota::builder::OtaAgentBuilder::stream_topic_prefix
+    pub fn from_str_with_prefix(s: &'a str, prefix: &str) -> Option<Self> {
+        match s
+            .strip_prefix(prefix)
+            .and_then(|s| s.strip_prefix("/streams/"))
+        {
+            Some(stream) => Self::from_stream(stream),
+            None => Self::from_str(s),
+        }
+    }
+
+    /// Parse the part of a stream topic following `/streams/`
+    fn from_stream(s: &'a str) -> Option<Self> {
+        let tt = s.splitn(4, '/').collect::<heapless::Vec<&str, 4>>();
+        // This is a stream topic! Figure out which
         Some(match (tt.get(0), tt.get(1), tt.get(2), tt.get(3)) {
-            (Some(&"$aws"), Some(&"things"), _, Some(&"streams")) => {
-                // This is a stream topic! Figure out which
-                match (tt.get(4), tt.get(5), tt.get(6), tt.get(7)) {
-                    (Some(stream_name), Some(&"data"), Some(encoding), None) => {
-                        Topic::Data(Encoding::from_str(encoding).ok()?, stream_name)
-                    }
-                    (Some(stream_name), Some(&"description"), Some(encoding), None) => {
-                        Topic::Description(Encoding::from_str(encoding).ok()?, stream_name)
-                    }
-                    (Some(stream_name), Some(&"rejected"), Some(encoding), None) => {
-                        Topic::Rejected(Encoding::from_str(encoding).ok()?, stream_name)
-                    }
-                    _ => return None,
-                }
+            (Some(stream_name), Some(&"data"), Some(encoding), None) => {
+                Topic::Data(Encoding::from_str(encoding).ok()?, stream_name)
+            }
+            (Some(stream_name), Some(&"description"), Some(encoding), None) => {
+                Topic::Description(Encoding::from_str(encoding).ok()?, stream_name)
+            }
+            (Some(stream_name), Some(&"rejected"), Some(encoding), None) => {
+                Topic::Rejected(Encoding::from_str(encoding).ok()?, stream_name)
             }
             _ => return None,
         })
@@ -87,31 +108,38 @@ enum OtaTopic<'a> {
     Rejected(Encoding, &'a str),
 
     Get(Encoding, &'a str),
+    Describe(Encoding, &'a str),
 }
 
 impl<'a> OtaTopic<'a> {
-    pub fn format<const L: usize>(&self, client_id: &str) -> Result<heapless::String<L>, OtaError> {
+    /// Format the topic below the stream topic prefix of `config`, defaulting
+    /// to `$aws/things/<client_id>`
+    pub fn format<const L: usize>(
+        &self,
+        config: &Config,
+        client_id: &str,
+    ) -> Result<heapless::String<L>, OtaError> {
         let mut topic_path = heapless::String::new();
-        match self {
-            Self::Data(encoding, stream_name) => topic_path.write_fmt(format_args!(
-                "$aws/things/{}/streams/{}/data/{}",
-                client_id, stream_name, encoding
-            )),
-            Self::Description(encoding, stream_name) => topic_path.write_fmt(format_args!(
-                "$aws/things/{}/streams/{}/description/{}",
-                client_id, stream_name, encoding
-            )),
-            Self::Rejected(encoding, stream_name) => topic_path.write_fmt(format_args!(
-                "$aws/things/{}/streams/{}/rejected/{}",
-                client_id, stream_name, encoding
-            )),
-            Self::Get(encoding, stream_name) => topic_path.write_fmt(format_args!(
-                "$aws/things/{}/streams/{}/get/{}",
-                client_id, stream_name, encoding
-            )),
+        match config.stream_topic_prefix {
+            Some(prefix) => topic_path.write_str(prefix),
+            None => topic_path.write_fmt(format_args!("$aws/things/{}", client_id)),
         }
         .map_err(|_| OtaError::Overflow)?;
 
+        let (stream_name, operation, encoding) = match self {
+            Self::Data(encoding, stream_name) => (stream_name, "data", encoding),
+            Self::Description(encoding, stream_name) => (stream_name, "description", encoding),
+            Self::Rejected(encoding, stream_name) => (stream_name, "rejected", encoding),
+            Self::Get(encoding, stream_name) => (stream_name, "get", encoding),
+            Self::Describe(encoding, stream_name) => (stream_name, "describe", encoding),
+        };
+        topic_path
+            .write_fmt(format_args!(
+                "/streams/{}/{}/{}",
+                stream_name, operation, encoding
+            ))
+            .map_err(|_| OtaError::Overflow)?;
+
         Ok(topic_path)
     }
 }
@@ -123,9 +151,17 @@ where
     const PROTOCOL: Protocol = Protocol::Mqtt;
 
     /// Init file transfer by subscribing to the OTA data stream topic
-    fn init_file_transfer(&self, file_ctx: &mut FileContext) -> Result<(), OtaError> {
+    fn init_file_transfer(
+        &self,
+        file_ctx: &mut FileContext,
+        config: &Config,
+    ) -> Result<(), OtaError> {
+        if file_ctx.stream_name.is_empty() {
+            return Err(OtaError::InvalidStreamName);
+        }
+
         let topic_path = OtaTopic::Data(Encoding::Cbor, file_ctx.stream_name.as_str())
-            .format::<256>(self.client_id())?;
+            .format::<256>(config, self.client_id())?;
         let topic = SubscribeTopic {
             topic_path: topic_path.as_str(),
             qos: mqttrust::QoS::AtLeastOnce,
@@ -155,7 +191,7 @@ where
                 stream_version: None,
                 file_id: file_ctx.fileid,
                 block_size: config.block_size,
-                block_offset: Some(file_ctx.block_offset),
+                block_offset: file_ctx.block_offset,
                 block_bitmap: Some(&file_ctx.bitmap),
                 number_of_blocks: None,
             },
@@ -165,7 +201,10 @@ where
 
         self.publish(
             OtaTopic::Get(Encoding::Cbor, file_ctx.stream_name.as_str())
-                .format::<{ MAX_STREAM_ID_LEN + MAX_THING_NAME_LEN + 30 }>(self.client_id())?
+                .format::<{ MAX_STREAM_ID_LEN + MAX_THING_NAME_LEN + 30 }>(
+                    config,
+                    self.client_id(),
+                )?
                 .as_str(),
             &buf[..len],
             QoS::AtMostOnce,
@@ -174,17 +213,14 @@ where
         Ok(())
     }
 
-    /// Decode a cbor encoded fileblock received from streaming service
+    /// Decode a cbor encoded fileblock received from streaming service, in
+    /// place
     fn decode_file_block<'c>(
         &self,
         _file_ctx: &mut FileContext,
-        payload: &'c mut [u8],
+        payload: &'c [u8],
     ) -> Result<FileBlock<'c>, OtaError> {
-        Ok(
-            serde_cbor::de::from_mut_slice::<cbor::GetStreamResponse>(payload)
-                .map_err(|_| OtaError::Encoding)?
-                .into(),
-        )
+        Ok(cbor::decode_get_stream_response(payload)?.into())
     }
 
     /// Perform any cleanup operations required for data plane
@@ -193,7 +229,7 @@ where
             // Unsubscribe from data stream topics
             self.unsubscribe(&[
                 OtaTopic::Data(Encoding::Cbor, file_ctx.stream_name.as_str())
-                    .format::<256>(self.client_id())?
+                    .format::<256>(config, self.client_id())?
                     .as_str(),
             ])?;
         }
@@ -201,6 +237,61 @@ where
     }
 }
 
+/// Subscribe to the description and rejected topics of the stream
+/// `stream_name`, and request its description by publishing to the describe
+/// topic.
+pub(crate) fn describe_stream<M: Mqtt>(
+    mqtt: &M,
+    stream_name: &str,
+    config: &Config,
+) -> Result<(), OtaError> {
+    let description = OtaTopic::Description(Encoding::Cbor, stream_name)
+        .format::<256>(config, mqtt.client_id())?;
+    let rejected =
+        OtaTopic::Rejected(Encoding::Cbor, stream_name).format::<256>(config, mqtt.client_id())?;
+    mqtt.subscribe(&[
+        SubscribeTopic {
+            topic_path: description.as_str(),
+            qos: QoS::AtLeastOnce,
+        },
+        SubscribeTopic {
+            topic_path: rejected.as_str(),
+            qos: QoS::AtLeastOnce,
+        },
+    ])?;
+
+    let buf = &mut [0u8; 8];
+    let len = cbor::to_slice(&cbor::DescribeStreamRequest { client_token: None }, buf)
+        .map_err(|_| OtaError::Encoding)?;
+
+    mqtt.publish(
+        OtaTopic::Describe(Encoding::Cbor, stream_name)
+            .format::<256>(config, mqtt.client_id())?
+            .as_str(),
+        &buf[..len],
+        QoS::AtMostOnce,
+    )?;
+
+    Ok(())
+}
+
+/// Unsubscribe from the topics subscribed to by [`describe_stream`]
+pub(crate) fn end_describe_stream<M: Mqtt>(
+    mqtt: &M,
+    stream_name: &str,
+    config: &Config,
+) -> Result<(), OtaError> {
+    mqtt.unsubscribe(&[
+        OtaTopic::Description(Encoding::Cbor, stream_name)
+            .format::<256>(config, mqtt.client_id())?
+            .as_str(),
+        OtaTopic::Rejected(Encoding::Cbor, stream_name)
+            .format::<256>(config, mqtt.client_id())?
+            .as_str(),
+    ])?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use mqttrust::{encoding::v4::decode_slice, Packet, SubscribeTopic};
@@ -219,7 +310,8 @@ mod tests {
 
         let mut file_ctx = test_file_ctx(&Config::default());
 
-        mqtt.init_file_transfer(&mut file_ctx).unwrap();
+        mqtt.init_file_transfer(&mut file_ctx, &Config::default())
+            .unwrap();
 
         assert_eq!(mqtt.tx.borrow_mut().len(), 1);
 
@@ -239,6 +331,72 @@ mod tests {
         );
     }
 
+    #[test]
+    fn stream_topic_prefix() {
+        let mqtt = &MockMqtt::new();
+
+        let config = Config {
+            stream_topic_prefix: Some("staging/device-1"),
+            ..Config::default()
+        };
+        let mut file_ctx = test_file_ctx(&config);
+
+        mqtt.init_file_transfer(&mut file_ctx, &config).unwrap();
+        mqtt.request_file_block(&mut file_ctx, &config).unwrap();
+
+        let topics: Vec<String> = mqtt
+            .tx
+            .borrow_mut()
+            .drain(..)
+            .map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Subscribe(ref s)) => s.topics().next().unwrap().topic_path.to_owned(),
+                Some(Packet::Publish(p)) => p.topic_name.to_owned(),
+                _ => panic!(),
+            })
+            .collect();
+        assert_eq!(
+            topics,
+            vec![
+                "staging/device-1/streams/test_stream/data/cbor",
+                "staging/device-1/streams/test_stream/get/cbor"
+            ]
+        );
+
+        assert!(matches!(
+            Topic::from_str_with_prefix(
+                "staging/device-1/streams/test_stream/data/cbor",
+                "staging/device-1"
+            ),
+            Some(Topic::Data(Encoding::Cbor, "test_stream"))
+        ));
+        assert!(matches!(
+            Topic::from_str_with_prefix(
+                "$aws/things/test_client/streams/test_stream/rejected/json",
+                "staging/device-1"
+            ),
+            Some(Topic::Rejected(Encoding::Json, "test_stream"))
+        ));
+        assert!(Topic::from_str_with_prefix(
+            "staging/device-2/streams/test_stream/data/cbor",
+            "staging/device-1"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn init_file_transfer_without_stream() {
+        let mqtt = &MockMqtt::new();
+
+        let mut file_ctx = test_file_ctx(&Config::default());
+        file_ctx.stream_name.clear();
+
+        assert_eq!(
+            mqtt.init_file_transfer(&mut file_ctx, &Config::default()),
+            Err(OtaError::InvalidStreamName)
+        );
+        assert_eq!(mqtt.tx.borrow_mut().len(), 0);
+    }
+
     #[test]
     fn request_file_block_publish() {
         let mqtt = &MockMqtt::new();
@@ -264,9 +422,7 @@ mod tests {
                 qos: QoS::AtMostOnce,
                 retain: false,
                 topic_name: "$aws/things/test_client/streams/test_stream/get/cbor",
-                payload: &[
-                    164, 97, 102, 0, 97, 108, 25, 1, 0, 97, 111, 0, 97, 98, 68, 255, 255, 255, 127
-                ],
+                payload: &[163, 97, 102, 0, 97, 108, 25, 1, 0, 97, 98, 68, 255, 255, 255, 127],
                 pid: None
             }
         );
@@ -278,7 +434,7 @@ mod tests {
 
         let mut file_ctx = test_file_ctx(&Config::default());
 
-        let payload = &mut [
+        let payload = &[
             191, 97, 102, 0, 97, 105, 0, 97, 108, 25, 4, 0, 97, 112, 89, 4, 0, 141, 62, 28, 246,
             80, 193, 2, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
@@ -1,6 +1,8 @@
+use core::convert::TryFrom;
+
 use serde::{Deserialize, Serialize};
 
-use crate::ota::data_interface::FileBlock;
+use crate::ota::{data_interface::FileBlock, error::OtaError};
 
 use super::Bitmap;
 
@@ -40,8 +42,10 @@ pub struct GetStreamRequest<'a> {
     pub file_id: u8,
     #[serde(rename = "l")]
     pub block_size: usize,
-    #[serde(rename = "o", skip_serializing_if = "Option::is_none")]
-    pub block_offset: Option<u32>,
+    /// Block to start from. The streaming service defaults to the first
+    /// block, so offset 0 is left out of the request.
+    #[serde(rename = "o", skip_serializing_if = "is_zero")]
+    pub block_offset: u32,
     #[serde(rename = "b", skip_serializing_if = "Option::is_none")]
     pub block_bitmap: Option<&'a Bitmap>,
     #[serde(rename = "n", skip_serializing_if = "Option::is_none")]
@@ -72,6 +76,12 @@ pub struct StreamError<'a> {
     pub client_token: Option<&'a str>,
 }
 
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+/// Serialize `value` into `slice`, returning the number of bytes written.
+/// Integers are encoded in the smallest width that holds their value.
 pub fn to_slice<T>(value: &T, slice: &mut [u8]) -> Result<usize, ()>
 where
     T: serde::ser::Serialize,
@@ -81,6 +91,204 @@ where
     Ok(serializer.into_inner().bytes_written())
 }
 
+/// Minimal CBOR reader, walking an encoded item in place.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) const BREAK: u8 = 0xFF;
+
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub(crate) fn peek(&self) -> Result<u8, OtaError> {
+        self.buf.get(self.pos).copied().ok_or(OtaError::Encoding)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], OtaError> {
+        let end = self.pos.checked_add(len).ok_or(OtaError::Encoding)?;
+        let bytes = self.buf.get(self.pos..end).ok_or(OtaError::Encoding)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Read the header of the next item, returning its major type and
+    /// argument. The argument is `None` for indefinite length items.
+    pub(crate) fn header(&mut self) -> Result<(u8, Option<u64>), OtaError> {
+        let initial = self.take(1)?[0];
+        let major = initial >> 5;
+
+        let arg = match initial & 0x1F {
+            info @ 0..=23 => Some(info as u64),
+            24 => Some(self.take(1)?[0] as u64),
+            25 => Some(self.take(2)?.iter().fold(0, |acc, b| acc << 8 | *b as u64)),
+            26 => Some(self.take(4)?.iter().fold(0, |acc, b| acc << 8 | *b as u64)),
+            27 => Some(self.take(8)?.iter().fold(0, |acc, b| acc << 8 | *b as u64)),
+            31 => None,
+            _ => return Err(OtaError::Encoding),
+        };
+
+        Ok((major, arg))
+    }
+
+    /// Read a definite length string of major type `major`
+    fn string(&mut self, major: u8) -> Result<&'a [u8], OtaError> {
+        match self.header()? {
+            (m, Some(len)) if m == major => {
+                self.take(usize::try_from(len).map_err(|_| OtaError::Encoding)?)
+            }
+            _ => Err(OtaError::Encoding),
+        }
+    }
+
+    pub(crate) fn uint(&mut self) -> Result<u64, OtaError> {
+        match self.header()? {
+            (0, Some(value)) => Ok(value),
+            _ => Err(OtaError::Encoding),
+        }
+    }
+
+    pub(crate) fn text(&mut self) -> Result<&'a str, OtaError> {
+        core::str::from_utf8(self.string(3)?).map_err(|_| OtaError::Encoding)
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], OtaError> {
+        self.string(2)
+    }
+
+    /// Whether another item of a map or array follows, given the `remaining`
+    /// number of items from its header. Consumes the break marker closing an
+    /// indefinite length map or array.
+    pub(crate) fn more(&mut self, remaining: &mut Option<u64>) -> Result<bool, OtaError> {
+        match remaining {
+            Some(0) => Ok(false),
+            Some(ref mut len) => {
+                *len -= 1;
+                Ok(true)
+            }
+            None if self.peek()? == Self::BREAK => self.take(1).map(|_| false),
+            None => Ok(true),
+        }
+    }
+
+    /// Skip over a scalar item, or a definite length string
+    pub(crate) fn skip(&mut self) -> Result<(), OtaError> {
+        match self.header()? {
+            (0 | 1 | 7, Some(_)) => Ok(()),
+            (2 | 3, Some(len)) => self
+                .take(usize::try_from(len).map_err(|_| OtaError::Encoding)?)
+                .map(drop),
+            _ => Err(OtaError::Encoding),
+        }
+    }
+}
+
+/// Decode a `GetStream` response in place.
+///
+/// Unlike deserializing through `serde_cbor`, no intermediate state is kept
+/// besides the position in `payload`, and the block payload is returned as a
+/// slice borrowed from `payload`. This way the only copy of the block in RAM
+/// is the received message itself.
+pub fn decode_get_stream_response(payload: &[u8]) -> Result<GetStreamResponse<'_>, OtaError> {
+    let mut reader = Reader::new(payload);
+
+    let mut remaining = match reader.header()? {
+        (5, len) => len,
+        _ => return Err(OtaError::Encoding),
+    };
+
+    let mut client_token = None;
+    let mut file_id = None;
+    let mut block_size = None;
+    let mut block_id = None;
+    let mut block_payload = None;
+
+    loop {
+        match remaining {
+            Some(0) => break,
+            Some(ref mut len) => *len -= 1,
+            None if reader.peek()? == Reader::BREAK => break,
+            None => {}
+        }
+
+        match reader.text()? {
+            "c" => client_token = Some(reader.text()?),
+            "f" => file_id = Some(reader.uint()?),
+            "l" => block_size = Some(reader.uint()?),
+            "i" => block_id = Some(reader.uint()?),
+            "p" => block_payload = Some(reader.bytes()?),
+            _ => reader.skip()?,
+        }
+    }
+
+    let field = |v: Option<u64>| {
+        v.and_then(|v| usize::try_from(v).ok())
+            .ok_or(OtaError::Encoding)
+    };
+
+    Ok(GetStreamResponse {
+        client_token,
+        file_id: u8::try_from(field(file_id)?).map_err(|_| OtaError::Encoding)?,
+        block_size: field(block_size)?,
+        block_id: field(block_id)?,
+        block_payload: block_payload.ok_or(OtaError::Encoding)?,
+    })
+}
+
+/// Decode a `DescribeStream` response, returning the size of file `file_id`,
+/// or `None` if the stream does not contain the file.
+pub fn decode_describe_stream_response(
+    payload: &[u8],
+    file_id: u8,
+) -> Result<Option<usize>, OtaError> {
+    let mut reader = Reader::new(payload);
+
+    let mut remaining = match reader.header()? {
+        (5, len) => len,
+        _ => return Err(OtaError::Encoding),
+    };
+
+    let mut file_size = None;
+    while reader.more(&mut remaining)? {
+        if reader.text()? != "r" {
+            reader.skip()?;
+            continue;
+        }
+
+        let mut files = match reader.header()? {
+            (4, len) => len,
+            _ => return Err(OtaError::Encoding),
+        };
+        while reader.more(&mut files)? {
+            let mut fields = match reader.header()? {
+                (5, len) => len,
+                _ => return Err(OtaError::Encoding),
+            };
+
+            let mut id = None;
+            let mut size = None;
+            while reader.more(&mut fields)? {
+                match reader.text()? {
+                    "f" => id = Some(reader.uint()?),
+                    "z" => size = Some(reader.uint()?),
+                    _ => reader.skip()?,
+                }
+            }
+
+            if id == Some(file_id as u64) {
+                file_size = size
+                    .map(|size| usize::try_from(size).map_err(|_| OtaError::Encoding))
+                    .transpose()?;
+            }
+        }
+    }
+
+    Ok(file_size)
+}
+
 impl<'a> From<GetStreamResponse<'a>> for FileBlock<'a> {
     fn from(v: GetStreamResponse<'a>) -> Self {
         Self {
@@ -125,7 +333,8 @@ mod test {
 
         let buf: &mut [u8] = &mut [0u8; 1024];
         let len = to_slice(&bitmap, buf).unwrap();
-        assert_eq!(&buf[..len], &[0x44, 0x13, 0x00, 0x80, 0x00]);
+        // Trailing zero bytes are left out
+        assert_eq!(&buf[..len], &[0x43, 0x13, 0x00, 0x80]);
     }
 
     #[test]
@@ -224,6 +433,62 @@ mod test {
             }
         );
     }
+    #[test]
+    fn decode_stream_response_in_place() {
+        // {"c": "rdy", "f": 1, "i": 2, "l": 4, "p": h'01020304', "x": 7}
+        let payload = &[
+            0xA6, 0x61, 0x63, 0x63, 0x72, 0x64, 0x79, 0x61, 0x66, 0x01, 0x61, 0x69, 0x02, 0x61,
+            0x6C, 0x04, 0x61, 0x70, 0x44, 0x01, 0x02, 0x03, 0x04, 0x61, 0x78, 0x07,
+        ];
+
+        let response = decode_get_stream_response(payload).unwrap();
+        assert_eq!(
+            response,
+            GetStreamResponse {
+                client_token: Some("rdy"),
+                file_id: 1,
+                block_size: 4,
+                block_id: 2,
+                block_payload: &[1, 2, 3, 4],
+            }
+        );
+
+        // The payload is borrowed, not copied
+        assert_eq!(response.block_payload.as_ptr(), payload[19..].as_ptr());
+    }
+
+    #[test]
+    fn decode_stream_response_indefinite_map() {
+        // {_ "f": 0, "i": 300, "l": 2, "p": h'AABB'}
+        let payload = &[
+            0xBF, 0x61, 0x66, 0x00, 0x61, 0x69, 0x19, 0x01, 0x2C, 0x61, 0x6C, 0x02, 0x61, 0x70,
+            0x42, 0xAA, 0xBB, 0xFF,
+        ];
+
+        let response = decode_get_stream_response(payload).unwrap();
+        assert_eq!(response.block_id, 300);
+        assert_eq!(response.block_payload, &[0xAA, 0xBB]);
+        assert_eq!(response.client_token, None);
+    }
+
+    #[test]
+    fn decode_stream_response_malformed() {
+        // Truncated payload
+        assert_eq!(
+            decode_get_stream_response(&[0xA1, 0x61, 0x70, 0x44, 0x01]),
+            Err(OtaError::Encoding)
+        );
+
+        // Missing block payload
+        assert_eq!(
+            decode_get_stream_response(&[0xA2, 0x61, 0x66, 0x00, 0x61, 0x69, 0x00]),
+            Err(OtaError::Encoding)
+        );
+
+        // Not a map
+        assert_eq!(decode_get_stream_response(&[0x80]), Err(OtaError::Encoding));
+    }
+
     #[test]
     fn serialize_stream_request() {
         let file_size = 181584;
@@ -242,7 +507,7 @@ mod test {
                 stream_version: None,
                 file_id: 0,
                 block_size: BLOCK_SIZE,
-                block_offset: Some(block_offset),
+                block_offset,
                 number_of_blocks: None,
                 block_bitmap: Some(&bitmap),
             };
@@ -250,8 +515,8 @@ mod test {
             let len = to_slice(&req, buf).unwrap();
 
             let expectation = [
-                165, 97, 99, 99, 114, 100, 121, 97, 102, 0, 97, 108, 25, 8, 0, 97, 111, 0, 97, 98,
-                68, 255, 255, 255, 127,
+                164, 97, 99, 99, 114, 100, 121, 97, 102, 0, 97, 108, 25, 8, 0, 97, 98, 68, 255,
+                255, 255, 127,
             ];
             assert_eq!(len, expectation.len(), "Arrays don't have the same length");
             assert_eq!(&buf[..len], &expectation);
@@ -268,7 +533,7 @@ mod test {
                 stream_version: None,
                 file_id: 0,
                 block_size: BLOCK_SIZE,
-                block_offset: Some(block_offset as u32),
+                block_offset: block_offset as u32,
                 number_of_blocks: None,
                 block_bitmap: Some(&bitmap),
             };
@@ -279,7 +544,7 @@ mod test {
                 &buf[..len],
                 &[
                     165, 97, 99, 99, 114, 100, 121, 97, 102, 0, 97, 108, 25, 8, 0, 97, 111, 24, 88,
-                    97, 98, 68, 1, 0, 0, 0
+                    97, 98, 65, 1
                 ]
             );
         }
@@ -303,7 +568,7 @@ mod test {
                 stream_version: None,
                 file_id: 0,
                 block_size: BLOCK_SIZE,
-                block_offset: Some(block_offset as u32),
+                block_offset: block_offset as u32,
                 number_of_blocks: None,
                 block_bitmap: Some(&bitmap),
             };
@@ -313,8 +578,8 @@ mod test {
             assert_eq!(
                 &buf[..len],
                 &[
-                    165, 97, 99, 99, 114, 100, 121, 97, 102, 0, 97, 108, 25, 2, 0, 97, 111, 0, 97,
-                    98, 68, 255, 255, 255, 127
+                    164, 97, 99, 99, 114, 100, 121, 97, 102, 0, 97, 108, 25, 2, 0, 97, 98, 68, 255,
+                    255, 255, 127
                 ]
             );
         }
@@ -330,7 +595,7 @@ mod test {
                 stream_version: None,
                 file_id: 0,
                 block_size: BLOCK_SIZE,
-                block_offset: Some(block_offset as u32),
+                block_offset: block_offset as u32,
                 number_of_blocks: None,
                 block_bitmap: Some(&bitmap),
             };
@@ -341,9 +606,48 @@ mod test {
                 &buf[..len],
                 &[
                     165, 97, 99, 99, 114, 100, 121, 97, 102, 0, 97, 108, 25, 2, 0, 97, 111, 25, 1,
-                    96, 97, 98, 68, 7, 0, 0, 0
+                    96, 97, 98, 65, 7
                 ]
             );
         }
     }
+
+    #[test]
+    fn decode_describe_stream() {
+        let files = [
+            StreamFile {
+                file_id: 0,
+                file_size: 123456,
+            },
+            StreamFile {
+                file_id: 3,
+                file_size: 42,
+            },
+        ];
+        let buf = &mut [0u8; 64];
+        let len = to_slice(
+            &DescribeStreamResponse {
+                client_token: Some("token"),
+                stream_version: 1,
+                description: "firmware",
+                files: &files,
+            },
+            buf,
+        )
+        .unwrap();
+
+        assert_eq!(
+            decode_describe_stream_response(&buf[..len], 0),
+            Ok(Some(123456))
+        );
+        assert_eq!(
+            decode_describe_stream_response(&buf[..len], 3),
+            Ok(Some(42))
+        );
+        assert_eq!(decode_describe_stream_response(&buf[..len], 1), Ok(None));
+        assert_eq!(
+            decode_describe_stream_response(&buf[..len - 1], 3),
+            Err(OtaError::Encoding)
+        );
+    }
 }
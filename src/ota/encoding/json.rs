@@ -1,26 +1,94 @@
-use crate::ota::data_interface::Protocol;
+use crate::ota::data_interface::{compression::Compression, Protocol};
+use crate::ota::error::OtaError;
+use core::fmt::Write;
 use core::str::FromStr;
-use serde::Deserialize;
+use serde::de::{Error, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
 
-/// OTA job document, compatible with FreeRTOS OTA process
+/// Maximum number of files in a single OTA job
+pub const MAX_FILES: usize = 4;
+
+/// Maximum number of protocols listed by a single OTA job, including any
+/// protocols unknown to the agent
+pub const MAX_PROTOCOLS: usize = 4;
+
+/// Maximum length of a base64 encoded file signature. This fits DER encoded
+/// ECDSA P-256 signatures.
+pub const MAX_SIGNATURE_LEN: usize = 96;
+
+/// Maximum number of custom attributes of a single file
+pub const MAX_FILE_ATTRIBUTES: usize = 4;
+
+/// Maximum length of the key of a custom file attribute
+pub const MAX_ATTRIBUTE_KEY_LEN: usize = 16;
+
+/// Maximum length of the raw value of a custom file attribute
+pub const MAX_ATTRIBUTE_VALUE_LEN: usize = 32;
+
+/// OTA job document, compatible with FreeRTOS OTA process.
+///
+/// Fields unknown to the agent are skipped, both here and in the file
+/// descriptions, such that documents of newer job services, or carrying
+/// application specific metadata, are still accepted. The metadata itself is
+/// available through [`OtaAgent::job_document`].
+///
+/// Jobs deployed by custom pipelines rather than AWS IoT OTA may leave out
+/// the protocols and stream name altogether, and only give an
+/// `update_data_url` for each file, e.g. pointing at a CDN. See
+/// [`Self::data_protocols`].
+///
+/// [`OtaAgent::job_document`]: crate::ota::agent::OtaAgent::job_document
 #[derive(Debug, PartialEq, Deserialize)]
 #[serde(rename = "afr_ota")]
 pub struct OtaJob<'a> {
-    pub protocols: heapless::Vec<Protocol, 2>,
+    #[serde(default)]
+    pub protocols: heapless::Vec<Protocol, MAX_PROTOCOLS>,
+    #[serde(default)]
     pub streamname: &'a str,
-    pub files: heapless::Vec<FileDescription<'a>, 1>,
+    pub files: heapless::Vec<FileDescription<'a>, MAX_FILES>,
+}
+
+impl<'a> OtaJob<'a> {
+    /// Protocols to transfer the files of the job with, in order of
+    /// preference. Jobs listing no protocols are transferred over HTTP, if
+    /// all of their files have an `update_data_url`.
+    pub fn data_protocols(&self) -> &[Protocol] {
+        if self.protocols.is_empty()
+            && !self.files.is_empty()
+            && self.files.iter().all(|f| f.update_data_url.is_some())
+        {
+            return &[Protocol::Http];
+        }
+        &self.protocols
+    }
+}
+
+/// Any job document, that might or might not contain an OTA job
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct JobDocument<'a> {
+    #[serde(rename = "afr_ota")]
+    #[serde(borrow)]
+    pub ota: Option<OtaJob<'a>>,
+}
+
+impl<'a> JobDocument<'a> {
+    pub fn from_slice(job_document: &'a [u8]) -> Result<Self, OtaError> {
+        serde_json_core::from_slice(job_document)
+            .map(|(document, _)| document)
+            .map_err(|_| OtaError::Encoding)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 pub enum Signature {
     #[serde(rename = "sig-sha1-rsa")]
-    Sha1Rsa(heapless::String<64>),
+    Sha1Rsa(heapless::String<MAX_SIGNATURE_LEN>),
     #[serde(rename = "sig-sha256-rsa")]
-    Sha256Rsa(heapless::String<64>),
+    Sha256Rsa(heapless::String<MAX_SIGNATURE_LEN>),
     #[serde(rename = "sig-sha1-ecdsa")]
-    Sha1Ecdsa(heapless::String<64>),
+    Sha1Ecdsa(heapless::String<MAX_SIGNATURE_LEN>),
     #[serde(rename = "sig-sha256-ecdsa")]
-    Sha256Ecdsa(heapless::String<64>),
+    Sha256Ecdsa(heapless::String<MAX_SIGNATURE_LEN>),
 }
 
 #[derive(Debug, Clone, PartialEq, serde::Deserialize)]
@@ -32,6 +100,7 @@ pub struct FileDescription<'a> {
     #[serde(rename = "fileid")]
     pub fileid: u8,
     #[serde(rename = "certfile")]
+    #[serde(default)]
     pub certfile: &'a str,
     #[serde(rename = "update_data_url")]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -42,37 +111,176 @@ pub struct FileDescription<'a> {
 
     #[serde(rename = "sig-sha1-rsa")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub sha1_rsa: Option<heapless::String<64>>,
+    pub sha1_rsa: Option<&'a str>,
     #[serde(rename = "sig-sha256-rsa")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub sha256_rsa: Option<heapless::String<64>>,
+    pub sha256_rsa: Option<&'a str>,
     #[serde(rename = "sig-sha1-ecdsa")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub sha1_ecdsa: Option<heapless::String<64>>,
+    pub sha1_ecdsa: Option<&'a str>,
     #[serde(rename = "sig-sha256-ecdsa")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub sha256_ecdsa: Option<heapless::String<64>>,
+    pub sha256_ecdsa: Option<&'a str>,
 
     #[serde(rename = "fileType")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub file_type: Option<u32>,
+
+    /// Marks the file as a delta image, that needs to be applied as a patch
+    /// on top of the currently running firmware.
+    #[serde(rename = "delta")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<bool>,
+
+    /// Compression applied to the file, if any.
+    #[serde(rename = "compression")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+
+    /// Hex encoded SHA-256 digest of the file, checked by the agent once the
+    /// file is received.
+    #[serde(rename = "sha256")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<&'a str>,
+
+    /// Vendor specific attributes of the file.
+    #[serde(rename = "attr")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<FileAttributes>,
+
+    /// Version of the image, as `major.minor.patch`. Checked against the
+    /// running firmware before the file is downloaded.
+    #[serde(rename = "appversion")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_version: Option<&'a str>,
+}
+
+/// Vendor specific attributes of a file, given as a flat object in the `attr`
+/// field of the file, e.g. `"attr":{"bootloader":true,"slot":2}`.
+///
+/// Values are kept in their raw textual form, with strings unquoted, such
+/// that the PAL can interpret them as needed. Nested objects and arrays are
+/// not supported.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileAttributes(
+    heapless::Vec<
+        (
+            heapless::String<MAX_ATTRIBUTE_KEY_LEN>,
+            heapless::String<MAX_ATTRIBUTE_VALUE_LEN>,
+        ),
+        MAX_FILE_ATTRIBUTES,
+    >,
+);
+
+impl FileAttributes {
+    /// Raw value of the attribute `key`, if present
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+impl<'de> Deserialize<'de> for FileAttributes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AttributesVisitor;
+
+        impl<'de> Visitor<'de> for AttributesVisitor {
+            type Value = FileAttributes;
+
+            fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+                formatter.write_str("an object of file attributes")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut attributes = heapless::Vec::new();
+                while let Some(k) = map.next_key::<&'de str>()? {
+                    let mut key = heapless::String::new();
+                    key.push_str(k)
+                        .map_err(|_| A::Error::invalid_length(k.len(), &self))?;
+                    let AttributeValue(value) = map.next_value()?;
+                    attributes
+                        .push((key, value))
+                        .map_err(|_| A::Error::invalid_length(MAX_FILE_ATTRIBUTES + 1, &self))?;
+                }
+                Ok(FileAttributes(attributes))
+            }
+        }
+
+        deserializer.deserialize_map(AttributesVisitor)
+    }
+}
+
+/// Scalar attribute value, in its textual form
+struct AttributeValue(heapless::String<MAX_ATTRIBUTE_VALUE_LEN>);
+
+impl<'de> Deserialize<'de> for AttributeValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ValueVisitor;
+
+        impl ValueVisitor {
+            fn write<E: Error>(&self, args: core::fmt::Arguments) -> Result<AttributeValue, E> {
+                let mut value = heapless::String::new();
+                value
+                    .write_fmt(args)
+                    .map_err(|_| E::custom("file attribute value too long"))?;
+                Ok(AttributeValue(value))
+            }
+        }
+
+        impl<'de> Visitor<'de> for ValueVisitor {
+            type Value = AttributeValue;
+
+            fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+                formatter.write_str("a string, number or boolean")
+            }
+
+            fn visit_bool<E: Error>(self, v: bool) -> Result<Self::Value, E> {
+                self.write(format_args!("{}", v))
+            }
+
+            fn visit_i64<E: Error>(self, v: i64) -> Result<Self::Value, E> {
+                self.write(format_args!("{}", v))
+            }
+
+            fn visit_u64<E: Error>(self, v: u64) -> Result<Self::Value, E> {
+                self.write(format_args!("{}", v))
+            }
+
+            fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
+                self.write(format_args!("{}", v))
+            }
+        }
+
+        deserializer.deserialize_any(ValueVisitor)
+    }
 }
 
 impl<'a> FileDescription<'a> {
-    pub fn signature(&self) -> Signature {
-        if let Some(ref sig) = self.sha1_rsa {
-            return Signature::Sha1Rsa(sig.clone());
+    /// Signature of the file, if any. `None` as well if the signature is
+    /// longer than [`MAX_SIGNATURE_LEN`].
+    pub fn signature(&self) -> Option<Signature> {
+        fn copy(sig: &str) -> Option<heapless::String<MAX_SIGNATURE_LEN>> {
+            let mut owned = heapless::String::new();
+            owned.push_str(sig).ok()?;
+            Some(owned)
+        }
+
+        if let Some(sig) = self.sha1_rsa {
+            return copy(sig).map(Signature::Sha1Rsa);
         }
-        if let Some(ref sig) = self.sha256_rsa {
-            return Signature::Sha256Rsa(sig.clone());
+        if let Some(sig) = self.sha256_rsa {
+            return copy(sig).map(Signature::Sha256Rsa);
         }
-        if let Some(ref sig) = self.sha1_ecdsa {
-            return Signature::Sha1Ecdsa(sig.clone());
+        if let Some(sig) = self.sha1_ecdsa {
+            return copy(sig).map(Signature::Sha1Ecdsa);
         }
-        if let Some(ref sig) = self.sha256_ecdsa {
-            return Signature::Sha256Ecdsa(sig.clone());
+        if let Some(sig) = self.sha256_ecdsa {
+            return copy(sig).map(Signature::Sha256Ecdsa);
         }
-        unreachable!()
+        None
     }
 }
 
@@ -101,6 +309,104 @@ impl JobStatusReason {
     }
 }
 
+/// Reason for the application to abort an OTA update, reported as `reason`
+/// in the status details of the failed job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum AbortReason {
+    /// Aborted without any particular reason given
+    User,
+    /// Battery level too low to safely complete the update
+    LowBattery,
+    /// Not enough storage available for the update
+    NoStorage,
+    /// The update is not applicable to this device
+    Unsupported,
+    /// Application specific reason code, reported in decimal
+    Custom(u32),
+}
+
+impl AbortReason {
+    /// Status details value of the reason
+    pub fn to_status(&self) -> Result<heapless::String<11>, OtaError> {
+        let mut status = heapless::String::new();
+        match self {
+            AbortReason::User => status.push_str("user"),
+            AbortReason::LowBattery => status.push_str("low_battery"),
+            AbortReason::NoStorage => status.push_str("no_storage"),
+            AbortReason::Unsupported => status.push_str("unsupported"),
+            AbortReason::Custom(code) => status.write_fmt(format_args!("{}", code)).map_err(|_| ()),
+        }
+        .map_err(|_| OtaError::Overflow)?;
+        Ok(status)
+    }
+}
+
+/// Machine readable cause of a failed OTA update, reported as `failure` in the
+/// status details of the failed job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum FailureReason {
+    /// The signature of the received file did not verify
+    SignatureMismatch,
+    /// The SHA-256 digest of the received file did not match the job
+    DigestMismatch,
+    /// The PAL failed to write or close the file
+    FlashWrite,
+    /// A received block was malformed or out of range
+    InvalidBlock,
+    /// The version of the new image was rejected in self test
+    VersionRejected,
+    /// The application self test rejected the new image
+    SelfTestRejected,
+    /// The image state reported by the PAL does not match the job
+    ImageStateMismatch,
+    /// The PAL failed to set the platform image state
+    ImageState,
+    /// None of the protocols of the job are supported
+    InvalidProtocol,
+    /// No response to block requests, or self test did not complete in time
+    Timeout,
+    /// The transfer was aborted by the application, or the job was canceled
+    Aborted,
+    /// Any other failure
+    Other,
+}
+
+impl FailureReason {
+    /// Status details value of the reason
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureReason::SignatureMismatch => "signature",
+            FailureReason::DigestMismatch => "digest",
+            FailureReason::FlashWrite => "flash_write",
+            FailureReason::InvalidBlock => "block",
+            FailureReason::VersionRejected => "version",
+            FailureReason::SelfTestRejected => "self_test",
+            FailureReason::ImageStateMismatch => "img_state",
+            FailureReason::ImageState => "pal_state",
+            FailureReason::InvalidProtocol => "protocol",
+            FailureReason::Timeout => "timeout",
+            FailureReason::Aborted => "aborted",
+            FailureReason::Other => "other",
+        }
+    }
+}
+
+impl From<OtaError> for FailureReason {
+    fn from(e: OtaError) -> Self {
+        match e {
+            OtaError::SignatureCheckFailed => FailureReason::SignatureMismatch,
+            OtaError::DigestMismatch => FailureReason::DigestMismatch,
+            OtaError::Pal(_) => FailureReason::FlashWrite,
+            OtaError::BlockOutOfRange | OtaError::Encoding => FailureReason::InvalidBlock,
+            OtaError::Momentum | OtaError::MomentumAbort => FailureReason::Timeout,
+            OtaError::VersionRejected => FailureReason::VersionRejected,
+            _ => FailureReason::Other,
+        }
+    }
+}
+
 impl FromStr for JobStatusReason {
     type Err = ();
 
@@ -122,6 +428,9 @@ mod tests {
     use heapless::String;
 
     use crate::jobs::StatusDetails;
+    use crate::ota::config::Config;
+    use crate::ota::pal::PalError;
+    use crate::ota::test::test_file_ctx;
 
     use super::*;
 
@@ -151,4 +460,190 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn abort_reason_status() {
+        let reasons = &[
+            (AbortReason::User, "user"),
+            (AbortReason::LowBattery, "low_battery"),
+            (AbortReason::NoStorage, "no_storage"),
+            (AbortReason::Unsupported, "unsupported"),
+            (AbortReason::Custom(42), "42"),
+            (AbortReason::Custom(u32::MAX), "4294967295"),
+        ];
+
+        for (reason, exp) in reasons {
+            assert_eq!(reason.to_status().unwrap().as_str(), *exp);
+        }
+    }
+
+    #[test]
+    fn failure_reason_status() {
+        let reasons = &[
+            (OtaError::SignatureCheckFailed, "signature"),
+            (OtaError::DigestMismatch, "digest"),
+            (OtaError::Pal(PalError::FileWriteFailed), "flash_write"),
+            (OtaError::BlockOutOfRange, "block"),
+            (OtaError::MomentumAbort, "timeout"),
+            (OtaError::Overflow, "other"),
+        ];
+
+        for (error, exp) in reasons {
+            let reason = FailureReason::from(*error);
+            assert_eq!(reason.as_str(), *exp);
+
+            let mut file_ctx = test_file_ctx(&Config::default());
+            file_ctx.set_failure(reason, Some(7)).unwrap();
+            assert_eq!(
+                file_ctx.status_details.get(&String::from("failure")),
+                Some(&String::from(*exp))
+            );
+            assert_eq!(
+                file_ctx.status_details.get(&String::from("failed_block")),
+                Some(&String::from("7"))
+            );
+        }
+    }
+
+    #[test]
+    fn all_status_details_fit() {
+        let mut file_ctx = test_file_ctx(&Config::default());
+        for key in [
+            "updated_by",
+            "self_test",
+            "receivedBlocks",
+            "totalBlocks",
+            "reason",
+        ] {
+            file_ctx
+                .status_details
+                .insert(String::from(key), String::from("1"))
+                .unwrap();
+        }
+
+        file_ctx
+            .set_failure(FailureReason::FlashWrite, Some(483))
+            .unwrap();
+        file_ctx.set_pal_error(PalError::Custom(42)).unwrap();
+        assert_eq!(file_ctx.status_details.len(), 9);
+    }
+
+    #[test]
+    fn file_attributes() {
+        let file = br#"{
+            "filepath":"bootloader.bin",
+            "filesize":1024,
+            "fileid":1,
+            "certfile":"cert",
+            "sig-sha256-ecdsa":"sig",
+            "attr":{"bootloader":true,"slot":2,"offset":-16,"board":"rev-b"}
+        }"#;
+
+        let (desc, _) = serde_json_core::from_slice::<FileDescription>(file).unwrap();
+        let attributes = desc.attributes.unwrap();
+        assert_eq!(attributes.get("bootloader"), Some("true"));
+        assert_eq!(attributes.get("slot"), Some("2"));
+        assert_eq!(attributes.get("offset"), Some("-16"));
+        assert_eq!(attributes.get("board"), Some("rev-b"));
+        assert_eq!(attributes.get("missing"), None);
+        assert_eq!(attributes.iter().count(), 4);
+
+        let nested = br#"{
+            "filepath":"app.bin",
+            "filesize":1024,
+            "fileid":0,
+            "certfile":"cert",
+            "attr":{"nested":{"a":1}}
+        }"#;
+        assert!(serde_json_core::from_slice::<FileDescription>(nested).is_err());
+    }
+
+    #[test]
+    fn job_document_dispatch() {
+        let ota = br#"{
+            "afr_ota":{
+                "protocols":["MQTT"],
+                "streamname":"AFR_OTA-0ba01295-9417-4ba7-9a99-4b31fb03d252",
+                "files":[{
+                    "filepath":"IMG_test.jpg",
+                    "filesize":2674792,
+                    "fileid":0,
+                    "certfile":"nope",
+                    "fileType":0,
+                    "sig-sha256-ecdsa":"This is my signature! Better believe it!"
+                }]
+            }
+        }"#;
+
+        let document = JobDocument::from_slice(ota).unwrap();
+        assert_eq!(
+            document.ota.map(|job| job.streamname),
+            Some("AFR_OTA-0ba01295-9417-4ba7-9a99-4b31fb03d252")
+        );
+
+        let custom = br#"{"operation":"reboot","delay":10}"#;
+        assert_eq!(
+            JobDocument::from_slice(custom),
+            Ok(JobDocument { ota: None })
+        );
+
+        assert_eq!(JobDocument::from_slice(b"{"), Err(OtaError::Encoding));
+    }
+
+    #[test]
+    fn unknown_job_fields() {
+        let ota = br#"{
+            "afr_ota":{
+                "protocols":["MQTT","QUIC"],
+                "streamname":"AFR_OTA-stream",
+                "rollout":{"wave":2,"regions":["eu","us"]},
+                "files":[{
+                    "filepath":"app.bin",
+                    "filesize":1024,
+                    "fileid":0,
+                    "certfile":"cert",
+                    "sig-sha256-ecdsa":"sig",
+                    "build":{"commit":"abc123","dirty":false},
+                    "tags":["beta"],
+                    "priority":7
+                }]
+            },
+            "vendor":{"campaign":"spring"}
+        }"#;
+
+        let job = JobDocument::from_slice(ota).unwrap().ota.unwrap();
+        assert_eq!(
+            job.protocols.as_slice(),
+            &[Protocol::Mqtt, Protocol::Unknown]
+        );
+        assert_eq!(job.streamname, "AFR_OTA-stream");
+        assert_eq!(job.files[0].filepath, "app.bin");
+        assert_eq!(job.files[0].filesize, 1024);
+    }
+
+    #[test]
+    fn custom_http_job() {
+        let ota = br#"{
+            "afr_ota":{
+                "files":[{
+                    "filepath":"app.bin",
+                    "filesize":1024,
+                    "fileid":0,
+                    "update_data_url":"https://cdn.example.com/app.bin",
+                    "sig-sha256-ecdsa":"sig"
+                }]
+            }
+        }"#;
+
+        let job = JobDocument::from_slice(ota).unwrap().ota.unwrap();
+        assert!(job.protocols.is_empty());
+        assert_eq!(job.streamname, "");
+        assert_eq!(job.data_protocols(), &[Protocol::Http]);
+
+        // Files without a URL can only be streamed, which needs protocols
+        let mut files = job.files.clone();
+        files[0].update_data_url = None;
+        let job = OtaJob { files, ..job };
+        assert!(job.data_protocols().is_empty());
+    }
 }
@@ -2,30 +2,77 @@
 pub mod cbor;
 pub mod json;
 
-use core::ops::{Deref, DerefMut};
+use core::fmt::Write;
+use core::ops::{Deref, DerefMut, Range};
 use core::str::FromStr;
 use serde::{Serialize, Serializer};
 
-use crate::jobs::StatusDetails;
+use crate::jobs::{StatusDetails, MAX_STREAM_ID_LEN};
 
-use self::json::{JobStatusReason, OtaJob, Signature};
+use self::json::{FailureReason, FileAttributes, JobStatusReason, OtaJob, Signature};
 
+use super::data_interface::compression::Compression;
 use super::error::OtaError;
-use super::{config::Config, pal::Version};
+use super::{
+    config::Config,
+    pal::{PalError, Version},
+};
 
+/// Maximum length of the `update_data_url` of a file. Pre-signed S3 URLs used
+/// by the HTTP data plane are considerably longer than the MQTT stream names,
+/// so the size is only ramped up when HTTP support is enabled.
+#[cfg(feature = "ota_http_data")]
+pub const MAX_UPDATE_DATA_URL_LEN: usize = 1024;
+#[cfg(not(feature = "ota_http_data"))]
+pub const MAX_UPDATE_DATA_URL_LEN: usize = 64;
+
+/// Window of missing blocks, starting at the `block_offset` of a file.
+///
+/// The window covers at most [`Bitmap::WINDOW`] blocks, regardless of the size
+/// of the file, and slides along as blocks are received.
 #[derive(Clone, PartialEq)]
 pub struct Bitmap(bitmaps::Bitmap<32>);
 
 impl Bitmap {
+    /// Number of blocks covered by the window
+    pub const WINDOW: usize = 32 - 1;
+
     pub fn new(file_size: usize, block_size: usize, block_offset: u32) -> Self {
         // Total number of blocks in file, rounded up
         let total_num_blocks = (file_size + block_size - 1) / block_size;
 
         Self(bitmaps::Bitmap::mask(core::cmp::min(
-            32 - 1,
-            total_num_blocks - block_offset as usize,
+            Self::WINDOW,
+            total_num_blocks.saturating_sub(block_offset as usize),
         )))
     }
+
+    /// Slide the window starting at `block_offset` past the blocks received
+    /// at its start, such that it starts at the first missing block. Returns
+    /// the new offset of the window.
+    pub fn slide(&mut self, file_size: usize, block_size: usize, block_offset: u32) -> u32 {
+        let shift = match self.0.first_index() {
+            Some(0) => return block_offset,
+            Some(index) => index,
+            None => Self::WINDOW,
+        };
+        let block_offset = block_offset + shift as u32;
+
+        // Blocks moving into the window from the end are all missing, while
+        // the rest of the window keeps its state
+        let missing = Self::new(file_size, block_size, block_offset).into_value();
+        let kept = (1u32 << (Self::WINDOW - shift)) - 1;
+        let value = ((self.0.into_value() >> shift) & kept) | (missing & !kept);
+
+        self.0 = bitmaps::Bitmap::from_value(value);
+        block_offset
+    }
+
+    /// Recreate a bitmap from its raw value, e.g. as persisted by a
+    /// [`BitmapStorage`](crate::ota::storage::BitmapStorage)
+    pub fn from_value(value: u32) -> Self {
+        Self(bitmaps::Bitmap::from_value(value))
+    }
 }
 
 impl Deref for Bitmap {
@@ -47,7 +94,11 @@ impl Serialize for Bitmap {
     where
         S: Serializer,
     {
-        Serializer::serialize_bytes(serializer, &self.deref().into_value().to_le_bytes())
+        // Trailing zero bytes request no blocks, and are left out to keep
+        // requests small
+        let bytes = self.deref().into_value().to_le_bytes();
+        let len = bytes.iter().rposition(|b| *b != 0).map_or(1, |i| i + 1);
+        Serializer::serialize_bytes(serializer, &bytes[..len])
     }
 }
 
@@ -60,17 +111,34 @@ pub struct FileContext {
     pub filesize: usize,
     pub fileid: u8,
     pub certfile: heapless::String<64>,
-    pub update_data_url: Option<heapless::String<64>>,
+    pub update_data_url: Option<heapless::String<MAX_UPDATE_DATA_URL_LEN>>,
     pub auth_scheme: Option<heapless::String<64>>,
     pub signature: Signature,
     pub file_type: Option<u32>,
+    pub delta: bool,
+    pub compression: Option<Compression>,
+    /// Vendor specific attributes from the `attr` field of the file
+    pub attributes: FileAttributes,
+    /// Expected SHA-256 digest of the file, if given by the job
+    pub sha256: Option<[u8; 32]>,
+    /// Version of the image, if given by the job
+    pub app_version: Option<Version>,
 
     pub status_details: StatusDetails,
     pub block_offset: u32,
     pub blocks_remaining: usize,
+    /// Number of bytes written after decompression, for compressed files
+    pub decompressed_offset: usize,
+    /// Number of bytes streamed through signature verification, in order
+    pub verified_offset: usize,
+    /// Number of bytes of the file prepared for writing by the PAL
+    pub prepared_offset: usize,
+    /// Set to report the progress with the next job status update, regardless
+    /// of the status update frequency
+    pub report_progress: bool,
     pub request_block_remaining: u32,
     pub job_name: heapless::String<64>,
-    pub stream_name: heapless::String<64>,
+    pub stream_name: heapless::String<MAX_STREAM_ID_LEN>,
     pub bitmap: Bitmap,
 }
 
@@ -103,7 +171,7 @@ impl FileContext {
             status
         };
 
-        let signature = file_desc.signature();
+        let signature = file_desc.signature().ok_or(OtaError::InvalidFile)?;
 
         let block_offset = 0;
         let bitmap = Bitmap::new(file_desc.filesize, config.block_size, block_offset);
@@ -113,10 +181,19 @@ impl FileContext {
             filesize: file_desc.filesize,
             fileid: file_desc.fileid,
             certfile: heapless::String::from(file_desc.certfile),
-            update_data_url: file_desc.update_data_url.map(heapless::String::from),
+            update_data_url: file_desc.update_data_url.map(Self::data_url).transpose()?,
             auth_scheme: file_desc.auth_scheme.map(heapless::String::from),
             signature,
             file_type: file_desc.file_type,
+            delta: file_desc.delta.unwrap_or(false),
+            compression: file_desc.compression,
+            attributes: file_desc.attributes.unwrap_or_default(),
+            sha256: file_desc.sha256.map(Self::digest).transpose()?,
+            app_version: file_desc
+                .app_version
+                .map(Version::from_str)
+                .transpose()
+                .map_err(|_| OtaError::Encoding)?,
 
             status_details: status,
 
@@ -124,11 +201,66 @@ impl FileContext {
             block_offset,
             request_block_remaining: bitmap.len() as u32,
             blocks_remaining: (file_desc.filesize + config.block_size - 1) / config.block_size,
-            stream_name: heapless::String::from(ota_job.streamname),
+            decompressed_offset: 0,
+            verified_offset: 0,
+            prepared_offset: 0,
+            report_progress: false,
+            stream_name: Self::stream_name(ota_job.streamname)?,
             bitmap,
         })
     }
 
+    /// Decode a hex encoded SHA-256 digest from a job document
+    pub(crate) fn digest(hex: &str) -> Result<[u8; 32], OtaError> {
+        let mut digest = [0u8; 32];
+        if hex.len() != digest.len() * 2 {
+            return Err(OtaError::Encoding);
+        }
+
+        for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let pair = core::str::from_utf8(pair).map_err(|_| OtaError::Encoding)?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| OtaError::Encoding)?;
+        }
+        Ok(digest)
+    }
+
+    /// Copy an `update_data_url` from a job document, failing rather than
+    /// panicking if the url is too long.
+    pub(crate) fn data_url(
+        url: &str,
+    ) -> Result<heapless::String<MAX_UPDATE_DATA_URL_LEN>, OtaError> {
+        let mut s = heapless::String::new();
+        s.push_str(url).map_err(|_| OtaError::Overflow)?;
+        Ok(s)
+    }
+
+    /// Copy the stream name from a job document, ensuring it is a valid
+    /// stream ID. As stream IDs only consist of `[a-zA-Z0-9_-]`, the name
+    /// never contains topic separators or wildcards once validated. An empty
+    /// name is accepted, as jobs transferring files over HTTP only have no
+    /// stream.
+    pub(crate) fn stream_name(name: &str) -> Result<heapless::String<MAX_STREAM_ID_LEN>, OtaError> {
+        if !name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+        {
+            return Err(OtaError::InvalidStreamName);
+        }
+
+        let mut s = heapless::String::new();
+        s.push_str(name).map_err(|_| OtaError::InvalidStreamName)?;
+        Ok(s)
+    }
+
+    /// Byte offset just past the last block of the window of requested
+    /// blocks
+    pub fn window_end(&self, block_size: usize) -> usize {
+        core::cmp::min(
+            (self.block_offset as usize + Bitmap::WINDOW) * block_size,
+            self.filesize,
+        )
+    }
+
     pub fn self_test(&self) -> bool {
         self.status_details
             .get(&heapless::String::from("self_test"))
@@ -140,6 +272,109 @@ impl FileContext {
             .unwrap_or(false)
     }
 
+    /// Record the cause of a failed update in the status details, along with
+    /// the index of the block being processed when it failed, if any.
+    pub fn set_failure(
+        &mut self,
+        reason: FailureReason,
+        block: Option<usize>,
+    ) -> Result<(), OtaError> {
+        self.status_details
+            .insert(
+                heapless::String::from("failure"),
+                heapless::String::from(reason.as_str()),
+            )
+            .map_err(|_| OtaError::Overflow)?;
+
+        if let Some(block) = block {
+            let mut value = heapless::String::new();
+            value
+                .write_fmt(format_args!("{}", block))
+                .map_err(|_| OtaError::Overflow)?;
+            self.status_details
+                .insert(heapless::String::from("failed_block"), value)
+                .map_err(|_| OtaError::Overflow)?;
+        }
+        Ok(())
+    }
+
+    /// Mark the already received `blocks` as missing, moving the window back
+    /// to the first of them if needed, such that they are requested and
+    /// written again. The blocks have to fit within [`Bitmap::WINDOW`] blocks
+    /// from the start of the window, once moved.
+    ///
+    /// Not supported for patches and compressed files, which can only be
+    /// processed sequentially.
+    pub fn mark_missing(
+        &mut self,
+        blocks: Range<usize>,
+        block_size: usize,
+    ) -> Result<(), OtaError> {
+        if self.delta || self.compression.is_some() {
+            return Err(OtaError::InvalidFile);
+        }
+
+        let total_blocks = (self.filesize + block_size - 1) / block_size;
+        let offset = self.block_offset as usize;
+        let new_offset = core::cmp::min(blocks.start, offset);
+        if blocks.is_empty() || blocks.end > total_blocks.min(new_offset + Bitmap::WINDOW) {
+            return Err(OtaError::BlockOutOfRange);
+        }
+
+        // Blocks before the window are all received, and blocks after it are
+        // all missing
+        let missing = |block: usize| {
+            block >= offset && (block >= offset + Bitmap::WINDOW || self.bitmap.get(block - offset))
+        };
+
+        let mut value = 0u32;
+        let mut reopened = 0;
+        for index in 0..Bitmap::WINDOW {
+            let block = new_offset + index;
+            if block >= total_blocks {
+                break;
+            }
+            if missing(block) || blocks.contains(&block) {
+                value |= 1 << index;
+                reopened += !missing(block) as usize;
+            }
+        }
+
+        // Moving the window back leaves the end of the current window behind,
+        // to be received again after sliding over it
+        for block in new_offset + Bitmap::WINDOW..total_blocks.min(offset + Bitmap::WINDOW) {
+            reopened += !missing(block) as usize;
+        }
+
+        self.bitmap = Bitmap::from_value(value);
+        self.block_offset = new_offset as u32;
+        self.blocks_remaining += reopened;
+        self.request_block_remaining = self.bitmap.len() as u32;
+        Ok(())
+    }
+
+    /// Record the PAL error behind a failed update in the status details,
+    /// along with the numeric code of platform specific errors.
+    pub fn set_pal_error(&mut self, error: PalError) -> Result<(), OtaError> {
+        self.status_details
+            .insert(
+                heapless::String::from("pal_error"),
+                heapless::String::from(error.as_str()),
+            )
+            .map_err(|_| OtaError::Overflow)?;
+
+        if let PalError::Custom(code) = error {
+            let mut value = heapless::String::new();
+            value
+                .write_fmt(format_args!("{}", code))
+                .map_err(|_| OtaError::Overflow)?;
+            self.status_details
+                .insert(heapless::String::from("pal_code"), value)
+                .map_err(|_| OtaError::Overflow)?;
+        }
+        Ok(())
+    }
+
     pub fn updated_by(&self) -> Option<Version> {
         self.status_details
             .get(&heapless::String::from("updated_by"))
@@ -151,6 +386,43 @@ impl FileContext {
 mod tests {
     use super::*;
 
+    #[test]
+    fn decode_digest() {
+        let digest =
+            FileContext::digest("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+                .unwrap();
+        assert_eq!(digest[..4], [0xe3, 0xb0, 0xc4, 0x42]);
+        assert_eq!(digest[31], 0x55);
+
+        assert_eq!(FileContext::digest("e3b0"), Err(OtaError::Encoding));
+        assert_eq!(
+            FileContext::digest("z3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            Err(OtaError::Encoding)
+        );
+    }
+
+    #[test]
+    fn validate_stream_name() {
+        assert_eq!(
+            FileContext::stream_name("AFR_OTA-0ba01295-9417-4ba7-9a99-4b31fb03d252")
+                .unwrap()
+                .as_str(),
+            "AFR_OTA-0ba01295-9417-4ba7-9a99-4b31fb03d252"
+        );
+        assert!(FileContext::stream_name("").unwrap().is_empty());
+
+        for name in &["stream/data", "stream+", "#", "stream name", "strëam"] {
+            assert_eq!(
+                FileContext::stream_name(name),
+                Err(OtaError::InvalidStreamName)
+            );
+        }
+        assert_eq!(
+            FileContext::stream_name(&"a".repeat(MAX_STREAM_ID_LEN + 1)),
+            Err(OtaError::InvalidStreamName)
+        );
+    }
+
     #[test]
     fn bitmap_masking() {
         let bitmap = Bitmap::new(255000, 256, 0);
@@ -158,4 +430,35 @@ mod tests {
         let true_indices: Vec<usize> = bitmap.into_iter().collect();
         assert_eq!((0..31).into_iter().collect::<Vec<usize>>(), true_indices);
     }
+
+    #[test]
+    fn bitmap_sliding() {
+        // 40 blocks in total
+        let mut bitmap = Bitmap::new(40 * 256, 256, 0);
+
+        // Nothing to slide past while the first block is missing
+        bitmap.set(1, false);
+        assert_eq!(bitmap.slide(40 * 256, 256, 0), 0);
+
+        bitmap.set(0, false);
+        bitmap.set(3, false);
+        assert_eq!(bitmap.slide(40 * 256, 256, 0), 2);
+
+        // Blocks 2 and 4..=32 are missing, block 3 was received
+        let missing: Vec<usize> = bitmap.into_iter().map(|i| i + 2).collect();
+        assert_eq!(
+            missing,
+            [2].iter().copied().chain(4..=32).collect::<Vec<_>>()
+        );
+
+        // Slide a completed window to the end of the file
+        for index in 0..Bitmap::WINDOW {
+            bitmap.set(index, false);
+        }
+        assert_eq!(bitmap.slide(40 * 256, 256, 2), 33);
+        assert_eq!(
+            bitmap.into_iter().collect::<Vec<usize>>(),
+            (0..7).collect::<Vec<_>>()
+        );
+    }
 }
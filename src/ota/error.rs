@@ -1,6 +1,6 @@
 use crate::jobs::JobError;
 
-use super::pal::OtaPalError;
+use super::pal::{OtaPalError, PalError, PalErrorCode};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OtaError {
@@ -14,9 +14,22 @@ pub enum OtaError {
     ZeroFileSize,
     Overflow,
     InvalidFile,
+    /// The stream name of the job is too long, or contains characters that
+    /// are not allowed in a stream ID
+    InvalidStreamName,
+    /// The thing name used as client ID is not a valid thing name
+    InvalidThingName,
+    /// The version of the image offered by the job was rejected, see
+    /// [`VersionPolicy::accept_update`](super::version_policy::VersionPolicy::accept_update)
+    VersionRejected,
     Mqtt(mqttrust::MqttError),
+    Http,
     Encoding,
-    Pal,
+    /// The PAL failed, see [`PalError`]
+    Pal(PalError),
+    Storage,
+    SignatureCheckFailed,
+    DigestMismatch,
     Timer,
 }
 
@@ -26,9 +39,9 @@ impl From<mqttrust::MqttError> for OtaError {
     }
 }
 
-impl<E: Copy> From<OtaPalError<E>> for OtaError {
-    fn from(_e: OtaPalError<E>) -> Self {
-        Self::Pal
+impl<E: PalErrorCode> From<OtaPalError<E>> for OtaError {
+    fn from(e: OtaPalError<E>) -> Self {
+        Self::Pal(e.into())
     }
 }
 
@@ -38,6 +51,10 @@ impl From<JobError> for OtaError {
             JobError::Overflow => Self::Overflow,
             JobError::Encoding => Self::Encoding,
             JobError::Mqtt(m) => Self::Mqtt(m),
+            JobError::Storage => Self::Storage,
+            JobError::Timer => Self::Timer,
+            JobError::InvalidThingName | JobError::UnknownThing => Self::InvalidThingName,
+            JobError::Timeout | JobError::Busy => Self::Momentum,
         }
     }
 }
@@ -0,0 +1,77 @@
+//! Agent events, for applications built around a central event loop.
+//!
+//! Besides notifying the PAL through [`OtaPal::complete_callback`], the agent
+//! returns an [`AgentEvent`] from [`OtaAgent::handle_message`] and
+//! [`OtaAgent::process_event`] whenever the update makes notable progress:
+//!
+//! ```ignore
+//! match ota_agent.handle_message(payload)? {
+//!     Some(AgentEvent::BlockReceived { blocks_received, blocks_total }) => {
+//!         display.progress(blocks_received, blocks_total)
+//!     }
+//!     Some(AgentEvent::Failed { reason }) => telemetry.report(reason),
+//!     _ => {}
+//! }
+//! ```
+//!
+//! Only the latest event is kept. Events raised outside of these calls, e.g.
+//! by [`OtaAgent::timer_callback`], are returned by the next call, or can be
+//! taken through [`OtaAgent::take_event`].
+//!
+//! For debugging updates that get stuck in the field, every transition of the
+//! underlying state machine is logged along with the event triggering it, and
+//! reported to the [`TransitionHook`] set through
+//! [`OtaAgentBuilder::transition_hook`], if any.
+//!
+//! [`OtaPal::complete_callback`]: super::pal::OtaPal::complete_callback
+//! [`OtaAgent::handle_message`]: super::agent::OtaAgent::handle_message
+//! [`OtaAgent::process_event`]: super::agent::OtaAgent::process_event
+//! [`OtaAgent::timer_callback`]: super::agent::OtaAgent::timer_callback
+//! [`OtaAgent::take_event`]: super::agent::OtaAgent::take_event
+//! [`OtaAgentBuilder::transition_hook`]: super::builder::OtaAgentBuilder::transition_hook
+
+use super::{encoding::json::FailureReason, pal::Bank};
+
+/// Notable transition of the OTA agent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum AgentEvent {
+    /// The transfer of a file was started
+    Started,
+    /// A file block was received and written
+    BlockReceived {
+        blocks_received: usize,
+        blocks_total: usize,
+    },
+    /// The new image is running, and waiting to be accepted
+    SelfTestPending,
+    /// The update was completed. For firmware images, this is raised once
+    /// the image is received and verified, just before it is activated.
+    Completed {
+        /// Bank the image was written to, as reported by
+        /// [`OtaPal::image_bank`]. Not given once the image is accepted in
+        /// self test, as it is the running image by then.
+        ///
+        /// [`OtaPal::image_bank`]: super::pal::OtaPal::image_bank
+        bank: Option<Bank>,
+    },
+    /// The update failed or was aborted
+    Failed { reason: FailureReason },
+}
+
+/// Transition of the agent state machine, named after the states and event
+/// involved. Transitions within a state, e.g. on each received block, are
+/// reported as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Transition {
+    pub from: &'static str,
+    pub to: &'static str,
+    /// Event that triggered the transition
+    pub trigger: &'static str,
+}
+
+/// Hook notified of every transition of the agent state machine
+pub trait TransitionHook {
+    fn on_transition(&mut self, transition: &Transition);
+}
@@ -0,0 +1,55 @@
+//! Integrity checks of received files, besides the built-in SHA-256 digest.
+//!
+//! Jobs created by AWS IoT OTA are covered by their code signature, and
+//! optionally by a `sha256` digest of each file, checked by the agent with the
+//! `ota_sha256` feature. Constrained parts may prefer a cheaper or hardware
+//! accelerated algorithm instead, e.g. the CRC32 or SHA-224 unit of a crypto
+//! peripheral. An [`IntegrityCheck`] registered on the builder is fed every
+//! byte of each file, in order, and consulted once the file is complete:
+//!
+//! ```ignore
+//! struct HwCrc32<'a> {
+//!     crc: &'a mut CrcPeripheral,
+//! }
+//!
+//! impl IntegrityCheck for HwCrc32<'_> {
+//!     fn reset(&mut self, _file: &FileContext) {
+//!         self.crc.reset();
+//!     }
+//!
+//!     fn update(&mut self, data: &[u8]) {
+//!         self.crc.feed(data);
+//!     }
+//!
+//!     fn verify(&mut self, file: &FileContext) -> bool {
+//!         file.attributes
+//!             .get("crc32")
+//!             .and_then(|crc| u32::from_str_radix(crc, 16).ok())
+//!             .map_or(false, |crc| crc == self.crc.result())
+//!     }
+//! }
+//!
+//! let agent = OtaAgent::builder(&mqtt, &mqtt, timer, pal)
+//!     .integrity_check(&mut crc32)
+//!     .build();
+//! ```
+//!
+//! Files failing the check are rejected with [`OtaError::DigestMismatch`].
+//!
+//! [`OtaError::DigestMismatch`]: super::error::OtaError::DigestMismatch
+
+use super::encoding::FileContext;
+
+/// Integrity check computed over the contents of each received file
+pub trait IntegrityCheck {
+    /// Reset the check, in preparation of receiving `file`
+    fn reset(&mut self, file: &FileContext);
+
+    /// Feed the next chunk of file data to the check
+    fn update(&mut self, data: &[u8]);
+
+    /// Whether all data fed since the last reset matches the value expected
+    /// for `file`, e.g. as given by one of its
+    /// [`attributes`](FileContext::attributes).
+    fn verify(&mut self, file: &FileContext) -> bool;
+}
@@ -0,0 +1,340 @@
+//! Running OTA jobs alongside application jobs.
+//!
+//! An OTA agent on its own subscribes to `notify-next` and requests the next
+//! pending job itself, which conflicts with a [`JobsAgent`] claiming the jobs
+//! of the same thing. When both are used, the jobs agent owns the jobs topics,
+//! and an [`OtaJobRouter`] given to it as its [`JobHandler`] forwards the jobs
+//! with an `afr_ota` document to the OTA agent, and all other jobs to the
+//! application handler. The OTA agent is built on a [`SharedJobs`] control
+//! interface, which leaves the jobs topics and requests alone.
+//!
+//! ```ignore
+//! #[derive(Deserialize)]
+//! enum Document<'a> {
+//!     #[serde(rename = "afr_ota")]
+//!     #[serde(borrow)]
+//!     Ota(OtaJob<'a>),
+//!     #[serde(rename = "reboot")]
+//!     Reboot(Reboot),
+//! }
+//!
+//! impl OtaDocument for Document<'_> {
+//!     fn ota_job(&self) -> Option<&OtaJob<'_>> {
+//!         match self {
+//!             Document::Ota(ota_job) => Some(ota_job),
+//!             _ => None,
+//!         }
+//!     }
+//! }
+//!
+//! let control = SharedJobs::new(&mqtt);
+//! let mut ota_agent = OtaAgent::builder(&control, &mqtt, ota_timer, pal).build();
+//! ota_agent.init();
+//!
+//! let router = OtaJobRouter::new(ota_agent, app_handler);
+//! let mut agent = JobsAgent::new(&mqtt, router, jobs_timer);
+//! agent.init()?;
+//!
+//! loop {
+//!     // All jobs topics go to the jobs agent, the stream topics to the OTA
+//!     // agent
+//!     agent.handle_message::<Document>(topic, payload)?;
+//!     agent.handler().ota().process_event()?;
+//! }
+//! ```
+//!
+//! The OTA agent reports the status of its jobs itself. Once it reported the
+//! final status, the `notify-next` message that follows ends the job in the
+//! jobs agent as well, as by a cancellation, and the next job is claimed.
+//!
+//! [`JobsAgent`]: crate::jobs::agent::JobsAgent
+//! [`SharedJobs`]: super::control_interface::shared::SharedJobs
+
+use embedded_hal::timer;
+
+use super::{
+    agent::OtaAgent, control_interface::ControlInterface, data_interface::DataInterface,
+    encoding::json::OtaJob, pal::OtaPal, state::Error,
+};
+use crate::jobs::{
+    agent::{JobHandler, JobOutcome},
+    data_types::JobExecution,
+    owned_status_details, StatusDetails,
+};
+use crate::rustot_log;
+
+/// Job document that might contain an OTA job
+pub trait OtaDocument {
+    /// The `afr_ota` section of the document, if any
+    fn ota_job(&self) -> Option<&OtaJob<'_>>;
+}
+
+/// Receiver of the OTA jobs claimed by a jobs agent, implemented by
+/// [`OtaAgent`]
+pub trait OtaJobs {
+    /// Start the OTA job `job_name`, claimed by the jobs agent
+    fn start(
+        &mut self,
+        job_name: &str,
+        ota_document: &OtaJob<'_>,
+        status_details: Option<&StatusDetails>,
+    ) -> Result<(), Error>;
+
+    /// The OTA job `job_name` is no longer pending on the service side
+    fn cancel(&mut self, job_name: &str);
+}
+
+impl<'a, C, DP, DS, T, ST, PAL> OtaJobs for OtaAgent<'a, C, DP, DS, T, ST, PAL>
+where
+    C: ControlInterface,
+    DP: DataInterface,
+    DS: DataInterface,
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+    ST: timer::nb::CountDown + timer::nb::Cancel,
+    ST::Time: From<u32>,
+    PAL: OtaPal,
+{
+    fn start(
+        &mut self,
+        job_name: &str,
+        ota_document: &OtaJob<'_>,
+        status_details: Option<&StatusDetails>,
+    ) -> Result<(), Error> {
+        self.job_update(job_name, ota_document, status_details)
+            .map(drop)
+    }
+
+    fn cancel(&mut self, job_name: &str) {
+        self.job_canceled(job_name).ok();
+    }
+}
+
+/// [`JobHandler`] forwarding OTA jobs to `O`, and all other jobs to `H`
+pub struct OtaJobRouter<O, H> {
+    ota: O,
+    handler: H,
+    /// Set while the running job is an OTA job
+    ota_running: bool,
+}
+
+impl<O: OtaJobs, H> OtaJobRouter<O, H> {
+    pub fn new(ota: O, handler: H) -> Self {
+        Self {
+            ota,
+            handler,
+            ota_running: false,
+        }
+    }
+
+    /// The OTA agent, e.g. to process its events and stream messages
+    pub fn ota(&mut self) -> &mut O {
+        &mut self.ota
+    }
+
+    /// The handler of the application jobs
+    pub fn handler(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    fn start_ota<J>(
+        &mut self,
+        execution: &JobExecution<'_, J>,
+        ota_job: &OtaJob<'_>,
+    ) -> JobOutcome {
+        let status_details = match execution
+            .status_details
+            .as_ref()
+            .map(owned_status_details)
+            .transpose()
+        {
+            Ok(status_details) => status_details,
+            Err(_) => {
+                rustot_log!(
+                    warn,
+                    "OTA job {} has invalid status details",
+                    execution.job_id
+                );
+                return JobOutcome::Rejected;
+            }
+        };
+
+        match self
+            .ota
+            .start(execution.job_id, ota_job, status_details.as_ref())
+        {
+            Ok(()) => {
+                self.ota_running = true;
+                // Reported by the OTA agent
+                JobOutcome::Pending
+            }
+            Err(_) => {
+                rustot_log!(warn, "OTA job {} not started", execution.job_id);
+                JobOutcome::Rejected
+            }
+        }
+    }
+}
+
+impl<J, O, H> JobHandler<J> for OtaJobRouter<O, H>
+where
+    J: OtaDocument,
+    O: OtaJobs,
+    H: JobHandler<J>,
+{
+    fn handle(&mut self, execution: &JobExecution<'_, J>) -> JobOutcome {
+        self.ota_running = false;
+        match execution.job_document.as_ref().and_then(J::ota_job) {
+            Some(ota_job) => self.start_ota(execution, ota_job),
+            None => self.handler.handle(execution),
+        }
+    }
+
+    fn cancel(&mut self, job_id: &str) {
+        if core::mem::take(&mut self.ota_running) {
+            self.ota.cancel(job_id);
+        } else {
+            self.handler.cancel(job_id);
+        }
+    }
+
+    fn step_timeout(&mut self, job_id: &str) -> bool {
+        // The OTA agent reports progress on its own schedule only
+        self.ota_running || self.handler.step_timeout(job_id)
+    }
+
+    fn resume(&mut self, execution: &JobExecution<'_, J>) -> JobOutcome {
+        self.ota_running = false;
+        // The OTA agent picks up an update in self-test by itself
+        match execution.job_document.as_ref().and_then(J::ota_job) {
+            Some(ota_job) => self.start_ota(execution, ota_job),
+            None => self.handler.resume(execution),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{
+        jobs::{agent::JobsAgent, data_types::JobStatus},
+        ota::{
+            clock::ClockTimer,
+            control_interface::shared::SharedJobs,
+            encoding::json::AbortReason,
+            state::States,
+            test::mock::{MockPal, MockTimer},
+        },
+        test::{jobs::MockJobs, MockClock, MockMqtt},
+    };
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    enum Document<'a> {
+        #[serde(rename = "afr_ota")]
+        #[serde(borrow)]
+        Ota(OtaJob<'a>),
+        #[serde(rename = "reboot")]
+        Reboot(Reboot),
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Reboot {}
+
+    impl OtaDocument for Document<'_> {
+        fn ota_job(&self) -> Option<&OtaJob<'_>> {
+            match self {
+                Document::Ota(ota_job) => Some(ota_job),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Handler {
+        handled: Vec<String>,
+    }
+
+    impl<'a> JobHandler<Document<'a>> for Handler {
+        fn handle(&mut self, execution: &JobExecution<'_, Document<'a>>) -> JobOutcome {
+            assert_eq!(execution.job_document, Some(Document::Reboot(Reboot {})));
+            self.handled.push(execution.job_id.to_string());
+            JobOutcome::Succeeded
+        }
+    }
+
+    const OTA_DOCUMENT: &str = r#"{
+        "afr_ota":{
+            "protocols":["MQTT"],
+            "streamname":"test_stream",
+            "files":[{
+                "filepath":"app.bin",
+                "filesize":1024,
+                "fileid":0,
+                "certfile":"cert",
+                "fileType":0,
+                "sig-sha1-rsa":"sig"
+            }]
+        }
+    }"#;
+
+    /// Answer the requests published on `mqtt`, until no more are sent
+    fn serve<H>(
+        jobs: &mut MockJobs,
+        mqtt: &MockMqtt,
+        agent: &mut JobsAgent<'_, MockMqtt, H, ClockTimer<MockClock>>,
+    ) where
+        H: for<'b> JobHandler<Document<'b>>,
+    {
+        loop {
+            let responses = jobs.serve(mqtt);
+            if responses.is_empty() {
+                return;
+            }
+            for response in responses {
+                agent
+                    .handle_message::<Document>(&response.topic, &response.payload)
+                    .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn ota_and_application_jobs() {
+        let mqtt = MockMqtt::new();
+        let control = SharedJobs::new(&mqtt);
+        let mut ota_agent =
+            OtaAgent::builder(&control, &mqtt, MockTimer::new(), MockPal::new()).build();
+        ota_agent.init();
+        ota_agent.process_event().unwrap();
+        assert!(matches!(ota_agent.state(), &States::WaitingForJob));
+
+        let mut jobs = MockJobs::new();
+        jobs.queue("ota-job", OTA_DOCUMENT);
+        jobs.queue("reboot-job", r#"{"reboot":{}}"#);
+
+        let router = OtaJobRouter::new(ota_agent, Handler::default());
+        let mut agent = JobsAgent::new(&mqtt, router, ClockTimer::new(MockClock::default()));
+        agent.init().unwrap();
+
+        // The OTA job is claimed by the jobs agent, without the OTA agent
+        // requesting any job itself
+        serve(&mut jobs, &mqtt, &mut agent);
+        assert_eq!(agent.job_id(), Some("ota-job"));
+        assert!(matches!(
+            agent.handler().ota().state(),
+            &States::CreatingFile
+        ));
+        assert!(agent.handler().handler().handled.is_empty());
+
+        // Once the OTA agent reported the outcome, the next job is claimed
+        agent.handler().ota().abort(AbortReason::User).unwrap();
+        serve(&mut jobs, &mqtt, &mut agent);
+        assert_eq!(agent.handler().handler().handled, vec!["reboot-job"]);
+        assert_eq!(agent.job_id(), None);
+
+        assert_eq!(jobs.job("ota-job").unwrap().status, JobStatus::Failed);
+        assert_eq!(jobs.job("reboot-job").unwrap().status, JobStatus::Succeeded);
+    }
+}
@@ -31,17 +31,38 @@
 //! - CBOR deserializer
 
 pub mod agent;
+#[cfg(feature = "ota_async")]
+pub mod asynch;
+pub mod backoff;
 pub mod builder;
+#[cfg(feature = "ota_sign_verify")]
+pub(crate) mod certificate;
+pub mod clock;
 pub mod config;
 pub mod control_interface;
+pub mod custom_job;
 pub mod data_interface;
 pub mod encoding;
 pub mod error;
+pub mod event;
+pub mod integrity;
+pub mod job_router;
 pub mod pal;
+pub mod self_test;
+#[cfg(feature = "ota_sign_verify")]
+pub(crate) mod signature;
+pub mod split;
 pub mod state;
+pub mod statistics;
+pub mod storage;
+#[cfg(feature = "ota_mqtt_data")]
+pub mod stream;
+pub mod version_policy;
 #[macro_use]
 pub mod logging;
 
+#[cfg(feature = "ota_http_data")]
+pub use data_interface::http::{HttpClient, HttpInterface};
 #[cfg(feature = "ota_mqtt_data")]
 pub use data_interface::mqtt::{Encoding, Topic};
 
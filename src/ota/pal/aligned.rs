@@ -0,0 +1,466 @@
+//! Coalescing of file writes into flash page aligned chunks.
+//!
+//! Many flash drivers can only program whole, aligned pages or sectors, while
+//! file blocks arrive in chunks of the configured block size. Wrapping a PAL in
+//! [`AlignedWrites`] buffers the incoming blocks, such that the wrapped
+//! [`OtaPal::write_block`] is only ever called with a full page at a
+//! page-aligned offset.
+//!
+//! Pages are written as soon as they are complete, i.e. once every byte of
+//! the page, or of the file up to its end, was received. Blocks may arrive
+//! out of order, so up to `SLOTS` incomplete pages are buffered at a time,
+//! and each page is written exactly once, as flash with ECC forbids
+//! programming a page twice. A block that would need another buffer is
+//! refused with [`OtaPalError::WouldBlock`], to be requested again once the
+//! buffered pages were completed. Pages covered entirely by a block are
+//! written straight from the block.
+//!
+//! The agent writes every block once, so the bytes received into a page are
+//! tracked by count. The last page of the file is padded with the erased
+//! value of the flash, so the wrapped PAL must accept writes extending past
+//! the end of the file.
+//!
+//! Buffered data does not survive a reset, so resuming a download through
+//! [`OtaPal::resume_file_for_rx`] is not supported by the wrapper, making the
+//! agent restart the file from block 0 instead.
+
+use super::{
+    Bank, ImageState, OtaEvent, OtaPal, OtaPalError, PalImageState, PatchApplier,
+    SignatureVerifier, Version,
+};
+use crate::ota::encoding::FileContext;
+
+/// Incomplete page buffered until all of its bytes were received
+#[derive(Clone, Copy)]
+struct Page<const PAGE: usize> {
+    data: [u8; PAGE],
+    /// Offset of the page from the beginning of the file
+    offset: usize,
+    /// Number of bytes received into the page
+    filled: usize,
+}
+
+/// [`OtaPal`] wrapper writing files in aligned pages of `PAGE` bytes,
+/// buffering up to `SLOTS` incomplete pages
+pub struct AlignedWrites<P: OtaPal, const PAGE: usize, const SLOTS: usize = 2> {
+    pal: P,
+    pages: [Option<Page<PAGE>>; SLOTS],
+    erased: u8,
+}
+
+impl<P: OtaPal, const PAGE: usize, const SLOTS: usize> AlignedWrites<P, PAGE, SLOTS> {
+    pub fn new(pal: P) -> Self {
+        // A block spanning pages may start and end in two incomplete ones
+        assert!(SLOTS >= 2);
+
+        Self {
+            pal,
+            pages: [None; SLOTS],
+            erased: 0xFF,
+        }
+    }
+
+    /// Value that the last page of the file is padded with. Defaults to
+    /// `0xFF`.
+    pub fn erased_value(self, erased: u8) -> Self {
+        Self { erased, ..self }
+    }
+
+    /// The wrapped PAL
+    pub fn inner(&mut self) -> &mut P {
+        &mut self.pal
+    }
+
+    fn discard(&mut self) {
+        self.pages = [None; SLOTS];
+    }
+
+    /// Number of bytes of the page at `page_offset` that belong to the file
+    fn page_len(file: &FileContext, page_offset: usize) -> usize {
+        match file.filesize.saturating_sub(page_offset) {
+            0 => PAGE,
+            remaining => core::cmp::min(PAGE, remaining),
+        }
+    }
+
+    /// Split the data at `offset` into the chunks of each page, as
+    /// `(page offset, chunk)`
+    fn chunks<'d>(offset: usize, data: &'d [u8]) -> impl Iterator<Item = (usize, &'d [u8])> {
+        let mut offset = offset;
+        let mut data = data;
+        core::iter::from_fn(move || {
+            if data.is_empty() {
+                return None;
+            }
+            let page_offset = offset - offset % PAGE;
+            let len = core::cmp::min(PAGE - (offset - page_offset), data.len());
+            let (chunk, rest) = data.split_at(len);
+            offset += len;
+            data = rest;
+            Some((page_offset, chunk))
+        })
+    }
+
+    fn buffered(&self, page_offset: usize) -> Option<usize> {
+        self.pages
+            .iter()
+            .position(|page| matches!(page, Some(page) if page.offset == page_offset))
+    }
+
+    /// Whether the chunks of a block that are not written straight away fit
+    /// into the buffered or the free pages
+    fn fits(&self, offset: usize, data: &[u8]) -> bool {
+        let free = self.pages.iter().filter(|page| page.is_none()).count();
+        let needed = Self::chunks(offset, data)
+            .filter(|(page_offset, chunk)| {
+                chunk.len() < PAGE && self.buffered(*page_offset).is_none()
+            })
+            .count();
+        needed <= free
+    }
+
+    /// Write the buffered page in `slot` and free the slot
+    fn flush(&mut self, file: &FileContext, slot: usize) -> Result<(), OtaPalError<P::Error>> {
+        if let Some(page) = self.pages[slot].take() {
+            self.pal.write_block(file, page.offset, &page.data)?;
+        }
+        Ok(())
+    }
+}
+
+impl<P: OtaPal, const PAGE: usize, const SLOTS: usize> OtaPal for AlignedWrites<P, PAGE, SLOTS> {
+    type Error = P::Error;
+
+    fn abort(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.discard();
+        self.pal.abort(file)
+    }
+
+    fn activate_new_image(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        self.pal.activate_new_image()
+    }
+
+    fn create_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.discard();
+        self.pal.create_file_for_rx(file)
+    }
+
+    fn get_platform_image_state(&self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+        self.pal.get_platform_image_state()
+    }
+
+    fn set_platform_image_state(
+        &mut self,
+        image_state: ImageState,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        self.pal.set_platform_image_state(image_state)
+    }
+
+    fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        self.pal.reset_device()
+    }
+
+    fn prepare_region(
+        &mut self,
+        file: &FileContext,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        self.pal.prepare_region(file, offset, len)
+    }
+
+    fn image_bank(&self, file: &FileContext) -> Option<Bank> {
+        self.pal.image_bank(file)
+    }
+
+    fn close_file(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        // Pages left incomplete, as the file is closed before all of it was
+        // received
+        for slot in 0..SLOTS {
+            self.flush(file, slot)?;
+        }
+        self.pal.close_file(file)
+    }
+
+    fn write_block(
+        &mut self,
+        file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        if !self.fits(block_offset, block_payload) {
+            return Err(OtaPalError::WouldBlock);
+        }
+
+        for (page_offset, chunk) in Self::chunks(block_offset, block_payload) {
+            if chunk.len() == PAGE {
+                self.pal.write_block(file, page_offset, chunk)?;
+                continue;
+            }
+
+            let slot = match self.buffered(page_offset) {
+                Some(slot) => slot,
+                None => {
+                    // Free slots were checked for above
+                    let slot = self.pages.iter().position(Option::is_none).unwrap();
+                    self.pages[slot] = Some(Page {
+                        data: [self.erased; PAGE],
+                        offset: page_offset,
+                        filled: 0,
+                    });
+                    slot
+                }
+            };
+
+            let complete = match self.pages[slot] {
+                Some(ref mut page) => {
+                    let start = block_offset.max(page_offset) - page_offset;
+                    page.data[start..start + chunk.len()].copy_from_slice(chunk);
+                    page.filled += chunk.len();
+                    page.filled >= Self::page_len(file, page_offset)
+                }
+                None => false,
+            };
+            if complete {
+                self.flush(file, slot)?;
+            }
+        }
+
+        Ok(block_payload.len())
+    }
+
+    fn read_block(
+        &mut self,
+        file: &FileContext,
+        block_offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        let len = self.pal.read_block(file, block_offset, buf)?;
+
+        // Data of incomplete pages is read from the buffer, as it was not
+        // written yet
+        for page in self.pages.iter().flatten() {
+            let start = block_offset.max(page.offset);
+            let end = (block_offset + len).min(page.offset + PAGE);
+            if start < end {
+                buf[start - block_offset..end - block_offset]
+                    .copy_from_slice(&page.data[start - page.offset..end - page.offset]);
+            }
+        }
+        Ok(len)
+    }
+
+    fn complete_callback(&mut self, event: OtaEvent) -> Result<(), OtaPalError<Self::Error>> {
+        self.pal.complete_callback(event)
+    }
+
+    fn on_progress(&mut self, blocks_received: usize, blocks_total: usize, bytes: usize) {
+        self.pal.on_progress(blocks_received, blocks_total, bytes)
+    }
+
+    fn on_long_operation(&mut self) {
+        self.pal.on_long_operation()
+    }
+
+    fn poll_ready(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        self.pal.poll_ready()
+    }
+
+    fn poll_close(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.pal.poll_close(file)
+    }
+
+    fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>> {
+        self.pal.get_active_firmware_version()
+    }
+
+    #[cfg(feature = "ota_sign_verify")]
+    fn code_signing_public_key(&self) -> Option<&[u8]> {
+        self.pal.code_signing_public_key()
+    }
+
+    #[cfg(feature = "ota_sign_verify")]
+    fn code_signing_certificate(&self, certfile: &str) -> Option<&[u8]> {
+        self.pal.code_signing_certificate(certfile)
+    }
+
+    fn signature_verifier(&mut self) -> Option<&mut dyn SignatureVerifier<Error = Self::Error>> {
+        self.pal.signature_verifier()
+    }
+
+    fn patch_applier(&mut self) -> Option<&mut dyn PatchApplier<Error = Self::Error>> {
+        self.pal.patch_applier()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::{config::Config, test::test_file_ctx};
+
+    #[derive(Default)]
+    struct FlashPal {
+        writes: Vec<(usize, Vec<u8>)>,
+    }
+
+    impl OtaPal for FlashPal {
+        type Error = ();
+
+        fn abort(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn create_file_for_rx(
+            &mut self,
+            _file: &FileContext,
+        ) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn get_platform_image_state(&self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+            Ok(PalImageState::Valid)
+        }
+
+        fn set_platform_image_state(
+            &mut self,
+            _image_state: ImageState,
+        ) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn close_file(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn write_block(
+            &mut self,
+            _file: &FileContext,
+            block_offset: usize,
+            block_payload: &[u8],
+        ) -> Result<usize, OtaPalError<Self::Error>> {
+            self.writes.push((block_offset, block_payload.to_vec()));
+            Ok(block_payload.len())
+        }
+
+        fn read_block(
+            &mut self,
+            _file: &FileContext,
+            _block_offset: usize,
+            buf: &mut [u8],
+        ) -> Result<usize, OtaPalError<Self::Error>> {
+            // Reads back erased flash only
+            buf.iter_mut().for_each(|b| *b = 0xFF);
+            Ok(buf.len())
+        }
+
+        fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>> {
+            Ok(Version::default())
+        }
+    }
+
+    fn file_of_size(filesize: usize) -> FileContext {
+        let mut file_ctx = test_file_ctx(&Config::default());
+        file_ctx.filesize = filesize;
+        file_ctx
+    }
+
+    #[test]
+    fn coalesces_in_order_blocks() {
+        let file = file_of_size(20);
+        let mut pal = AlignedWrites::<_, 8>::new(FlashPal::default());
+
+        pal.create_file_for_rx(&file).unwrap();
+        for (i, block) in [0u8, 1, 2, 3, 4].iter().enumerate() {
+            assert_eq!(pal.write_block(&file, i * 4, &[*block; 4]).unwrap(), 4);
+        }
+
+        // The last page is written as soon as the end of the file was received
+        assert_eq!(
+            pal.inner().writes,
+            vec![
+                (0, vec![0, 0, 0, 0, 1, 1, 1, 1]),
+                (8, vec![2, 2, 2, 2, 3, 3, 3, 3]),
+                (16, vec![4, 4, 4, 4, 0xFF, 0xFF, 0xFF, 0xFF]),
+            ]
+        );
+
+        pal.close_file(&file).unwrap();
+        assert_eq!(pal.inner().writes.len(), 3);
+    }
+
+    #[test]
+    fn blocks_spanning_pages() {
+        let file = file_of_size(12);
+        let mut pal = AlignedWrites::<_, 8>::new(FlashPal::default());
+
+        pal.create_file_for_rx(&file).unwrap();
+        pal.write_block(&file, 0, &[1; 6]).unwrap();
+        pal.write_block(&file, 6, &[2; 6]).unwrap();
+
+        assert_eq!(
+            pal.inner().writes,
+            vec![
+                (0, vec![1, 1, 1, 1, 1, 1, 2, 2]),
+                (8, vec![2, 2, 2, 2, 0xFF, 0xFF, 0xFF, 0xFF]),
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_order_blocks() {
+        let file = file_of_size(32);
+        let mut pal = AlignedWrites::<_, 8>::new(FlashPal::default()).erased_value(0x00);
+
+        pal.create_file_for_rx(&file).unwrap();
+        pal.write_block(&file, 4, &[1; 4]).unwrap();
+        assert!(pal.inner().writes.is_empty());
+
+        // Moving on to another page keeps the incomplete one buffered
+        pal.write_block(&file, 16, &[2; 4]).unwrap();
+        assert!(pal.inner().writes.is_empty());
+
+        // Without a free buffer, the block is to be requested again
+        assert!(matches!(
+            pal.write_block(&file, 8, &[3; 4]),
+            Err(OtaPalError::WouldBlock)
+        ));
+
+        // Each page is written once, when complete
+        pal.write_block(&file, 0, &[4; 4]).unwrap();
+        assert_eq!(pal.inner().writes, vec![(0, vec![4, 4, 4, 4, 1, 1, 1, 1])]);
+        pal.write_block(&file, 20, &[5; 4]).unwrap();
+        assert_eq!(pal.inner().writes[1], (16, vec![2, 2, 2, 2, 5, 5, 5, 5]));
+
+        // Buffered data reads back before it was written
+        pal.write_block(&file, 12, &[6; 4]).unwrap();
+        let mut buf = [0xAA; 4];
+        assert_eq!(pal.read_block(&file, 12, &mut buf).unwrap(), 4);
+        assert_eq!(buf, [6; 4]);
+
+        pal.write_block(&file, 8, &[3; 4]).unwrap();
+        assert_eq!(pal.inner().writes[2], (8, vec![3, 3, 3, 3, 6, 6, 6, 6]));
+        assert_eq!(pal.inner().writes.len(), 3);
+    }
+
+    #[test]
+    fn incomplete_pages_written_on_close() {
+        let file = file_of_size(32);
+        let mut pal = AlignedWrites::<_, 8>::new(FlashPal::default()).erased_value(0x00);
+
+        pal.create_file_for_rx(&file).unwrap();
+        pal.write_block(&file, 20, &[2; 4]).unwrap();
+        pal.close_file(&file).unwrap();
+        assert_eq!(pal.inner().writes, vec![(16, vec![0, 0, 0, 0, 2, 2, 2, 2])]);
+
+        // Aborting discards buffered data
+        pal.create_file_for_rx(&file).unwrap();
+        pal.write_block(&file, 0, &[3; 4]).unwrap();
+        pal.abort(&file).unwrap();
+        pal.close_file(&file).unwrap();
+        assert_eq!(pal.inner().writes.len(), 1);
+    }
+}
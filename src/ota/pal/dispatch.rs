@@ -0,0 +1,334 @@
+//! Routing of files to different PALs, based on the `fileType` of the job
+//! document.
+//!
+//! A host MCU might e.g. write its own firmware to internal flash, while
+//! forwarding modem or FPGA images to a different handler:
+//!
+//! ```ignore
+//! let mut dispatch = PalDispatch::<_, 2>::new(internal_flash);
+//! dispatch.register(MODEM_FILE_TYPE, &mut modem_pal)?;
+//! dispatch.register(FPGA_FILE_TYPE, &mut fpga_pal)?;
+//!
+//! let agent = OtaAgent::builder(&mqtt, &mqtt, timer, dispatch).build();
+//! ```
+//!
+//! All file operations are routed to the PAL registered for the `fileType` of
+//! the file, falling back to the primary PAL for files without a `fileType`,
+//! or with an unregistered one. Operations on the platform image, such as
+//! image states, activation and device resets, always go to the primary PAL.
+
+use super::{
+    Bank, ImageState, OtaEvent, OtaPal, OtaPalError, PalImageState, PatchApplier,
+    SignatureVerifier, Version,
+};
+use crate::ota::{encoding::FileContext, error::OtaError};
+
+/// PAL that files of a registered `fileType` are routed to
+pub type FilePal<'a, E> = dyn OtaPal<Error = E> + 'a;
+
+/// [`OtaPal`] dispatching files to up to `N` additional PALs, keyed by
+/// `fileType`.
+pub struct PalDispatch<'a, P: OtaPal, const N: usize> {
+    primary: P,
+    pals: heapless::Vec<(u32, &'a mut FilePal<'a, P::Error>), N>,
+    /// Index into `pals` of the PAL receiving the current file, if not the
+    /// primary PAL
+    active: Option<usize>,
+}
+
+impl<'a, P: OtaPal, const N: usize> PalDispatch<'a, P, N> {
+    pub fn new(primary: P) -> Self {
+        Self {
+            primary,
+            pals: heapless::Vec::new(),
+            active: None,
+        }
+    }
+
+    /// Route all files of `file_type` to `pal`, replacing any PAL previously
+    /// registered for it.
+    pub fn register(
+        &mut self,
+        file_type: u32,
+        pal: &'a mut FilePal<'a, P::Error>,
+    ) -> Result<(), OtaError> {
+        if let Some((_, registered)) = self.pals.iter_mut().find(|(t, _)| *t == file_type) {
+            *registered = pal;
+            return Ok(());
+        }
+
+        self.pals
+            .push((file_type, pal))
+            .map_err(|_| OtaError::Overflow)
+    }
+
+    /// The primary PAL, handling the platform image
+    pub fn primary(&mut self) -> &mut P {
+        &mut self.primary
+    }
+
+    fn index_of(&self, file: &FileContext) -> Option<usize> {
+        let file_type = file.file_type?;
+        self.pals.iter().position(|(t, _)| *t == file_type)
+    }
+
+    fn route(&mut self, index: Option<usize>) -> &mut FilePal<'_, P::Error> {
+        match index {
+            Some(i) => &mut *self.pals[i].1,
+            None => &mut self.primary,
+        }
+    }
+
+    fn pal_for(&mut self, file: &FileContext) -> &mut FilePal<'_, P::Error> {
+        let index = self.index_of(file);
+        self.route(index)
+    }
+}
+
+impl<'a, P: OtaPal, const N: usize> OtaPal for PalDispatch<'a, P, N> {
+    type Error = P::Error;
+
+    fn abort(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.active = None;
+        self.pal_for(file).abort(file)
+    }
+
+    fn activate_new_image(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        self.primary.activate_new_image()
+    }
+
+    fn create_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.active = self.index_of(file);
+        self.pal_for(file).create_file_for_rx(file)
+    }
+
+    fn resume_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.active = self.index_of(file);
+        self.pal_for(file).resume_file_for_rx(file)
+    }
+
+    fn prepare_region(
+        &mut self,
+        file: &FileContext,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        self.pal_for(file).prepare_region(file, offset, len)
+    }
+
+    fn image_bank(&self, file: &FileContext) -> Option<Bank> {
+        match self.index_of(file) {
+            Some(i) => self.pals[i].1.image_bank(file),
+            None => self.primary.image_bank(file),
+        }
+    }
+
+    fn get_platform_image_state(&self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+        self.primary.get_platform_image_state()
+    }
+
+    fn set_platform_image_state(
+        &mut self,
+        image_state: ImageState,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        self.primary.set_platform_image_state(image_state)
+    }
+
+    fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        self.primary.reset_device()
+    }
+
+    fn close_file(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.active = None;
+        self.pal_for(file).close_file(file)
+    }
+
+    fn write_block(
+        &mut self,
+        file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        self.pal_for(file)
+            .write_block(file, block_offset, block_payload)
+    }
+
+    fn read_block(
+        &mut self,
+        file: &FileContext,
+        block_offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        self.pal_for(file).read_block(file, block_offset, buf)
+    }
+
+    fn complete_callback(&mut self, event: OtaEvent) -> Result<(), OtaPalError<Self::Error>> {
+        self.primary.complete_callback(event)
+    }
+
+    fn on_progress(&mut self, blocks_received: usize, blocks_total: usize, bytes: usize) {
+        let active = self.active;
+        self.route(active)
+            .on_progress(blocks_received, blocks_total, bytes)
+    }
+
+    fn on_long_operation(&mut self) {
+        self.primary.on_long_operation()
+    }
+
+    fn poll_ready(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        self.route(self.active).poll_ready()
+    }
+
+    fn poll_close(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.pal_for(file).poll_close(file)
+    }
+
+    fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>> {
+        self.primary.get_active_firmware_version()
+    }
+
+    #[cfg(feature = "ota_sign_verify")]
+    fn code_signing_public_key(&self) -> Option<&[u8]> {
+        match self.active {
+            Some(i) => self.pals[i].1.code_signing_public_key(),
+            None => self.primary.code_signing_public_key(),
+        }
+    }
+
+    #[cfg(feature = "ota_sign_verify")]
+    fn code_signing_certificate(&self, certfile: &str) -> Option<&[u8]> {
+        match self.active {
+            Some(i) => self.pals[i].1.code_signing_certificate(certfile),
+            None => self.primary.code_signing_certificate(certfile),
+        }
+    }
+
+    fn signature_verifier(&mut self) -> Option<&mut dyn SignatureVerifier<Error = Self::Error>> {
+        let active = self.active;
+        self.route(active).signature_verifier()
+    }
+
+    fn patch_applier(&mut self) -> Option<&mut dyn PatchApplier<Error = Self::Error>> {
+        let active = self.active;
+        self.route(active).patch_applier()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::{config::Config, test::test_file_ctx};
+
+    #[derive(Default)]
+    struct RecordingPal {
+        created: usize,
+        writes: Vec<usize>,
+        closed: usize,
+        resets: usize,
+    }
+
+    impl OtaPal for RecordingPal {
+        type Error = ();
+
+        fn abort(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn create_file_for_rx(
+            &mut self,
+            _file: &FileContext,
+        ) -> Result<(), OtaPalError<Self::Error>> {
+            self.created += 1;
+            Ok(())
+        }
+
+        fn get_platform_image_state(&self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+            Ok(PalImageState::Valid)
+        }
+
+        fn set_platform_image_state(
+            &mut self,
+            _image_state: ImageState,
+        ) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+            self.resets += 1;
+            Ok(())
+        }
+
+        fn close_file(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+            self.closed += 1;
+            Ok(())
+        }
+
+        fn write_block(
+            &mut self,
+            _file: &FileContext,
+            block_offset: usize,
+            block_payload: &[u8],
+        ) -> Result<usize, OtaPalError<Self::Error>> {
+            self.writes.push(block_offset);
+            Ok(block_payload.len())
+        }
+
+        fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>> {
+            Ok(Version::default())
+        }
+    }
+
+    fn file_of_type(file_type: Option<u32>) -> FileContext {
+        let mut file_ctx = test_file_ctx(&Config::default());
+        file_ctx.file_type = file_type;
+        file_ctx
+    }
+
+    #[test]
+    fn routes_by_file_type() {
+        let mut modem = RecordingPal::default();
+        let mut dispatch = PalDispatch::<_, 2>::new(RecordingPal::default());
+        dispatch.register(101, &mut modem).unwrap();
+
+        for file in &[
+            file_of_type(Some(0)),
+            file_of_type(None),
+            file_of_type(Some(7)),
+        ] {
+            dispatch.create_file_for_rx(file).unwrap();
+            dispatch.write_block(file, 0, &[0; 4]).unwrap();
+            dispatch.close_file(file).unwrap();
+        }
+
+        let modem_file = file_of_type(Some(101));
+        dispatch.create_file_for_rx(&modem_file).unwrap();
+        dispatch.write_block(&modem_file, 256, &[0; 4]).unwrap();
+        dispatch.close_file(&modem_file).unwrap();
+
+        // Activating always resets through the primary PAL
+        dispatch.activate_new_image().unwrap();
+
+        let primary = dispatch.primary();
+        assert_eq!(primary.created, 3);
+        assert_eq!(primary.writes, vec![0, 0, 0]);
+        assert_eq!(primary.closed, 3);
+        assert_eq!(primary.resets, 1);
+
+        drop(dispatch);
+        assert_eq!(modem.created, 1);
+        assert_eq!(modem.writes, vec![256]);
+        assert_eq!(modem.closed, 1);
+        assert_eq!(modem.resets, 0);
+    }
+
+    #[test]
+    fn register_limit() {
+        let mut modem = RecordingPal::default();
+        let mut fpga = RecordingPal::default();
+        let mut dispatch = PalDispatch::<_, 1>::new(RecordingPal::default());
+
+        dispatch.register(101, &mut modem).unwrap();
+        assert_eq!(dispatch.register(102, &mut fpga), Err(OtaError::Overflow));
+    }
+}
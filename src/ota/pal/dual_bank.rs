@@ -0,0 +1,235 @@
+//! Helper for platforms with A/B flash banks, such as dual-bank STM32 and
+//! nRF devices.
+//!
+//! New images are always written to the inactive bank. Activating the image
+//! swaps the banks and resets the device, such that it boots into the new
+//! image in self test. Rejecting the image while in self test swaps back to
+//! the previous image.
+//!
+//! Platforms implementing [`OtaPalDualBank`] can forward the image state
+//! handling of their [`OtaPal`] implementation to it:
+//!
+//! ```ignore
+//! fn set_platform_image_state(&mut self, state: ImageState) -> Result<(), OtaPalError<Self::Error>> {
+//!     self.set_dual_bank_image_state(state)
+//! }
+//!
+//! fn activate_new_image(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+//!     self.activate_dual_bank()
+//! }
+//!
+//! fn image_bank(&self, _file: &FileContext) -> Option<Bank> {
+//!     self.dual_bank_image()
+//! }
+//! ```
+
+use super::{ImageState, OtaPal, OtaPalError, PalImageState};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum Bank {
+    A,
+    B,
+}
+
+impl Bank {
+    /// The opposite bank
+    pub fn other(self) -> Self {
+        match self {
+            Bank::A => Bank::B,
+            Bank::B => Bank::A,
+        }
+    }
+}
+
+/// Extension of [`OtaPal`] for dual-bank platforms.
+pub trait OtaPalDualBank: OtaPal {
+    /// Bank the running firmware was booted from.
+    fn active_bank(&self) -> Result<Bank, OtaPalError<Self::Error>>;
+
+    /// Make the device boot from the currently inactive bank on the next
+    /// reset.
+    fn swap_banks(&mut self) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Mark the image in `bank` as invalid, such that it is never booted.
+    fn invalidate_bank(&mut self, bank: Bank) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Implementation of [`OtaPal::set_platform_image_state`] for dual-bank
+    /// platforms.
+    fn set_dual_bank_image_state(
+        &mut self,
+        image_state: ImageState,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        match image_state {
+            // The banks were already swapped on activation, nothing more
+            // to do to keep running the new image
+            ImageState::Accepted | ImageState::Testing | ImageState::Unknown => Ok(()),
+            ImageState::Rejected | ImageState::Aborted => {
+                if self.get_platform_image_state()? == PalImageState::PendingCommit {
+                    // Running the new image in self test, so roll back to the
+                    // previous image on the next reset
+                    self.swap_banks()
+                } else {
+                    // Running the previous image, so the new image (if any)
+                    // lives in the inactive bank
+                    let inactive = self.active_bank()?.other();
+                    self.invalidate_bank(inactive)
+                }
+            }
+        }
+    }
+
+    /// Implementation of [`OtaPal::image_bank`] for dual-bank platforms. New
+    /// images are always received into the inactive bank.
+    fn dual_bank_image(&self) -> Option<Bank> {
+        self.active_bank().ok().map(Bank::other)
+    }
+
+    /// Implementation of [`OtaPal::activate_new_image`] for dual-bank
+    /// platforms.
+    fn activate_dual_bank(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        self.swap_banks()?;
+        self.reset_device()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::{config::Config, encoding::FileContext, pal::Version, test::test_file_ctx};
+
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        Swap,
+        Invalidate(Bank),
+        Reset,
+    }
+
+    struct DualBankPal {
+        active: Bank,
+        image_state: PalImageState,
+        calls: Vec<Call>,
+    }
+
+    impl DualBankPal {
+        fn new(active: Bank, image_state: PalImageState) -> Self {
+            Self {
+                active,
+                image_state,
+                calls: Vec::new(),
+            }
+        }
+    }
+
+    impl OtaPal for DualBankPal {
+        type Error = ();
+
+        fn abort(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn activate_new_image(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+            self.activate_dual_bank()
+        }
+
+        fn create_file_for_rx(
+            &mut self,
+            _file: &FileContext,
+        ) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn get_platform_image_state(&self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+            Ok(self.image_state)
+        }
+
+        fn set_platform_image_state(
+            &mut self,
+            image_state: ImageState,
+        ) -> Result<(), OtaPalError<Self::Error>> {
+            self.set_dual_bank_image_state(image_state)
+        }
+
+        fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+            self.calls.push(Call::Reset);
+            Ok(())
+        }
+
+        fn close_file(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn write_block(
+            &mut self,
+            _file: &FileContext,
+            _block_offset: usize,
+            block_payload: &[u8],
+        ) -> Result<usize, OtaPalError<Self::Error>> {
+            Ok(block_payload.len())
+        }
+
+        fn image_bank(&self, _file: &FileContext) -> Option<Bank> {
+            self.dual_bank_image()
+        }
+
+        fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>> {
+            Ok(Version::default())
+        }
+    }
+
+    impl OtaPalDualBank for DualBankPal {
+        fn active_bank(&self) -> Result<Bank, OtaPalError<Self::Error>> {
+            Ok(self.active)
+        }
+
+        fn swap_banks(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+            self.calls.push(Call::Swap);
+            Ok(())
+        }
+
+        fn invalidate_bank(&mut self, bank: Bank) -> Result<(), OtaPalError<Self::Error>> {
+            self.calls.push(Call::Invalidate(bank));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn activate_swaps_and_resets() {
+        let mut pal = DualBankPal::new(Bank::A, PalImageState::Valid);
+        pal.activate_new_image().unwrap();
+        assert_eq!(pal.calls, vec![Call::Swap, Call::Reset]);
+    }
+
+    #[test]
+    fn image_in_inactive_bank() {
+        let file_ctx = test_file_ctx(&Config::default());
+        let pal = DualBankPal::new(Bank::A, PalImageState::Valid);
+        assert_eq!(pal.image_bank(&file_ctx), Some(Bank::B));
+    }
+
+    #[test]
+    fn accept_keeps_banks() {
+        let mut pal = DualBankPal::new(Bank::B, PalImageState::PendingCommit);
+        pal.set_platform_image_state(ImageState::Testing).unwrap();
+        pal.set_platform_image_state(ImageState::Accepted).unwrap();
+        assert!(pal.calls.is_empty());
+    }
+
+    #[test]
+    fn reject_in_self_test_rolls_back() {
+        let mut pal = DualBankPal::new(Bank::B, PalImageState::PendingCommit);
+        pal.set_platform_image_state(ImageState::Rejected).unwrap();
+        assert_eq!(pal.calls, vec![Call::Swap]);
+    }
+
+    #[test]
+    fn abort_download_invalidates_inactive_bank() {
+        let mut pal = DualBankPal::new(Bank::A, PalImageState::Valid);
+        pal.set_platform_image_state(ImageState::Aborted).unwrap();
+        assert_eq!(pal.calls, vec![Call::Invalidate(Bank::B)]);
+
+        let mut pal = DualBankPal::new(Bank::B, PalImageState::Valid);
+        pal.set_platform_image_state(ImageState::Rejected).unwrap();
+        assert_eq!(pal.calls, vec![Call::Invalidate(Bank::A)]);
+    }
+}
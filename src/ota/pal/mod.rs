@@ -0,0 +1,633 @@
+//! Platform abstraction trait for OTA updates
+
+pub mod aligned;
+pub mod dispatch;
+pub mod dual_bank;
+
+use core::fmt::Write;
+use core::str::FromStr;
+
+use crate::rustot_log;
+
+use super::encoding::{json::Signature, FileContext};
+
+pub use aligned::AlignedWrites;
+pub use dispatch::PalDispatch;
+pub use dual_bank::{Bank, OtaPalDualBank};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum ImageState {
+    Unknown,
+    Aborted,
+    Rejected,
+    Accepted,
+    Testing,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OtaPalError<E: Copy> {
+    SignatureCheckFailed,
+    FileWriteFailed,
+    FileTooLarge,
+    FileCloseFailed,
+    BadFileHandle,
+    Unsupported,
+    BadImageState,
+    CommitFailed,
+    VersionCheck,
+    /// The PAL can not accept the write right now, e.g. while forwarding
+    /// previous blocks to a co-processor
+    WouldBlock,
+    Custom(E),
+}
+
+/// Platform specific PAL errors, reduced to a numeric code when reported to
+/// the application through [`OtaError::Pal`] and in the job status details.
+///
+/// Implemented for `()`, reporting no code, and for the primitive integer
+/// types. Fieldless error enums are typically implemented as
+/// `fn code(&self) -> u32 { *self as u32 }`.
+///
+/// [`OtaError::Pal`]: super::error::OtaError::Pal
+pub trait PalErrorCode: Copy {
+    fn code(&self) -> u32;
+}
+
+impl PalErrorCode for () {
+    fn code(&self) -> u32 {
+        0
+    }
+}
+
+macro_rules! impl_pal_error_code {
+    ($($t:ty),*) => {
+        $(
+            impl PalErrorCode for $t {
+                fn code(&self) -> u32 {
+                    *self as u32
+                }
+            }
+        )*
+    };
+}
+
+impl_pal_error_code!(u8, u16, u32, i8, i16, i32);
+
+/// [`OtaPalError`] without the platform specific error type, as carried by
+/// [`OtaError::Pal`](super::error::OtaError::Pal)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum PalError {
+    SignatureCheckFailed,
+    FileWriteFailed,
+    FileTooLarge,
+    FileCloseFailed,
+    BadFileHandle,
+    Unsupported,
+    BadImageState,
+    CommitFailed,
+    VersionCheck,
+    WouldBlock,
+    /// [`OtaPalError::Custom`], with the [`PalErrorCode`] of the error
+    Custom(u32),
+}
+
+impl PalError {
+    /// Status details value of the error
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PalError::SignatureCheckFailed => "signature",
+            PalError::FileWriteFailed => "write",
+            PalError::FileTooLarge => "too_large",
+            PalError::FileCloseFailed => "close",
+            PalError::BadFileHandle => "bad_handle",
+            PalError::Unsupported => "unsupported",
+            PalError::BadImageState => "img_state",
+            PalError::CommitFailed => "commit",
+            PalError::VersionCheck => "version",
+            PalError::WouldBlock => "would_block",
+            PalError::Custom(_) => "custom",
+        }
+    }
+}
+
+impl<E: PalErrorCode> From<OtaPalError<E>> for PalError {
+    fn from(e: OtaPalError<E>) -> Self {
+        match e {
+            OtaPalError::SignatureCheckFailed => Self::SignatureCheckFailed,
+            OtaPalError::FileWriteFailed => Self::FileWriteFailed,
+            OtaPalError::FileTooLarge => Self::FileTooLarge,
+            OtaPalError::FileCloseFailed => Self::FileCloseFailed,
+            OtaPalError::BadFileHandle => Self::BadFileHandle,
+            OtaPalError::Unsupported => Self::Unsupported,
+            OtaPalError::BadImageState => Self::BadImageState,
+            OtaPalError::CommitFailed => Self::CommitFailed,
+            OtaPalError::VersionCheck => Self::VersionCheck,
+            OtaPalError::WouldBlock => Self::WouldBlock,
+            OtaPalError::Custom(e) => Self::Custom(e.code()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum PalImageState {
+    /// the new firmware image is in the self test phase
+    PendingCommit,
+    /// the new firmware image is already committed
+    Valid,
+    /// the new firmware image is invalid or non-existent
+    Invalid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum OtaEvent {
+    /// OTA update is authenticated and ready to activate.
+    Activate,
+    /// OTA update failed. Unable to use this update.
+    Fail,
+    /// OTA job is now ready for optional user self tests.
+    StartTest,
+    /// OTA job is in self test, awaiting an explicit `accept_image()` from
+    /// the application.
+    SelfTestPending,
+
+    SelfTestFailed,
+
+    UpdateComplete,
+}
+
+#[derive(Debug, Clone, Eq)]
+pub struct Version {
+    major: u8,
+    minor: u8,
+    patch: u8,
+}
+
+#[cfg(feature = "defmt-impl")]
+impl defmt::Format for Version {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{=u8}.{=u8}.{=u8}", self.major, self.minor, self.patch)
+    }
+}
+
+impl Default for Version {
+    fn default() -> Self {
+        Self::new(0, 0, 0)
+    }
+}
+
+impl FromStr for Version {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut iter = s.split('.');
+        Ok(Self {
+            major: iter.next().and_then(|v| v.parse().ok()).ok_or(())?,
+            minor: iter.next().and_then(|v| v.parse().ok()).ok_or(())?,
+            patch: iter.next().and_then(|v| v.parse().ok()).ok_or(())?,
+        })
+    }
+}
+
+impl Version {
+    pub fn new(major: u8, minor: u8, patch: u8) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    pub fn to_string<const L: usize>(&self) -> heapless::String<L> {
+        let mut s = heapless::String::new();
+        s.write_fmt(format_args!("{}.{}.{}", self.major, self.minor, self.patch))
+            .unwrap();
+        s
+    }
+}
+
+impl core::cmp::PartialEq for Version {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.major == other.major && self.minor == other.minor && self.patch == other.patch
+    }
+}
+
+impl core::cmp::PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl core::cmp::Ord for Version {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        match self.major.cmp(&other.major) {
+            core::cmp::Ordering::Equal => {}
+            r => return r,
+        }
+
+        match self.minor.cmp(&other.minor) {
+            core::cmp::Ordering::Equal => {}
+            r => return r,
+        }
+
+        match self.patch.cmp(&other.patch) {
+            core::cmp::Ordering::Equal => {}
+            r => return r,
+        }
+
+        core::cmp::Ordering::Equal
+    }
+}
+/// Applies delta images on top of the currently running firmware.
+///
+/// When a file in the job document is marked as `delta`, the downloaded data
+/// is a patch (e.g. detools or bsdiff) rather than a full image. Instead of
+/// writing the blocks through [`OtaPal::write_block`], the agent feeds them
+/// to the patch applier, which is responsible for reconstructing the new
+/// image from the patch and the running firmware, and writing it to its
+/// destination.
+///
+/// Patches are always fed sequentially, starting at offset zero. Blocks
+/// arriving out of order are dropped by the agent, and requested again later.
+pub trait PatchApplier {
+    type Error: PalErrorCode;
+
+    /// Prepare for applying a new patch.
+    ///
+    /// - `file`: [`FileContext`] File description of the delta image
+    fn begin(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Apply the next chunk of the patch.
+    ///
+    /// - `file`: [`FileContext`] File description of the delta image
+    /// - `patch_offset`: Byte offset of `patch` from the beginning of the
+    ///   patch file.
+    /// - `patch`: Byte array of patch data.
+    fn apply(
+        &mut self,
+        file: &FileContext,
+        patch_offset: usize,
+        patch: &[u8],
+    ) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Finish applying the patch, flushing any data still buffered by the
+    /// patch applier. This is called before [`OtaPal::close_file`].
+    ///
+    /// - `file`: [`FileContext`] File description of the delta image
+    fn finish(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>>;
+}
+
+/// Verifies the code signature of received files.
+///
+/// The agent streams the content of each file through the verifier, in order,
+/// as it is being received. Blocks received out of order are read back through
+/// [`OtaPal::read_block`] once the file is complete. This allows offloading
+/// the verification to hardware crypto accelerators, such as the ATECC608 or
+/// SE050.
+pub trait SignatureVerifier {
+    type Error: PalErrorCode;
+
+    /// Reset the verifier, in preparation of verifying a new file
+    fn reset(&mut self);
+
+    /// Feed the next chunk of file data to the verifier
+    fn update(&mut self, data: &[u8]) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Check `signature` against all data fed since the last reset.
+    fn finalize(&mut self, signature: &Signature) -> Result<(), OtaPalError<Self::Error>>;
+}
+
+/// Platform abstraction layer for OTA jobs
+pub trait OtaPal {
+    type Error: PalErrorCode;
+
+    /// OTA abort.
+    ///
+    /// The user may register a callback function when initializing the OTA
+    /// Agent. This callback is used to override the behavior of how a job is
+    /// aborted.
+    ///
+    /// - `file`: [`FileContext`] File description of the job being aborted
+    fn abort(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Activate the newest MCU image received via OTA.
+    ///
+    /// This function shall do whatever is necessary to activate the newest MCU
+    /// firmware received via OTA. It is typically just a reset of the device.
+    ///
+    /// **note** This function SHOULD not return. If it does, the platform
+    /// doesn't support an automatic reset or an error occurred.
+    ///
+    /// **return**: The OTA PAL layer error code combined with the MCU specific
+    /// error code.
+    fn activate_new_image(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        self.reset_device()
+    }
+
+    /// OTA create file to store received data.
+    ///
+    /// The user may register a callback function when initializing the OTA
+    /// Agent. This callback is used to override the behavior of how a new file
+    /// is created.
+    ///
+    /// - `file`: [`FileContext`] File description of the job being aborted
+    fn create_file_for_rx(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Reopen a partially received file, after download progress was restored
+    /// from a [`BitmapStorage`](crate::ota::storage::BitmapStorage).
+    ///
+    /// Unlike `create_file_for_rx`, this must leave already written data
+    /// intact. Returning an error makes the agent discard the stored progress
+    /// and fall back to `create_file_for_rx`, restarting the download from
+    /// block 0.
+    ///
+    /// - `file`: [`FileContext`] File description of the job being resumed
+    fn resume_file_for_rx(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        Err(OtaPalError::Unsupported)
+    }
+
+    /// Prepare the region of `len` bytes at `offset` from the beginning of
+    /// the file for writing, e.g. by erasing the flash sectors it spans.
+    ///
+    /// The agent calls this ahead of requesting the blocks covering the
+    /// region, and at most once for every byte of the file, such that erases
+    /// are spread across the download instead of all happening on file
+    /// creation. Regions are neither sector aligned nor sector sized, so the
+    /// implementation has to keep track of the sectors already erased.
+    /// Only called for files that are neither compressed nor delta images.
+    ///
+    /// - `file`: [`FileContext`] File description of the file being received
+    /// - `offset`: Byte offset of the region from the beginning of the file
+    /// - `len`: Length of the region in bytes
+    fn prepare_region(
+        &mut self,
+        _file: &FileContext,
+        _offset: usize,
+        _len: usize,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    /// Bank or slot the new image of `file` was written to, on platforms
+    /// with more than one. Queried once the file is received, and reported to
+    /// the application with [`AgentEvent::Completed`], such that bootloader
+    /// configuration can be kept in sync with the PAL. Dual-bank platforms can
+    /// forward this to [`OtaPalDualBank::dual_bank_image`].
+    ///
+    /// - `file`: [`FileContext`] File description of the received file
+    ///
+    /// [`AgentEvent::Completed`]: crate::ota::event::AgentEvent::Completed
+    fn image_bank(&self, _file: &FileContext) -> Option<Bank> {
+        None
+    }
+
+    /// Get the state of the OTA update image.
+    ///
+    /// We read this at OTA_Init time and when the latest OTA job reports itself
+    /// in self test. If the update image is in the "pending commit" state, we
+    /// start a self test timer to assure that we can successfully connect to
+    /// the OTA services and accept the OTA update image within a reasonable
+    /// amount of time (user configurable). If we don't satisfy that
+    /// requirement, we assume there is something wrong with the firmware and
+    /// automatically reset the device, causing it to roll back to the
+    /// previously known working code.
+    ///
+    /// If the update image state is not in "pending commit," the self test
+    /// timer is not started.
+    ///
+    /// **return** An [`PalImageState`].
+    fn get_platform_image_state(&self) -> Result<PalImageState, OtaPalError<Self::Error>>;
+
+    /// Attempt to set the state of the OTA update image.
+    ///
+    /// Do whatever is required by the platform to Accept/Reject the OTA update
+    /// image (or bundle). Refer to the PAL implementation to determine what
+    /// happens on your platform.
+    ///
+    /// - `state`: [`ImageState`] The desired state of the OTA update image.
+    ///
+    /// **return** The [`OtaPalError`] error code combined with the MCU specific
+    /// error code.
+    fn set_platform_image_state(
+        &mut self,
+        image_state: ImageState,
+    ) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Reset the device.
+    ///
+    /// This function shall reset the MCU and cause a reboot of the system.
+    ///
+    /// **note** This function SHOULD not return. If it does, the platform
+    /// doesn't support an automatic reset or an error occurred.
+    ///
+    /// **return** The OTA PAL layer error code combined with the MCU specific
+    /// error code.
+    fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Authenticate and close the underlying receive file in the specified OTA
+    /// context.
+    ///
+    /// If the signature verification fails, file close should still be
+    /// attempted.
+    ///
+    /// - `file`: [`FileContext`] File description of the job being aborted
+    ///
+    /// **return** The OTA PAL layer error code combined with the MCU specific
+    /// error code.
+    ///
+    /// PALs verifying large images, e.g. hashing an image in external flash,
+    /// may return [`OtaPalError::WouldBlock`] and carry on in the background
+    /// instead. The file is then completed once the application calls
+    /// [`OtaAgent::finalize_file`], and [`OtaPal::poll_close`] succeeds.
+    ///
+    /// [`OtaAgent::finalize_file`]: crate::ota::agent::OtaAgent::finalize_file
+    fn close_file(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>>;
+
+    /// Write a block of data to the specified file at the given offset.
+    ///
+    /// - `file`: [`FileContext`] File description of the job being aborted.
+    /// - `block_offset`: Byte offset to write to from the beginning of the
+    ///   file.
+    /// - `block_payload`: Byte array of data to write.
+    ///
+    /// **return** The number of bytes written on a success, or a negative error
+    /// code from the platform abstraction layer.
+    ///
+    /// PALs forwarding blocks over a slow transport may return
+    /// [`OtaPalError::WouldBlock`] instead of buffering: the block is dropped
+    /// and requested again, and no further blocks are requested until
+    /// [`OtaPal::poll_ready`] succeeds. This is only supported for files
+    /// that are neither compressed nor delta images.
+    fn write_block(
+        &mut self,
+        file: &FileContext,
+        block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>>;
+
+    /// Read back a block of data from the specified file at the given offset.
+    ///
+    /// Used by the agent to verify the file after it has been received.
+    ///
+    /// - `file`: [`FileContext`] File description of the job.
+    /// - `block_offset`: Byte offset to read from, from the beginning of the
+    ///   file.
+    /// - `buf`: Buffer to read into.
+    ///
+    /// **return** The number of bytes read into `buf`.
+    fn read_block(
+        &mut self,
+        _file: &FileContext,
+        _block_offset: usize,
+        _buf: &mut [u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        Err(OtaPalError::Unsupported)
+    }
+
+    /// OTA update complete.
+    ///
+    /// The user may register a callback function when initializing the OTA
+    /// Agent. This callback is used to notify the main application when the OTA
+    /// update job is complete. Typically, it is used to reset the device after
+    /// a successful update by calling `OtaPal::activate_new_image()` and may
+    /// also be used to kick off user specified self tests during the Self Test
+    /// phase. If the user does not supply a custom callback function, a default
+    /// callback handler is used that automatically calls
+    /// `OtaPal::activate_new_image()` after a successful update.
+    ///
+    /// **note**:
+    ///
+    /// The callback function is called with one of the following arguments:
+    ///
+    /// - `OtaEvent::Activate`      OTA update is authenticated and ready to
+    ///   activate.
+    /// - `OtaEvent::Fail`          OTA update failed. Unable to use this
+    ///   update.
+    /// - `OtaEvent::StartTest`     OTA job is now ready for optional user self
+    ///   tests.
+    ///
+    /// When `OtaEvent::Activate` is received, the job status details have been
+    /// updated with the state as ready for Self Test. After reboot, the new
+    /// firmware will (normally) be notified that it is in the Self Test phase
+    /// via the callback and the application may then optionally run its own
+    /// tests before committing the new image.
+    ///
+    /// If the callback function is called with a result of `OtaEvent::Fail`,
+    /// the OTA update job has failed in some way and should be rejected.
+    ///
+    /// - `event` [`OtaEvent`] An OTA update event from the `OtaEvent` enum.
+    fn complete_callback(&mut self, event: OtaEvent) -> Result<(), OtaPalError<Self::Error>> {
+        match event {
+            OtaEvent::Activate => self.activate_new_image(),
+            OtaEvent::Fail | OtaEvent::UpdateComplete | OtaEvent::SelfTestPending => {
+                // Nothing special to do. The OTA agent handles it
+                Ok(())
+            }
+            OtaEvent::StartTest => {
+                // Accept the image since it was a good transfer
+                // and networking and services are all working.
+                self.set_platform_image_state(ImageState::Accepted)?;
+                Ok(())
+            }
+            OtaEvent::SelfTestFailed => {
+                // Requires manual activation of previous image as self-test for
+                // new image downloaded failed.*/
+                rustot_log!(error, "Self-test failed, shutting down OTA Agent.");
+
+                // Shutdown OTA Agent, if it is required that the unsubscribe operations are not
+                // performed while shutting down please set the second parameter to 0 instead of 1.
+                Ok(())
+            }
+        }
+    }
+
+    /// Download progress of the file currently being received.
+    ///
+    /// Called by the agent after every ingested block, allowing the
+    /// application to e.g. update a display or publish progress telemetry.
+    ///
+    /// - `blocks_received`: Number of blocks of the file received so far.
+    /// - `blocks_total`: Total number of blocks of the file.
+    /// - `bytes`: Number of bytes of the file received so far.
+    fn on_progress(&mut self, _blocks_received: usize, _blocks_total: usize, _bytes: usize) {}
+
+    /// Hook invoked around operations that may take long enough to trip a
+    /// watchdog, e.g. to feed an independent watchdog timer.
+    ///
+    /// The agent calls this before creating and closing a file, and
+    /// repeatedly while reading back, verifying and decompressing files.
+    /// Implementations erasing or writing large regions of flash should call
+    /// it from within those loops as well.
+    fn on_long_operation(&mut self) {}
+
+    /// Poll whether the PAL is ready to accept blocks again, after
+    /// [`OtaPal::write_block`] returned [`OtaPalError::WouldBlock`].
+    ///
+    /// **return** [`OtaPalError::WouldBlock`] while previous writes are still
+    /// in flight.
+    fn poll_ready(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    /// Poll the outcome of closing `file`, after [`OtaPal::close_file`]
+    /// returned [`OtaPalError::WouldBlock`].
+    ///
+    /// **return** [`OtaPalError::WouldBlock`] while the file is still being
+    /// closed, or the result of closing it.
+    fn poll_close(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    ///
+    fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>>;
+
+    /// Public key used to verify the code signature of received files, as a
+    /// SEC1 encoded P-256 point.
+    ///
+    /// Only used if no key was compiled in through
+    /// [`OtaAgentBuilder::code_signing_key`](crate::ota::builder::OtaAgentBuilder::code_signing_key).
+    #[cfg(feature = "ota_sign_verify")]
+    fn code_signing_public_key(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// DER encoded X.509 code signing certificate referenced by `certfile` in
+    /// the job document, e.g. a path or an ID of a certificate in storage.
+    ///
+    /// The certificate must be issued by the root key set through
+    /// [`OtaAgentBuilder::code_signing_root_key`](crate::ota::builder::OtaAgentBuilder::code_signing_root_key),
+    /// and takes precedence over [`Self::code_signing_public_key`].
+    #[cfg(feature = "ota_sign_verify")]
+    fn code_signing_certificate(&self, _certfile: &str) -> Option<&[u8]> {
+        None
+    }
+
+    /// Signature verifier that received files are streamed through.
+    ///
+    /// Platforms checking signatures elsewhere, e.g. in `close_file` or in
+    /// the bootloader, can leave this unimplemented.
+    fn signature_verifier(&mut self) -> Option<&mut dyn SignatureVerifier<Error = Self::Error>> {
+        None
+    }
+
+    /// Patch applier used for files marked as delta images.
+    ///
+    /// Platforms without support for delta updates can leave this
+    /// unimplemented, causing delta images to be rejected with
+    /// `OtaPalError::Unsupported`.
+    fn patch_applier(&mut self) -> Option<&mut dyn PatchApplier<Error = Self::Error>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Platform abstraction layer tests.
+    //!
+    //! These tests utilize the `MockPal` to test that the `OtaPal` trait
+    //! functions are called correctly.
+}
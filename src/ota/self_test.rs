@@ -0,0 +1,27 @@
+//! Application checks of a new image, before it is accepted.
+//!
+//! After activating a new image, the device boots into it with the job still
+//! in self test. Rather than accepting any image that manages to reconnect,
+//! the application can register a [`SelfTest`] on the builder, checking e.g.
+//! connectivity or attached sensors before the image is committed.
+
+/// Outcome of the application self test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub enum SelfTestResult {
+    /// Commit the new image, and report the job as succeeded
+    Accept,
+    /// Reject the new image, report the job as failed and reset the device to
+    /// roll back to the previous image
+    Reject,
+}
+
+/// Application self test of a newly activated image.
+pub trait SelfTest {
+    /// Run the self test of the image currently under test.
+    ///
+    /// Called once the agent receives the job in self test, while the
+    /// platform image is pending commit. The agent sets the platform image
+    /// state and updates the job status according to the returned result.
+    fn run(&mut self) -> SelfTestResult;
+}
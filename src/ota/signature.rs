@@ -0,0 +1,156 @@
+//! Built-in verification of `sig-sha256-ecdsa` code signatures.
+//!
+//! The signature from the job document is the base64 encoded, DER encoded
+//! ECDSA P-256 signature of the SHA-256 digest of the file, as produced by AWS
+//! code signing.
+
+use p256::ecdsa::{signature::DigestVerifier, Signature as EcdsaSignature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use super::encoding::{json::Signature, FileContext};
+use super::error::OtaError;
+use crate::rustot_log;
+
+/// Maximum size of a DER encoded ECDSA P-256 signature
+const MAX_DER_SIGNATURE_LEN: usize = 72;
+
+/// Verify the signature of `file_ctx` against the digest of `hasher`, using
+/// a SEC1 encoded `public_key`.
+pub(crate) fn verify(
+    file_ctx: &FileContext,
+    public_key: &[u8],
+    hasher: Sha256,
+) -> Result<(), OtaError> {
+    let encoded = match file_ctx.signature {
+        Signature::Sha256Ecdsa(ref sig) => sig,
+        _ => {
+            rustot_log!(
+                error,
+                "Unsupported signature type, expected sig-sha256-ecdsa"
+            );
+            return Err(OtaError::SignatureCheckFailed);
+        }
+    };
+
+    let mut der = [0u8; MAX_DER_SIGNATURE_LEN];
+    let len = base64_decode(encoded.as_bytes(), &mut der)?;
+
+    let signature =
+        EcdsaSignature::from_der(&der[..len]).map_err(|_| OtaError::SignatureCheckFailed)?;
+    let key =
+        VerifyingKey::from_sec1_bytes(public_key).map_err(|_| OtaError::SignatureCheckFailed)?;
+
+    key.verify_digest(hasher, &signature)
+        .map_err(|_| OtaError::SignatureCheckFailed)
+}
+
+/// Decode standard, padded base64 into `out`, returning the number of bytes
+/// written.
+fn base64_decode(input: &[u8], out: &mut [u8]) -> Result<usize, OtaError> {
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut len = 0;
+
+    for &c in input {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return Err(OtaError::Encoding),
+        };
+
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            *out.get_mut(len).ok_or(OtaError::Overflow)? = (acc >> bits) as u8;
+            acc &= (1 << bits) - 1;
+            len += 1;
+        }
+    }
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::{config::Config, test::test_file_ctx};
+
+    const PUBLIC_KEY: &[u8] = &[
+        4, 71, 28, 62, 117, 140, 73, 4, 40, 91, 186, 126, 83, 17, 142, 208, 245, 36, 173, 235, 7,
+        87, 210, 91, 210, 248, 231, 176, 215, 109, 250, 113, 76, 221, 82, 15, 122, 202, 138, 139,
+        145, 122, 204, 55, 245, 29, 232, 240, 201, 187, 227, 173, 133, 131, 130, 231, 2, 220, 37,
+        161, 45, 9, 247, 168, 88,
+    ];
+
+    const SIGNATURE: &str = "MEUCIA5yOGKQWYm3Jx4XQmpKbXUMduACdNlusITCoKTImVe7AiEAg9H/kkR3HlCKnJkCuNAAthYEZo7PPv4bn22uwyO5sZ8=";
+
+    fn signed_data() -> Vec<u8> {
+        let mut data: Vec<u8> = (0..=255u8).cycle().take(768).collect();
+        data.extend_from_slice(b"rustot");
+        data
+    }
+
+    fn signed_file_ctx() -> FileContext {
+        let mut file_ctx = test_file_ctx(&Config::default());
+        file_ctx.filesize = signed_data().len();
+        file_ctx.signature = Signature::Sha256Ecdsa(heapless::String::from(SIGNATURE));
+        file_ctx
+    }
+
+    #[test]
+    fn decode_base64() {
+        let out = &mut [0u8; 8];
+        assert_eq!(base64_decode(b"cnVzdG90", out), Ok(6));
+        assert_eq!(&out[..6], b"rustot");
+
+        assert_eq!(base64_decode(b"cnVzdA==", out), Ok(4));
+        assert_eq!(&out[..4], b"rust");
+
+        assert_eq!(base64_decode(b"cnV*", out), Err(OtaError::Encoding));
+    }
+
+    #[test]
+    fn valid_signature() {
+        let file_ctx = signed_file_ctx();
+
+        let mut hasher = Sha256::new();
+        hasher.update(&signed_data());
+
+        assert_eq!(verify(&file_ctx, PUBLIC_KEY, hasher), Ok(()));
+    }
+
+    #[test]
+    fn tampered_data() {
+        let file_ctx = signed_file_ctx();
+
+        let mut data = signed_data();
+        data[42] ^= 0x01;
+        let mut hasher = Sha256::new();
+        hasher.update(&data);
+
+        assert_eq!(
+            verify(&file_ctx, PUBLIC_KEY, hasher),
+            Err(OtaError::SignatureCheckFailed)
+        );
+    }
+
+    #[test]
+    fn unsupported_signature_type() {
+        let mut file_ctx = signed_file_ctx();
+        file_ctx.signature = Signature::Sha1Rsa(heapless::String::from(SIGNATURE));
+
+        let mut hasher = Sha256::new();
+        hasher.update(&signed_data());
+
+        assert_eq!(
+            verify(&file_ctx, PUBLIC_KEY, hasher),
+            Err(OtaError::SignatureCheckFailed)
+        );
+    }
+}
@@ -0,0 +1,313 @@
+//! Split of the OTA agent into a control-plane and a data-plane half.
+//!
+//! Handling a file block involves decoding it and writing it to flash through
+//! the PAL, which can block the caller for a while. On dual-core parts, such
+//! as the ESP32 or RP2040, the MQTT receive task is better off handing file
+//! blocks over to the core running the agent. Splitting the agent yields an
+//! [`OtaData`] half, which only copies received blocks into a lock-free
+//! [`BlockQueue`], and an [`OtaControl`] half, owning the agent and handling
+//! the queued blocks:
+//!
+//! ```ignore
+//! static mut BLOCKS: BlockQueue<1024, 4> = BlockQueue::new();
+//!
+//! let (mut control, mut data) = unsafe { BLOCKS.split(agent) };
+//!
+//! // MQTT receive task, on the data topic of the active file
+//! data.handle_message(payload).ok();
+//!
+//! // OTA task
+//! control.job_update(job_name, &ota_document, status_details)?;
+//! loop {
+//!     control.timer_callback()?;
+//!     control.process()?;
+//! }
+//! ```
+//!
+//! A block dropped because the queue is full is simply requested again.
+//!
+//! Where publishes are received in interrupt context, not even the agent
+//! itself can be shared with the receive path. A [`MessageQueue`] instead
+//! takes raw publishes, topic and all, and only copies them. The queued
+//! messages are handled later on by [`OtaAgent::process`], which stops at the
+//! first message not on a stream data topic, leaving it to the application:
+//!
+//! ```ignore
+//! static mut MESSAGES: MessageQueue<1100, 4> = MessageQueue::new();
+//!
+//! let (mut producer, mut consumer) = unsafe { MESSAGES.split() };
+//!
+//! // MQTT receive interrupt
+//! producer.enqueue(topic, payload).ok();
+//!
+//! // OTA task
+//! ota_agent.process(&mut consumer)?;
+//! while let Some(message) = consumer.dequeue() {
+//!     jobs.handle(message.topic(), message.payload())?;
+//!     ota_agent.process(&mut consumer)?;
+//! }
+//! ```
+
+use core::ops::{Deref, DerefMut};
+
+use embedded_hal::timer;
+use heapless::spsc::{Consumer, Producer, Queue};
+
+use super::{
+    agent::OtaAgent, control_interface::ControlInterface, data_interface::DataInterface,
+    error::OtaError, event::AgentEvent, pal::OtaPal, state::Error,
+};
+
+/// Queue of up to `Q - 1` received file blocks of at most `N` bytes each.
+///
+/// `N` must fit the encoded block, i.e. the block size along with a few bytes
+/// of framing.
+pub struct BlockQueue<const N: usize, const Q: usize> {
+    queue: Queue<heapless::Vec<u8, N>, Q>,
+}
+
+impl<const N: usize, const Q: usize> BlockQueue<N, Q> {
+    pub const fn new() -> Self {
+        Self {
+            queue: Queue::new(),
+        }
+    }
+
+    /// Split `agent` into the halves sharing this queue
+    #[allow(clippy::type_complexity)]
+    pub fn split<'q, 'a, C, DP, DS, T, ST, PAL>(
+        &'q mut self,
+        agent: OtaAgent<'a, C, DP, DS, T, ST, PAL>,
+    ) -> (
+        OtaControl<'q, 'a, C, DP, DS, T, ST, PAL, N, Q>,
+        OtaData<'q, N, Q>,
+    )
+    where
+        C: ControlInterface,
+        DP: DataInterface,
+        DS: DataInterface,
+        T: timer::nb::CountDown + timer::nb::Cancel,
+        T::Time: From<u32>,
+        ST: timer::nb::CountDown + timer::nb::Cancel,
+        ST::Time: From<u32>,
+        PAL: OtaPal,
+    {
+        let (producer, consumer) = self.queue.split();
+        (
+            OtaControl {
+                agent,
+                blocks: consumer,
+            },
+            OtaData { blocks: producer },
+        )
+    }
+}
+
+impl<const N: usize, const Q: usize> Default for BlockQueue<N, Q> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Queue of up to `Q - 1` raw publishes, of at most `N` bytes of topic and
+/// payload each
+pub struct MessageQueue<const N: usize, const Q: usize> {
+    queue: Queue<Message<N>, Q>,
+}
+
+impl<const N: usize, const Q: usize> MessageQueue<N, Q> {
+    pub const fn new() -> Self {
+        Self {
+            queue: Queue::new(),
+        }
+    }
+
+    /// Split the queue into the half taking publishes in interrupt context,
+    /// and the half handing them on to the agent
+    pub fn split(&mut self) -> (MessageProducer<'_, N, Q>, MessageConsumer<'_, N, Q>) {
+        let (producer, consumer) = self.queue.split();
+        (
+            MessageProducer { messages: producer },
+            MessageConsumer { messages: consumer },
+        )
+    }
+}
+
+impl<const N: usize, const Q: usize> Default for MessageQueue<N, Q> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Raw publish, as queued by a [`MessageProducer`]
+pub struct Message<const N: usize> {
+    topic_len: usize,
+    buf: heapless::Vec<u8, N>,
+}
+
+impl<const N: usize> Message<N> {
+    pub fn topic(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.topic_len]).unwrap_or_default()
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[self.topic_len..]
+    }
+}
+
+/// Half of a [`MessageQueue`] taking raw publishes, e.g. in the receive
+/// interrupt of the MQTT client
+pub struct MessageProducer<'q, const N: usize, const Q: usize> {
+    messages: Producer<'q, Message<N>, Q>,
+}
+
+impl<'q, const N: usize, const Q: usize> MessageProducer<'q, N, Q> {
+    /// Queue a publish received on `topic`. This only copies the message,
+    /// and never blocks.
+    ///
+    /// Fails with [`OtaError::Overflow`] if topic and payload together exceed
+    /// `N` bytes, or with [`OtaError::SignalEventFailed`] if the queue is full.
+    pub fn enqueue(&mut self, topic: &str, payload: &[u8]) -> Result<(), OtaError> {
+        let mut buf =
+            heapless::Vec::from_slice(topic.as_bytes()).map_err(|_| OtaError::Overflow)?;
+        buf.extend_from_slice(payload)
+            .map_err(|_| OtaError::Overflow)?;
+        self.messages
+            .enqueue(Message {
+                topic_len: topic.len(),
+                buf,
+            })
+            .map_err(|_| OtaError::SignalEventFailed)
+    }
+
+    /// Whether another message can be queued
+    pub fn ready(&self) -> bool {
+        self.messages.ready()
+    }
+}
+
+/// Half of a [`MessageQueue`] handing queued publishes on to
+/// [`OtaAgent::process`]
+pub struct MessageConsumer<'q, const N: usize, const Q: usize> {
+    messages: Consumer<'q, Message<N>, Q>,
+}
+
+impl<'q, const N: usize, const Q: usize> MessageConsumer<'q, N, Q> {
+    /// Take the next queued message, e.g. one left in the queue by
+    /// [`OtaAgent::process`]
+    pub fn dequeue(&mut self) -> Option<Message<N>> {
+        self.messages.dequeue()
+    }
+
+    /// Next queued message, without taking it
+    pub fn peek(&self) -> Option<&Message<N>> {
+        self.messages.peek()
+    }
+}
+
+/// Data-plane half of a split agent, receiving file blocks
+pub struct OtaData<'q, const N: usize, const Q: usize> {
+    blocks: Producer<'q, heapless::Vec<u8, N>, Q>,
+}
+
+impl<'q, const N: usize, const Q: usize> OtaData<'q, N, Q> {
+    /// Queue a message received on the data topic of the active file, to be
+    /// handled by [`OtaControl::process`].
+    ///
+    /// Fails with [`OtaError::Overflow`] if the message exceeds `N` bytes, or
+    /// with [`OtaError::SignalEventFailed`] if the queue is full.
+    pub fn handle_message(&mut self, payload: &[u8]) -> Result<(), OtaError> {
+        let block = heapless::Vec::from_slice(payload).map_err(|_| OtaError::Overflow)?;
+        self.blocks
+            .enqueue(block)
+            .map_err(|_| OtaError::SignalEventFailed)
+    }
+
+    /// Whether another block can be queued
+    pub fn ready(&self) -> bool {
+        self.blocks.ready()
+    }
+}
+
+/// Control-plane half of a split agent, owning the agent itself.
+///
+/// Dereferences to the [`OtaAgent`], for handling job documents, timers and
+/// everything else but file blocks.
+pub struct OtaControl<'q, 'a, C, DP, DS, T, ST, PAL, const N: usize, const Q: usize>
+where
+    C: ControlInterface,
+    DP: DataInterface,
+    DS: DataInterface,
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+    ST: timer::nb::CountDown + timer::nb::Cancel,
+    ST::Time: From<u32>,
+    PAL: OtaPal,
+{
+    agent: OtaAgent<'a, C, DP, DS, T, ST, PAL>,
+    blocks: Consumer<'q, heapless::Vec<u8, N>, Q>,
+}
+
+impl<'q, 'a, C, DP, DS, T, ST, PAL, const N: usize, const Q: usize>
+    OtaControl<'q, 'a, C, DP, DS, T, ST, PAL, N, Q>
+where
+    C: ControlInterface,
+    DP: DataInterface,
+    DS: DataInterface,
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+    ST: timer::nb::CountDown + timer::nb::Cancel,
+    ST::Time: From<u32>,
+    PAL: OtaPal,
+{
+    /// Handle all blocks queued by [`OtaData`] so far, followed by the next
+    /// queued agent event. Returns the latest [`AgentEvent`] raised, if any.
+    pub fn process(&mut self) -> Result<Option<AgentEvent>, Error> {
+        let mut event = None;
+        while let Some(block) = self.blocks.dequeue() {
+            event = self.agent.handle_message(&block)?.or(event);
+        }
+        Ok(self.agent.process_event()?.or(event))
+    }
+
+    /// Get back the underlying agent, dropping any queued blocks
+    pub fn into_inner(self) -> OtaAgent<'a, C, DP, DS, T, ST, PAL> {
+        self.agent
+    }
+}
+
+impl<'q, 'a, C, DP, DS, T, ST, PAL, const N: usize, const Q: usize> Deref
+    for OtaControl<'q, 'a, C, DP, DS, T, ST, PAL, N, Q>
+where
+    C: ControlInterface,
+    DP: DataInterface,
+    DS: DataInterface,
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+    ST: timer::nb::CountDown + timer::nb::Cancel,
+    ST::Time: From<u32>,
+    PAL: OtaPal,
+{
+    type Target = OtaAgent<'a, C, DP, DS, T, ST, PAL>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.agent
+    }
+}
+
+impl<'q, 'a, C, DP, DS, T, ST, PAL, const N: usize, const Q: usize> DerefMut
+    for OtaControl<'q, 'a, C, DP, DS, T, ST, PAL, N, Q>
+where
+    C: ControlInterface,
+    DP: DataInterface,
+    DS: DataInterface,
+    T: timer::nb::CountDown + timer::nb::Cancel,
+    T::Time: From<u32>,
+    ST: timer::nb::CountDown + timer::nb::Cancel,
+    ST::Time: From<u32>,
+    PAL: OtaPal,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.agent
+    }
+}
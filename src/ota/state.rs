@@ -1,16 +1,25 @@
 use embedded_hal::timer;
 use smlang::statemachine;
 
+use super::backoff::{self, Rng};
+use super::clock::Clock;
 use super::config::Config;
 use super::control_interface::ControlInterface;
-use super::data_interface::{DataInterface, Protocol};
-use super::encoding::json::JobStatusReason;
-use super::encoding::json::OtaJob;
-use super::encoding::FileContext;
+use super::custom_job::CustomJobHandler;
+use super::data_interface::{compression::Decompressor, DataInterface, FileBlock, Protocol};
+use super::encoding::json::{AbortReason, FailureReason, JobStatusReason};
+use super::encoding::json::{OtaJob, MAX_FILES};
+use super::encoding::{Bitmap, FileContext};
+use super::event::{AgentEvent, TransitionHook};
+use super::integrity::IntegrityCheck;
 use super::pal::OtaPal;
 use super::pal::OtaPalError;
+use super::pal::PalErrorCode;
+use super::self_test::{SelfTest, SelfTestResult};
+use super::statistics::Statistics;
+use super::storage::{BitmapStorage, DownloadProgress};
+use super::version_policy::{Increasing, VersionPolicy};
 
-use crate::ota::encoding::Bitmap;
 use crate::ota::pal::OtaEvent;
 use crate::rustot_log;
 use crate::{
@@ -24,15 +33,33 @@ use super::{
 };
 
 #[derive(Clone, Copy)]
-pub enum ImageStateReason<E: Copy> {
+pub enum ImageStateReason<E: PalErrorCode> {
     ImageStateMismatch,
     SignatureCheckPassed,
     InvalidDataProtocol,
     UserAbort,
     VersionCheck,
+    SelfTest,
+    Timeout,
     Pal(OtaPalError<E>),
 }
 
+impl<E: PalErrorCode> ImageStateReason<E> {
+    /// Failure cause reported in the job status details
+    fn failure(self) -> Option<FailureReason> {
+        match self {
+            Self::ImageStateMismatch => Some(FailureReason::ImageStateMismatch),
+            Self::InvalidDataProtocol => Some(FailureReason::InvalidProtocol),
+            Self::VersionCheck => Some(FailureReason::VersionRejected),
+            Self::SelfTest => Some(FailureReason::SelfTestRejected),
+            Self::Timeout => Some(FailureReason::Timeout),
+            Self::Pal(_) => Some(FailureReason::ImageState),
+            // The abort reason is reported by the application
+            Self::SignatureCheckPassed | Self::UserAbort => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RestartReason {
     Activate(u8),
@@ -53,6 +80,8 @@ impl RestartReason {
 pub struct JobEventData<'a> {
     pub job_name: &'a str,
     pub ota_document: &'a OtaJob<'a>,
+    /// Raw job document, if available
+    pub job_document: Option<&'a [u8]>,
     pub status_details: Option<&'a StatusDetails>,
 }
 
@@ -65,38 +94,95 @@ statemachine! {
         WaitingForJob + ReceivedJobDocument(JobEventData<'a>) [process_job_handler] = CreatingFile,
         WaitingForJob + Start [request_job_handler] = WaitingForJob,
         CreatingFile + StartSelfTest [in_self_test_handler] = WaitingForJob,
+        WaitingForJob + AcceptImage [accept_image_handler] = WaitingForJob,
         CreatingFile + CreateFile [init_file_handler] = RequestingFileBlock,
         CreatingFile + RequestTimer [init_file_handler] = RequestingFileBlock,
         CreatingFile + Restart(RestartReason) [restart_handler] = Restarting,
         RequestingFileBlock + RequestFileBlock [request_data_handler] = WaitingForFileBlock,
         RequestingFileBlock + RequestTimer [request_data_handler] = WaitingForFileBlock,
-        WaitingForFileBlock + ReceivedFileBlock(&'a mut [u8]) [process_data_handler]  = WaitingForFileBlock,
+        WaitingForFileBlock + ReceivedFileBlock(&'a [u8]) [process_data_handler]  = WaitingForFileBlock,
         WaitingForFileBlock + RequestTimer [request_data_handler] = WaitingForFileBlock,
         WaitingForFileBlock + RequestFileBlock [request_data_handler] = WaitingForFileBlock,
         WaitingForFileBlock + RequestJobDocument [request_job_handler] = WaitingForJob,
         WaitingForFileBlock + ReceivedJobDocument(JobEventData<'a>) [job_notification_handler] = RequestingJob,
         WaitingForFileBlock + CloseFile [close_file_handler] = WaitingForJob,
+        WaitingForFileBlock + NextFile [next_file_handler] = WaitingForJob,
+        WaitingForFileBlock + FinalizeFile [finalize_file_handler] = WaitingForFileBlock,
         WaitingForJob + Restart(RestartReason) [restart_handler] = Restarting,
         Restarting + Restart(RestartReason) [restart_handler] = Restarting,
         Suspended + Resume [resume_job_handler] = RequestingJob,
+        Suspended + ResumeTransfer [resume_transfer_handler] = RequestingFileBlock,
         Ready + Suspend = Suspended,
         RequestingJob + Suspend = Suspended,
         WaitingForJob + Suspend = Suspended,
-        CreatingFile + Suspend = Suspended,
-        RequestingFileBlock + Suspend = Suspended,
-        WaitingForFileBlock + Suspend = Suspended,
-        Ready + UserAbort [user_abort_handler] = WaitingForJob,
-        RequestingJob + UserAbort [user_abort_handler] = WaitingForJob,
-        WaitingForJob + UserAbort [user_abort_handler] = WaitingForJob,
-        CreatingFile + UserAbort [user_abort_handler] = WaitingForJob,
-        RequestingFileBlock + UserAbort [user_abort_handler] = WaitingForJob,
-        WaitingForFileBlock + UserAbort [user_abort_handler] = WaitingForJob,
+        CreatingFile + Suspend [suspend_transfer_handler] = Suspended,
+        RequestingFileBlock + Suspend [suspend_transfer_handler] = Suspended,
+        WaitingForFileBlock + Suspend [suspend_transfer_handler] = Suspended,
+        Ready + UserAbort(AbortReason) [user_abort_handler] = WaitingForJob,
+        RequestingJob + UserAbort(AbortReason) [user_abort_handler] = WaitingForJob,
+        WaitingForJob + UserAbort(AbortReason) [user_abort_handler] = WaitingForJob,
+        CreatingFile + UserAbort(AbortReason) [user_abort_handler] = WaitingForJob,
+        RequestingFileBlock + UserAbort(AbortReason) [user_abort_handler] = WaitingForJob,
+        WaitingForFileBlock + UserAbort(AbortReason) [user_abort_handler] = WaitingForJob,
+        Suspended + UserAbort(AbortReason) [user_abort_handler] = WaitingForJob,
+        CreatingFile + JobCanceled [job_canceled_handler] = WaitingForJob,
+        RequestingFileBlock + JobCanceled [job_canceled_handler] = WaitingForJob,
+        WaitingForFileBlock + JobCanceled [job_canceled_handler] = WaitingForJob,
+        Suspended + JobCanceled [job_canceled_handler] = WaitingForJob,
         Ready + Shutdown [shutdown_handler] = Ready,
         RequestingJob + Shutdown [shutdown_handler] = Ready,
         WaitingForJob + Shutdown [shutdown_handler] = Ready,
         CreatingFile + Shutdown [shutdown_handler] = Ready,
         RequestingFileBlock + Shutdown [shutdown_handler] = Ready,
         WaitingForFileBlock + Shutdown [shutdown_handler] = Ready,
+        Suspended + Shutdown [shutdown_handler] = Ready,
+    }
+}
+
+impl States {
+    /// Name of the state, as reported in a [`Transition`]
+    ///
+    /// [`Transition`]: super::event::Transition
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ready => "Ready",
+            Self::RequestingJob => "RequestingJob",
+            Self::WaitingForJob => "WaitingForJob",
+            Self::CreatingFile => "CreatingFile",
+            Self::RequestingFileBlock => "RequestingFileBlock",
+            Self::WaitingForFileBlock => "WaitingForFileBlock",
+            Self::Restarting => "Restarting",
+            Self::Suspended => "Suspended",
+        }
+    }
+}
+
+impl<'a> Events<'a> {
+    /// Name of the event, as reported as the trigger of a [`Transition`]
+    ///
+    /// [`Transition`]: super::event::Transition
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Start => "Start",
+            Self::RequestJobDocument => "RequestJobDocument",
+            Self::RequestTimer => "RequestTimer",
+            Self::ReceivedJobDocument(_) => "ReceivedJobDocument",
+            Self::StartSelfTest => "StartSelfTest",
+            Self::AcceptImage => "AcceptImage",
+            Self::CreateFile => "CreateFile",
+            Self::Restart(_) => "Restart",
+            Self::RequestFileBlock => "RequestFileBlock",
+            Self::ReceivedFileBlock(_) => "ReceivedFileBlock",
+            Self::CloseFile => "CloseFile",
+            Self::NextFile => "NextFile",
+            Self::FinalizeFile => "FinalizeFile",
+            Self::Resume => "Resume",
+            Self::ResumeTransfer => "ResumeTransfer",
+            Self::Suspend => "Suspend",
+            Self::UserAbort(_) => "UserAbort",
+            Self::JobCanceled => "JobCanceled",
+            Self::Shutdown => "Shutdown",
+        }
     }
 }
 
@@ -155,12 +241,45 @@ where
     #[cfg(not(all(feature = "ota_mqtt_data", feature = "ota_http_data")))]
     pub(crate) data_secondary: core::marker::PhantomData<DS>,
     pub(crate) active_interface: Option<Interface>,
+    /// Indices of the remaining files of a multi-file job into its job
+    /// document, received from the back
+    pub(crate) pending_files: heapless::Vec<usize, MAX_FILES>,
+    /// Set while a file transfer is suspended, to be picked up on resume
+    pub(crate) suspended_transfer: bool,
+    /// Set while the job lists another protocol to fall back to
+    pub(crate) protocol_fallback: bool,
+    /// Set while the PAL drains writes, after a write returned `WouldBlock`
+    pub(crate) pal_busy: bool,
+    /// Set while the PAL closes the file in the background, after closing
+    /// it returned `WouldBlock`
+    pub(crate) close_pending: bool,
     pub(crate) pal: PAL,
+    pub(crate) decompressor: Option<&'a mut dyn Decompressor>,
+    pub(crate) bitmap_storage: Option<&'a mut dyn BitmapStorage>,
+    pub(crate) custom_job_handler: Option<&'a mut dyn CustomJobHandler>,
+    pub(crate) rng: Option<&'a mut dyn Rng>,
+    pub(crate) progress_clock: Option<&'a dyn Clock>,
+    /// Time of the latest progress report due to the progress interval
+    pub(crate) last_progress_ms: u64,
+    pub(crate) self_test: Option<&'a mut dyn SelfTest>,
+    pub(crate) integrity_check: Option<&'a mut dyn IntegrityCheck>,
+    pub(crate) transition_hook: Option<&'a mut dyn TransitionHook>,
+    pub(crate) version_policy: Option<&'a mut dyn VersionPolicy>,
+    /// Buffer keeping the raw document of the active job
+    pub(crate) job_document_buf: Option<&'a mut [u8]>,
+    /// Length of the raw job document kept in `job_document_buf`, if any
+    pub(crate) job_document_len: Option<usize>,
+    /// Running digest of the file being received
+    #[cfg(feature = "ota_sha256")]
+    pub(crate) file_hasher: sha2::Sha256,
     pub(crate) request_momentum: u8,
+    pub(crate) statistics: Statistics,
     pub(crate) request_timer: T,
     pub(crate) self_test_timer: Option<ST>,
     pub(crate) config: Config,
     pub(crate) image_state: ImageState,
+    /// Latest event, to be returned to the application
+    pub(crate) agent_event: Option<AgentEvent>,
 }
 
 impl<'a, C, DP, DS, T, ST, PAL, const L: usize> SmContext<'a, C, DP, DS, T, ST, PAL, L>
@@ -183,13 +302,7 @@ where
     ) -> Result<FileContext, OtaError> {
         let file_idx = 0;
 
-        if ota_document
-            .files
-            .get(file_idx)
-            .map(|f| f.filesize)
-            .unwrap_or_default()
-            == 0
-        {
+        if ota_document.files.is_empty() || ota_document.files.iter().any(|f| f.filesize == 0) {
             return Err(OtaError::ZeroFileSize);
         }
 
@@ -210,6 +323,7 @@ where
 
                 // Cleanup related to selected protocol
                 data_interface!(self.cleanup, &self.config)?;
+                self.pending_files.clear();
 
                 // Set new active job
                 Ok(FileContext::new_from(
@@ -220,14 +334,39 @@ where
                     &self.config,
                     self.pal.get_active_firmware_version()?,
                 )?)
+            } else if file_ctx.blocks_remaining == 0 && !self.pending_files.is_empty() {
+                // The previous file of a multi-file job is complete, so
+                // continue with the next file of the job
+                let status_details = file_ctx.status_details.clone();
+                let file_idx = self.pending_files.pop().ok_or(OtaError::InvalidFile)?;
+
+                let mut file_ctx = FileContext::new_from(
+                    job_name,
+                    ota_document,
+                    Some(status_details),
+                    file_idx,
+                    &self.config,
+                    self.pal.get_active_firmware_version()?,
+                )?;
+
+                rustot_log!(
+                    info,
+                    "Continuing with file {:?} of the job",
+                    file_ctx.fileid
+                );
+                self.create_file(&mut file_ctx)?;
+                return Ok(file_ctx);
             } else {
                 // The same job is being reported so update the url
                 rustot_log!(info, "New job document ID is identical to the current job: Updating the URL based on the new job document");
                 file_ctx.update_data_url = ota_document
                     .files
-                    .get(0)
-                    .map(|f| f.update_data_url.map(heapless::String::from))
-                    .ok_or(OtaError::InvalidFile)?;
+                    .iter()
+                    .find(|f| f.fileid == file_ctx.fileid)
+                    .ok_or(OtaError::InvalidFile)?
+                    .update_data_url
+                    .map(FileContext::data_url)
+                    .transpose()?;
 
                 Err(file_ctx.clone())
             }
@@ -271,22 +410,162 @@ where
             }
         };
 
-        // Create/Open the OTA file on the file system
-        if let Err(e) = self.pal.create_file_for_rx(&file_ctx) {
+        self.check_update_version(&mut file_ctx)?;
+
+        if ota_document.files.len() > 1 {
+            self.queue_files(job_name, ota_document, &mut file_ctx)?;
+        }
+
+        self.create_file(&mut file_ctx)?;
+
+        Ok(file_ctx)
+    }
+
+    /// Queue the indices of all files of a multi-file job, replacing
+    /// `file_ctx` with the file to receive first. Firmware images (`fileType`
+    /// 0) are received last, such that the device is only activated once all
+    /// other files of the job are in place.
+    fn queue_files(
+        &mut self,
+        job_name: &str,
+        ota_document: &OtaJob,
+        file_ctx: &mut FileContext,
+    ) -> Result<(), OtaError> {
+        self.pending_files.clear();
+
+        // Files are popped from the back of the queue
+        for &firmware in &[true, false] {
+            for (file_idx, file) in ota_document.files.iter().enumerate().rev() {
+                if (file.file_type == Some(0)) != firmware {
+                    continue;
+                }
+
+                self.pending_files
+                    .push(file_idx)
+                    .map_err(|_| OtaError::Overflow)?;
+            }
+        }
+
+        let file_idx = self.pending_files.pop().ok_or(OtaError::InvalidFile)?;
+        *file_ctx = FileContext::new_from(
+            job_name,
+            ota_document,
+            Some(file_ctx.status_details.clone()),
+            file_idx,
+            &self.config,
+            self.pal.get_active_firmware_version()?,
+        )?;
+        Ok(())
+    }
+
+    /// Create/Open the OTA file on the file system, and abort the job if
+    /// that fails
+    fn create_file(&mut self, file_ctx: &mut FileContext) -> Result<(), OtaError> {
+        if let Some(verifier) = self.pal.signature_verifier() {
+            verifier.reset();
+        }
+        #[cfg(feature = "ota_sha256")]
+        {
+            self.file_hasher = Default::default();
+        }
+        if let Some(check) = self.integrity_check.as_deref_mut() {
+            check.reset(file_ctx);
+        }
+
+        // Create/Open the OTA file on the file system, and prepare for
+        // patching if this is a delta image. A download interrupted by a
+        // reset is picked up where it left off instead. Either might involve
+        // erasing flash.
+        self.pal.on_long_operation();
+        let mut created = if self.resume_download(file_ctx) {
+            Ok(())
+        } else {
+            match self.pal.create_file_for_rx(file_ctx) {
+                Ok(()) if file_ctx.delta => match self.pal.patch_applier() {
+                    Some(applier) => applier.begin(file_ctx),
+                    None => Err(OtaPalError::Unsupported),
+                },
+                r => r,
+            }
+        };
+
+        // Compressed files can only be received with a matching decompressor
+        if let (Ok(()), Some(compression)) = (created, file_ctx.compression) {
+            match self.decompressor {
+                Some(ref mut decompressor) if decompressor.compression() == compression => {
+                    decompressor.reset()
+                }
+                _ => created = Err(OtaPalError::Unsupported),
+            }
+        }
+
+        if created.is_ok() {
+            created = Self::prepare_window(&mut self.pal, &self.config, file_ctx);
+        }
+
+        if let Err(e) = created {
             self.image_state = Self::set_image_state_with_reason(
                 self.control,
                 &mut self.pal,
                 &self.config,
-                &mut file_ctx,
+                file_ctx,
                 ImageState::Aborted,
                 Some(ImageStateReason::Pal(e)),
             )?;
 
             self.ota_close()?;
+            self.agent_event = Some(AgentEvent::Failed {
+                reason: FailureReason::FlashWrite,
+            });
             return Err(e.into());
         }
 
-        Ok(file_ctx)
+        self.agent_event = Some(AgentEvent::Started);
+        Ok(())
+    }
+
+    /// Restore the download progress of `file_ctx` from the bitmap storage,
+    /// if it holds progress of the very same file. Returns `true` if the file
+    /// was successfully reopened by the PAL.
+    fn resume_download(&mut self, file_ctx: &mut FileContext) -> bool {
+        let storage = match self.bitmap_storage {
+            Some(ref mut storage) => storage,
+            None => return false,
+        };
+
+        let progress = match storage.load() {
+            Ok(None) => return false,
+            Ok(Some(progress))
+                if progress.matches(file_ctx)
+                    && !file_ctx.delta
+                    && file_ctx.compression.is_none() =>
+            {
+                progress
+            }
+            _ => {
+                storage.clear().ok();
+                return false;
+            }
+        };
+
+        let mut resumed = file_ctx.clone();
+        progress.apply(&mut resumed);
+
+        // The window of the stored progress was prepared before the reset
+        resumed.prepared_offset = resumed.window_end(self.config.block_size);
+
+        if self.pal.resume_file_for_rx(&resumed).is_ok() {
+            rustot_log!(
+                info,
+                "Resuming download with {:?} blocks remaining",
+                resumed.blocks_remaining
+            );
+            *file_ctx = resumed;
+            true
+        } else {
+            storage.clear().ok();
+            false
+        }
     }
 
     fn select_interface(
@@ -294,21 +573,69 @@ where
         file_ctx: FileContext,
         protocols: &[Protocol],
     ) -> Result<Interface, FileContext> {
-        if protocols.contains(&DP::PROTOCOL) {
-            Ok(Interface::Primary(file_ctx))
-        } else {
+        // Protocols are listed in order of preference
+        for protocol in protocols {
+            if *protocol == DP::PROTOCOL {
+                return Ok(Interface::Primary(file_ctx));
+            }
+
             #[cfg(all(feature = "ota_mqtt_data", feature = "ota_http_data"))]
-            if protocols.contains(&DS::PROTOCOL) && self.data_secondary.is_some() {
-                Ok(Interface::Secondary(file_ctx))
-            } else {
-                Err(file_ctx)
+            if *protocol == DS::PROTOCOL && self.data_secondary.is_some() {
+                return Ok(Interface::Secondary(file_ctx));
             }
+        }
+
+        Err(file_ctx)
+    }
+
+    /// Check if the job can fall back to the other data interface, should the
+    /// selected one fail.
+    fn can_fall_back(&self, protocols: &[Protocol]) -> bool {
+        #[cfg(all(feature = "ota_mqtt_data", feature = "ota_http_data"))]
+        {
+            protocols.contains(&DP::PROTOCOL)
+                && protocols.contains(&DS::PROTOCOL)
+                && self.data_secondary.is_some()
+        }
 
-            #[cfg(not(all(feature = "ota_mqtt_data", feature = "ota_http_data")))]
-            Err(file_ctx)
+        #[cfg(not(all(feature = "ota_mqtt_data", feature = "ota_http_data")))]
+        {
+            let _ = protocols;
+            false
         }
     }
 
+    /// Hand the file transfer over to the other data interface, after the
+    /// active one failed repeatedly. Each job falls back at most once.
+    ///
+    /// Returns `true` if the transfer was handed over.
+    fn fall_back(&mut self) -> Result<bool, OtaError> {
+        if !core::mem::take(&mut self.protocol_fallback) {
+            return Ok(false);
+        }
+
+        #[cfg(all(feature = "ota_mqtt_data", feature = "ota_http_data"))]
+        {
+            rustot_log!(warn, "Falling back to the next protocol of the job");
+
+            // Best effort, as the interface is failing anyway
+            data_interface!(self.cleanup, &self.config).ok();
+
+            self.active_interface = match self.active_interface.take() {
+                Some(Interface::Primary(file_ctx)) => Some(Interface::Secondary(file_ctx)),
+                Some(Interface::Secondary(file_ctx)) => Some(Interface::Primary(file_ctx)),
+                None => return Err(OtaError::InvalidInterface),
+            };
+
+            data_interface!(self.init_file_transfer, &self.config)?;
+            self.request_momentum = 0;
+            Ok(true)
+        }
+
+        #[cfg(not(all(feature = "ota_mqtt_data", feature = "ota_http_data")))]
+        Ok(false)
+    }
+
     /// Check if the current image is `PendingCommit` and thus is in selftest
     fn platform_in_selftest(&self) -> bool {
         // Get the platform state from the OTA pal layer
@@ -317,6 +644,52 @@ where
             .map_or(false, |i| i == PalImageState::PendingCommit)
     }
 
+    /// Check the version of the firmware image offered by a job, if given,
+    /// failing the job if it is rejected by the version policy. This spares
+    /// downloading an image that would be rejected in self test anyway.
+    fn check_update_version(&mut self, file_ctx: &mut FileContext) -> Result<(), OtaError> {
+        let offered = match file_ctx.app_version {
+            // Only check for versions if the target is self
+            Some(ref version) if file_ctx.fileid == 0 && file_ctx.file_type == Some(0) => {
+                version.clone()
+            }
+            _ => return Ok(()),
+        };
+
+        let active_version = self
+            .pal
+            .get_active_firmware_version()
+            .unwrap_or_else(|_| Version::new(0, 0, 0));
+
+        let version_check = match self.version_policy.as_deref_mut() {
+            Some(policy) => policy.accept_update(&offered, &active_version),
+            None => Increasing.accept_update(&offered, &active_version),
+        };
+        if self.config.allow_downgrade || version_check {
+            return Ok(());
+        }
+
+        rustot_log!(
+            warn,
+            "Rejecting update to version {:?}, running {:?}",
+            offered,
+            active_version
+        );
+        self.image_state = Self::set_image_state_with_reason(
+            self.control,
+            &mut self.pal,
+            &self.config,
+            file_ctx,
+            ImageState::Aborted,
+            Some(ImageStateReason::VersionCheck),
+        )?;
+
+        self.agent_event = Some(AgentEvent::Failed {
+            reason: FailureReason::VersionRejected,
+        });
+        Err(OtaError::VersionRejected)
+    }
+
     /// Validate update version when receiving job doc in self test state
     fn handle_self_test_job(&mut self, file_ctx: &mut FileContext) -> Result<(), OtaError> {
         rustot_log!(info, "In self test mode");
@@ -327,9 +700,11 @@ where
             .unwrap_or_else(|_| Version::new(0, 0, 0));
 
         let version_check = if file_ctx.fileid == 0 && file_ctx.file_type == Some(0) {
-            // Only check for versions if the target is self & always allow
-            // updates if updated_by is not present.
-            file_ctx.updated_by().map_or(true, |v| v < active_version)
+            // Only check for versions if the target is self
+            match self.version_policy.as_deref_mut() {
+                Some(policy) => policy.accept(file_ctx, &active_version),
+                None => Increasing.accept(file_ctx, &active_version),
+            }
         } else {
             true
         };
@@ -362,6 +737,9 @@ where
                 Some(ImageStateReason::VersionCheck),
             )?;
 
+            self.agent_event = Some(AgentEvent::Failed {
+                reason: FailureReason::VersionRejected,
+            });
             self.pal.complete_callback(OtaEvent::SelfTestFailed)?;
 
             // Handle self-test failure in the platform specific implementation,
@@ -404,6 +782,21 @@ where
             }
         }
 
+        if matches!(image_state, ImageState::Rejected | ImageState::Aborted) {
+            // The details are best-effort, the image state and job status
+            // are updated regardless
+            if let Some(failure) = reason.and_then(ImageStateReason::failure) {
+                if file_ctx.set_failure(failure, None).is_err() {
+                    rustot_log!(warn, "Failed to report the failure reason");
+                }
+            }
+            if let Some(ImageStateReason::Pal(e)) = reason {
+                if file_ctx.set_pal_error(e.into()).is_err() {
+                    rustot_log!(warn, "Failed to report the PAL error");
+                }
+            }
+        }
+
         // Now update the image state and job status on server side
         match image_state {
             ImageState::Testing => {
@@ -452,6 +845,70 @@ where
         Ok(image_state)
     }
 
+    /// Accept the image under test, completing the job
+    fn complete_self_test(&mut self) -> Result<(), OtaError> {
+        let file_ctx = self
+            .active_interface
+            .as_mut()
+            .ok_or(OtaError::InvalidInterface)?
+            .mut_file_ctx();
+
+        self.image_state = ImageState::Accepted;
+        self.control.update_job_status(
+            file_ctx,
+            &self.config,
+            JobStatus::Succeeded,
+            JobStatusReason::Accepted,
+        )?;
+
+        file_ctx
+            .status_details
+            .insert(
+                heapless::String::from("self_test"),
+                heapless::String::from(JobStatusReason::Accepted.as_str()),
+            )
+            .map_err(|_| OtaError::Overflow)?;
+
+        // Stop the self test timer as it is no longer required
+        if let Some(ref mut self_test_timer) = self.self_test_timer {
+            self_test_timer.cancel().map_err(|_| OtaError::Timer)?;
+        }
+
+        self.agent_event = Some(AgentEvent::Completed { bank: None });
+        Ok(())
+    }
+
+    /// Called when the self test timer expires before the image was
+    /// accepted. Rejects the image under test, and resets the device to roll
+    /// back to the previous image.
+    pub(crate) fn self_test_timeout(&mut self) -> Result<(), OtaError> {
+        rustot_log!(
+            error,
+            "Self test failed to complete within {} ms",
+            self.config.self_test_timeout_ms
+        );
+
+        if let (ImageState::Testing, Some(interface)) =
+            (self.image_state, self.active_interface.as_mut())
+        {
+            self.image_state = Self::set_image_state_with_reason(
+                self.control,
+                &mut self.pal,
+                &self.config,
+                interface.mut_file_ctx(),
+                ImageState::Rejected,
+                Some(ImageStateReason::Timeout),
+            )?;
+        }
+
+        self.agent_event = Some(AgentEvent::Failed {
+            reason: FailureReason::Timeout,
+        });
+        self.pal.complete_callback(OtaEvent::SelfTestFailed)?;
+        self.pal.reset_device()?;
+        Ok(())
+    }
+
     pub fn ota_close(&mut self) -> Result<(), OtaError> {
         // Cleanup related to selected protocol.
         data_interface!(self.cleanup, &self.config)?;
@@ -465,12 +922,194 @@ where
 
         self.pal.abort(file_ctx)?;
 
+        // The file is either complete or abandoned, so there is nothing left
+        // to resume
+        if let Some(ref mut storage) = self.bitmap_storage {
+            if storage.clear().is_err() {
+                rustot_log!(warn, "Failed to clear stored download progress");
+            }
+        }
+
+        self.pending_files.clear();
+        self.suspended_transfer = false;
+        self.protocol_fallback = false;
+        self.pal_busy = false;
+        self.close_pending = false;
+        self.job_document_len = None;
         self.active_interface = None;
         Ok(())
     }
 
-    fn ingest_data_block(&mut self, payload: &mut [u8]) -> Result<bool, OtaError> {
-        let block = data_interface!(self.decode_file_block, payload)?;
+    /// Copy the raw job document into the job document buffer, dropping it
+    /// rather than failing the job if it does not fit, as it is not needed by
+    /// the agent itself.
+    fn keep_job_document(&mut self, document: Option<&[u8]>) {
+        self.job_document_len = match (self.job_document_buf.as_deref_mut(), document) {
+            (Some(buf), Some(document)) if document.len() <= buf.len() => {
+                buf[..document.len()].copy_from_slice(document);
+                Some(document.len())
+            }
+            (Some(buf), Some(document)) => {
+                rustot_log!(
+                    warn,
+                    "Job document of {} bytes too long to keep in {} bytes",
+                    document.len(),
+                    buf.len()
+                );
+                None
+            }
+            _ => None,
+        };
+    }
+
+    /// Write a chunk of file data, either through the PAL, or through the
+    /// patch applier for delta images.
+    fn write_data(
+        pal: &mut PAL,
+        file_ctx: &FileContext,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), OtaError> {
+        if file_ctx.delta {
+            pal.patch_applier()
+                .ok_or(OtaPalError::<PAL::Error>::Unsupported)?
+                .apply(file_ctx, offset, data)?;
+        } else {
+            pal.write_block(file_ctx, offset, data)?;
+        }
+        Ok(())
+    }
+
+    /// Have the PAL prepare the part of the window of requested blocks that
+    /// was not prepared yet. Only plain files are written at the offsets of
+    /// their blocks, so compressed and delta files are left out.
+    fn prepare_window(
+        pal: &mut PAL,
+        config: &Config,
+        file_ctx: &mut FileContext,
+    ) -> Result<(), OtaPalError<PAL::Error>> {
+        if file_ctx.delta || file_ctx.compression.is_some() {
+            return Ok(());
+        }
+
+        let start = file_ctx.prepared_offset;
+        let end = file_ctx.window_end(config.block_size);
+        if end > start {
+            pal.prepare_region(file_ctx, start, end - start)?;
+            file_ctx.prepared_offset = end;
+        }
+        Ok(())
+    }
+
+    /// Feed a chunk of file data to the signature verifiers
+    fn verify_update(
+        pal: &mut PAL,
+        #[cfg(feature = "ota_sha256")] hasher: &mut sha2::Sha256,
+        integrity_check: Option<&mut (dyn IntegrityCheck + '_)>,
+        data: &[u8],
+    ) -> Result<(), OtaError> {
+        if let Some(verifier) = pal.signature_verifier() {
+            verifier.update(data)?;
+        }
+        if let Some(check) = integrity_check {
+            check.update(data);
+        }
+        #[cfg(feature = "ota_sha256")]
+        sha2::Digest::update(hasher, data);
+        Ok(())
+    }
+
+    /// Check the digest and signature of a completely received file. Any data
+    /// that was received out of order, and thus not yet verified, is read back
+    /// from the PAL first.
+    fn verify_signature(
+        pal: &mut PAL,
+        #[cfg(feature = "ota_sha256")] hasher: &mut sha2::Sha256,
+        mut integrity_check: Option<&mut (dyn IntegrityCheck + '_)>,
+        config: &Config,
+        file_ctx: &mut FileContext,
+    ) -> Result<(), OtaError> {
+        let check_digest = (cfg!(feature = "ota_sha256") && file_ctx.sha256.is_some())
+            || integrity_check.is_some();
+        if pal.signature_verifier().is_none() && !cfg!(feature = "ota_sign_verify") && !check_digest
+        {
+            return Ok(());
+        }
+
+        let mut buf = [0u8; 256];
+        while file_ctx.verified_offset < file_ctx.filesize {
+            pal.on_long_operation();
+            let len = core::cmp::min(buf.len(), file_ctx.filesize - file_ctx.verified_offset);
+            let read = pal.read_block(file_ctx, file_ctx.verified_offset, &mut buf[..len])?;
+            if read == 0 {
+                return Err(OtaError::SignatureCheckFailed);
+            }
+
+            Self::verify_update(
+                pal,
+                #[cfg(feature = "ota_sha256")]
+                hasher,
+                integrity_check.as_deref_mut(),
+                &buf[..read],
+            )?;
+            file_ctx.verified_offset += read;
+        }
+
+        #[cfg(feature = "ota_sha256")]
+        {
+            if let Some(ref expected) = file_ctx.sha256 {
+                if sha2::Digest::finalize(hasher.clone()).as_slice() != &expected[..] {
+                    rustot_log!(error, "SHA-256 digest of the file does not match the job");
+                    return Err(OtaError::DigestMismatch);
+                }
+                rustot_log!(info, "Digest check passed.");
+            }
+        }
+
+        if let Some(check) = integrity_check {
+            if !check.verify(file_ctx) {
+                rustot_log!(error, "Integrity check of the file failed");
+                return Err(OtaError::DigestMismatch);
+            }
+        }
+
+        if let Some(verifier) = pal.signature_verifier() {
+            verifier.finalize(&file_ctx.signature)?;
+        }
+
+        #[cfg(feature = "ota_sign_verify")]
+        {
+            let public_key = match (
+                config.code_signing_key,
+                pal.code_signing_certificate(file_ctx.certfile.as_str()),
+            ) {
+                (Some(key), _) => key,
+                (None, Some(certificate)) => {
+                    let root_key = config
+                        .code_signing_root_key
+                        .ok_or(OtaError::SignatureCheckFailed)?;
+                    super::certificate::signing_key(certificate, root_key)?
+                }
+                (None, None) => pal
+                    .code_signing_public_key()
+                    .ok_or(OtaError::SignatureCheckFailed)?,
+            };
+
+            super::signature::verify(file_ctx, public_key, core::mem::take(hasher))?;
+        }
+        #[cfg(not(feature = "ota_sign_verify"))]
+        let _ = config;
+
+        rustot_log!(info, "Signature check passed.");
+        Ok(())
+    }
+
+    fn decode_data_block<'p>(&mut self, payload: &'p [u8]) -> Result<FileBlock<'p>, OtaError> {
+        data_interface!(self.decode_file_block, payload)
+    }
+
+    fn ingest_data_block(&mut self, block: &FileBlock<'_>) -> Result<bool, OtaError> {
+        self.statistics.blocks_received += 1;
 
         let file_ctx = self
             .active_interface
@@ -478,34 +1117,152 @@ where
             .ok_or(OtaError::InvalidInterface)?
             .mut_file_ctx();
 
+        if block.file_id != file_ctx.fileid {
+            rustot_log!(
+                info,
+                "Block {:?} of file {:?} is STALE. Expected file {:?}.",
+                block.block_id,
+                block.file_id,
+                file_ctx.fileid
+            );
+            self.statistics.blocks_dropped += 1;
+            self.statistics.blocks_stale += 1;
+            return Ok(false);
+        }
+
         if block.validate(self.config.block_size, file_ctx.filesize) {
-            if block.block_id < file_ctx.block_offset as usize
-                || !file_ctx
-                    .bitmap
-                    .get(block.block_id - file_ctx.block_offset as usize)
-            {
-                rustot_log!(
-                    info,
-                    "Block {:?} is a DUPLICATE. {:?} blocks remaining.",
-                    block.block_id,
-                    file_ctx.blocks_remaining
-                );
+            // The stream may answer overlapping requests out of order, so any
+            // missing block within the window is taken. Blocks ahead of the
+            // window can't be tracked yet, and are requested again once the
+            // window gets to them.
+            match block.block_id.checked_sub(file_ctx.block_offset as usize) {
+                Some(index) if index >= Bitmap::WINDOW => {
+                    rustot_log!(
+                        info,
+                        "Block {:?} is ahead of the window at {:?}.",
+                        block.block_id,
+                        file_ctx.block_offset
+                    );
+                    self.statistics.blocks_dropped += 1;
+                    self.statistics.blocks_out_of_window += 1;
+                    return Ok(false);
+                }
+                Some(index) if file_ctx.bitmap.get(index) => {}
+                _ => {
+                    rustot_log!(
+                        info,
+                        "Block {:?} is a DUPLICATE. {:?} blocks remaining.",
+                        block.block_id,
+                        file_ctx.blocks_remaining
+                    );
 
-                // Just return same progress as before
-                return Ok(false);
+                    // Just return same progress as before
+                    self.statistics.blocks_dropped += 1;
+                    self.statistics.blocks_duplicate += 1;
+                    return Ok(false);
+                }
             }
 
-            self.pal.write_block(
-                file_ctx,
-                block.block_id * self.config.block_size,
-                block.block_payload,
-            )?;
+            if self.pal_busy {
+                match self.pal.poll_ready() {
+                    Ok(()) => self.pal_busy = false,
+                    Err(OtaPalError::WouldBlock) => {
+                        self.statistics.blocks_dropped += 1;
+                        return Ok(false);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            if file_ctx.delta || file_ctx.compression.is_some() {
+                // Patches and compressed files can only be processed
+                // sequentially, so anything but the first missing block is
+                // dropped, to be requested again.
+                let next_block =
+                    file_ctx.block_offset as usize + file_ctx.bitmap.first_index().unwrap_or(0);
+                if block.block_id != next_block {
+                    rustot_log!(
+                        info,
+                        "Block {:?} is out of order. Expected {:?}.",
+                        block.block_id,
+                        next_block
+                    );
+                    self.statistics.blocks_dropped += 1;
+                    return Ok(false);
+                }
+            }
+
+            // Stream the block through signature verification if it is next
+            // in line. The signature covers the file as uploaded, so this has
+            // to happen before decompressing or patching.
+            if block.block_id * self.config.block_size == file_ctx.verified_offset {
+                Self::verify_update(
+                    &mut self.pal,
+                    #[cfg(feature = "ota_sha256")]
+                    &mut self.file_hasher,
+                    self.integrity_check.as_deref_mut(),
+                    block.block_payload,
+                )?;
+                file_ctx.verified_offset += block.block_payload.len();
+            }
+
+            if file_ctx.compression.is_some() {
+                let decompressor = self.decompressor.as_mut().ok_or(OtaError::InvalidFile)?;
+                let pal = &mut self.pal;
+                let file: &FileContext = file_ctx;
+                let mut offset = file.decompressed_offset;
+
+                decompressor.decompress(block.block_payload, &mut |data| {
+                    pal.on_long_operation();
+                    Self::write_data(pal, file, offset, data)?;
+                    offset += data.len();
+                    Ok(())
+                })?;
+
+                file_ctx.decompressed_offset = offset;
+            } else if !file_ctx.delta {
+                let offset = block.block_id * self.config.block_size;
+                match self.pal.write_block(file_ctx, offset, block.block_payload) {
+                    Ok(_) => {}
+                    Err(OtaPalError::WouldBlock) => {
+                        // Drop the block, to be requested again once the PAL
+                        // caught up
+                        rustot_log!(info, "PAL busy, dropping block {:?}", block.block_id);
+                        self.pal_busy = true;
+                        self.statistics.blocks_dropped += 1;
+                        return Ok(false);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            } else {
+                Self::write_data(
+                    &mut self.pal,
+                    file_ctx,
+                    block.block_id * self.config.block_size,
+                    block.block_payload,
+                )?;
+            }
 
             file_ctx
                 .bitmap
                 .set(block.block_id - file_ctx.block_offset as usize, false);
 
             file_ctx.blocks_remaining -= 1;
+            self.statistics.blocks_processed += 1;
+            self.statistics.bytes_received += block.block_payload.len() as u32;
+
+            let blocks_total =
+                (file_ctx.filesize + self.config.block_size - 1) / self.config.block_size;
+            let blocks_received = blocks_total - file_ctx.blocks_remaining;
+            self.pal.on_progress(
+                blocks_received,
+                blocks_total,
+                core::cmp::min(blocks_received * self.config.block_size, file_ctx.filesize),
+            );
+            self.agent_event = Some(AgentEvent::BlockReceived {
+                blocks_received,
+                blocks_total,
+            });
 
             if file_ctx.blocks_remaining == 0 {
                 rustot_log!(info, "Received final expected block of file.");
@@ -515,18 +1272,56 @@ where
                     .cancel()
                     .map_err(|_| OtaError::Timer)?;
 
-                self.pal.close_file(file_ctx)?;
+                // Finishing a patch, verifying and closing the file all
+                // take a while on larger images
+                self.pal.on_long_operation();
+
+                if file_ctx.delta {
+                    self.pal
+                        .patch_applier()
+                        .ok_or(OtaPalError::<PAL::Error>::Unsupported)?
+                        .finish(file_ctx)?;
+                }
+
+                Self::verify_signature(
+                    &mut self.pal,
+                    #[cfg(feature = "ota_sha256")]
+                    &mut self.file_hasher,
+                    self.integrity_check.as_deref_mut(),
+                    &self.config,
+                    file_ctx,
+                )?;
+
+                match self.pal.close_file(file_ctx) {
+                    Err(OtaPalError::WouldBlock) => {
+                        // The PAL carries on closing the file in the
+                        // background, until the application finalizes it
+                        rustot_log!(info, "Closing file {:?} deferred", file_ctx.fileid);
+                        self.close_pending = true;
+                        return Ok(false);
+                    }
+                    result => result?,
+                }
 
                 // Return true to indicate end of file.
                 Ok(true)
             } else {
-                if file_ctx.bitmap.is_empty() {
-                    file_ctx.block_offset += 31;
-                    file_ctx.bitmap = Bitmap::new(
-                        file_ctx.filesize,
-                        self.config.block_size,
-                        file_ctx.block_offset,
-                    );
+                // Keep the window of requested blocks at the first missing
+                // block, rather than waiting for the whole window to complete
+                file_ctx.block_offset = file_ctx.bitmap.slide(
+                    file_ctx.filesize,
+                    self.config.block_size,
+                    file_ctx.block_offset,
+                );
+                Self::prepare_window(&mut self.pal, &self.config, file_ctx)?;
+
+                if let Some(ref mut storage) = self.bitmap_storage {
+                    if !file_ctx.delta
+                        && file_ctx.compression.is_none()
+                        && storage.store(&DownloadProgress::new(file_ctx)).is_err()
+                    {
+                        rustot_log!(warn, "Failed to store download progress");
+                    }
                 }
 
                 Ok(false)
@@ -602,15 +1397,49 @@ where
             .map_err(|_| OtaError::SignalEventFailed)
     }
 
+    /// Pause the file transfer in progress, keeping the file context, such
+    /// that it can be picked up again on resume
+    fn suspend_transfer_handler(&mut self) -> Result<(), OtaError> {
+        rustot_log!(debug, "suspend_transfer_handler");
+
+        // Stop receiving file blocks while suspended. Blocks in flight are
+        // simply requested again on resume, as they are still marked as
+        // missing in the bitmap.
+        data_interface!(self.cleanup, &self.config)?;
+
+        // Drop any pending requests
+        while self.events.dequeue().is_some() {}
+
+        self.suspended_transfer = true;
+        Ok(())
+    }
+
+    /// Pick up a suspended file transfer where it was left
+    fn resume_transfer_handler(&mut self) -> Result<(), OtaError> {
+        rustot_log!(debug, "resume_transfer_handler");
+        data_interface!(self.init_file_transfer, &self.config)?;
+
+        self.suspended_transfer = false;
+        self.request_momentum = 0;
+
+        self.events
+            .enqueue(Events::RequestFileBlock)
+            .map_err(|_| OtaError::SignalEventFailed)
+    }
+
     /// Initiate a request for a job
     fn request_job_handler(&mut self) -> Result<(), OtaError> {
         rustot_log!(debug, "request_job_handler");
         match self.control.request_job() {
             Err(e) => {
-                if self.request_momentum < self.config.max_request_momentum {
+                if self.request_momentum < self.config.max_describe_retries {
                     // Start request timer
                     self.request_timer
-                        .start(self.config.request_wait_ms)
+                        .start(backoff::request_wait_ms(
+                            &self.config,
+                            self.request_momentum,
+                            self.rng.as_deref_mut(),
+                        ))
                         .map_err(|_| OtaError::Timer)?;
 
                     self.request_momentum += 1;
@@ -629,6 +1458,9 @@ where
                     // Too many requests have been sent without a response or
                     // too many failures when trying to publish the request
                     // message. Abort.
+                    self.agent_event = Some(AgentEvent::Failed {
+                        reason: FailureReason::Timeout,
+                    });
                     Err(OtaError::MomentumAbort)
                 }
             }
@@ -648,22 +1480,45 @@ where
     /// Initialize and handle file transfer
     fn init_file_handler(&mut self) -> Result<(), OtaError> {
         rustot_log!(debug, "init_file_handler");
-        match data_interface!(self.init_file_transfer) {
+        match data_interface!(self.init_file_transfer, &self.config) {
             Err(e) => {
-                if self.request_momentum < self.config.max_request_momentum {
+                if self.request_momentum < self.config.max_block_retries {
                     // Start request timer
                     self.request_timer
-                        .start(self.config.request_wait_ms)
+                        .start(backoff::request_wait_ms(
+                            &self.config,
+                            self.request_momentum,
+                            self.rng.as_deref_mut(),
+                        ))
                         .map_err(|_| OtaError::Timer)?;
 
                     self.request_momentum += 1;
                     Err(e)
+                } else if self.fall_back()? {
+                    self.statistics = Statistics::default();
+
+                    self.events
+                        .enqueue(Events::RequestFileBlock)
+                        .map_err(|_| OtaError::SignalEventFailed)
                 } else {
                     // Stop request timer
                     self.request_timer
                         .cancel()
                         .map_err(|_| OtaError::Timer)?;
 
+                    // Report the job as failed, rather than leaving it in
+                    // progress
+                    if let Some(ref mut interface) = self.active_interface {
+                        self.image_state = Self::set_image_state_with_reason(
+                            self.control,
+                            &mut self.pal,
+                            &self.config,
+                            interface.mut_file_ctx(),
+                            ImageState::Aborted,
+                            Some(ImageStateReason::Timeout),
+                        )?;
+                    }
+
                     // Send shutdown event to the OTA Agent task
                     self.events
                         .enqueue(Events::Shutdown)
@@ -672,7 +1527,9 @@ where
                     // Too many requests have been sent without a response or
                     // too many failures when trying to publish the request
                     // message. Abort.
-
+                    self.agent_event = Some(AgentEvent::Failed {
+                        reason: FailureReason::Timeout,
+                    });
                     Err(OtaError::MomentumAbort)
                 }
             }
@@ -680,7 +1537,7 @@ where
                 // Reset the request momentum
                 self.request_momentum = 0;
 
-                // TODO: Reset the OTA statistics
+                self.statistics = Statistics::default();
 
                 rustot_log!(info, "Initialized file handler! Requesting file blocks");
 
@@ -699,38 +1556,59 @@ where
         // Check the platform's OTA update image state. It should also be in
         // self test
         let in_self_test = self.platform_in_selftest();
-        // Clear self-test flag
-        let file_ctx = self
-            .active_interface
-            .as_mut()
-            .ok_or(OtaError::InvalidInterface)?
-            .mut_file_ctx();
-
-        if in_self_test {
+        if let (true, Some(self_test)) = (in_self_test, self.self_test.as_deref_mut()) {
+            match self_test.run() {
+                SelfTestResult::Accept => {
+                    rustot_log!(info, "Image accepted by application self test");
+                    self.pal.set_platform_image_state(ImageState::Accepted)?;
+                    self.complete_self_test()?;
+                }
+                SelfTestResult::Reject => {
+                    rustot_log!(error, "Image rejected by application self test");
+                    let file_ctx = self
+                        .active_interface
+                        .as_mut()
+                        .ok_or(OtaError::InvalidInterface)?
+                        .mut_file_ctx();
+
+                    self.image_state = Self::set_image_state_with_reason(
+                        self.control,
+                        &mut self.pal,
+                        &self.config,
+                        file_ctx,
+                        ImageState::Rejected,
+                        Some(ImageStateReason::SelfTest),
+                    )?;
+
+                    self.agent_event = Some(AgentEvent::Failed {
+                        reason: FailureReason::SelfTestRejected,
+                    });
+                    self.pal.complete_callback(OtaEvent::SelfTestFailed)?;
+
+                    // Reset the device to roll back to the previous image
+                    self.events
+                        .enqueue(Events::Restart(RestartReason::Restart(0)))
+                        .map_err(|_| OtaError::SignalEventFailed)?;
+                }
+            }
+        } else if in_self_test && self.config.explicit_accept {
+            // Leave the self test timer running, until the application
+            // accepts the image
+            self.agent_event = Some(AgentEvent::SelfTestPending);
+            self.pal.complete_callback(OtaEvent::SelfTestPending)?;
+            rustot_log!(info, "Application callback! OtaEvent::SelfTestPending");
+        } else if in_self_test {
             self.pal.complete_callback(OtaEvent::StartTest)?;
             rustot_log!(info, "Application callback! OtaEvent::StartTest");
 
-            self.image_state = ImageState::Accepted;
-            self.control.update_job_status(
-                file_ctx,
-                &self.config,
-                JobStatus::Succeeded,
-                JobStatusReason::Accepted,
-            )?;
-
-            file_ctx
-                .status_details
-                .insert(
-                    heapless::String::from("self_test"),
-                    heapless::String::from(JobStatusReason::Accepted.as_str()),
-                )
-                .map_err(|_| OtaError::Overflow)?;
-
-            // Stop the self test timer as it is no longer required
-            if let Some(ref mut self_test_timer) = self.self_test_timer {
-                self_test_timer.cancel().map_err(|_| OtaError::Timer)?;
-            }
+            self.complete_self_test()?;
         } else {
+            let file_ctx = self
+                .active_interface
+                .as_mut()
+                .ok_or(OtaError::InvalidInterface)?
+                .mut_file_ctx();
+
             // The job is in self test but the platform image state is not so it
             // could be an attack on the platform image state. Reject the update
             // (this should also cause the image to be erased), aborting the job
@@ -745,6 +1623,9 @@ where
                 Some(ImageStateReason::ImageStateMismatch),
             )?;
 
+            self.agent_event = Some(AgentEvent::Failed {
+                reason: FailureReason::ImageStateMismatch,
+            });
             self.events
                 .enqueue(Events::Restart(RestartReason::Restart(0)))
                 .map_err(|_| OtaError::SignalEventFailed)?;
@@ -752,11 +1633,23 @@ where
         Ok(())
     }
 
+    /// Accept the image under test, on request of the application
+    fn accept_image_handler(&mut self) -> Result<(), OtaError> {
+        if self.image_state != ImageState::Testing || self.active_interface.is_none() {
+            return Err(OtaError::NoActiveJob);
+        }
+
+        rustot_log!(info, "Image accepted by application");
+        self.pal.set_platform_image_state(ImageState::Accepted)?;
+        self.complete_self_test()
+    }
+
     /// Update file context from job document
     fn process_job_handler(&mut self, data: &JobEventData<'_>) -> Result<(), OtaError> {
         let JobEventData {
             job_name,
             ota_document,
+            job_document,
             status_details,
         } = data;
 
@@ -766,10 +1659,12 @@ where
             status_details.map(Clone::clone),
         )?;
 
-        match self.select_interface(file_ctx, &ota_document.protocols) {
+        match self.select_interface(file_ctx, ota_document.data_protocols()) {
             Ok(interface) => {
                 rustot_log!(info, "Setting OTA data interface");
                 self.active_interface = Some(interface);
+                self.protocol_fallback = self.can_fall_back(ota_document.data_protocols());
+                self.keep_job_document(*job_document);
             }
             Err(mut file_ctx) => {
                 // Failed to set the data interface so abort the OTA. If there
@@ -787,6 +1682,9 @@ where
                     ImageState::Aborted,
                     Some(ImageStateReason::InvalidDataProtocol),
                 )?;
+                self.agent_event = Some(AgentEvent::Failed {
+                    reason: FailureReason::InvalidProtocol,
+                });
                 return Err(OtaError::InvalidInterface);
             }
         }
@@ -828,31 +1726,78 @@ where
 
     /// Request for data blocks
     fn request_data_handler(&mut self) -> Result<(), OtaError> {
-        let file_ctx = self
+        let blocks_remaining = self
             .active_interface
-            .as_mut()
+            .as_ref()
             .ok_or(OtaError::InvalidInterface)?
-            .mut_file_ctx();
-        if file_ctx.blocks_remaining > 0 {
+            .file_ctx()
+            .blocks_remaining;
+        if blocks_remaining > 0 {
+            if self.pal_busy {
+                match self.pal.poll_ready() {
+                    Ok(()) => self.pal_busy = false,
+                    Err(OtaPalError::WouldBlock) => {
+                        // Hold off on requesting blocks until the PAL
+                        // drained, without building up momentum
+                        return self
+                            .request_timer
+                            .start(backoff::request_wait_ms(
+                                &self.config,
+                                0,
+                                self.rng.as_deref_mut(),
+                            ))
+                            .map_err(|_| OtaError::Timer);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
             // Start the request timer
             self.request_timer
-                .start(self.config.request_wait_ms)
+                .start(backoff::request_wait_ms(
+                    &self.config,
+                    self.request_momentum,
+                    self.rng.as_deref_mut(),
+                ))
                 .map_err(|_| OtaError::Timer)?;
 
-            if self.request_momentum <= self.config.max_request_momentum {
+            if self.request_momentum <= self.config.max_block_retries {
                 // Each request increases the momentum until a response is
                 // received. Too much momentum is interpreted as a failure to
                 // communicate and will cause us to abort the OTA.
+                if self.request_momentum > 0 {
+                    self.statistics.request_retries += 1;
+                }
                 self.request_momentum += 1;
 
                 // Request data blocks
                 data_interface!(self.request_file_block, &self.config)
+            } else if self.fall_back()? {
+                self.events
+                    .enqueue(Events::RequestFileBlock)
+                    .map_err(|_| OtaError::SignalEventFailed)
             } else {
                 // Stop the request timer
                 self.request_timer
                     .cancel()
                     .map_err(|_| OtaError::Timer)?;
 
+                let file_ctx = self
+                    .active_interface
+                    .as_mut()
+                    .ok_or(OtaError::InvalidInterface)?
+                    .mut_file_ctx();
+
+                // Report the block that could not be received
+                let next_block =
+                    file_ctx.block_offset as usize + file_ctx.bitmap.first_index().unwrap_or(0);
+                if file_ctx
+                    .set_failure(FailureReason::Timeout, Some(next_block))
+                    .is_err()
+                {
+                    rustot_log!(warn, "Failed to report the failure reason");
+                }
+
                 // Failed to send data request abort and close file.
                 self.image_state = Self::set_image_state_with_reason(
                     self.control,
@@ -860,7 +1805,7 @@ where
                     &self.config,
                     file_ctx,
                     ImageState::Aborted,
-                    None,
+                    Some(ImageStateReason::Timeout),
                 )?;
 
                 rustot_log!(warn, "Shutdown [request_data_handler]");
@@ -874,6 +1819,9 @@ where
                 // Too many requests have been sent without a response or too
                 // many failures when trying to publish the request message.
                 // Abort.
+                self.agent_event = Some(AgentEvent::Failed {
+                    reason: FailureReason::Timeout,
+                });
                 Err(OtaError::MomentumAbort)
             }
         } else {
@@ -896,9 +1844,76 @@ where
     }
 
     /// Process incoming data blocks
-    fn process_data_handler(&mut self, payload: &mut [u8]) -> Result<(), OtaError> {
-        // Decode the file block received
-        match self.ingest_data_block(payload) {
+    fn process_data_handler(&mut self, payload: &[u8]) -> Result<(), OtaError> {
+        // Decode the file block received, keeping track of the block in case
+        // it fails
+        let mut failed_block = None;
+        let result = self.decode_data_block(payload).and_then(|block| {
+            failed_block = Some(block.block_id);
+            self.ingest_data_block(&block)
+        });
+
+        self.data_block_handled(result, failed_block)
+    }
+
+    /// Complete closing the file, once the PAL is done closing it in the
+    /// background
+    fn finalize_file_handler(&mut self) -> Result<(), OtaError> {
+        let file_ctx = self
+            .active_interface
+            .as_ref()
+            .ok_or(OtaError::InvalidInterface)?
+            .file_ctx();
+
+        let result = match self.pal.poll_close(file_ctx) {
+            Err(OtaPalError::WouldBlock) => return Ok(()),
+            result => result.map(|_| true).map_err(OtaError::from),
+        };
+
+        self.close_pending = false;
+        self.data_block_handled(result, None)
+    }
+
+    /// Update the job, and queue the next event, according to the `result` of
+    /// ingesting a block
+    fn data_block_handled(
+        &mut self,
+        result: Result<bool, OtaError>,
+        failed_block: Option<usize>,
+    ) -> Result<(), OtaError> {
+        match result {
+            Ok(false) if self.close_pending => {
+                // Nothing to request while the file is being closed
+            }
+            Ok(true) if !self.pending_files.is_empty() => {
+                let file_ctx = self
+                    .active_interface
+                    .as_mut()
+                    .ok_or(OtaError::InvalidInterface)?
+                    .mut_file_ctx();
+
+                rustot_log!(
+                    info,
+                    "File {:?} completed, {:?} file(s) remaining in job",
+                    file_ctx.fileid,
+                    self.pending_files.len()
+                );
+
+                // The job is still in progress, until all of its files are
+                // received
+                self.control.update_job_status(
+                    file_ctx,
+                    &self.config,
+                    JobStatus::InProgress,
+                    JobStatusReason::Receiving,
+                )?;
+
+                self.request_momentum = 0;
+
+                self.events
+                    .enqueue(Events::NextFile)
+                    .map_err(|_| OtaError::SignalEventFailed)?;
+            }
             Ok(true) => {
                 let file_ctx = self
                     .active_interface
@@ -929,9 +1944,9 @@ where
                     .enqueue(Events::CloseFile)
                     .map_err(|_| OtaError::SignalEventFailed)?;
 
-                // TODO: Last file block processed, increment the statistics
-                // otaAgent.statistics.otaPacketsProcessed++;
-
+                self.agent_event = Some(AgentEvent::Completed {
+                    bank: self.pal.image_bank(file_ctx),
+                });
                 match event {
                     OtaEvent::Activate => {
                         self.events
@@ -948,12 +1963,19 @@ where
                     .ok_or(OtaError::InvalidInterface)?
                     .mut_file_ctx();
 
-                // File block processed, increment the statistics.
-                // otaAgent.statistics.otaPacketsProcessed++;
-
                 // Reset the momentum counter since we received a good block
                 self.request_momentum = 0;
 
+                if let (Some(clock), Some(interval)) =
+                    (self.progress_clock, self.config.progress_interval_ms)
+                {
+                    let now = clock.now_ms();
+                    if now.saturating_sub(self.last_progress_ms) >= interval as u64 {
+                        self.last_progress_ms = now;
+                        file_ctx.report_progress = true;
+                    }
+                }
+
                 // We're actively receiving a file so update the job status as
                 // needed
                 self.control.update_job_status(
@@ -965,10 +1987,22 @@ where
 
                 if file_ctx.request_block_remaining > 1 {
                     file_ctx.request_block_remaining -= 1;
+                } else if let Some(delay) =
+                    backoff::throttle_ms(&self.config, file_ctx.bitmap.len())
+                {
+                    // Hold off on the next window until it is due at the
+                    // configured rate. The request timer requests it then.
+                    self.request_timer
+                        .start(delay)
+                        .map_err(|_| OtaError::Timer)?;
                 } else {
                     // Start the request timer.
                     self.request_timer
-                        .start(self.config.request_wait_ms)
+                        .start(backoff::request_wait_ms(
+                            &self.config,
+                            self.request_momentum,
+                            self.rng.as_deref_mut(),
+                        ))
                         .map_err(|_| OtaError::Timer)?;
 
                     self.events
@@ -987,16 +2021,29 @@ where
                     "Failed to ingest data block, rejecting image: ingest_data_block returned error"
                 );
 
+                // The details are best-effort, the image is rejected
+                // regardless
+                if file_ctx
+                    .set_failure(FailureReason::from(e), failed_block)
+                    .is_err()
+                {
+                    rustot_log!(warn, "Failed to report the failure reason");
+                }
+                if let OtaError::Pal(pal_error) = e {
+                    if file_ctx.set_pal_error(pal_error).is_err() {
+                        rustot_log!(warn, "Failed to report the PAL error");
+                    }
+                }
+
                 // Call the platform specific code to reject the image
                 // TODO: This should never write to current image flags?!
                 self.pal.set_platform_image_state(ImageState::Rejected)?;
 
-                // TODO: Pal reason
                 self.control.update_job_status(
                     file_ctx,
                     &self.config,
                     JobStatus::Failed,
-                    JobStatusReason::Pal(0),
+                    JobStatusReason::Rejected,
                 )?;
 
                 // Stop the request timer.
@@ -1009,6 +2056,9 @@ where
                     .enqueue(Events::CloseFile)
                     .map_err(|_| OtaError::SignalEventFailed)?;
 
+                self.agent_event = Some(AgentEvent::Failed {
+                    reason: FailureReason::from(e),
+                });
                 self.pal.complete_callback(OtaEvent::Fail)?;
                 rustot_log!(info, "Application callback! OtaEvent::Fail");
                 return Err(e);
@@ -1020,15 +2070,42 @@ where
         Ok(())
     }
 
+    /// Move on to the next file of a multi-file job, once the previous file
+    /// has been closed. Only the indices of the remaining files are kept, so
+    /// the job document is requested again to create the next file from.
+    fn next_file_handler(&mut self) -> Result<(), OtaError> {
+        // Cleanup related to selected protocol, as the next file is
+        // transferred from scratch
+        data_interface!(self.cleanup, &self.config)?;
+
+        // Progress stored for the previous file is of no further use
+        if let Some(ref mut storage) = self.bitmap_storage {
+            storage.clear().ok();
+        }
+
+        self.request_job_handler()
+    }
+
     /// Close file opened for download
     fn close_file_handler(&mut self) -> Result<(), OtaError> {
         self.ota_close()
     }
 
     /// Handle user interrupt to abort task
-    fn user_abort_handler(&mut self) -> Result<(), OtaError> {
-        rustot_log!(warn, "User abort OTA!");
+    fn user_abort_handler(&mut self, reason: &AbortReason) -> Result<(), OtaError> {
+        rustot_log!(warn, "User abort OTA! {:?}", reason);
         if let Some(ref mut interface) = self.active_interface {
+            // Stop requesting file blocks
+            self.request_timer
+                .cancel()
+                .map_err(|_| OtaError::Timer)?;
+
+            interface
+                .mut_file_ctx()
+                .status_details
+                .insert(heapless::String::from("reason"), reason.to_status()?)
+                .map_err(|_| OtaError::Overflow)?;
+
             self.image_state = Self::set_image_state_with_reason(
                 self.control,
                 &mut self.pal,
@@ -1037,12 +2114,35 @@ where
                 ImageState::Aborted,
                 Some(ImageStateReason::UserAbort),
             )?;
+            self.agent_event = Some(AgentEvent::Failed {
+                reason: FailureReason::Aborted,
+            });
             self.ota_close()
         } else {
             Err(OtaError::NoActiveJob)
         }
     }
 
+    /// Abandon the transfer of a job that was canceled in the cloud. The job
+    /// can no longer be updated, so the transfer is only cleaned up locally.
+    fn job_canceled_handler(&mut self) -> Result<(), OtaError> {
+        rustot_log!(warn, "Job canceled, aborting the transfer in progress");
+
+        // Stop requesting file blocks
+        self.request_timer
+            .cancel()
+            .map_err(|_| OtaError::Timer)?;
+
+        self.image_state = ImageState::Aborted;
+        self.ota_close()?;
+
+        self.agent_event = Some(AgentEvent::Failed {
+            reason: FailureReason::Aborted,
+        });
+        self.pal.complete_callback(OtaEvent::Fail)?;
+        Ok(())
+    }
+
     /// Handle user interrupt to abort task
     fn shutdown_handler(&mut self) -> Result<(), OtaError> {
         rustot_log!(warn, "Shutting down OTA!");
@@ -0,0 +1,57 @@
+//! Download health counters of the OTA agent.
+
+/// Statistics of the file transfer currently in progress.
+///
+/// The counters are reset whenever the agent starts transferring a new file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-impl", derive(defmt::Format))]
+pub struct Statistics {
+    /// Number of file blocks received, including duplicates
+    pub blocks_received: u32,
+    /// Number of file blocks successfully written
+    pub blocks_processed: u32,
+    /// Number of file blocks dropped without being written, including
+    /// duplicate and stale blocks
+    pub blocks_dropped: u32,
+    /// Number of dropped blocks that were already written, e.g. due to the
+    /// broker redelivering a block, or answering a retried request twice
+    pub blocks_duplicate: u32,
+    /// Number of dropped blocks of another file than the one in progress,
+    /// e.g. still in flight from a previous file of the job
+    pub blocks_stale: u32,
+    /// Number of dropped blocks ahead of the window of requested blocks,
+    /// e.g. answering a request made before the window was rewound
+    pub blocks_out_of_window: u32,
+    /// Number of block requests repeated due to a missing response
+    pub request_retries: u32,
+    /// Number of payload bytes of processed blocks
+    pub bytes_received: u32,
+}
+
+impl Statistics {
+    /// Estimate the download rate, given the time elapsed since the transfer
+    /// started.
+    pub fn bytes_per_sec(&self, elapsed_ms: u32) -> u32 {
+        if elapsed_ms == 0 {
+            return 0;
+        }
+        (self.bytes_received as u64 * 1000 / elapsed_ms as u64) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_per_sec() {
+        let stats = Statistics {
+            bytes_received: 10240,
+            ..Statistics::default()
+        };
+
+        assert_eq!(stats.bytes_per_sec(0), 0);
+        assert_eq!(stats.bytes_per_sec(2000), 5120);
+        assert_eq!(stats.bytes_per_sec(500), 20480);
+    }
+}
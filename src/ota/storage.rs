@@ -0,0 +1,117 @@
+//! Persistence of download progress across resets.
+//!
+//! By default, a download interrupted by a power cycle restarts from block 0.
+//! Registering a [`BitmapStorage`] with the agent allows it to persist the
+//! received-block bitmap of the active file, and pick up where it left off
+//! when the same job is received again after boot.
+//!
+//! Similarly, an [`ExecutionStorage`] keeps track of the job in progress, such
+//! that [`PersistentJobs`] can pick up reporting on that job after boot.
+//!
+//! [`ExecutionStorage`]: crate::jobs::storage::ExecutionStorage
+//! [`PersistentJobs`]: super::control_interface::persistent::PersistentJobs
+
+use serde::{Deserialize, Serialize};
+
+use super::encoding::{Bitmap, FileContext};
+use super::error::OtaError;
+
+/// Snapshot of the progress of a file download, as persisted by a
+/// [`BitmapStorage`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DownloadProgress {
+    pub job_name: heapless::String<64>,
+    pub stream_name: heapless::String<64>,
+    pub fileid: u8,
+    pub filesize: usize,
+    pub block_offset: u32,
+    /// Raw value of the bitmap window starting at `block_offset`
+    pub bitmap: u32,
+    pub blocks_remaining: usize,
+}
+
+impl DownloadProgress {
+    pub fn new(file_ctx: &FileContext) -> Self {
+        Self {
+            job_name: file_ctx.job_name.clone(),
+            stream_name: file_ctx.stream_name.clone(),
+            fileid: file_ctx.fileid,
+            filesize: file_ctx.filesize,
+            block_offset: file_ctx.block_offset,
+            bitmap: file_ctx.bitmap.into_value(),
+            blocks_remaining: file_ctx.blocks_remaining,
+        }
+    }
+
+    /// Whether this progress belongs to the file described by `file_ctx`
+    pub fn matches(&self, file_ctx: &FileContext) -> bool {
+        self.job_name == file_ctx.job_name
+            && self.stream_name == file_ctx.stream_name
+            && self.fileid == file_ctx.fileid
+            && self.filesize == file_ctx.filesize
+    }
+
+    /// Restore the progress into a freshly created `file_ctx`
+    pub fn apply(&self, file_ctx: &mut FileContext) {
+        file_ctx.block_offset = self.block_offset;
+        file_ctx.bitmap = Bitmap::from_value(self.bitmap);
+        file_ctx.blocks_remaining = self.blocks_remaining;
+        file_ctx.request_block_remaining = file_ctx.bitmap.len() as u32;
+    }
+}
+
+/// Non-volatile storage of the download progress of the active file.
+///
+/// The agent stores the progress after every ingested block, and clears it
+/// once the file is closed or the job is aborted. Implementations writing to
+/// flash may want to buffer writes, at the cost of re-downloading a few
+/// blocks after a reset.
+///
+/// Delta and compressed files are never resumed, as the state of the patch
+/// applier or decompressor cannot be recovered.
+pub trait BitmapStorage {
+    /// Persist `progress`, replacing any previously stored progress
+    fn store(&mut self, progress: &DownloadProgress) -> Result<(), OtaError>;
+
+    /// Load the stored progress, if any
+    fn load(&mut self) -> Result<Option<DownloadProgress>, OtaError>;
+
+    /// Erase the stored progress
+    fn clear(&mut self) -> Result<(), OtaError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::{config::Config, test::test_file_ctx};
+
+    #[test]
+    fn progress_round_trip() {
+        let config = Config::default();
+        let mut file_ctx = test_file_ctx(&config);
+        file_ctx.block_offset = 31;
+        file_ctx.bitmap.set(3, false);
+        file_ctx.blocks_remaining = 451;
+
+        let progress = DownloadProgress::new(&file_ctx);
+
+        let mut restored = test_file_ctx(&config);
+        assert!(progress.matches(&restored));
+        progress.apply(&mut restored);
+
+        assert_eq!(restored.block_offset, 31);
+        assert!(restored.bitmap == file_ctx.bitmap);
+        assert_eq!(restored.blocks_remaining, 451);
+        assert_eq!(restored.request_block_remaining, 30);
+    }
+
+    #[test]
+    fn progress_of_other_job_does_not_match() {
+        let config = Config::default();
+        let progress = DownloadProgress::new(&test_file_ctx(&config));
+
+        let mut file_ctx = test_file_ctx(&config);
+        file_ctx.job_name = heapless::String::from("Other-job");
+        assert!(!progress.matches(&file_ctx));
+    }
+}
@@ -0,0 +1,406 @@
+//! Download of a single file from an AWS IoT stream, without a job.
+//!
+//! The [`OtaAgent`] only receives files listed in an OTA job document. For
+//! factory provisioning, or testing a PAL against a live stream, a
+//! [`StreamDownloader`] fetches a file straight from a stream created through
+//! `aws iot create-stream`, given its name and the ID of the file:
+//!
+//! ```ignore
+//! let mut downloader = StreamDownloader::new(&mqtt, &mut pal, "factory-image", 0)?;
+//! downloader.start()?;
+//!
+//! loop {
+//!     match mqtt.poll() {
+//!         // Messages on any of the stream topics
+//!         Some((topic, payload)) => {
+//!             if downloader.handle_message(topic, payload)? {
+//!                 break;
+//!             }
+//!         }
+//!         // Nothing received for a while
+//!         None => downloader.request_blocks()?,
+//!     }
+//! }
+//! ```
+//!
+//! The stream is described first, to learn the size of the file. Blocks are
+//! then requested and written to the PAL as by the agent, and the file is
+//! closed once complete. As there is no job, there is no signature to verify,
+//! no status to report and no image state to set: the downloader only calls
+//! [`OtaPal::create_file_for_rx`], [`OtaPal::write_block`],
+//! [`OtaPal::close_file`] and [`OtaPal::abort`].
+//!
+//! [`OtaAgent`]: super::agent::OtaAgent
+
+use mqttrust::Mqtt;
+
+use super::{
+    config::Config,
+    data_interface::{
+        mqtt::{describe_stream, end_describe_stream, Encoding, Topic},
+        DataInterface,
+    },
+    encoding::{
+        cbor,
+        json::{FileDescription, OtaJob},
+        Bitmap, FileContext,
+    },
+    error::OtaError,
+    pal::{OtaPal, Version},
+};
+use crate::jobs::MAX_STREAM_ID_LEN;
+use crate::rustot_log;
+
+/// Downloads file `file_id` of a stream into an [`OtaPal`]
+pub struct StreamDownloader<'a, M, PAL>
+where
+    M: Mqtt,
+    PAL: OtaPal,
+{
+    mqtt: &'a M,
+    pal: &'a mut PAL,
+    config: Config,
+    stream_name: heapless::String<MAX_STREAM_ID_LEN>,
+    file_id: u8,
+    file_ctx: Option<FileContext>,
+}
+
+impl<'a, M, PAL> StreamDownloader<'a, M, PAL>
+where
+    M: Mqtt,
+    PAL: OtaPal,
+{
+    pub fn new(
+        mqtt: &'a M,
+        pal: &'a mut PAL,
+        stream_name: &str,
+        file_id: u8,
+    ) -> Result<Self, OtaError> {
+        if stream_name.is_empty() {
+            return Err(OtaError::InvalidStreamName);
+        }
+
+        Ok(Self {
+            mqtt,
+            pal,
+            config: Config::default(),
+            stream_name: FileContext::stream_name(stream_name)?,
+            file_id,
+            file_ctx: None,
+        })
+    }
+
+    /// Size of the blocks requested from the stream. Defaults to 256 bytes.
+    pub fn block_size(self, block_size: usize) -> Self {
+        Self {
+            config: Config {
+                block_size,
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Prefix of the stream topics, replacing `$aws/things/<thing name>`.
+    /// See [`OtaAgentBuilder::stream_topic_prefix`].
+    ///
+    /// [`OtaAgentBuilder::stream_topic_prefix`]: super::builder::OtaAgentBuilder::stream_topic_prefix
+    pub fn stream_topic_prefix(self, prefix: &'static str) -> Self {
+        Self {
+            config: Config {
+                stream_topic_prefix: Some(prefix),
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Start the download by requesting the description of the stream
+    pub fn start(&mut self) -> Result<(), OtaError> {
+        describe_stream(self.mqtt, self.stream_name.as_str(), &self.config)
+    }
+
+    /// Request the blocks still missing from the current window again, or
+    /// the description of the stream if it was not received yet. To be
+    /// called whenever nothing was received for a while.
+    pub fn request_blocks(&mut self) -> Result<(), OtaError> {
+        match self.file_ctx {
+            Some(ref mut file_ctx) => self.mqtt.request_file_block(file_ctx, &self.config),
+            None => describe_stream(self.mqtt, self.stream_name.as_str(), &self.config),
+        }
+    }
+
+    /// Number of blocks left to receive, once the stream was described
+    pub fn blocks_remaining(&self) -> Option<usize> {
+        self.file_ctx
+            .as_ref()
+            .map(|file_ctx| file_ctx.blocks_remaining)
+    }
+
+    /// Handle a message received on `topic`. Returns `true` once the file is
+    /// complete and closed.
+    ///
+    /// Messages on topics of other streams are ignored. A rejected request
+    /// fails the download with [`OtaError::InvalidFile`], leaving it to the
+    /// application to [`abort`](Self::abort).
+    pub fn handle_message(&mut self, topic: &str, payload: &[u8]) -> Result<bool, OtaError> {
+        let topic = match self.config.stream_topic_prefix {
+            Some(prefix) => Topic::from_str_with_prefix(topic, prefix),
+            None => Topic::from_str(topic),
+        };
+
+        match topic {
+            Some(Topic::Description(Encoding::Cbor, name)) if self.is_stream(name) => {
+                self.described(payload).map(|_| false)
+            }
+            Some(Topic::Data(Encoding::Cbor, name)) if self.is_stream(name) => self.ingest(payload),
+            Some(Topic::Rejected(_, name)) if self.is_stream(name) => {
+                rustot_log!(error, "Request rejected by stream {}", name);
+                Err(OtaError::InvalidFile)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn is_stream(&self, name: &str) -> bool {
+        name == self.stream_name.as_str()
+    }
+
+    /// Abort the download, releasing the file in the PAL if it was created
+    pub fn abort(&mut self) -> Result<(), OtaError> {
+        match self.file_ctx.take() {
+            Some(mut file_ctx) => {
+                self.pal.abort(&file_ctx)?;
+                self.mqtt.cleanup(&mut file_ctx, &self.config)
+            }
+            None => end_describe_stream(self.mqtt, self.stream_name.as_str(), &self.config),
+        }
+    }
+
+    /// Create the file described by the stream, and request its first blocks
+    fn described(&mut self, payload: &[u8]) -> Result<(), OtaError> {
+        if self.file_ctx.is_some() {
+            // Answer to a repeated request
+            return Ok(());
+        }
+
+        let filesize = cbor::decode_describe_stream_response(payload, self.file_id)?
+            .ok_or(OtaError::InvalidFile)?;
+        if filesize == 0 {
+            return Err(OtaError::ZeroFileSize);
+        }
+
+        let mut file_ctx = self.file_context(filesize)?;
+        end_describe_stream(self.mqtt, self.stream_name.as_str(), &self.config)?;
+
+        self.pal.create_file_for_rx(&file_ctx)?;
+        self.mqtt.init_file_transfer(&mut file_ctx, &self.config)?;
+        self.mqtt.request_file_block(&mut file_ctx, &self.config)?;
+
+        self.file_ctx = Some(file_ctx);
+        Ok(())
+    }
+
+    /// Describe the file as if it was the only file of an OTA job
+    fn file_context(&self, filesize: usize) -> Result<FileContext, OtaError> {
+        let mut files = heapless::Vec::new();
+        files
+            .push(FileDescription {
+                filepath: "",
+                filesize,
+                fileid: self.file_id,
+                certfile: "",
+                update_data_url: None,
+                auth_scheme: None,
+                sha1_rsa: None,
+                sha256_rsa: None,
+                sha1_ecdsa: None,
+                // Streams carry no signature, and none is ever verified
+                sha256_ecdsa: Some(""),
+                file_type: None,
+                delta: None,
+                compression: None,
+                sha256: None,
+                attributes: None,
+                app_version: None,
+            })
+            .map_err(|_| OtaError::Overflow)?;
+
+        let job = OtaJob {
+            protocols: heapless::Vec::new(),
+            streamname: self.stream_name.as_str(),
+            files,
+        };
+
+        FileContext::new_from("", &job, None, None, 0, &self.config, Version::default())
+    }
+
+    /// Write a received block, returning `true` once the file is complete
+    fn ingest(&mut self, payload: &[u8]) -> Result<bool, OtaError> {
+        let file_ctx = match self.file_ctx {
+            Some(ref mut file_ctx) => file_ctx,
+            // Blocks of an earlier download of the same stream
+            None => return Ok(false),
+        };
+
+        let block = self.mqtt.decode_file_block(file_ctx, payload)?;
+        if block.file_id != file_ctx.fileid {
+            return Ok(false);
+        }
+        if !block.validate(self.config.block_size, file_ctx.filesize) {
+            return Err(OtaError::BlockOutOfRange);
+        }
+
+        let index = match block.block_id.checked_sub(file_ctx.block_offset as usize) {
+            Some(index) if index < Bitmap::WINDOW && file_ctx.bitmap.get(index) => index,
+            // Duplicate, or outside of the window of requested blocks
+            _ => return Ok(false),
+        };
+
+        self.pal.write_block(
+            file_ctx,
+            block.block_id * self.config.block_size,
+            block.block_payload,
+        )?;
+
+        file_ctx.bitmap.set(index, false);
+        file_ctx.blocks_remaining -= 1;
+
+        if file_ctx.blocks_remaining == 0 {
+            self.pal.close_file(file_ctx)?;
+            self.mqtt.cleanup(file_ctx, &self.config)?;
+            return Ok(true);
+        }
+
+        file_ctx.block_offset = file_ctx.bitmap.slide(
+            file_ctx.filesize,
+            self.config.block_size,
+            file_ctx.block_offset,
+        );
+
+        if file_ctx.request_block_remaining > 1 {
+            file_ctx.request_block_remaining -= 1;
+        } else {
+            self.mqtt.request_file_block(file_ctx, &self.config)?;
+        }
+
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::pal::{ImageState, OtaPalError, PalImageState};
+    use crate::test::{stream::MockStream, MockMqtt};
+
+    /// PAL keeping the received file in memory
+    #[derive(Default)]
+    struct MemoryPal {
+        image: Vec<u8>,
+        closed: bool,
+    }
+
+    impl OtaPal for MemoryPal {
+        type Error = ();
+
+        fn abort(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn create_file_for_rx(
+            &mut self,
+            file: &FileContext,
+        ) -> Result<(), OtaPalError<Self::Error>> {
+            self.image = vec![0; file.filesize];
+            Ok(())
+        }
+
+        fn get_platform_image_state(&self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+            Ok(PalImageState::Valid)
+        }
+
+        fn set_platform_image_state(
+            &mut self,
+            _image_state: ImageState,
+        ) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+            Ok(())
+        }
+
+        fn close_file(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+            self.closed = true;
+            Ok(())
+        }
+
+        fn write_block(
+            &mut self,
+            _file: &FileContext,
+            block_offset: usize,
+            block_payload: &[u8],
+        ) -> Result<usize, OtaPalError<Self::Error>> {
+            self.image[block_offset..block_offset + block_payload.len()]
+                .copy_from_slice(block_payload);
+            Ok(block_payload.len())
+        }
+
+        fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>> {
+            Ok(Version::default())
+        }
+    }
+
+    #[test]
+    fn download_without_job() {
+        let mqtt = MockMqtt::new();
+        let mut pal = MemoryPal::default();
+        let mut stream = MockStream::new(2, 10000);
+
+        let mut downloader = StreamDownloader::new(&mqtt, &mut pal, "factory-image", 2).unwrap();
+        downloader.start().unwrap();
+        assert_eq!(downloader.blocks_remaining(), None);
+
+        let mut complete = false;
+        while !complete {
+            let responses = stream.serve(&mqtt);
+            assert!(!responses.is_empty());
+
+            for response in responses {
+                complete |= downloader
+                    .handle_message(&response.topic, &response.payload)
+                    .unwrap();
+            }
+        }
+
+        assert_eq!(downloader.blocks_remaining(), Some(0));
+        // 40 blocks, requested in windows of 31 blocks
+        assert_eq!(stream.requests, 2);
+
+        drop(downloader);
+        assert!(pal.closed);
+        assert_eq!(pal.image, stream.file());
+    }
+
+    #[test]
+    fn unknown_file() {
+        let mqtt = MockMqtt::new();
+        let mut pal = MemoryPal::default();
+        let mut stream = MockStream::new(0, 10000);
+
+        let mut downloader = StreamDownloader::new(&mqtt, &mut pal, "factory-image", 1).unwrap();
+        downloader.start().unwrap();
+
+        let response = stream.serve(&mqtt).pop().unwrap();
+        assert_eq!(
+            downloader.handle_message(&response.topic, &response.payload),
+            Err(OtaError::InvalidFile)
+        );
+
+        assert!(matches!(
+            StreamDownloader::new(&mqtt, &mut pal, "factory/#", 1),
+            Err(OtaError::InvalidStreamName)
+        ));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::ota::{
     encoding::FileContext,
-    pal::{ImageState, OtaPal, OtaPalError, PalImageState, Version},
+    pal::{ImageState, OtaPal, OtaPalError, PalErrorCode, PalImageState, Version},
 };
 
 ///
@@ -40,14 +40,83 @@ impl embedded_hal::timer::nb::Cancel for MockTimer {
     }
 }
 
+/// Behaviour of a [`MockPal`] overridden by a test. Each hook defaults to
+/// that of a PAL accepting every call, so that a test implements only the
+/// hooks it exercises.
+pub trait PalHooks {
+    type Error: PalErrorCode;
+
+    fn get_platform_image_state(&self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+        Ok(PalImageState::Valid)
+    }
+
+    fn set_platform_image_state(
+        &mut self,
+        _image_state: ImageState,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    fn prepare_region(
+        &mut self,
+        _file: &FileContext,
+        _offset: usize,
+        _len: usize,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    fn close_file(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    fn poll_close(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    fn write_block(
+        &mut self,
+        _file: &FileContext,
+        _block_offset: usize,
+        block_payload: &[u8],
+    ) -> Result<usize, OtaPalError<Self::Error>> {
+        Ok(block_payload.len())
+    }
+
+    fn poll_ready(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        Ok(())
+    }
+
+    fn on_long_operation(&mut self) {}
+}
+
+impl PalHooks for () {
+    type Error = ();
+}
+
 ///
 /// Mock Platform abstration layer used for unit tests. Implements `OtaPal`
-/// trait.
+/// trait, delegating to the [`PalHooks`] of a test.
 ///
-pub struct MockPal {}
+#[derive(Default)]
+pub struct MockPal<H: PalHooks = ()> {
+    pub hooks: H,
+}
 
-impl OtaPal for MockPal {
-    type Error = ();
+impl MockPal {
+    pub fn new() -> Self {
+        Self { hooks: () }
+    }
+}
+
+impl<H: PalHooks> MockPal<H> {
+    pub fn with_hooks(hooks: H) -> Self {
+        Self { hooks }
+    }
+}
+
+impl<H: PalHooks> OtaPal for MockPal<H> {
+    type Error = H::Error;
 
     fn abort(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
         Ok(())
@@ -57,32 +126,53 @@ impl OtaPal for MockPal {
         Ok(())
     }
 
+    fn prepare_region(
+        &mut self,
+        file: &FileContext,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), OtaPalError<Self::Error>> {
+        self.hooks.prepare_region(file, offset, len)
+    }
+
     fn get_platform_image_state(&self) -> Result<PalImageState, OtaPalError<Self::Error>> {
-        Ok(PalImageState::Valid)
+        self.hooks.get_platform_image_state()
     }
 
     fn set_platform_image_state(
         &mut self,
-        _image_state: ImageState,
+        image_state: ImageState,
     ) -> Result<(), OtaPalError<Self::Error>> {
-        Ok(())
+        self.hooks.set_platform_image_state(image_state)
     }
 
     fn reset_device(&mut self) -> Result<(), OtaPalError<Self::Error>> {
         Ok(())
     }
 
-    fn close_file(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
-        Ok(())
+    fn close_file(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.hooks.close_file(file)
     }
 
     fn write_block(
         &mut self,
-        _file: &FileContext,
-        _block_offset: usize,
+        file: &FileContext,
+        block_offset: usize,
         block_payload: &[u8],
     ) -> Result<usize, OtaPalError<Self::Error>> {
-        Ok(block_payload.len())
+        self.hooks.write_block(file, block_offset, block_payload)
+    }
+
+    fn poll_ready(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+        self.hooks.poll_ready()
+    }
+
+    fn poll_close(&mut self, file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+        self.hooks.poll_close(file)
+    }
+
+    fn on_long_operation(&mut self) {
+        self.hooks.on_long_operation()
     }
 
     fn get_active_firmware_version(&self) -> Result<Version, OtaPalError<Self::Error>> {
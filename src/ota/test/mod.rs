@@ -21,11 +21,16 @@ pub fn test_job_doc() -> OtaJob<'static> {
             certfile: "cert",
             update_data_url: None,
             auth_scheme: None,
-            sha1_rsa: Some(heapless::String::from("")),
+            sha1_rsa: Some(""),
             file_type: Some(0),
             sha256_rsa: None,
             sha1_ecdsa: None,
             sha256_ecdsa: None,
+            delta: None,
+            compression: None,
+            sha256: None,
+            attributes: None,
+            app_version: None,
         }])
         .unwrap(),
     }
@@ -39,8 +44,11 @@ pub fn test_file_ctx(config: &Config) -> FileContext {
 pub mod ota_tests {
     use crate::jobs::data_types::{DescribeJobExecutionResponse, JobExecution, JobStatus};
     use crate::ota::data_interface::Protocol;
-    use crate::ota::encoding::json::{FileDescription, OtaJob};
+    use crate::ota::encoding::json::{AbortReason, FailureReason, FileDescription, OtaJob};
+    use crate::ota::encoding::Bitmap;
     use crate::ota::error::OtaError;
+    use crate::ota::event::AgentEvent;
+    use crate::ota::split::{BlockQueue, MessageQueue};
     use crate::ota::state::{Error, Events, States};
     use crate::ota::test::test_job_doc;
     use crate::ota::{
@@ -50,7 +58,7 @@ pub mod ota_tests {
         pal::OtaPal,
         test::mock::{MockPal, MockTimer},
     };
-    use crate::test::MockMqtt;
+    use crate::test::{stream::MockStream, MockMqtt};
     use embedded_hal::timer;
     use mqttrust::encoding::v4::{decode_slice, utils::Pid, PacketType};
     use mqttrust::{MqttError, Packet, QoS, SubscribeTopic};
@@ -73,13 +81,44 @@ pub mod ota_tests {
     ) -> OtaAgent<'_, MockMqtt, &MockMqtt, NoInterface, MockTimer, MockTimer, MockPal> {
         let request_timer = MockTimer::new();
         let self_test_timer = MockTimer::new();
-        let pal = MockPal {};
+        let pal = MockPal::new();
 
         OtaAgent::builder(mqtt, mqtt, request_timer, pal)
             .with_self_test_timeout(self_test_timer, 16000)
             .build()
     }
 
+    /// Payloads of the status updates of `Test-job`, draining all packets
+    /// sent so far
+    fn job_updates(mqtt: &MockMqtt) -> Vec<String> {
+        mqtt.tx
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Publish(p))
+                    if p.topic_name == "$aws/things/test_client/jobs/Test-job/update" =>
+                {
+                    Some(core::str::from_utf8(p.payload).unwrap().to_owned())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// CBOR encoded full block `block_id` of file `file_id`, as received on
+    /// the MQTT data topic
+    fn test_block(file_id: u8, block_id: u8) -> Vec<u8> {
+        let mut payload = vec![0xbf, 0x61, b'f', file_id, 0x61, b'i'];
+        if block_id >= 24 {
+            payload.push(0x18);
+        }
+        payload.push(block_id);
+        payload.extend_from_slice(&[0x61, b'l', 0x19, 0x01, 0x00, 0x61, b'p', 0x59, 0x01, 0x00]);
+        payload.extend_from_slice(&[0u8; 256]);
+        payload.push(0xff);
+        payload
+    }
+
     fn run_to_state<'a, C, DP, DS, T, ST, PAL>(
         agent: &mut OtaAgent<'a, C, DP, DS, T, ST, PAL>,
         state: States,
@@ -97,166 +136,1684 @@ pub mod ota_tests {
             return;
         }
 
-        match state {
-            States::Ready => {
-                println!(
-                    "Running to 'States::Ready', events: {}",
-                    agent.state.context().events.len()
-                );
-                agent.state.process_event(Events::Shutdown).unwrap();
-            }
-            States::CreatingFile => {
-                println!(
-                    "Running to 'States::CreatingFile', events: {}",
-                    agent.state.context().events.len()
-                );
-                run_to_state(agent, States::WaitingForJob);
+        match state {
+            States::Ready => {
+                println!(
+                    "Running to 'States::Ready', events: {}",
+                    agent.state.context().events.len()
+                );
+                agent.state.process_event(Events::Shutdown).unwrap();
+            }
+            States::CreatingFile => {
+                println!(
+                    "Running to 'States::CreatingFile', events: {}",
+                    agent.state.context().events.len()
+                );
+                run_to_state(agent, States::WaitingForJob);
+
+                let job_doc = test_job_doc();
+                agent.job_update("Test-job", &job_doc, None).unwrap();
+                agent.state.context_mut().events.dequeue();
+            }
+            States::RequestingFileBlock => {
+                println!(
+                    "Running to 'States::RequestingFileBlock', events: {}",
+                    agent.state.context().events.len()
+                );
+                run_to_state(agent, States::CreatingFile);
+                agent.state.process_event(Events::CreateFile).unwrap();
+                agent.state.context_mut().events.dequeue();
+            }
+            States::RequestingJob => {
+                println!(
+                    "Running to 'States::RequestingJob', events: {}",
+                    agent.state.context().events.len()
+                );
+                run_to_state(agent, States::Ready);
+                agent.state.process_event(Events::Start).unwrap();
+                agent.state.context_mut().events.dequeue();
+            }
+            States::Suspended => {
+                println!(
+                    "Running to 'States::Suspended', events: {}",
+                    agent.state.context().events.len()
+                );
+                run_to_state(agent, States::Ready);
+                agent.suspend().unwrap();
+            }
+            States::WaitingForFileBlock => {
+                println!(
+                    "Running to 'States::Suspended', events: {}",
+                    agent.state.context().events.len()
+                );
+                run_to_state(agent, States::RequestingFileBlock);
+                agent.state.process_event(Events::RequestFileBlock).unwrap();
+                agent.state.context_mut().events.dequeue();
+            }
+            States::WaitingForJob => {
+                println!(
+                    "Running to 'States::WaitingForJob', events: {}",
+                    agent.state.context().events.len()
+                );
+                run_to_state(agent, States::RequestingJob);
+                agent.check_for_update().unwrap();
+            }
+            States::Restarting => {}
+        }
+    }
+
+    pub fn set_pid(buf: &mut [u8], pid: Pid) -> Result<(), ()> {
+        let mut offset = 0;
+        let (header, _) = mqttrust::encoding::v4::decoder::read_header(buf, &mut offset)
+            .map_err(|_| ())?
+            .ok_or(())?;
+
+        match (header.typ, header.qos) {
+            (PacketType::Publish, QoS::AtLeastOnce | QoS::ExactlyOnce) => {
+                if buf[offset..].len() < 2 {
+                    return Err(());
+                }
+                let len = ((buf[offset] as usize) << 8) | buf[offset + 1] as usize;
+
+                offset += 2;
+                if len > buf[offset..].len() {
+                    return Err(());
+                } else {
+                    offset += len;
+                }
+            }
+            (PacketType::Subscribe | PacketType::Unsubscribe | PacketType::Suback, _) => {}
+            (
+                PacketType::Puback
+                | PacketType::Pubrec
+                | PacketType::Pubrel
+                | PacketType::Pubcomp
+                | PacketType::Unsuback,
+                _,
+            ) => {}
+            _ => return Ok(()),
+        }
+
+        pid.to_buffer(buf, &mut offset).map_err(|_| ())
+    }
+
+    #[test]
+    fn ready_when_stopped() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        assert!(matches!(ota_agent.state.state(), &States::Ready));
+        run_to_state(&mut ota_agent, States::Ready);
+        assert!(matches!(ota_agent.state.state(), &States::Ready));
+        assert_eq!(ota_agent.state.context().events.len(), 0);
+        assert_eq!(mqtt.tx.borrow_mut().len(), 0);
+    }
+
+    #[test]
+    fn abort_when_stopped() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::Ready);
+        assert_eq!(ota_agent.state.context().events.len(), 0);
+
+        assert_eq!(
+            ota_agent.abort(AbortReason::User).err(),
+            Some(Error::GuardFailed(OtaError::NoActiveJob))
+        );
+        ota_agent.process_event().unwrap();
+        assert!(matches!(ota_agent.state.state(), &States::Ready));
+        assert_eq!(mqtt.tx.borrow_mut().len(), 0);
+    }
+
+    #[test]
+    fn abort_reports_reason() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        mqtt.tx.borrow_mut().clear();
+
+        assert!(matches!(
+            ota_agent.abort(AbortReason::LowBattery).unwrap(),
+            &States::WaitingForJob
+        ));
+        assert!(ota_agent.state.context().active_interface.is_none());
+
+        let bytes = mqtt.tx.borrow_mut().pop_front().unwrap();
+        let publish = match decode_slice(bytes.as_slice()).unwrap() {
+            Some(Packet::Publish(p)) => p,
+            _ => panic!(),
+        };
+
+        assert_eq!(
+            publish.topic_name,
+            "$aws/things/test_client/jobs/Test-job/update"
+        );
+        let payload = core::str::from_utf8(publish.payload).unwrap();
+        assert!(payload.contains(r#""status":"FAILED""#));
+        assert!(payload.contains(r#""reason":"low_battery""#));
+    }
+
+    #[test]
+    fn request_timeout_reports_failure() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        mqtt.tx.borrow_mut().clear();
+
+        // Keep requesting the first block without ever receiving it
+        let error = (0..10)
+            .find_map(|_| ota_agent.state.process_event(Events::RequestTimer).err())
+            .unwrap();
+        assert_eq!(error, Error::GuardFailed(OtaError::MomentumAbort));
+
+        let payload = mqtt
+            .tx
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Publish(p))
+                    if p.topic_name == "$aws/things/test_client/jobs/Test-job/update" =>
+                {
+                    Some(core::str::from_utf8(p.payload).unwrap().to_owned())
+                }
+                _ => None,
+            })
+            .last()
+            .unwrap();
+
+        assert!(payload.contains(r#""status":"FAILED""#));
+        assert!(payload.contains(r#""failure":"timeout""#));
+        assert!(payload.contains(r#""failed_block":"0""#));
+    }
+
+    #[test]
+    fn block_retry_limit() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal::new())
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .max_block_retries(1)
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        ota_agent.take_event();
+
+        assert_eq!(
+            ota_agent.state.process_event(Events::RequestTimer).err(),
+            None
+        );
+        assert_eq!(ota_agent.statistics().request_retries, 1);
+        assert_eq!(
+            ota_agent.state.process_event(Events::RequestTimer).err(),
+            Some(Error::GuardFailed(OtaError::MomentumAbort))
+        );
+        assert_eq!(
+            ota_agent.take_event(),
+            Some(AgentEvent::Failed {
+                reason: FailureReason::Timeout
+            })
+        );
+    }
+
+    #[test]
+    fn job_canceled_mid_download() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        mqtt.tx.borrow_mut().clear();
+
+        // Cancellation of any other job is ignored
+        assert!(matches!(
+            ota_agent.job_canceled("Other-job").unwrap(),
+            &States::WaitingForFileBlock
+        ));
+
+        assert!(matches!(
+            ota_agent.job_canceled("Test-job").unwrap(),
+            &States::WaitingForJob
+        ));
+        assert!(ota_agent.state.context().active_interface.is_none());
+        assert!(!ota_agent.state.context().request_timer.is_started);
+
+        // The data topic is unsubscribed, without updating the canceled job
+        let bytes = mqtt.tx.borrow_mut().pop_front().unwrap();
+        assert!(matches!(
+            decode_slice(bytes.as_slice()).unwrap(),
+            Some(Packet::Unsubscribe(_))
+        ));
+        assert_eq!(mqtt.tx.borrow_mut().len(), 0);
+    }
+
+    #[test]
+    fn job_canceled_by_notify_next() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        // Without a transfer in progress, there is nothing to cancel
+        ota_agent
+            .handle_job_message(
+                "$aws/things/test_client/jobs/notify-next",
+                br#"{"timestamp":1587381778}"#,
+            )
+            .unwrap();
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+
+        // Messages on other topics are ignored
+        assert!(matches!(
+            ota_agent
+                .handle_job_message("$aws/things/test_client/streams/test_stream/data/cbor", b"")
+                .unwrap(),
+            &States::WaitingForFileBlock
+        ));
+
+        assert!(matches!(
+            ota_agent
+                .handle_job_message(
+                    "$aws/things/test_client/jobs/notify-next",
+                    br#"{"timestamp":1587381778}"#,
+                )
+                .unwrap(),
+            &States::WaitingForJob
+        ));
+        assert!(ota_agent.state.context().active_interface.is_none());
+    }
+
+    #[test]
+    fn job_canceled_by_rejected_update() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+
+        // A rejection for any other reason leaves the transfer running
+        assert!(matches!(
+            ota_agent
+                .handle_job_message(
+                    "$aws/things/test_client/jobs/Test-job/update/rejected",
+                    br#"{"code":"RequestThrottled","message":"Rate exceeded","timestamp":1587381778}"#,
+                )
+                .unwrap(),
+            &States::WaitingForFileBlock
+        ));
+
+        assert!(matches!(
+            ota_agent
+                .handle_job_message(
+                    "$aws/things/test_client/jobs/Test-job/update/rejected",
+                    br#"{"code":"TerminalStateReached","message":"Job is canceled","timestamp":1587381778}"#,
+                )
+                .unwrap(),
+            &States::WaitingForJob
+        ));
+        assert!(ota_agent.state.context().active_interface.is_none());
+    }
+
+    #[test]
+    fn agent_events() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::RequestingFileBlock);
+        assert_eq!(ota_agent.take_event(), Some(AgentEvent::Started));
+        assert_eq!(ota_agent.take_event(), None);
+
+        // Requesting the block raises no event
+        assert_eq!(ota_agent.process_event().unwrap(), None);
+
+        ota_agent.abort(AbortReason::User).unwrap();
+        assert_eq!(
+            ota_agent.take_event(),
+            Some(AgentEvent::Failed {
+                reason: FailureReason::Aborted
+            })
+        );
+    }
+
+    #[test]
+    fn update_version_check() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+        mqtt.tx.borrow_mut().clear();
+
+        // The running firmware is 0.0.0, so the same version is rejected
+        // before anything is downloaded
+        let mut job_doc = test_job_doc();
+        job_doc.files[0].app_version = Some("0.0.0");
+        assert_eq!(
+            ota_agent.job_update("Test-job", &job_doc, None).err(),
+            Some(Error::GuardFailed(OtaError::VersionRejected))
+        );
+        assert!(matches!(ota_agent.state.state(), &States::WaitingForJob));
+        assert!(ota_agent.state.context().active_interface.is_none());
+        assert_eq!(
+            ota_agent.take_event(),
+            Some(AgentEvent::Failed {
+                reason: FailureReason::VersionRejected
+            })
+        );
+
+        let payload = mqtt
+            .tx
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Publish(p))
+                    if p.topic_name == "$aws/things/test_client/jobs/Test-job/update" =>
+                {
+                    Some(core::str::from_utf8(p.payload).unwrap().to_owned())
+                }
+                _ => None,
+            })
+            .last()
+            .unwrap();
+        assert!(payload.contains(r#""status":"FAILED""#));
+        assert!(payload.contains(r#""failure":"version""#));
+
+        // Newer versions are downloaded as usual
+        job_doc.files[0].app_version = Some("0.1.0");
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+        assert!(matches!(ota_agent.state.state(), &States::CreatingFile));
+        assert_eq!(ota_agent.take_event(), Some(AgentEvent::Started));
+    }
+
+    #[test]
+    fn duplicate_and_stale_blocks() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+
+        ota_agent.handle_message(&test_block(0, 1)).unwrap();
+        ota_agent.handle_message(&test_block(0, 1)).unwrap();
+        ota_agent.handle_message(&test_block(1, 0)).unwrap();
+
+        let statistics = ota_agent.statistics();
+        assert_eq!(statistics.blocks_received, 3);
+        assert_eq!(statistics.blocks_processed, 1);
+        assert_eq!(statistics.blocks_dropped, 2);
+        assert_eq!(statistics.blocks_duplicate, 1);
+        assert_eq!(statistics.blocks_stale, 1);
+    }
+
+    #[test]
+    fn out_of_order_blocks() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+
+        // Any block missing from the window is taken, in any order
+        for block_id in [2, 30, 1, 0] {
+            ota_agent.handle_message(&test_block(0, block_id)).unwrap();
+        }
+
+        // Blocks behind and ahead of the window are dropped
+        ota_agent.handle_message(&test_block(0, 1)).unwrap();
+        ota_agent.handle_message(&test_block(0, 40)).unwrap();
+
+        let statistics = ota_agent.statistics();
+        assert_eq!(statistics.blocks_processed, 4);
+        assert_eq!(statistics.blocks_dropped, 2);
+        assert_eq!(statistics.blocks_duplicate, 1);
+        assert_eq!(statistics.blocks_out_of_window, 1);
+
+        // Only the holes of the window are requested again
+        let file_ctx = ota_agent
+            .state
+            .context()
+            .active_interface
+            .as_ref()
+            .unwrap()
+            .file_ctx();
+        assert_eq!(file_ctx.block_offset, 3);
+        assert_eq!(file_ctx.blocks_remaining, 483 - 4);
+        assert!(!file_ctx.bitmap.get(30 - 3));
+        assert_eq!(file_ctx.bitmap.len(), Bitmap::WINDOW - 1);
+    }
+
+    #[test]
+    fn download_from_stream() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+        let mut stream = MockStream::new(0, test_job_doc().files[0].filesize);
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+
+        let mut completed = false;
+        while matches!(ota_agent.state(), &States::WaitingForFileBlock) {
+            let responses = stream.serve(&mqtt);
+            assert!(!responses.is_empty());
+
+            for response in responses {
+                assert_eq!(
+                    response.topic,
+                    "$aws/things/test_client/streams/test_stream/data/cbor"
+                );
+                let event = ota_agent.handle_message(&response.payload).unwrap();
+                completed |= matches!(event, Some(AgentEvent::Completed { .. }));
+            }
+
+            while !ota_agent.state.context().events.is_empty() {
+                ota_agent.process_event().unwrap();
+            }
+        }
+
+        assert!(completed);
+        assert_eq!(stream.requests, 16);
+
+        let statistics = ota_agent.statistics();
+        assert_eq!(statistics.blocks_processed, 483);
+        assert_eq!(statistics.blocks_dropped, 0);
+        assert_eq!(statistics.bytes_received, 123456);
+    }
+
+    #[test]
+    fn custom_integrity_check() {
+        use crate::ota::{encoding::FileContext, integrity::IntegrityCheck};
+
+        /// Wrapping sum of all file bytes
+        struct ByteSum {
+            sum: u8,
+            expected: u8,
+        }
+
+        impl IntegrityCheck for ByteSum {
+            fn reset(&mut self, _file: &FileContext) {
+                self.sum = 0;
+            }
+
+            fn update(&mut self, data: &[u8]) {
+                self.sum = data.iter().fold(self.sum, |sum, b| sum.wrapping_add(*b));
+            }
+
+            fn verify(&mut self, _file: &FileContext) -> bool {
+                self.sum == self.expected
+            }
+        }
+
+        let filesize = test_job_doc().files[0].filesize;
+        let expected = (0..filesize)
+            .map(MockStream::byte)
+            .fold(0u8, |sum, b| sum.wrapping_add(b));
+
+        for (expected, passes) in [(expected, true), (expected.wrapping_add(1), false)] {
+            let mqtt = MockMqtt::new();
+            let mut check = ByteSum { sum: 0, expected };
+            let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal::new())
+                .with_self_test_timeout(MockTimer::new(), 16000)
+                .integrity_check(&mut check)
+                .build();
+            let mut stream = MockStream::new(0, filesize);
+
+            run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+
+            let mut result = Ok(None);
+            while result.is_ok() && matches!(ota_agent.state(), &States::WaitingForFileBlock) {
+                for response in stream.serve(&mqtt) {
+                    result = ota_agent.handle_message(&response.payload);
+                    if result.is_err() {
+                        break;
+                    }
+                }
+
+                while !ota_agent.state.context().events.is_empty() {
+                    ota_agent.process_event().unwrap();
+                }
+            }
+
+            if passes {
+                assert!(matches!(result, Ok(Some(AgentEvent::Completed { .. }))));
+            } else {
+                assert_eq!(
+                    result.err(),
+                    Some(Error::GuardFailed(OtaError::DigestMismatch))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn split_agent() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        ota_agent.take_event();
+
+        let mut blocks = BlockQueue::<300, 2>::new();
+        let (mut control, mut data) = blocks.split(ota_agent);
+
+        let payload = test_block(0, 0);
+
+        assert_eq!(data.handle_message(&[0u8; 301]), Err(OtaError::Overflow));
+        data.handle_message(&payload).unwrap();
+        assert!(!data.ready());
+        assert_eq!(
+            data.handle_message(&payload),
+            Err(OtaError::SignalEventFailed)
+        );
+
+        assert_eq!(
+            control.process().unwrap(),
+            Some(AgentEvent::BlockReceived {
+                blocks_received: 1,
+                blocks_total: 483
+            })
+        );
+        assert!(data.ready());
+        assert!(matches!(control.state(), &States::WaitingForFileBlock));
+    }
+
+    #[test]
+    fn process_queued_messages() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        ota_agent.take_event();
+
+        let mut messages = MessageQueue::<400, 4>::new();
+        let (mut producer, mut consumer) = messages.split();
+
+        let data_topic = "$aws/things/test_client/streams/test_stream/data/cbor";
+        let job_topic = "$aws/things/test_client/jobs/notify-next";
+        let block = test_block(0, 0);
+
+        assert_eq!(
+            producer.enqueue(data_topic, &[0u8; 350]),
+            Err(OtaError::Overflow)
+        );
+        producer.enqueue(data_topic, &block).unwrap();
+        producer.enqueue(job_topic, b"{}").unwrap();
+        producer.enqueue(data_topic, &test_block(0, 1)).unwrap();
+        assert!(!producer.ready());
+
+        // Only the block queued ahead of the job message is handled
+        assert_eq!(
+            ota_agent.process(&mut consumer).unwrap(),
+            Some(AgentEvent::BlockReceived {
+                blocks_received: 1,
+                blocks_total: 483
+            })
+        );
+
+        let message = consumer.dequeue().unwrap();
+        assert_eq!(message.topic(), job_topic);
+        assert_eq!(message.payload(), b"{}");
+
+        assert_eq!(
+            ota_agent.process(&mut consumer).unwrap(),
+            Some(AgentEvent::BlockReceived {
+                blocks_received: 2,
+                blocks_total: 483
+            })
+        );
+        assert!(consumer.dequeue().is_none());
+        assert_eq!(ota_agent.statistics().blocks_processed, 2);
+    }
+
+    #[test]
+    fn transition_hook() {
+        use crate::ota::event::{Transition, TransitionHook};
+
+        #[derive(Default)]
+        struct Recorder {
+            transitions: Vec<Transition>,
+        }
+
+        impl TransitionHook for Recorder {
+            fn on_transition(&mut self, transition: &Transition) {
+                self.transitions.push(*transition);
+            }
+        }
+
+        let mqtt = MockMqtt::new();
+        let mut recorder = Recorder::default();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal::new())
+            .with_self_test_timeout(MockTimer::new(), 16000)
+            .transition_hook(&mut recorder)
+            .build();
+
+        ota_agent.init();
+        while !ota_agent.state.context().events.is_empty() {
+            ota_agent.process_event().unwrap();
+        }
+        ota_agent
+            .job_update("Test-job", &test_job_doc(), None)
+            .unwrap();
+        while !ota_agent.state.context().events.is_empty() {
+            ota_agent.process_event().unwrap();
+        }
+        assert!(matches!(ota_agent.state(), &States::WaitingForFileBlock));
+        ota_agent.handle_message(&test_block(0, 0)).unwrap();
+
+        // Rejected events are not reported
+        assert_eq!(ota_agent.accept_image().err(), Some(Error::InvalidEvent));
+        drop(ota_agent);
+
+        let transitions = recorder.transitions;
+        assert_eq!(
+            transitions.first(),
+            Some(&Transition {
+                from: "Ready",
+                to: "RequestingJob",
+                trigger: "Start",
+            })
+        );
+        assert!(transitions.contains(&Transition {
+            from: "WaitingForJob",
+            to: "CreatingFile",
+            trigger: "ReceivedJobDocument",
+        }));
+        assert_eq!(
+            transitions.last(),
+            Some(&Transition {
+                from: "WaitingForFileBlock",
+                to: "WaitingForFileBlock",
+                trigger: "ReceivedFileBlock",
+            })
+        );
+    }
+
+    #[test]
+    fn resume_when_stopped() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::Ready);
+        assert_eq!(ota_agent.state.context().events.len(), 0);
+
+        assert!(matches!(
+            ota_agent.resume().err().unwrap(),
+            Error::InvalidEvent
+        ));
+        ota_agent.process_event().unwrap();
+        assert!(matches!(ota_agent.state.state(), &States::Ready));
+        assert_eq!(mqtt.tx.borrow_mut().len(), 0);
+    }
+
+    #[test]
+    fn resume_when_suspended() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::Suspended);
+        assert_eq!(ota_agent.state.context().events.len(), 0);
+
+        assert!(matches!(
+            ota_agent.resume().unwrap(),
+            &States::RequestingJob
+        ));
+        assert_eq!(mqtt.tx.borrow_mut().len(), 0);
+    }
+
+    #[test]
+    fn suspend_and_resume_transfer() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        mqtt.tx.borrow_mut().clear();
+
+        assert!(matches!(ota_agent.suspend().unwrap(), &States::Suspended));
+        assert!(ota_agent.state.context().active_interface.is_some());
+        assert_eq!(ota_agent.state.context().events.len(), 0);
+
+        let bytes = mqtt.tx.borrow_mut().pop_front().unwrap();
+        let packet = decode_slice(bytes.as_slice()).unwrap();
+        let topics = match packet {
+            Some(Packet::Unsubscribe(ref s)) => s.topics().collect::<Vec<_>>(),
+            _ => panic!(),
+        };
+        assert_eq!(
+            topics,
+            vec!["$aws/things/test_client/streams/test_stream/data/cbor"]
+        );
+
+        assert!(matches!(
+            ota_agent.resume().unwrap(),
+            &States::RequestingFileBlock
+        ));
+        assert!(matches!(
+            ota_agent.state.context_mut().events.dequeue(),
+            Some(Events::RequestFileBlock)
+        ));
+
+        let bytes = mqtt.tx.borrow_mut().pop_front().unwrap();
+        let packet = decode_slice(bytes.as_slice()).unwrap();
+        assert!(matches!(packet, Some(Packet::Subscribe(_))));
+
+        // The transfer continues where it was suspended
+        assert!(matches!(
+            ota_agent.state.process_event(Events::RequestFileBlock),
+            Ok(&States::WaitingForFileBlock)
+        ));
+    }
+
+    #[cfg(all(feature = "ota_mqtt_data", feature = "ota_http_data"))]
+    #[test]
+    fn protocol_fallback() {
+        use crate::ota::{
+            config::Config, data_interface::FileBlock, encoding::FileContext, state::Interface,
+        };
+
+        /// HTTP interface that never manages to start a transfer
+        struct FailingHttp;
+
+        impl DataInterface for FailingHttp {
+            const PROTOCOL: Protocol = Protocol::Http;
+
+            fn init_file_transfer(
+                &self,
+                _file_ctx: &mut FileContext,
+                _config: &Config,
+            ) -> Result<(), OtaError> {
+                Err(OtaError::Http)
+            }
+
+            fn request_file_block(
+                &self,
+                _file_ctx: &mut FileContext,
+                _config: &Config,
+            ) -> Result<(), OtaError> {
+                Err(OtaError::Http)
+            }
+
+            fn decode_file_block<'a>(
+                &self,
+                _file_ctx: &mut FileContext,
+                _payload: &'a [u8],
+            ) -> Result<FileBlock<'a>, OtaError> {
+                Err(OtaError::Http)
+            }
+
+            fn cleanup(
+                &self,
+                _file_ctx: &mut FileContext,
+                _config: &Config,
+            ) -> Result<(), OtaError> {
+                Ok(())
+            }
+        }
+
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal::new())
+            .data_secondary(FailingHttp)
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+
+        // HTTP is preferred by the job document
+        let job_doc = OtaJob {
+            protocols: heapless::Vec::from_slice(&[Protocol::Http, Protocol::Mqtt]).unwrap(),
+            ..test_job_doc()
+        };
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+        ota_agent.state.context_mut().events.dequeue();
+        assert!(matches!(
+            ota_agent.state.context().active_interface,
+            Some(Interface::Secondary(_))
+        ));
+
+        assert_eq!(
+            ota_agent.state.process_event(Events::CreateFile).err(),
+            Some(Error::GuardFailed(OtaError::Http))
+        );
+        for _ in 0..2 {
+            assert_eq!(
+                ota_agent.state.process_event(Events::RequestTimer).err(),
+                Some(Error::GuardFailed(OtaError::Http))
+            );
+        }
+
+        // Out of momentum, so the transfer falls back to MQTT
+        assert!(matches!(
+            ota_agent.state.process_event(Events::RequestTimer),
+            Ok(&States::RequestingFileBlock)
+        ));
+        assert!(matches!(
+            ota_agent.state.context().active_interface,
+            Some(Interface::Primary(_))
+        ));
+        assert!(matches!(
+            ota_agent.state.context_mut().events.dequeue(),
+            Some(Events::RequestFileBlock)
+        ));
+    }
+
+    #[test]
+    fn write_backpressure() {
+        use crate::ota::{encoding::FileContext, pal::OtaPalError, test::mock::PalHooks};
+
+        /// PAL forwarding blocks to a co-processor, accepting one block at a
+        /// time
+        #[derive(Default)]
+        struct ForwardingPal {
+            in_flight: bool,
+            written: usize,
+        }
+
+        impl PalHooks for ForwardingPal {
+            type Error = ();
+
+            fn write_block(
+                &mut self,
+                _file: &FileContext,
+                _block_offset: usize,
+                block_payload: &[u8],
+            ) -> Result<usize, OtaPalError<Self::Error>> {
+                if self.in_flight {
+                    return Err(OtaPalError::WouldBlock);
+                }
+                self.in_flight = true;
+                self.written += 1;
+                Ok(block_payload.len())
+            }
+
+            fn poll_ready(&mut self) -> Result<(), OtaPalError<Self::Error>> {
+                if self.in_flight {
+                    Err(OtaPalError::WouldBlock)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(
+            &mqtt,
+            &mqtt,
+            MockTimer::new(),
+            MockPal::<ForwardingPal>::default(),
+        )
+        .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        mqtt.tx.borrow_mut().clear();
+
+        // The second block is dropped while the first one is in flight
+        ota_agent.handle_message(&test_block(0, 0)).unwrap();
+        ota_agent.handle_message(&test_block(0, 1)).unwrap();
+        assert_eq!(ota_agent.statistics().blocks_processed, 1);
+        assert_eq!(ota_agent.statistics().blocks_dropped, 1);
+        mqtt.tx.borrow_mut().clear();
+
+        // No blocks are requested until the PAL drained
+        ota_agent.state.process_event(Events::RequestTimer).unwrap();
+        assert_eq!(mqtt.tx.borrow_mut().len(), 0);
+        assert_eq!(ota_agent.state.context().request_momentum, 0);
+
+        ota_agent.state.context_mut().pal.hooks.in_flight = false;
+        ota_agent.state.process_event(Events::RequestTimer).unwrap();
+        assert_eq!(mqtt.tx.borrow_mut().len(), 1);
+
+        ota_agent.handle_message(&test_block(0, 1)).unwrap();
+        assert_eq!(ota_agent.state.context().pal.hooks.written, 2);
+    }
+
+    #[test]
+    fn deferred_close() {
+        use crate::ota::{encoding::FileContext, pal::OtaPalError, test::mock::PalHooks};
+
+        /// PAL verifying the image in the background after closing it
+        #[derive(Default)]
+        struct VerifyingPal {
+            verifying: bool,
+        }
+
+        impl PalHooks for VerifyingPal {
+            type Error = ();
+
+            fn close_file(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+                self.verifying = true;
+                Err(OtaPalError::WouldBlock)
+            }
+
+            fn poll_close(&mut self, _file: &FileContext) -> Result<(), OtaPalError<Self::Error>> {
+                if self.verifying {
+                    Err(OtaPalError::WouldBlock)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(
+            &mqtt,
+            &mqtt,
+            MockTimer::new(),
+            MockPal::<VerifyingPal>::default(),
+        )
+        .build();
+        let mut stream = MockStream::new(0, test_job_doc().files[0].filesize);
+
+        assert_eq!(ota_agent.finalize_file().err(), Some(Error::InvalidEvent));
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+
+        while !ota_agent.state.context().close_pending {
+            for response in stream.serve(&mqtt) {
+                ota_agent.handle_message(&response.payload).unwrap();
+            }
+
+            while !ota_agent.state.context().events.is_empty() {
+                ota_agent.process_event().unwrap();
+            }
+        }
+
+        // Nothing is requested while the file is being closed
+        ota_agent.handle_message(&test_block(0, 0)).unwrap();
+        assert!(stream.serve(&mqtt).is_empty());
+        assert_eq!(ota_agent.finalize_file().unwrap(), None);
+        assert!(ota_agent.state.context().close_pending);
+
+        ota_agent.state.context_mut().pal.hooks.verifying = false;
+        assert!(matches!(
+            ota_agent.finalize_file().unwrap(),
+            Some(AgentEvent::Completed { .. })
+        ));
+        assert!(!ota_agent.state.context().close_pending);
+    }
+
+    #[test]
+    fn prepare_regions() {
+        use crate::ota::{encoding::FileContext, pal::OtaPalError, test::mock::PalHooks};
+
+        /// PAL recording the regions it was asked to prepare
+        #[derive(Default)]
+        struct ErasingPal {
+            regions: Vec<(usize, usize)>,
+        }
+
+        impl PalHooks for ErasingPal {
+            type Error = ();
+
+            fn prepare_region(
+                &mut self,
+                _file: &FileContext,
+                offset: usize,
+                len: usize,
+            ) -> Result<(), OtaPalError<Self::Error>> {
+                self.regions.push((offset, len));
+                Ok(())
+            }
+        }
+
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(
+            &mqtt,
+            &mqtt,
+            MockTimer::new(),
+            MockPal::<ErasingPal>::default(),
+        )
+        .build();
+
+        // The first window is prepared along with creating the file
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        assert_eq!(
+            ota_agent.state.context().pal.hooks.regions,
+            vec![(0, 31 * 256)]
+        );
+
+        // Sliding the window prepares the block that moved into it
+        ota_agent.handle_message(&test_block(0, 0)).unwrap();
+        assert_eq!(
+            ota_agent.state.context().pal.hooks.regions,
+            vec![(0, 31 * 256), (31 * 256, 256)]
+        );
+
+        // Blocks out of order leave the window in place
+        ota_agent.handle_message(&test_block(0, 2)).unwrap();
+        assert_eq!(ota_agent.state.context().pal.hooks.regions.len(), 2);
+    }
+
+    #[test]
+    fn validated_config() {
+        use crate::ota::config::ConfigError;
+
+        let mqtt = MockMqtt::new();
+        let builder = || OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal::new());
+
+        assert!(builder().try_build().is_ok());
+        assert_eq!(
+            builder().block_size(128).try_build().err(),
+            Some(ConfigError::BlockSize)
+        );
+        assert_eq!(
+            builder()
+                .block_size(1024)
+                .max_payload_len(1024)
+                .try_build()
+                .err(),
+            Some(ConfigError::BlockExceedsPayload)
+        );
+        assert_eq!(
+            builder()
+                .with_self_test_timeout(MockTimer::new(), 4000)
+                .try_build()
+                .err(),
+            Some(ConfigError::SelfTestTimeout)
+        );
+    }
+
+    #[test]
+    fn progress_interval() {
+        use crate::ota::clock::Clock;
+        use core::cell::Cell;
+
+        struct TestClock(Cell<u64>);
+
+        impl Clock for TestClock {
+            fn now_ms(&self) -> u64 {
+                self.0.get()
+            }
+        }
+
+        let mqtt = MockMqtt::new();
+        let clock = TestClock(Cell::new(500));
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal::new())
+            .status_update_frequency(1000)
+            .progress_interval(&clock, 1000)
+            .build();
+
+        let job_updates = |mqtt: &MockMqtt| {
+            mqtt.tx
+                .borrow_mut()
+                .drain(..)
+                .filter_map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                    Some(Packet::Publish(p))
+                        if p.topic_name == "$aws/things/test_client/jobs/Test-job/update" =>
+                    {
+                        Some(core::str::from_utf8(p.payload).unwrap().to_owned())
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        job_updates(&mqtt);
+
+        // Neither the block count nor the interval is due yet
+        ota_agent.handle_message(&test_block(0, 0)).unwrap();
+        ota_agent.handle_message(&test_block(0, 1)).unwrap();
+        assert!(job_updates(&mqtt).is_empty());
+
+        clock.0.set(1000);
+        ota_agent.handle_message(&test_block(0, 2)).unwrap();
+        let updates = job_updates(&mqtt);
+        assert_eq!(updates.len(), 1);
+        assert!(updates[0].contains(r#""receivedBlocks":"3""#));
+        assert!(updates[0].contains(r#""totalBlocks":"483""#));
+
+        // The next report is due a full interval later
+        clock.0.set(1999);
+        ota_agent.handle_message(&test_block(0, 3)).unwrap();
+        assert!(job_updates(&mqtt).is_empty());
+    }
+
+    #[test]
+    fn request_blocks_again() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        // Only while downloading
+        assert_eq!(
+            ota_agent.request_blocks(0..1).err(),
+            Some(Error::InvalidEvent)
+        );
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        for block_id in 0..3 {
+            ota_agent.handle_message(&test_block(0, block_id)).unwrap();
+        }
+        assert_eq!(
+            ota_agent
+                .state
+                .context()
+                .active_interface
+                .as_ref()
+                .unwrap()
+                .file_ctx()
+                .block_offset,
+            3
+        );
+        mqtt.tx.borrow_mut().clear();
+
+        ota_agent.request_blocks(1..3).unwrap();
+        assert!(matches!(
+            ota_agent.state.state(),
+            &States::WaitingForFileBlock
+        ));
+
+        let file_ctx = ota_agent
+            .state
+            .context()
+            .active_interface
+            .as_ref()
+            .unwrap()
+            .file_ctx();
+        assert_eq!(file_ctx.block_offset, 1);
+        assert_eq!(file_ctx.blocks_remaining, 483 - 1);
+        assert_eq!(file_ctx.bitmap.len(), 31);
+
+        let bytes = mqtt.tx.borrow_mut().pop_back().unwrap();
+        match decode_slice(bytes.as_slice()).unwrap() {
+            Some(Packet::Publish(p)) => assert_eq!(
+                p.topic_name,
+                "$aws/things/test_client/streams/test_stream/get/cbor"
+            ),
+            _ => panic!(),
+        }
+
+        // The blocks are written again, rather than dropped as duplicates
+        ota_agent.handle_message(&test_block(0, 1)).unwrap();
+        assert_eq!(ota_agent.statistics().blocks_duplicate, 0);
+        assert_eq!(
+            ota_agent
+                .state
+                .context()
+                .active_interface
+                .as_ref()
+                .unwrap()
+                .file_ctx()
+                .blocks_remaining,
+            483 - 2
+        );
+
+        // Ranges beyond a single window are refused
+        assert_eq!(
+            ota_agent.request_blocks(0..40).err(),
+            Some(Error::GuardFailed(OtaError::BlockOutOfRange))
+        );
+    }
+
+    #[test]
+    fn throttle_block_requests() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal::new())
+            .max_blocks_per_second(10)
+            .build();
+
+        let block_requests = |mqtt: &MockMqtt| {
+            mqtt.tx
+                .borrow_mut()
+                .drain(..)
+                .filter(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                    Some(Packet::Publish(p)) => {
+                        p.topic_name == "$aws/things/test_client/streams/test_stream/get/cbor"
+                    }
+                    _ => false,
+                })
+                .count()
+        };
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        assert_eq!(block_requests(&mqtt), 1);
+
+        // Receiving the full window only starts the request timer
+        for block_id in 0..31 {
+            ota_agent.handle_message(&test_block(0, block_id)).unwrap();
+        }
+        assert_eq!(ota_agent.state.context().events.len(), 0);
+        assert!(ota_agent.state.context().request_timer.is_started);
+        assert_eq!(block_requests(&mqtt), 0);
+
+        // The next window is requested once it is due
+        ota_agent.state.process_event(Events::RequestTimer).unwrap();
+        assert!(matches!(
+            ota_agent.state.state(),
+            &States::WaitingForFileBlock
+        ));
+        assert_eq!(block_requests(&mqtt), 1);
+    }
 
-                let job_doc = test_job_doc();
-                agent.job_update("Test-job", &job_doc, None).unwrap();
-                agent.state.context_mut().events.dequeue();
-            }
-            States::RequestingFileBlock => {
-                println!(
-                    "Running to 'States::RequestingFileBlock', events: {}",
-                    agent.state.context().events.len()
-                );
-                run_to_state(agent, States::CreatingFile);
-                agent.state.process_event(Events::CreateFile).unwrap();
-                agent.state.context_mut().events.dequeue();
-            }
-            States::RequestingJob => {
-                println!(
-                    "Running to 'States::RequestingJob', events: {}",
-                    agent.state.context().events.len()
-                );
-                run_to_state(agent, States::Ready);
-                agent.state.process_event(Events::Start).unwrap();
-                agent.state.context_mut().events.dequeue();
-            }
-            States::Suspended => {
-                println!(
-                    "Running to 'States::Suspended', events: {}",
-                    agent.state.context().events.len()
-                );
-                run_to_state(agent, States::Ready);
-                agent.suspend().unwrap();
-            }
-            States::WaitingForFileBlock => {
-                println!(
-                    "Running to 'States::Suspended', events: {}",
-                    agent.state.context().events.len()
-                );
-                run_to_state(agent, States::RequestingFileBlock);
-                agent.state.process_event(Events::RequestFileBlock).unwrap();
-                agent.state.context_mut().events.dequeue();
-            }
-            States::WaitingForJob => {
-                println!(
-                    "Running to 'States::WaitingForJob', events: {}",
-                    agent.state.context().events.len()
-                );
-                run_to_state(agent, States::RequestingJob);
-                agent.check_for_update().unwrap();
+    #[test]
+    fn pal_error_details() {
+        use crate::ota::{
+            encoding::FileContext,
+            pal::{OtaPalError, PalError},
+            test::mock::PalHooks,
+        };
+
+        /// PAL failing all writes with a driver specific error code
+        struct FailingPal;
+
+        impl PalHooks for FailingPal {
+            type Error = u8;
+
+            fn write_block(
+                &mut self,
+                _file: &FileContext,
+                _block_offset: usize,
+                _block_payload: &[u8],
+            ) -> Result<usize, OtaPalError<Self::Error>> {
+                Err(OtaPalError::Custom(42))
             }
-            States::Restarting => {}
         }
+
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(
+            &mqtt,
+            &mqtt,
+            MockTimer::new(),
+            MockPal::with_hooks(FailingPal),
+        )
+        .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        mqtt.tx.borrow_mut().clear();
+
+        assert_eq!(
+            ota_agent.handle_message(&test_block(0, 0)).err(),
+            Some(Error::GuardFailed(OtaError::Pal(PalError::Custom(42))))
+        );
+
+        let payload = job_updates(&mqtt).pop().unwrap();
+
+        assert!(payload.contains(r#""failure":"flash_write""#));
+        assert!(payload.contains(r#""pal_error":"custom""#));
+        assert!(payload.contains(r#""pal_code":"42""#));
     }
 
-    pub fn set_pid(buf: &mut [u8], pid: Pid) -> Result<(), ()> {
-        let mut offset = 0;
-        let (header, _) = mqttrust::encoding::v4::decoder::read_header(buf, &mut offset)
-            .map_err(|_| ())?
-            .ok_or(())?;
+    #[test]
+    fn pal_error_after_progress() {
+        use crate::ota::{
+            encoding::FileContext,
+            pal::{OtaPalError, PalError},
+            test::mock::PalHooks,
+        };
 
-        match (header.typ, header.qos) {
-            (PacketType::Publish, QoS::AtLeastOnce | QoS::ExactlyOnce) => {
-                if buf[offset..].len() < 2 {
-                    return Err(());
-                }
-                let len = ((buf[offset] as usize) << 8) | buf[offset + 1] as usize;
+        /// PAL failing with a driver specific error code once out of space
+        struct FullPal {
+            blocks_left: usize,
+        }
 
-                offset += 2;
-                if len > buf[offset..].len() {
-                    return Err(());
-                } else {
-                    offset += len;
+        impl PalHooks for FullPal {
+            type Error = u8;
+
+            fn write_block(
+                &mut self,
+                _file: &FileContext,
+                _block_offset: usize,
+                block_payload: &[u8],
+            ) -> Result<usize, OtaPalError<Self::Error>> {
+                if self.blocks_left == 0 {
+                    return Err(OtaPalError::Custom(42));
                 }
+                self.blocks_left -= 1;
+                Ok(block_payload.len())
             }
-            (PacketType::Subscribe | PacketType::Unsubscribe | PacketType::Suback, _) => {}
-            (
-                PacketType::Puback
-                | PacketType::Pubrec
-                | PacketType::Pubrel
-                | PacketType::Pubcomp
-                | PacketType::Unsuback,
-                _,
-            ) => {}
-            _ => return Ok(()),
         }
 
-        pid.to_buffer(buf, &mut offset).map_err(|_| ())
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = OtaAgent::builder(
+            &mqtt,
+            &mqtt,
+            MockTimer::new(),
+            MockPal::with_hooks(FullPal { blocks_left: 2 }),
+        )
+        .status_update_frequency(1)
+        .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForFileBlock);
+        job_updates(&mqtt);
+
+        for block_id in 0..2 {
+            ota_agent.handle_message(&test_block(0, block_id)).unwrap();
+        }
+        let updates = job_updates(&mqtt);
+        assert_eq!(updates.len(), 2);
+        assert!(updates[1].contains(r#""receivedBlocks":"2""#));
+
+        assert_eq!(
+            ota_agent.handle_message(&test_block(0, 2)).err(),
+            Some(Error::GuardFailed(OtaError::Pal(PalError::Custom(42))))
+        );
+
+        // The failure is reported along with the progress so far
+        let payload = job_updates(&mqtt).pop().unwrap();
+        assert!(payload.contains(r#""status":"FAILED""#));
+        assert!(payload.contains(r#""receivedBlocks":"2""#));
+        assert!(payload.contains(r#""failure":"flash_write""#));
+        assert!(payload.contains(r#""failed_block":"2""#));
+        assert!(payload.contains(r#""pal_code":"42""#));
     }
 
     #[test]
-    fn ready_when_stopped() {
+    fn long_operation_hook() {
+        use crate::ota::test::mock::PalHooks;
+
+        /// PAL counting the calls of the long operation hook
+        #[derive(Default)]
+        struct WatchdogPal {
+            feeds: usize,
+        }
+
+        impl PalHooks for WatchdogPal {
+            type Error = ();
+
+            fn on_long_operation(&mut self) {
+                self.feeds += 1;
+            }
+        }
+
         let mqtt = MockMqtt::new();
-        let mut ota_agent = new_agent(&mqtt);
+        let mut ota_agent = OtaAgent::builder(
+            &mqtt,
+            &mqtt,
+            MockTimer::new(),
+            MockPal::<WatchdogPal>::default(),
+        )
+        .build();
 
-        assert!(matches!(ota_agent.state.state(), &States::Ready));
-        run_to_state(&mut ota_agent, States::Ready);
-        assert!(matches!(ota_agent.state.state(), &States::Ready));
-        assert_eq!(ota_agent.state.context().events.len(), 0);
-        assert_eq!(mqtt.tx.borrow_mut().len(), 0);
+        run_to_state(&mut ota_agent, States::CreatingFile);
+        assert_eq!(ota_agent.state.context().pal.hooks.feeds, 0);
+
+        run_to_state(&mut ota_agent, States::RequestingFileBlock);
+        assert_eq!(ota_agent.state.context().pal.hooks.feeds, 1);
     }
 
     #[test]
-    fn abort_when_stopped() {
+    fn application_self_test() {
+        use crate::jobs::StatusDetails;
+        use crate::ota::{
+            pal::{ImageState, OtaPalError, PalImageState},
+            self_test::{SelfTest, SelfTestResult},
+            test::mock::PalHooks,
+        };
+
+        /// PAL booted into a new image, pending commit
+        #[derive(Default)]
+        struct PendingPal {
+            image_states: Vec<ImageState>,
+        }
+
+        impl PalHooks for PendingPal {
+            type Error = ();
+
+            fn get_platform_image_state(&self) -> Result<PalImageState, OtaPalError<Self::Error>> {
+                Ok(PalImageState::PendingCommit)
+            }
+
+            fn set_platform_image_state(
+                &mut self,
+                image_state: ImageState,
+            ) -> Result<(), OtaPalError<Self::Error>> {
+                self.image_states.push(image_state);
+                Ok(())
+            }
+        }
+
+        struct Checks(SelfTestResult);
+
+        impl SelfTest for Checks {
+            fn run(&mut self) -> SelfTestResult {
+                self.0
+            }
+        }
+
+        for (result, image_state, job_status) in &[
+            (SelfTestResult::Accept, ImageState::Accepted, "SUCCEEDED"),
+            (SelfTestResult::Reject, ImageState::Rejected, "FAILED"),
+        ] {
+            let mqtt = MockMqtt::new();
+            let mut checks = Checks(*result);
+            let mut ota_agent = OtaAgent::builder(
+                &mqtt,
+                &mqtt,
+                MockTimer::new(),
+                MockPal::<PendingPal>::default(),
+            )
+            .self_test(&mut checks)
+            .build();
+
+            run_to_state(&mut ota_agent, States::WaitingForJob);
+
+            let mut status_details = StatusDetails::new();
+            status_details
+                .insert(
+                    heapless::String::from("self_test"),
+                    heapless::String::from("active"),
+                )
+                .unwrap();
+            ota_agent
+                .job_update("Test-job", &test_job_doc(), Some(&status_details))
+                .unwrap();
+            mqtt.tx.borrow_mut().clear();
+
+            ota_agent.process_event().unwrap();
+            assert!(matches!(ota_agent.state(), &States::WaitingForJob));
+
+            let context = ota_agent.state.context();
+            assert_eq!(context.image_state, *image_state);
+            assert_eq!(
+                context.pal.hooks.image_states,
+                vec![ImageState::Testing, *image_state]
+            );
+            if *result == SelfTestResult::Reject {
+                assert!(matches!(context.events.peek(), Some(Events::Restart(_))));
+            }
+
+            let bytes = mqtt.tx.borrow_mut().pop_front().unwrap();
+            let publish = match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Publish(p)) => p,
+                _ => panic!(),
+            };
+            let payload = core::str::from_utf8(publish.payload).unwrap();
+            assert!(payload.contains(&format!(r#""status":"{}""#, job_status)));
+        }
+    }
+
+    #[test]
+    fn accept_image_without_self_test() {
         let mqtt = MockMqtt::new();
         let mut ota_agent = new_agent(&mqtt);
 
-        run_to_state(&mut ota_agent, States::Ready);
-        assert_eq!(ota_agent.state.context().events.len(), 0);
+        run_to_state(&mut ota_agent, States::WaitingForJob);
 
         assert_eq!(
-            ota_agent.abort().err(),
+            ota_agent.accept_image().err(),
             Some(Error::GuardFailed(OtaError::NoActiveJob))
         );
-        ota_agent.process_event().unwrap();
-        assert!(matches!(ota_agent.state.state(), &States::Ready));
-        assert_eq!(mqtt.tx.borrow_mut().len(), 0);
+        assert!(matches!(ota_agent.state.state(), &States::WaitingForJob));
     }
 
     #[test]
-    fn resume_when_stopped() {
+    fn custom_job_forwarded_to_handler() {
+        use crate::jobs::StatusDetails;
+        use crate::ota::custom_job::CustomJobHandler;
+
+        #[derive(Default)]
+        struct Handler {
+            jobs: Vec<(String, Vec<u8>)>,
+        }
+
+        impl CustomJobHandler for Handler {
+            fn handle_job(
+                &mut self,
+                job_name: &str,
+                job_document: &[u8],
+                _status_details: Option<&StatusDetails>,
+            ) -> Result<(), OtaError> {
+                self.jobs
+                    .push((job_name.to_string(), job_document.to_vec()));
+                Ok(())
+            }
+        }
+
         let mqtt = MockMqtt::new();
-        let mut ota_agent = new_agent(&mqtt);
+        let mut handler = Handler::default();
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal::new())
+            .custom_job_handler(&mut handler)
+            .build();
 
-        run_to_state(&mut ota_agent, States::Ready);
-        assert_eq!(ota_agent.state.context().events.len(), 0);
+        run_to_state(&mut ota_agent, States::WaitingForJob);
 
+        let custom = br#"{"operation":"reboot"}"#;
         assert!(matches!(
-            ota_agent.resume().err().unwrap(),
-            Error::InvalidEvent
+            ota_agent.handle_job_document("Reboot-job", custom, None),
+            Ok(&States::WaitingForJob)
         ));
-        ota_agent.process_event().unwrap();
-        assert!(matches!(ota_agent.state.state(), &States::Ready));
-        assert_eq!(mqtt.tx.borrow_mut().len(), 0);
+        assert_eq!(ota_agent.state.context().events.len(), 0);
+
+        drop(ota_agent);
+        assert_eq!(
+            handler.jobs,
+            vec![(String::from("Reboot-job"), custom.to_vec())]
+        );
     }
 
     #[test]
-    fn resume_when_suspended() {
+    fn custom_job_without_handler() {
         let mqtt = MockMqtt::new();
         let mut ota_agent = new_agent(&mqtt);
 
-        run_to_state(&mut ota_agent, States::Suspended);
-        assert_eq!(ota_agent.state.context().events.len(), 0);
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+
+        assert_eq!(
+            ota_agent
+                .handle_job_document("Reboot-job", br#"{"operation":"reboot"}"#, None)
+                .err(),
+            Some(Error::GuardFailed(OtaError::InvalidFile))
+        );
+        assert!(matches!(ota_agent.state.state(), &States::WaitingForJob));
+    }
+
+    #[test]
+    fn raw_job_document() {
+        let mqtt = MockMqtt::new();
+        let mut buf = [0u8; 512];
+        let mut ota_agent = OtaAgent::builder(&mqtt, &mqtt, MockTimer::new(), MockPal::new())
+            .job_document_buffer(&mut buf)
+            .build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+
+        let document = br#"{
+            "afr_ota":{
+                "protocols":["MQTT"],
+                "streamname":"test_stream",
+                "files":[{
+                    "filepath":"app.bin",
+                    "filesize":1024,
+                    "fileid":0,
+                    "certfile":"cert",
+                    "fileType":0,
+                    "sig-sha1-rsa":"sig",
+                    "vendor":{"slot":"b","rollback":[1,2]}
+                }]
+            },
+            "pipeline":"canary"
+        }"#;
+        ota_agent
+            .handle_job_document("Test-job", document, None)
+            .unwrap();
+
+        let file_ctx = ota_agent
+            .state
+            .context()
+            .active_interface
+            .as_ref()
+            .unwrap()
+            .file_ctx();
+        assert_eq!(file_ctx.filepath.as_str(), "app.bin");
+        assert_eq!(ota_agent.job_document(), Some(document.as_slice()));
+
+        // The document is dropped along with the job
+        ota_agent.abort(AbortReason::User).unwrap();
+        assert_eq!(ota_agent.job_document(), None);
+    }
+
+    #[test]
+    fn custom_http_job() {
+        use crate::ota::{
+            config::Config, data_interface::FileBlock, encoding::FileContext, state::Interface,
+        };
+
+        /// HTTP interface recording nothing, standing in for a CDN download
+        struct CdnData;
+
+        impl DataInterface for CdnData {
+            const PROTOCOL: Protocol = Protocol::Http;
+
+            fn init_file_transfer(
+                &self,
+                _file_ctx: &mut FileContext,
+                _config: &Config,
+            ) -> Result<(), OtaError> {
+                Ok(())
+            }
+
+            fn request_file_block(
+                &self,
+                _file_ctx: &mut FileContext,
+                _config: &Config,
+            ) -> Result<(), OtaError> {
+                Ok(())
+            }
+
+            fn decode_file_block<'a>(
+                &self,
+                _file_ctx: &mut FileContext,
+                _payload: &'a [u8],
+            ) -> Result<FileBlock<'a>, OtaError> {
+                Err(OtaError::Http)
+            }
+
+            fn cleanup(
+                &self,
+                _file_ctx: &mut FileContext,
+                _config: &Config,
+            ) -> Result<(), OtaError> {
+                Ok(())
+            }
+        }
+
+        let mqtt = MockMqtt::new();
+        let mut ota_agent =
+            OtaAgent::builder(&mqtt, CdnData, MockTimer::new(), MockPal::new()).build();
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+
+        let document = br#"{
+            "afr_ota":{
+                "files":[{
+                    "filepath":"app.bin",
+                    "filesize":1024,
+                    "fileid":0,
+                    "fileType":0,
+                    "update_data_url":"https://cdn.example.com/app.bin",
+                    "sig-sha256-ecdsa":"sig"
+                }]
+            }
+        }"#;
+        ota_agent
+            .handle_job_document("Test-job", document, None)
+            .unwrap();
 
         assert!(matches!(
-            ota_agent.resume().unwrap(),
-            &States::RequestingJob
+            ota_agent.state.context().active_interface,
+            Some(Interface::Primary(_))
         ));
-        assert_eq!(mqtt.tx.borrow_mut().len(), 0);
+        let file_ctx = ota_agent
+            .state
+            .context()
+            .active_interface
+            .as_ref()
+            .unwrap()
+            .file_ctx();
+        assert_eq!(
+            file_ctx.update_data_url.as_deref(),
+            Some("https://cdn.example.com/app.bin")
+        );
+        assert!(file_ctx.stream_name.is_empty());
     }
 
     #[test]
@@ -337,7 +1894,7 @@ pub mod ota_tests {
         );
 
         // Fail the maximum number of attempts to request a job document
-        for _ in 0..ota_agent.state.context().config.max_request_momentum {
+        for _ in 0..ota_agent.state.context().config.max_describe_retries {
             ota_agent.process_event().unwrap();
             assert!(ota_agent.state.context().request_timer.is_started);
             ota_agent.timer_callback().ok();
@@ -436,9 +1993,7 @@ pub mod ota_tests {
                 qos: QoS::AtMostOnce,
                 retain: false,
                 topic_name: "$aws/things/test_client/streams/test_stream/get/cbor",
-                payload: &[
-                    164, 97, 102, 0, 97, 108, 25, 1, 0, 97, 111, 0, 97, 98, 68, 255, 255, 255, 127
-                ],
+                payload: &[163, 97, 102, 0, 97, 108, 25, 1, 0, 97, 98, 68, 255, 255, 255, 127],
                 pid: None
             }
         );
@@ -451,6 +2006,77 @@ pub mod ota_tests {
         assert_eq!(mqtt.tx.borrow_mut().len(), 3);
     }
 
+    #[test]
+    fn multi_file_job_receives_firmware_last() {
+        let mqtt = MockMqtt::new();
+        let mut ota_agent = new_agent(&mqtt);
+
+        run_to_state(&mut ota_agent, States::WaitingForJob);
+
+        let firmware = test_job_doc().files[0].clone();
+        let job_doc = OtaJob {
+            protocols: heapless::Vec::from_slice(&[Protocol::Mqtt]).unwrap(),
+            streamname: "test_stream",
+            files: heapless::Vec::from_slice(&[
+                firmware.clone(),
+                FileDescription {
+                    fileid: 1,
+                    filesize: 1024,
+                    file_type: Some(1),
+                    ..firmware.clone()
+                },
+                FileDescription {
+                    fileid: 2,
+                    filesize: 512,
+                    file_type: Some(2),
+                    ..firmware
+                },
+            ])
+            .unwrap(),
+        };
+
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+
+        let context = ota_agent.state.context();
+        assert_eq!(
+            context.active_interface.as_ref().unwrap().file_ctx().fileid,
+            1
+        );
+
+        // Queue is popped from the back
+        assert_eq!(context.pending_files.as_slice(), &[0, 2]);
+
+        ota_agent.state.process_event(Events::CreateFile).unwrap();
+        ota_agent.state.context_mut().events.dequeue();
+        ota_agent
+            .state
+            .process_event(Events::RequestFileBlock)
+            .unwrap();
+        ota_agent.state.context_mut().events.dequeue();
+
+        // Once a file is complete, the job document is requested again to
+        // create the next file from
+        ota_agent
+            .state
+            .context_mut()
+            .active_interface
+            .as_mut()
+            .unwrap()
+            .mut_file_ctx()
+            .blocks_remaining = 0;
+        ota_agent.state.process_event(Events::NextFile).unwrap();
+        assert_eq!(ota_agent.state(), &States::WaitingForJob);
+
+        ota_agent.job_update("Test-job", &job_doc, None).unwrap();
+
+        let context = ota_agent.state.context();
+        assert_eq!(
+            context.active_interface.as_ref().unwrap().file_ctx().fileid,
+            2
+        );
+        assert_eq!(context.pending_files.as_slice(), &[0]);
+    }
+
     #[test]
     fn deserialize_describe_job_execution_response_ota() {
         let payload = br#"{
@@ -501,10 +2127,13 @@ pub mod ota_tests {
                             sha1_rsa: None,
                             sha256_rsa: None,
                             sha1_ecdsa: None,
-                            sha256_ecdsa: Some(heapless::String::from(
-                                "This is my signature! Better believe it!"
-                            )),
+                            sha256_ecdsa: Some("This is my signature! Better believe it!"),
                             file_type: Some(0),
+                            delta: None,
+                            compression: None,
+                            sha256: None,
+                            attributes: None,
+                            app_version: None,
                         }])
                         .unwrap(),
                     })),
@@ -0,0 +1,127 @@
+//! Version checks of images, before the download and in self test.
+//!
+//! When a job in self test is received after activating a new image, the
+//! agent compares the version of the running firmware against the
+//! `updated_by` version recorded in the job status details, i.e. the version
+//! that started the update. Jobs giving the version of a firmware image as
+//! `appversion` are checked against the running firmware up front, such that
+//! images that would fail this check are not downloaded at all. By default
+//! the new image must be strictly newer, while a [`VersionPolicy`] set through
+//! [`OtaAgentBuilder::version_policy`] can relax or replace these checks.
+//!
+//! [`OtaAgentBuilder::version_policy`]: super::builder::OtaAgentBuilder::version_policy
+
+use super::{encoding::FileContext, pal::Version};
+
+/// Policy deciding whether the running firmware is a valid result of the
+/// update in self test.
+pub trait VersionPolicy {
+    /// Check the `active` firmware version against the file under self test.
+    ///
+    /// `file_ctx` carries the job status details, including the
+    /// `updated_by` version, as well as any other details reported by the
+    /// device before activating the image (e.g. a build hash). Returning
+    /// `false` rejects the image.
+    fn accept(&mut self, file_ctx: &FileContext, active: &Version) -> bool;
+
+    /// Check the `offered` version of a firmware image, given as
+    /// `appversion` by the job, against the `active` firmware version,
+    /// before the image is downloaded. Returning `false` fails the job.
+    ///
+    /// Defaults to only accepting newer images.
+    fn accept_update(&mut self, offered: &Version, active: &Version) -> bool {
+        offered > active
+    }
+}
+
+/// Only accept images newer than the firmware that performed the update.
+/// This is the default policy.
+pub struct Increasing;
+
+impl VersionPolicy for Increasing {
+    fn accept(&mut self, file_ctx: &FileContext, active: &Version) -> bool {
+        file_ctx.updated_by().map_or(true, |v| v < *active)
+    }
+}
+
+/// Accept downgrades, only rejecting the image if the firmware that performed
+/// the update is still running, e.g. because the bootloader rolled back.
+pub struct AllowDowngrade;
+
+impl VersionPolicy for AllowDowngrade {
+    fn accept(&mut self, file_ctx: &FileContext, active: &Version) -> bool {
+        file_ctx.updated_by().map_or(true, |v| v != *active)
+    }
+
+    fn accept_update(&mut self, offered: &Version, active: &Version) -> bool {
+        offered != active
+    }
+}
+
+/// Accept any version, e.g. for development fleets flashing the same version
+/// repeatedly.
+pub struct AnyVersion;
+
+impl VersionPolicy for AnyVersion {
+    fn accept(&mut self, _file_ctx: &FileContext, _active: &Version) -> bool {
+        true
+    }
+
+    fn accept_update(&mut self, _offered: &Version, _active: &Version) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ota::{config::Config, test::test_file_ctx};
+
+    fn updated_by(version: Option<&str>) -> FileContext {
+        let mut file_ctx = test_file_ctx(&Config::default());
+        if let Some(version) = version {
+            file_ctx
+                .status_details
+                .insert(
+                    heapless::String::from("updated_by"),
+                    heapless::String::from(version),
+                )
+                .unwrap();
+        }
+        file_ctx
+    }
+
+    #[test]
+    fn policies() {
+        let active = Version::new(1, 2, 0);
+        let cases = &[
+            (None, true, true),
+            (Some("1.1.9"), true, true),
+            (Some("1.2.0"), false, false),
+            (Some("2.0.0"), false, true),
+        ];
+
+        for (version, increasing, downgrade) in cases {
+            let file_ctx = updated_by(*version);
+            assert_eq!(Increasing.accept(&file_ctx, &active), *increasing);
+            assert_eq!(AllowDowngrade.accept(&file_ctx, &active), *downgrade);
+            assert!(AnyVersion.accept(&file_ctx, &active));
+        }
+    }
+
+    #[test]
+    fn update_policies() {
+        let active = Version::new(1, 2, 0);
+        let cases = &[
+            (Version::new(1, 1, 9), false, true),
+            (Version::new(1, 2, 0), false, false),
+            (Version::new(1, 2, 1), true, true),
+        ];
+
+        for (offered, increasing, downgrade) in cases {
+            assert_eq!(Increasing.accept_update(offered, &active), *increasing);
+            assert_eq!(AllowDowngrade.accept_update(offered, &active), *downgrade);
+            assert!(AnyVersion.accept_update(offered, &active));
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+
+/// The `state` section of a shadow document
+#[derive(Debug, PartialEq, Serialize)]
+pub struct State<S> {
+    #[serde(rename = "desired")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desired: Option<S>,
+    #[serde(rename = "reported")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reported: Option<S>,
+}
+
+/// Publish a request document on `<shadow>/update`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Request<'a, S> {
+    #[serde(rename = "state")]
+    pub state: State<S>,
+    /// A client token used to correlate requests and responses. Enter an
+    /// arbitrary value here and it is reflected in the response.
+    #[serde(rename = "clientToken")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_token: Option<&'a str>,
+    /// The update is rejected unless the shadow document is at this version.
+    #[serde(rename = "version")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+}
+
+/// Subscribe to `<shadow>/update/delta` to receive the fields of the desired
+/// state that differ from the reported state, as the partial state `P`.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct DeltaResponse<'a, P> {
+    #[serde(rename = "state")]
+    pub state: Option<P>,
+    /// The current version of the shadow document.
+    #[serde(rename = "version")]
+    pub version: u32,
+    /// The time, in seconds since the epoch, when the response was sent.
+    #[serde(rename = "timestamp")]
+    pub timestamp: Option<i64>,
+    #[serde(rename = "clientToken")]
+    #[serde(borrow)]
+    pub client_token: Option<&'a str>,
+}
+
+/// The `state` section of an accepted document, of which only the delta is
+/// of interest to the device
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct AcceptedState<P> {
+    #[serde(rename = "delta")]
+    pub delta: Option<P>,
+}
+
+/// Subscribe to `<shadow>/get/accepted` and `<shadow>/update/accepted` to
+/// receive the accepted shadow documents.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct AcceptedResponse<'a, P> {
+    #[serde(rename = "state")]
+    pub state: Option<AcceptedState<P>>,
+    /// The current version of the shadow document.
+    #[serde(rename = "version")]
+    pub version: u32,
+    /// The time, in seconds since the epoch, when the response was sent.
+    #[serde(rename = "timestamp")]
+    pub timestamp: Option<i64>,
+    #[serde(rename = "clientToken")]
+    #[serde(borrow)]
+    pub client_token: Option<&'a str>,
+}
+
+/// Subscribe to `<shadow>/get/rejected`, `<shadow>/update/rejected` and
+/// `<shadow>/delete/rejected` to receive error responses.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct ErrorResponse<'a> {
+    /// HTTP status code of the error, e.g. 404 if the shadow does not exist
+    #[serde(rename = "code")]
+    pub code: u16,
+    #[serde(rename = "message")]
+    pub message: &'a str,
+    /// The time, in seconds since the epoch, when the response was sent.
+    #[serde(rename = "timestamp")]
+    pub timestamp: Option<i64>,
+    #[serde(rename = "clientToken")]
+    #[serde(borrow)]
+    pub client_token: Option<&'a str>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json_core::{from_slice, to_string};
+
+    #[derive(Debug, PartialEq, Serialize)]
+    struct Config {
+        interval: u32,
+    }
+
+    #[test]
+    fn serialize_request() {
+        let req = Request {
+            state: State {
+                desired: None,
+                reported: Some(Config { interval: 60 }),
+            },
+            client_token: None,
+            version: Some(4),
+        };
+        assert_eq!(
+            &to_string::<_, 128>(&req).unwrap(),
+            r#"{"state":{"reported":{"interval":60}},"version":4}"#
+        );
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct PartialConfig {
+        interval: Option<u32>,
+    }
+
+    #[test]
+    fn deserialize_get_accepted() {
+        let payload = br#"{
+                "state": {
+                    "desired": { "interval": 30 },
+                    "reported": { "interval": 60 },
+                    "delta": { "interval": 30 }
+                },
+                "metadata": {
+                    "desired": { "interval": { "timestamp": 1587381770 } },
+                    "reported": { "interval": { "timestamp": 1587381700 } }
+                },
+                "version": 7,
+                "timestamp": 1587381778
+            }"#;
+
+        let (response, _) = from_slice::<AcceptedResponse<PartialConfig>>(payload).unwrap();
+        assert_eq!(response.version, 7);
+        assert_eq!(
+            response.state.and_then(|state| state.delta),
+            Some(PartialConfig { interval: Some(30) })
+        );
+    }
+
+    #[test]
+    fn deserialize_error_response() {
+        let payload = br#"{
+                "code": 404,
+                "message": "No shadow exists with name: 'test_client~config'",
+                "timestamp": 1587381778,
+                "clientToken": "0:test_client"
+            }"#;
+
+        let (response, _) = from_slice::<ErrorResponse>(payload).unwrap();
+        assert_eq!(response.code, 404);
+        assert_eq!(response.client_token, Some("0:test_client"));
+    }
+}
@@ -0,0 +1,20 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowError {
+    Overflow,
+    /// The document could not be serialized, or a message could not be
+    /// deserialized
+    InvalidPayload,
+    Mqtt(mqttrust::MqttError),
+    /// The thing name used as client ID is empty, too long, or contains
+    /// characters that are not allowed in a thing name
+    InvalidThingName,
+    /// The shadow name is empty, too long, starts with `$`, or contains
+    /// characters that are not allowed in a shadow name
+    InvalidShadowName,
+}
+
+impl From<mqttrust::MqttError> for ShadowError {
+    fn from(e: mqttrust::MqttError) -> Self {
+        Self::Mqtt(e)
+    }
+}
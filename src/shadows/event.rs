@@ -0,0 +1,19 @@
+use super::{data_types::ErrorResponse, Topic};
+
+/// Message of a shadow, with the delta typed as the partial state `P` of its
+/// [`ShadowState`](super::ShadowState)
+#[derive(Debug, PartialEq)]
+pub enum ShadowEvent<'a, P> {
+    /// The fields of the desired state that differ from the reported state,
+    /// from `update/delta`, or from the `delta` section of `get/accepted`.
+    /// The device applies them, e.g. through
+    /// [`ShadowState::apply_patch`](super::ShadowState::apply_patch), and
+    /// reports its new state.
+    Delta { state: P, version: u32 },
+    /// A get or update request was accepted, with no delta to apply
+    Accepted { version: u32 },
+    /// The shadow was deleted
+    Deleted,
+    /// The request of `topic` was rejected
+    Rejected(Topic, ErrorResponse<'a>),
+}
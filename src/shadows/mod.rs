@@ -0,0 +1,416 @@
+//! AWS IoT Device Shadow service.
+//!
+//! A thing has a classic, unnamed shadow, and any number of named shadows,
+//! e.g. to keep its configuration, telemetry and diagnostics apart. Each
+//! [`Shadow`] handles one of them, so several can share one MQTT client:
+//!
+//! ```ignore
+//! let config = Shadow::<_>::named(&mqtt, "config")?;
+//! let diagnostics = Shadow::<_>::named(&mqtt, "diagnostics")?;
+//! config.subscribe(QoS::AtLeastOnce)?;
+//! diagnostics.subscribe(QoS::AtLeastOnce)?;
+//!
+//! diagnostics.update(&Diagnostics { uptime: 42 })?;
+//!
+//! match config.matches(topic) {
+//!     Some(Topic::UpdateDelta) => apply_config(payload),
+//!     Some(Topic::GetRejected) => config.update(&default_config())?,
+//!     _ => {}
+//! }
+//! ```
+//!
+//! With the state of the shadow described by a [`ShadowState`], its deltas
+//! arrive typed as the partial state, holding only the fields to change:
+//!
+//! ```ignore
+//! let shadow = Shadow::<_>::of::<Config>(&mqtt)?;
+//!
+//! match shadow.handle_message::<Config>(topic, payload)? {
+//!     Some(ShadowEvent::Delta { state: delta, .. }) => {
+//!         config.apply_patch(delta);
+//!         shadow.report(&mut reported, &config)?;
+//!     }
+//!     Some(ShadowEvent::Rejected(Topic::GetRejected, _)) => shadow.update(&config)?,
+//!     _ => {}
+//! }
+//! ```
+//!
+//! [`Shadow::report`] publishes only the fields that changed since the last
+//! report, keeping the updates and the stored document small.
+
+pub mod data_types;
+mod error;
+pub mod event;
+pub mod reported;
+pub mod state;
+pub mod topics;
+
+use mqttrust::{Mqtt, QoS};
+use serde::Serialize;
+
+use crate::rustot_log;
+
+use self::data_types::{AcceptedResponse, DeltaResponse, ErrorResponse, Request, State};
+pub use error::ShadowError;
+pub use event::ShadowEvent;
+pub use reported::Reported;
+pub use rustot_derive::ShadowState;
+pub use state::ShadowState;
+pub use topics::Topic;
+use topics::{validate_shadow_name, Subscribe, Unsubscribe};
+
+pub const MAX_SHADOW_NAME_LEN: usize = 64;
+
+/// Length of the longest topic of a named shadow, i.e.
+/// `$aws/things/<thingName>/shadow/name/<shadowName>/update/documents`
+pub const MAX_TOPIC_LEN: usize = crate::jobs::MAX_THING_NAME_LEN + MAX_SHADOW_NAME_LEN + 42;
+
+/// The classic or a named shadow of the thing of the MQTT client, with
+/// request documents of at most `L` bytes
+pub struct Shadow<'a, M: Mqtt, const L: usize = 512> {
+    mqtt: &'a M,
+    name: Option<&'a str>,
+}
+
+impl<'a, M: Mqtt, const L: usize> Shadow<'a, M, L> {
+    /// The classic shadow
+    pub fn classic(mqtt: &'a M) -> Self {
+        Self { mqtt, name: None }
+    }
+
+    /// The shadow named `name`
+    pub fn named(mqtt: &'a M, name: &'a str) -> Result<Self, ShadowError> {
+        validate_shadow_name(name)?;
+
+        Ok(Self {
+            mqtt,
+            name: Some(name),
+        })
+    }
+
+    /// The shadow keeping the state `S`, as named by
+    /// [`ShadowState::NAME`]
+    pub fn of<S: ShadowState>(mqtt: &'a M) -> Result<Self, ShadowError> {
+        match S::NAME {
+            Some(name) => Self::named(mqtt, name),
+            None => Ok(Self::classic(mqtt)),
+        }
+    }
+
+    /// Name of the shadow, `None` for the classic shadow
+    pub fn name(&self) -> Option<&'a str> {
+        self.name
+    }
+
+    /// Subscribe to the responses and the deltas of the shadow
+    pub fn subscribe(&self, qos: QoS) -> Result<(), ShadowError> {
+        Subscribe::<7>::new()
+            .topic(Topic::GetAccepted, qos)
+            .topic(Topic::GetRejected, qos)
+            .topic(Topic::UpdateDelta, qos)
+            .topic(Topic::UpdateAccepted, qos)
+            .topic(Topic::UpdateRejected, qos)
+            .topic(Topic::DeleteAccepted, qos)
+            .topic(Topic::DeleteRejected, qos)
+            .send(self.mqtt, self.name)
+    }
+
+    pub fn unsubscribe(&self) -> Result<(), ShadowError> {
+        Unsubscribe::<7>::new()
+            .topic(Topic::GetAccepted)
+            .topic(Topic::GetRejected)
+            .topic(Topic::UpdateDelta)
+            .topic(Topic::UpdateAccepted)
+            .topic(Topic::UpdateRejected)
+            .topic(Topic::DeleteAccepted)
+            .topic(Topic::DeleteRejected)
+            .send(self.mqtt, self.name)
+    }
+
+    /// Request the shadow document, answered on `get/accepted`, or on
+    /// `get/rejected` if the shadow does not exist
+    pub fn get(&self) -> Result<(), ShadowError> {
+        self.publish(Topic::Get, b"")
+    }
+
+    /// Report the state of the device
+    pub fn update<S: Serialize>(&self, reported: &S) -> Result<(), ShadowError> {
+        let payload = serde_json_core::to_vec::<_, L>(&Request {
+            state: State {
+                desired: None,
+                reported: Some(reported),
+            },
+            client_token: None,
+            version: None,
+        })
+        .map_err(|_| ShadowError::Overflow)?;
+
+        self.publish(Topic::Update, &payload)
+    }
+
+    /// Report `state`, with only the fields that changed since the state last
+    /// recorded in `reported`, or in full if none was recorded. Returns
+    /// whether an update was published, i.e. `false` if nothing changed.
+    pub fn report<S: ShadowState + Clone>(
+        &self,
+        reported: &mut Reported<S>,
+        state: &S,
+    ) -> Result<bool, ShadowError> {
+        match reported.changes(state) {
+            Some(changes) if changes == S::PartialState::default() => return Ok(false),
+            Some(changes) => self.update(&changes)?,
+            None => self.update(state)?,
+        }
+
+        reported.set(state);
+        Ok(true)
+    }
+
+    /// Delete the shadow
+    pub fn delete(&self) -> Result<(), ShadowError> {
+        self.publish(Topic::Delete, b"")
+    }
+
+    /// The topic, if `topic_name` is one of the topics of this shadow, rather
+    /// than of another shadow of the thing
+    pub fn matches(&self, topic_name: &str) -> Option<Topic> {
+        match Topic::from_str(topic_name) {
+            Some((topic, thing_name, shadow_name))
+                if thing_name == self.mqtt.client_id() && shadow_name == self.name =>
+            {
+                Some(topic)
+            }
+            _ => None,
+        }
+    }
+
+    /// Handle a message of the shadow with the state `S`. Returns `None` for
+    /// any topic of another shadow or service.
+    pub fn handle_message<'b, S: ShadowState>(
+        &self,
+        topic_name: &str,
+        payload: &'b [u8],
+    ) -> Result<Option<ShadowEvent<'b, S::PartialState>>, ShadowError> {
+        let topic = match self.matches(topic_name) {
+            Some(topic) => topic,
+            None => return Ok(None),
+        };
+
+        let event = match topic {
+            Topic::UpdateDelta => {
+                let (response, _) =
+                    serde_json_core::from_slice::<DeltaResponse<S::PartialState>>(payload)
+                        .map_err(|_| ShadowError::InvalidPayload)?;
+                match response.state {
+                    Some(state) => ShadowEvent::Delta {
+                        state,
+                        version: response.version,
+                    },
+                    None => return Ok(None),
+                }
+            }
+            Topic::GetAccepted | Topic::UpdateAccepted => {
+                let (response, _) =
+                    serde_json_core::from_slice::<AcceptedResponse<S::PartialState>>(payload)
+                        .map_err(|_| ShadowError::InvalidPayload)?;
+                match response.state.and_then(|state| state.delta) {
+                    Some(state) => ShadowEvent::Delta {
+                        state,
+                        version: response.version,
+                    },
+                    None => ShadowEvent::Accepted {
+                        version: response.version,
+                    },
+                }
+            }
+            Topic::DeleteAccepted => ShadowEvent::Deleted,
+            Topic::GetRejected | Topic::UpdateRejected | Topic::DeleteRejected => {
+                let (response, _) = serde_json_core::from_slice::<ErrorResponse>(payload)
+                    .map_err(|_| ShadowError::InvalidPayload)?;
+                rustot_log!(warn, "Shadow request rejected: {}", response.message);
+                ShadowEvent::Rejected(topic, response)
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(event))
+    }
+
+    fn publish(&self, topic: Topic, payload: &[u8]) -> Result<(), ShadowError> {
+        let topic = topic.format::<MAX_TOPIC_LEN>(self.mqtt.client_id(), self.name)?;
+        self.mqtt
+            .publish(topic.as_str(), payload, QoS::AtLeastOnce)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mqttrust::{encoding::v4::decode_slice, Packet};
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::test::MockMqtt;
+
+    #[derive(Serialize)]
+    struct Diagnostics {
+        uptime: u32,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ShadowState)]
+    #[shadow(name = "config")]
+    struct Config {
+        interval: u32,
+        led: bool,
+    }
+
+    fn published(mqtt: &MockMqtt) -> Vec<(String, String)> {
+        mqtt.tx
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|bytes| match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Publish(p)) => Some((
+                    p.topic_name.to_string(),
+                    core::str::from_utf8(p.payload).unwrap().to_string(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn classic_and_named_shadows() {
+        let mqtt = &MockMqtt::new();
+
+        let classic = Shadow::<_>::classic(mqtt);
+        let diagnostics = Shadow::<_>::named(mqtt, "diagnostics").unwrap();
+        assert!(Shadow::<_>::named(mqtt, "$diagnostics").is_err());
+
+        classic.get().unwrap();
+        diagnostics.update(&Diagnostics { uptime: 42 }).unwrap();
+        assert_eq!(
+            published(mqtt),
+            [
+                (
+                    "$aws/things/test_client/shadow/get".to_string(),
+                    "".to_string()
+                ),
+                (
+                    "$aws/things/test_client/shadow/name/diagnostics/update".to_string(),
+                    r#"{"state":{"reported":{"uptime":42}}}"#.to_string()
+                ),
+            ]
+        );
+
+        let delta = "$aws/things/test_client/shadow/name/diagnostics/update/delta";
+        assert_eq!(diagnostics.matches(delta), Some(Topic::UpdateDelta));
+        assert_eq!(classic.matches(delta), None);
+        assert_eq!(
+            classic.matches("$aws/things/test_client/shadow/update/delta"),
+            Some(Topic::UpdateDelta)
+        );
+        assert_eq!(
+            classic.matches("$aws/things/other_thing/shadow/update/delta"),
+            None
+        );
+    }
+
+    #[test]
+    fn typed_deltas() {
+        let mqtt = &MockMqtt::new();
+        let shadow = Shadow::<_>::of::<Config>(mqtt).unwrap();
+        assert_eq!(shadow.name(), Some("config"));
+
+        let mut config = Config {
+            interval: 60,
+            led: false,
+        };
+
+        let event = shadow
+            .handle_message::<Config>(
+                "$aws/things/test_client/shadow/name/config/update/delta",
+                br#"{"state":{"led":true},"metadata":{"led":{"timestamp":1587381778}},"version":5,"timestamp":1587381778}"#,
+            )
+            .unwrap();
+        let delta = match event {
+            Some(ShadowEvent::Delta { state, version: 5 }) => state,
+            e => panic!("{:?}", e),
+        };
+        assert_eq!(delta.interval, None);
+        config.apply_patch(delta);
+        assert_eq!(
+            config,
+            Config {
+                interval: 60,
+                led: true,
+            }
+        );
+
+        assert_eq!(
+            shadow
+                .handle_message::<Config>(
+                    "$aws/things/test_client/shadow/name/config/get/accepted",
+                    br#"{"state":{"desired":{"led":true},"reported":{"interval":60,"led":true}},"version":6,"timestamp":1587381779}"#,
+                )
+                .unwrap(),
+            Some(ShadowEvent::Accepted { version: 6 })
+        );
+
+        match shadow
+            .handle_message::<Config>(
+                "$aws/things/test_client/shadow/name/config/get/rejected",
+                br#"{"code":404,"message":"No shadow exists with name: 'test_client~config'"}"#,
+            )
+            .unwrap()
+        {
+            Some(ShadowEvent::Rejected(Topic::GetRejected, response)) => {
+                assert_eq!(response.code, 404)
+            }
+            e => panic!("{:?}", e),
+        }
+
+        // The deltas of the classic shadow are not the deltas of the config
+        assert_eq!(
+            shadow
+                .handle_message::<Config>(
+                    "$aws/things/test_client/shadow/update/delta",
+                    br#"{"state":{"led":false},"version":2}"#,
+                )
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn report_changes_only() {
+        let mqtt = &MockMqtt::new();
+        let shadow = Shadow::<_>::of::<Config>(mqtt).unwrap();
+        let mut reported = Reported::new();
+
+        let mut config = Config {
+            interval: 60,
+            led: false,
+        };
+        assert!(shadow.report(&mut reported, &config).unwrap());
+
+        // Nothing changed
+        assert!(!shadow.report(&mut reported, &config).unwrap());
+
+        config.led = true;
+        assert!(shadow.report(&mut reported, &config).unwrap());
+
+        let topic = "$aws/things/test_client/shadow/name/config/update".to_string();
+        assert_eq!(
+            published(mqtt),
+            [
+                (
+                    topic.clone(),
+                    r#"{"state":{"reported":{"interval":60,"led":false}}}"#.to_string()
+                ),
+                (topic, r#"{"state":{"reported":{"led":true}}}"#.to_string()),
+            ]
+        );
+        assert_eq!(reported.last(), Some(&config));
+    }
+}
@@ -0,0 +1,95 @@
+use super::ShadowState;
+
+/// The state last reported to a shadow, so that later reports carry only the
+/// fields that changed since:
+///
+/// ```ignore
+/// let mut reported = Reported::new();
+///
+/// // Reports the full state
+/// shadow.report(&mut reported, &config)?;
+///
+/// config.led = true;
+/// // Reports `{"led":true}` only
+/// shadow.report(&mut reported, &config)?;
+/// ```
+///
+/// After the shadow was deleted, or an update rejected, [`Reported::forget`]
+/// makes the next report carry the full state again.
+#[derive(Debug)]
+pub struct Reported<S> {
+    last: Option<S>,
+}
+
+impl<S> Default for Reported<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Reported<S> {
+    pub const fn new() -> Self {
+        Self { last: None }
+    }
+
+    /// The state last reported, `None` if none was reported yet
+    pub fn last(&self) -> Option<&S> {
+        self.last.as_ref()
+    }
+
+    pub fn forget(&mut self) {
+        self.last = None;
+    }
+}
+
+impl<S: ShadowState + Clone> Reported<S> {
+    /// The fields of `state` that changed since the last report. `None` if
+    /// nothing was reported yet, i.e. the full state is to be reported.
+    pub fn changes(&self, state: &S) -> Option<S::PartialState> {
+        self.last.as_ref().map(|last| last.diff(state))
+    }
+
+    /// Record `state` as reported
+    pub fn set(&mut self, state: &S) {
+        self.last = Some(state.clone());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ShadowState)]
+    struct Config {
+        interval: u32,
+        led: bool,
+    }
+
+    #[test]
+    fn changes_since_last_report() {
+        let mut reported = Reported::new();
+        let mut config = Config {
+            interval: 60,
+            led: false,
+        };
+        assert_eq!(reported.changes(&config), None);
+
+        reported.set(&config);
+        assert_eq!(reported.changes(&config), Some(PartialConfig::default()));
+
+        config.led = true;
+        assert_eq!(
+            reported.changes(&config),
+            Some(PartialConfig {
+                led: Some(true),
+                ..Default::default()
+            })
+        );
+
+        reported.forget();
+        assert_eq!(reported.last(), None);
+        assert_eq!(reported.changes(&config), None);
+    }
+}
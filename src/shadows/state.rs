@@ -0,0 +1,92 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+/// State of the device kept in a shadow, usually derived through
+/// `#[derive(ShadowState)]` rather than implemented by hand:
+///
+/// ```ignore
+/// #[derive(Clone, Serialize, Deserialize, ShadowState)]
+/// #[shadow(name = "config")]
+/// pub struct Config {
+///     pub interval_s: u32,
+///     pub led: bool,
+/// }
+///
+/// let mut config = Config { interval_s: 60, led: false };
+/// config.apply_patch(PartialConfig { led: Some(true), ..Default::default() });
+/// ```
+pub trait ShadowState: Serialize {
+    /// Name of the shadow keeping the state, `None` for the classic shadow
+    const NAME: Option<&'static str> = None;
+
+    /// The state with all fields optional, e.g. the fields of a delta, or the
+    /// fields changed since the last report
+    type PartialState: Serialize + DeserializeOwned + Default + PartialEq;
+
+    /// Set the fields present in `partial`, leaving the others untouched
+    fn apply_patch(&mut self, partial: Self::PartialState);
+
+    /// The fields of `other` that differ from `self`
+    fn diff(&self, other: &Self) -> Self::PartialState;
+}
+
+#[cfg(test)]
+mod test {
+    use serde::{Deserialize, Serialize};
+
+    use crate::shadows::ShadowState;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ShadowState)]
+    struct Classic {
+        led: bool,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ShadowState)]
+    #[shadow(name = "config")]
+    struct Config {
+        #[serde(rename = "intervalS")]
+        interval_s: u32,
+        led: bool,
+        label: heapless::String<16>,
+    }
+
+    fn config() -> Config {
+        Config {
+            interval_s: 60,
+            led: false,
+            label: heapless::String::from("kitchen"),
+        }
+    }
+
+    #[test]
+    fn derived_state() {
+        assert_eq!(Classic::NAME, None);
+        assert_eq!(Config::NAME, Some("config"));
+
+        let (partial, _) =
+            serde_json_core::from_slice::<PartialConfig>(br#"{"intervalS":30}"#).unwrap();
+        assert_eq!(
+            partial,
+            PartialConfig {
+                interval_s: Some(30),
+                ..Default::default()
+            }
+        );
+
+        let mut state = config();
+        state.apply_patch(partial);
+        assert_eq!(
+            state,
+            Config {
+                interval_s: 30,
+                ..config()
+            }
+        );
+
+        let diff = config().diff(&state);
+        assert_eq!(
+            &serde_json_core::to_string::<_, 64>(&diff).unwrap(),
+            r#"{"intervalS":30}"#
+        );
+        assert_eq!(state.diff(&state), PartialConfig::default());
+    }
+}
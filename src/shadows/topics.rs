@@ -0,0 +1,342 @@
+use core::fmt::Write;
+
+use mqttrust::{Mqtt, QoS, SubscribeTopic};
+
+use crate::jobs::validate_thing_name;
+
+use super::{ShadowError, MAX_SHADOW_NAME_LEN, MAX_TOPIC_LEN};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// Topics of a device shadow, relative to
+/// `$aws/things/<thingName>/shadow` for the classic shadow, or
+/// `$aws/things/<thingName>/shadow/name/<shadowName>` for a named shadow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topic {
+    // ---- Outgoing Topics
+    /// `<shadow>/get`
+    Get,
+    /// `<shadow>/update`
+    Update,
+    /// `<shadow>/delete`
+    Delete,
+
+    // ---- Incoming Topics
+    /// `<shadow>/get/accepted`
+    GetAccepted,
+    /// `<shadow>/get/rejected`
+    GetRejected,
+    /// `<shadow>/update/delta`
+    UpdateDelta,
+    /// `<shadow>/update/accepted`
+    UpdateAccepted,
+    /// `<shadow>/update/documents`
+    UpdateDocuments,
+    /// `<shadow>/update/rejected`
+    UpdateRejected,
+    /// `<shadow>/delete/accepted`
+    DeleteAccepted,
+    /// `<shadow>/delete/rejected`
+    DeleteRejected,
+}
+
+impl Topic {
+    const PREFIX: &'static str = "$aws/things";
+
+    /// Parse a shadow topic into the topic, the name of the thing, and the
+    /// name of the shadow, `None` for the classic shadow
+    pub fn from_str(s: &str) -> Option<(Self, &str, Option<&str>)> {
+        let tt = s.splitn(9, '/').collect::<heapless::Vec<&str, 9>>();
+        let (thing_name, rest) = match (tt.get(0), tt.get(1), tt.get(2), tt.get(3)) {
+            (Some(&"$aws"), Some(&"things"), Some(thing_name), Some(&"shadow")) => {
+                (*thing_name, &tt[4..])
+            }
+            _ => return None,
+        };
+
+        let (shadow_name, rest) = match rest {
+            ["name", shadow_name, rest @ ..] => (Some(*shadow_name), rest),
+            rest => (None, rest),
+        };
+
+        let topic = match rest {
+            ["get"] => Topic::Get,
+            ["update"] => Topic::Update,
+            ["delete"] => Topic::Delete,
+            ["get", "accepted"] => Topic::GetAccepted,
+            ["get", "rejected"] => Topic::GetRejected,
+            ["update", "delta"] => Topic::UpdateDelta,
+            ["update", "accepted"] => Topic::UpdateAccepted,
+            ["update", "documents"] => Topic::UpdateDocuments,
+            ["update", "rejected"] => Topic::UpdateRejected,
+            ["delete", "accepted"] => Topic::DeleteAccepted,
+            ["delete", "rejected"] => Topic::DeleteRejected,
+            _ => return None,
+        };
+
+        Some((topic, thing_name, shadow_name))
+    }
+
+    pub fn direction(&self) -> Direction {
+        if matches!(self, Topic::Get | Topic::Update | Topic::Delete) {
+            Direction::Outgoing
+        } else {
+            Direction::Incoming
+        }
+    }
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            Topic::Get => "get",
+            Topic::Update => "update",
+            Topic::Delete => "delete",
+            Topic::GetAccepted => "get/accepted",
+            Topic::GetRejected => "get/rejected",
+            Topic::UpdateDelta => "update/delta",
+            Topic::UpdateAccepted => "update/accepted",
+            Topic::UpdateDocuments => "update/documents",
+            Topic::UpdateRejected => "update/rejected",
+            Topic::DeleteAccepted => "delete/accepted",
+            Topic::DeleteRejected => "delete/rejected",
+        }
+    }
+
+    /// Format the topic of the shadow `shadow_name` of `thing_name`, or of
+    /// its classic shadow if `shadow_name` is `None`
+    pub fn format<const L: usize>(
+        &self,
+        thing_name: &str,
+        shadow_name: Option<&str>,
+    ) -> Result<heapless::String<L>, ShadowError> {
+        validate_thing_name(thing_name).map_err(|_| ShadowError::InvalidThingName)?;
+
+        let mut topic_path = heapless::String::new();
+        match shadow_name {
+            Some(shadow_name) => {
+                validate_shadow_name(shadow_name)?;
+                topic_path.write_fmt(format_args!(
+                    "{}/{}/shadow/name/{}/{}",
+                    Self::PREFIX,
+                    thing_name,
+                    shadow_name,
+                    self.suffix()
+                ))
+            }
+            None => topic_path.write_fmt(format_args!(
+                "{}/{}/shadow/{}",
+                Self::PREFIX,
+                thing_name,
+                self.suffix()
+            )),
+        }
+        .map_err(|_| ShadowError::Overflow)?;
+
+        Ok(topic_path)
+    }
+}
+
+/// Check that `shadow_name` is a valid name of a named shadow, i.e. at most
+/// [`MAX_SHADOW_NAME_LEN`] of `a-z`, `A-Z`, `0-9`, `:`, `_` and `-`.
+/// Names starting with `$` are reserved by AWS IoT.
+pub fn validate_shadow_name(shadow_name: &str) -> Result<(), ShadowError> {
+    let valid = !shadow_name.is_empty()
+        && shadow_name.len() <= MAX_SHADOW_NAME_LEN
+        && shadow_name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b':' || c == b'_' || c == b'-');
+
+    if !valid {
+        return Err(ShadowError::InvalidShadowName);
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct Subscribe<const N: usize> {
+    topics: heapless::Vec<(Topic, QoS), N>,
+}
+
+impl<const N: usize> Subscribe<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn topic(self, topic: Topic, qos: QoS) -> Self {
+        // Ignore attempts to subscribe to outgoing topics
+        if topic.direction() != Direction::Incoming {
+            return self;
+        }
+
+        if self.topics.iter().any(|(t, _)| t == &topic) {
+            return self;
+        }
+
+        let mut topics = self.topics;
+        topics.push((topic, qos)).ok();
+
+        Self { topics }
+    }
+
+    pub fn topics(
+        self,
+        thing_name: &str,
+        shadow_name: Option<&str>,
+    ) -> Result<heapless::Vec<(heapless::String<MAX_TOPIC_LEN>, QoS), N>, ShadowError> {
+        self.topics
+            .iter()
+            .map(|(topic, qos)| Ok((topic.format(thing_name, shadow_name)?, *qos)))
+            .collect()
+    }
+
+    /// Subscribe to the topics of the shadow `shadow_name` of the thing of
+    /// the MQTT client, or of its classic shadow if `shadow_name` is `None`
+    pub fn send<M: Mqtt>(self, mqtt: &M, shadow_name: Option<&str>) -> Result<(), ShadowError> {
+        if self.topics.is_empty() {
+            return Ok(());
+        }
+
+        let topic_paths = self.topics(mqtt.client_id(), shadow_name)?;
+
+        let topics: heapless::Vec<_, N> = topic_paths
+            .iter()
+            .map(|(s, qos)| SubscribeTopic {
+                topic_path: s.as_str(),
+                qos: *qos,
+            })
+            .collect();
+
+        crate::rustot_log!(debug, "Subscribing!");
+
+        for t in topics.chunks(5) {
+            mqtt.subscribe(t)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Unsubscribe<const N: usize> {
+    topics: heapless::Vec<Topic, N>,
+}
+
+impl<const N: usize> Unsubscribe<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn topic(self, topic: Topic) -> Self {
+        // Ignore attempts to unsubscribe from outgoing topics
+        if topic.direction() != Direction::Incoming {
+            return self;
+        }
+
+        if self.topics.iter().any(|t| t == &topic) {
+            return self;
+        }
+
+        let mut topics = self.topics;
+        topics.push(topic).ok();
+        Self { topics }
+    }
+
+    pub fn topics(
+        self,
+        thing_name: &str,
+        shadow_name: Option<&str>,
+    ) -> Result<heapless::Vec<heapless::String<MAX_TOPIC_LEN>, N>, ShadowError> {
+        self.topics
+            .iter()
+            .map(|topic| topic.format(thing_name, shadow_name))
+            .collect()
+    }
+
+    pub fn send<M: Mqtt>(self, mqtt: &M, shadow_name: Option<&str>) -> Result<(), ShadowError> {
+        if self.topics.is_empty() {
+            return Ok(());
+        }
+
+        let topic_paths = self.topics(mqtt.client_id(), shadow_name)?;
+        let topics: heapless::Vec<_, N> = topic_paths.iter().map(|s| s.as_str()).collect();
+
+        for t in topics.chunks(5) {
+            mqtt.unsubscribe(t)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::jobs::MAX_THING_NAME_LEN;
+
+    #[test]
+    fn format_classic_and_named() {
+        assert_eq!(
+            Topic::UpdateDelta
+                .format::<MAX_TOPIC_LEN>("test_client", None)
+                .unwrap()
+                .as_str(),
+            "$aws/things/test_client/shadow/update/delta"
+        );
+        assert_eq!(
+            Topic::Get
+                .format::<MAX_TOPIC_LEN>("test_client", Some("config"))
+                .unwrap()
+                .as_str(),
+            "$aws/things/test_client/shadow/name/config/get"
+        );
+
+        // The longest possible topic fits
+        let thing_name = "t".repeat(MAX_THING_NAME_LEN);
+        let shadow_name = "s".repeat(MAX_SHADOW_NAME_LEN);
+        assert!(Topic::UpdateDocuments
+            .format::<MAX_TOPIC_LEN>(&thing_name, Some(&shadow_name))
+            .is_ok());
+    }
+
+    #[test]
+    fn parse_classic_and_named() {
+        assert_eq!(
+            Topic::from_str("$aws/things/test_client/shadow/get/accepted"),
+            Some((Topic::GetAccepted, "test_client", None))
+        );
+        assert_eq!(
+            Topic::from_str("$aws/things/test_client/shadow/name/diagnostics/update/delta"),
+            Some((Topic::UpdateDelta, "test_client", Some("diagnostics")))
+        );
+        assert_eq!(
+            Topic::from_str("$aws/things/test_client/shadow/name/diagnostics"),
+            None
+        );
+        assert_eq!(
+            Topic::from_str("$aws/things/test_client/jobs/notify-next"),
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_shadow_name() {
+        let format =
+            |shadow_name| Topic::Get.format::<MAX_TOPIC_LEN>("test_client", Some(shadow_name));
+
+        assert!(format("my-shadow:01_a").is_ok());
+        assert_eq!(format(""), Err(ShadowError::InvalidShadowName));
+        assert_eq!(format("$config"), Err(ShadowError::InvalidShadowName));
+        assert_eq!(format("my/shadow"), Err(ShadowError::InvalidShadowName));
+        assert_eq!(
+            format(&"a".repeat(MAX_SHADOW_NAME_LEN + 1)),
+            Err(ShadowError::InvalidShadowName)
+        );
+        assert_eq!(
+            Topic::Get.format::<MAX_TOPIC_LEN>("my/thing", None),
+            Err(ShadowError::InvalidThingName)
+        );
+    }
+}
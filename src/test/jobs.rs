@@ -0,0 +1,375 @@
+use mqttrust::{encoding::v4::decode_slice, Mqtt, Packet};
+use serde::Deserialize;
+
+use super::{MockMqtt, MockResponse};
+use crate::jobs::{data_types::JobStatus, StatusDetails, NEXT_JOB_ID};
+
+///
+/// Mock AWS IoT Jobs service used for unit tests. Answers the
+/// GetPendingJobExecutions, StartNextPendingJobExecution, DescribeJobExecution
+/// and UpdateJobExecution requests published through a `MockMqtt`, keeping
+/// track of the status and version of each job execution of the thing.
+///
+pub struct MockJobs {
+    pub jobs: Vec<MockJob>,
+    /// Error code to reject the next update with, whatever its contents
+    reject_update: Option<&'static str>,
+    timestamp: i64,
+}
+
+/// Job execution of the mock jobs service
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockJob {
+    pub job_id: String,
+    /// Job document, as JSON
+    pub document: String,
+    pub status: JobStatus,
+    pub status_details: Option<StatusDetails>,
+    pub version_number: i64,
+    pub execution_number: i64,
+    /// Number of updates accepted so far
+    pub updates: usize,
+}
+
+#[derive(Deserialize)]
+struct Request<'a> {
+    #[serde(rename = "clientToken")]
+    client_token: Option<&'a str>,
+    #[serde(rename = "executionNumber")]
+    execution_number: Option<i64>,
+    #[serde(rename = "expectedVersion")]
+    expected_version: Option<i64>,
+    #[serde(rename = "includeJobDocument")]
+    include_job_document: Option<bool>,
+    #[serde(rename = "includeJobExecutionState")]
+    include_job_execution_state: Option<bool>,
+    #[serde(rename = "maxResults")]
+    _max_results: Option<u8>,
+    #[serde(rename = "nextToken")]
+    _next_token: Option<&'a str>,
+    status: Option<JobStatus>,
+    #[serde(rename = "statusDetails")]
+    status_details: Option<StatusDetails>,
+    #[serde(rename = "stepTimeoutInMinutes")]
+    _step_timeout_in_minutes: Option<i64>,
+}
+
+impl MockJob {
+    fn active(&self) -> bool {
+        matches!(self.status, JobStatus::Queued | JobStatus::InProgress)
+    }
+
+    fn execution(&self, include_job_document: bool, timestamp: i64) -> String {
+        let document = if include_job_document {
+            format!(r#","jobDocument":{}"#, self.document)
+        } else {
+            String::new()
+        };
+        format!(
+            r#"{{"jobId":"{}","status":{},"queuedAt":1587036256,"lastUpdatedAt":{},"versionNumber":{},"executionNumber":{}{}}}"#,
+            self.job_id,
+            json(&self.status),
+            timestamp,
+            self.version_number,
+            self.execution_number,
+            document
+        )
+    }
+
+    fn execution_state(&self) -> String {
+        format!(
+            r#"{{"status":{},"versionNumber":{}}}"#,
+            json(&self.status),
+            self.version_number
+        )
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            r#"{{"jobId":"{}","queuedAt":1587036256,"lastUpdatedAt":1587381778,"versionNumber":{},"executionNumber":{}}}"#,
+            self.job_id, self.version_number, self.execution_number
+        )
+    }
+}
+
+impl MockJobs {
+    pub fn new() -> Self {
+        Self {
+            jobs: Vec::new(),
+            reject_update: None,
+            timestamp: 1587381778,
+        }
+    }
+
+    /// Queue a job execution, with `document` given as JSON
+    pub fn queue(&mut self, job_id: &str, document: &str) {
+        self.jobs.push(MockJob {
+            job_id: job_id.to_string(),
+            document: document.to_string(),
+            status: JobStatus::Queued,
+            status_details: None,
+            version_number: 1,
+            execution_number: 1,
+            updates: 0,
+        });
+    }
+
+    pub fn job(&self, job_id: &str) -> Option<&MockJob> {
+        self.jobs.iter().find(|job| job.job_id == job_id)
+    }
+
+    /// Reject the next update with `code`, e.g. `RequestThrottled`
+    pub fn reject_next_update(&mut self, code: &'static str) {
+        self.reject_update = Some(code);
+    }
+
+    /// Cancel `job_id` in the cloud, returning the `notify-next` notification
+    /// the device would receive
+    pub fn cancel(&mut self, client_id: &str, job_id: &str) -> MockResponse {
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.job_id == job_id) {
+            job.status = JobStatus::Canceled;
+            job.version_number += 1;
+        }
+        self.notify_next(client_id)
+    }
+
+    /// Answer all jobs requests published on `mqtt` so far, in order.
+    ///
+    /// Any other packets sent by the client are dropped.
+    pub fn serve(&mut self, mqtt: &MockMqtt) -> Vec<MockResponse> {
+        let packets: Vec<Vec<u8>> = mqtt.tx.borrow_mut().drain(..).collect();
+        let prefix = format!("$aws/things/{}/jobs/", mqtt.client_id());
+        let mut responses = Vec::new();
+
+        for bytes in packets {
+            let publish = match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Publish(p)) => p,
+                _ => continue,
+            };
+            let api = match publish.topic_name.strip_prefix(prefix.as_str()) {
+                Some(api) => api,
+                None => continue,
+            };
+            let request: Request = serde_json_core::from_slice(publish.payload).unwrap().0;
+            self.timestamp += 1;
+
+            match api {
+                "get" => responses.push(self.get_pending(&prefix, &request)),
+                "start-next" => responses.push(self.start_next(&prefix, &request)),
+                _ => match (api.strip_suffix("/get"), api.strip_suffix("/update")) {
+                    (Some(job_id), _) => responses.push(self.describe(&prefix, job_id, &request)),
+                    (_, Some(job_id)) => {
+                        let (response, ended) = self.update(&prefix, job_id, &request);
+                        responses.push(response);
+                        if ended {
+                            responses.push(self.notify_next(mqtt.client_id()));
+                        }
+                    }
+                    _ => continue,
+                },
+            }
+        }
+        responses
+    }
+
+    fn get_pending(&self, prefix: &str, request: &Request) -> MockResponse {
+        let list = |status| {
+            self.jobs
+                .iter()
+                .filter(|job| job.status == status)
+                .map(MockJob::summary)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        response(
+            format!("{}get/accepted", prefix),
+            format!(
+                r#"{{"inProgressJobs":[{}],"queuedJobs":[{}],"timestamp":{},"clientToken":"{}"}}"#,
+                list(JobStatus::InProgress),
+                list(JobStatus::Queued),
+                self.timestamp,
+                request.client_token.unwrap_or_default()
+            ),
+        )
+    }
+
+    fn start_next(&mut self, prefix: &str, request: &Request) -> MockResponse {
+        let timestamp = self.timestamp;
+        let execution = self.next().map(|i| {
+            let job = &mut self.jobs[i];
+            if job.status == JobStatus::Queued {
+                job.status = JobStatus::InProgress;
+                job.version_number += 1;
+            }
+            format!(r#","execution":{}"#, job.execution(true, timestamp))
+        });
+        response(
+            format!("{}start-next/accepted", prefix),
+            format!(
+                r#"{{"clientToken":"{}","timestamp":{}{}}}"#,
+                request.client_token.unwrap_or_default(),
+                timestamp,
+                execution.unwrap_or_default()
+            ),
+        )
+    }
+
+    fn describe(&self, prefix: &str, job_id: &str, request: &Request) -> MockResponse {
+        let job = match job_id {
+            NEXT_JOB_ID => self.next().map(|i| &self.jobs[i]),
+            _ => self.jobs.iter().find(|job| {
+                job.job_id == job_id
+                    && request
+                        .execution_number
+                        .map_or(true, |number| number == job.execution_number)
+            }),
+        };
+
+        match job {
+            Some(job) => response(
+                format!("{}{}/get/accepted", prefix, job_id),
+                format!(
+                    r#"{{"clientToken":"{}","timestamp":{},"execution":{}}}"#,
+                    request.client_token.unwrap_or_default(),
+                    self.timestamp,
+                    job.execution(request.include_job_document.unwrap_or(true), self.timestamp)
+                ),
+            ),
+            None => self.rejected(
+                format!("{}{}/get/rejected", prefix, job_id),
+                request,
+                "ResourceNotFound",
+                None,
+            ),
+        }
+    }
+
+    /// Update `job_id`, also returning whether the job reached a terminal
+    /// state
+    fn update(&mut self, prefix: &str, job_id: &str, request: &Request) -> (MockResponse, bool) {
+        let rejected = format!("{}{}/update/rejected", prefix, job_id);
+        let i = match self.jobs.iter().position(|job| job.job_id == job_id) {
+            Some(i) => i,
+            None => {
+                return (
+                    self.rejected(rejected, request, "ResourceNotFound", None),
+                    false,
+                )
+            }
+        };
+
+        if let Some(code) = self.reject_update.take() {
+            return (self.rejected(rejected, request, code, None), false);
+        }
+
+        let job = &self.jobs[i];
+        if request
+            .expected_version
+            .map_or(false, |version| version != job.version_number)
+        {
+            let state = job.execution_state();
+            return (
+                self.rejected(rejected, request, "VersionMismatch", Some(state)),
+                false,
+            );
+        }
+        if !job.active() {
+            let state = job.execution_state();
+            return (
+                self.rejected(rejected, request, "InvalidStateTransition", Some(state)),
+                false,
+            );
+        }
+
+        let job = &mut self.jobs[i];
+        job.status = request.status.unwrap_or(job.status);
+        if request.status_details.is_some() {
+            job.status_details = request.status_details.clone();
+        }
+        job.version_number += 1;
+        job.updates += 1;
+
+        let execution_state = match request.include_job_execution_state {
+            Some(true) => format!(r#""executionState":{},"#, job.execution_state()),
+            _ => String::new(),
+        };
+        let ended = !job.active();
+        (
+            response(
+                format!("{}{}/update/accepted", prefix, job_id),
+                format!(
+                    r#"{{{}"timestamp":{},"clientToken":"{}"}}"#,
+                    execution_state,
+                    self.timestamp,
+                    request.client_token.unwrap_or_default()
+                ),
+            ),
+            ended,
+        )
+    }
+
+    fn notify_next(&self, client_id: &str) -> MockResponse {
+        let execution = self
+            .jobs
+            .iter()
+            .find(|job| job.active())
+            .map(|job| format!(r#","execution":{}"#, job.execution(true, self.timestamp)));
+        response(
+            format!("$aws/things/{}/jobs/notify-next", client_id),
+            format!(
+                r#"{{"timestamp":{}{}}}"#,
+                self.timestamp,
+                execution.unwrap_or_default()
+            ),
+        )
+    }
+
+    fn rejected(
+        &self,
+        topic: String,
+        request: &Request,
+        code: &str,
+        execution_state: Option<String>,
+    ) -> MockResponse {
+        let execution_state = execution_state
+            .map(|state| format!(r#","executionState":{}"#, state))
+            .unwrap_or_default();
+        response(
+            topic,
+            format!(
+                r#"{{"code":"{}","message":"{}","clientToken":"{}","timestamp":{}{}}}"#,
+                code,
+                code,
+                request.client_token.unwrap_or_default(),
+                self.timestamp,
+                execution_state
+            ),
+        )
+    }
+
+    /// Index of the next pending job execution, the ones in progress first
+    fn next(&self) -> Option<usize> {
+        self.jobs
+            .iter()
+            .position(|job| job.status == JobStatus::InProgress)
+            .or_else(|| {
+                self.jobs
+                    .iter()
+                    .position(|job| job.status == JobStatus::Queued)
+            })
+    }
+}
+
+fn response(topic: String, payload: String) -> MockResponse {
+    MockResponse {
+        topic,
+        payload: payload.into_bytes(),
+    }
+}
+
+fn json(status: &JobStatus) -> String {
+    serde_json_core::to_string::<_, 16>(status)
+        .unwrap()
+        .as_str()
+        .to_string()
+}
@@ -1,7 +1,16 @@
-use std::{cell::RefCell, collections::VecDeque};
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+};
 
 use mqttrust::{encoding::v4::encode_slice, Mqtt, MqttError, Packet};
 
+use crate::ota::clock::Clock;
+
+pub mod jobs;
+#[cfg(feature = "ota_mqtt_data")]
+pub mod stream;
+
 ///
 /// Mock Mqtt client used for unit tests. Implements `mqttrust::Mqtt` trait.
 ///
@@ -38,3 +47,27 @@ impl Mqtt for MockMqtt {
         "test_client"
     }
 }
+
+/// Response of a mock AWS IoT service, to be handed to the agent
+pub struct MockResponse {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+///
+/// Mock clock used for unit tests, advanced manually through `set`.
+///
+#[derive(Default)]
+pub struct MockClock(Cell<u64>);
+
+impl MockClock {
+    pub fn set(&self, now_ms: u64) {
+        self.0.set(now_ms);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_ms(&self) -> u64 {
+        self.0.get()
+    }
+}
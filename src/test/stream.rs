@@ -0,0 +1,201 @@
+use mqttrust::{encoding::v4::decode_slice, Packet};
+
+use super::{MockMqtt, MockResponse};
+use crate::ota::encoding::cbor::{self, DescribeStreamResponse, Reader, StreamFile};
+use crate::ota::error::OtaError;
+
+///
+/// Mock AWS IoT streaming service used for unit tests. Answers the
+/// `DescribeStream` and `GetStream` requests published through a `MockMqtt`
+/// with the blocks of a synthetic file.
+///
+pub struct MockStream {
+    pub file_id: u8,
+    pub file_size: usize,
+    /// Number of `GetStream` requests answered so far
+    pub requests: usize,
+}
+
+struct GetStream {
+    file_id: u8,
+    block_size: usize,
+    block_offset: usize,
+    bitmap: Option<Vec<u8>>,
+    number_of_blocks: Option<usize>,
+}
+
+impl MockStream {
+    pub fn new(file_id: u8, file_size: usize) -> Self {
+        Self {
+            file_id,
+            file_size,
+            requests: 0,
+        }
+    }
+
+    /// Byte at `offset` of the synthetic file
+    pub fn byte(offset: usize) -> u8 {
+        (offset % 251) as u8
+    }
+
+    /// Contents of the synthetic file
+    pub fn file(&self) -> Vec<u8> {
+        (0..self.file_size).map(Self::byte).collect()
+    }
+
+    /// Answer all stream requests published on `mqtt` so far, in order.
+    ///
+    /// Any other packets sent by the client are dropped.
+    pub fn serve(&mut self, mqtt: &MockMqtt) -> Vec<MockResponse> {
+        let packets: Vec<Vec<u8>> = mqtt.tx.borrow_mut().drain(..).collect();
+        let mut responses = Vec::new();
+
+        for bytes in packets {
+            let publish = match decode_slice(bytes.as_slice()).unwrap() {
+                Some(Packet::Publish(p)) => p,
+                _ => continue,
+            };
+
+            let prefix = match publish.topic_name.strip_suffix("/get/cbor") {
+                Some(prefix) => prefix,
+                None => match publish.topic_name.strip_suffix("/describe/cbor") {
+                    Some(prefix) => {
+                        responses.push(self.describe(prefix));
+                        continue;
+                    }
+                    None => continue,
+                },
+            };
+
+            let request = Self::decode_get_stream(publish.payload).unwrap();
+            assert_eq!(request.file_id, self.file_id);
+            self.requests += 1;
+
+            for block_id in self.requested_blocks(&request) {
+                responses.push(MockResponse {
+                    topic: format!("{}/data/cbor", prefix),
+                    payload: self.block(block_id, request.block_size),
+                });
+            }
+        }
+
+        responses
+    }
+
+    fn describe(&self, prefix: &str) -> MockResponse {
+        let files = [StreamFile {
+            file_id: self.file_id,
+            file_size: self.file_size,
+        }];
+        let buf = &mut [0u8; 64];
+        let len = cbor::to_slice(
+            &DescribeStreamResponse {
+                client_token: None,
+                stream_version: 1,
+                description: "",
+                files: &files,
+            },
+            buf,
+        )
+        .unwrap();
+
+        MockResponse {
+            topic: format!("{}/description/cbor", prefix),
+            payload: buf[..len].to_vec(),
+        }
+    }
+
+    fn requested_blocks(&self, request: &GetStream) -> Vec<usize> {
+        let total_blocks = (self.file_size + request.block_size - 1) / request.block_size;
+
+        let blocks: Vec<usize> = match request.bitmap {
+            Some(ref bitmap) => (0..bitmap.len() * 8)
+                .filter(|bit| bitmap[bit / 8] & (1 << (bit % 8)) != 0)
+                .map(|bit| request.block_offset + bit)
+                .collect(),
+            None => (request.block_offset..total_blocks)
+                .take(request.number_of_blocks.unwrap_or(1))
+                .collect(),
+        };
+
+        blocks
+            .into_iter()
+            .filter(|block_id| *block_id < total_blocks)
+            .collect()
+    }
+
+    /// Encode block `block_id` of the file as a `GetStream` response
+    fn block(&self, block_id: usize, block_size: usize) -> Vec<u8> {
+        let start = block_id * block_size;
+        let end = core::cmp::min(start + block_size, self.file_size);
+        let data: Vec<u8> = (start..end).map(Self::byte).collect();
+
+        let mut payload = vec![0xa4];
+        for (key, value) in [
+            ("f", self.file_id as usize),
+            ("i", block_id),
+            ("l", data.len()),
+        ] {
+            Self::encode_head(&mut payload, 3, key.len());
+            payload.extend_from_slice(key.as_bytes());
+            Self::encode_head(&mut payload, 0, value);
+        }
+        Self::encode_head(&mut payload, 3, 1);
+        payload.push(b'p');
+        Self::encode_head(&mut payload, 2, data.len());
+        payload.extend_from_slice(&data);
+        payload
+    }
+
+    fn encode_head(buf: &mut Vec<u8>, major: u8, value: usize) {
+        let major = major << 5;
+        match value {
+            0..=23 => buf.push(major | value as u8),
+            24..=0xFF => buf.extend_from_slice(&[major | 24, value as u8]),
+            0x100..=0xFFFF => {
+                buf.push(major | 25);
+                buf.extend_from_slice(&(value as u16).to_be_bytes());
+            }
+            _ => {
+                buf.push(major | 26);
+                buf.extend_from_slice(&(value as u32).to_be_bytes());
+            }
+        }
+    }
+
+    fn decode_get_stream(payload: &[u8]) -> Result<GetStream, OtaError> {
+        let mut reader = Reader::new(payload);
+        let mut remaining = match reader.header()? {
+            (5, len) => len,
+            _ => return Err(OtaError::Encoding),
+        };
+
+        let mut request = GetStream {
+            file_id: 0,
+            block_size: 0,
+            block_offset: 0,
+            bitmap: None,
+            number_of_blocks: None,
+        };
+
+        loop {
+            match remaining {
+                Some(0) => break,
+                Some(ref mut len) => *len -= 1,
+                None if reader.peek()? == Reader::BREAK => break,
+                None => {}
+            }
+
+            match reader.text()? {
+                "f" => request.file_id = reader.uint()? as u8,
+                "l" => request.block_size = reader.uint()? as usize,
+                "o" => request.block_offset = reader.uint()? as usize,
+                "b" => request.bitmap = Some(reader.bytes()?.to_vec()),
+                "n" => request.number_of_blocks = Some(reader.uint()? as usize),
+                _ => reader.skip()?,
+            }
+        }
+
+        Ok(request)
+    }
+}